@@ -15,6 +15,17 @@ fn main() {
     .unwrap();
     println!("cargo:rerun-if-changed=build.rs");
 
+    // Cap'n Proto bindings, included by src/tower_defense_capnp.rs
+    #[cfg(feature = "rpc")]
+    {
+        println!("cargo:rerun-if-changed=schema/tower_defense.capnp");
+        capnpc::CompilerCommand::new()
+            .src_prefix("schema")
+            .file("schema/tower_defense.capnp")
+            .run()
+            .expect("failed to compile schema/tower_defense.capnp, is 'capnp' installed?");
+    }
+
     // Check if we're building on the Windows platform, and if so, it'll be "DLL" based rather than "SO"
     if env::var("CARGO_CFG_TARGET_FAMILY").unwrap() == "windows" {
         // Tell Rust where to find SDL2.dll
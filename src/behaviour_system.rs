@@ -0,0 +1,679 @@
+// Behaviour trees for the Simulation's computer-controlled walkers (i.e. wave creeps), so that
+// each kind of enemy can behave differently: one follows its path no matter what, another
+// raids whatever structure it comes across and runs when hurt.  Trees are data (see Scenario),
+// picked per prototype (EntityPrototype::behaviour_id), and ticked once per Simulation tick for
+// every agent.  Actions only decide where to go and what to fire at; walking happens after the
+// tree is ticked, a cell at a time along a path (see pathfinding_system) that each agent keeps
+// until it goes stale.  NOTE: ai.rs is the specs frontend's wandering, it plays no part in the
+// Simulation
+use crate::diplomacy_system::Diplomacy;
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::{get_distance_squared, Map};
+use crate::pathfinding_system;
+use crate::prototype_system;
+use crate::status_effect_system::BASE_PERCENT;
+use crate::unit_system;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+pub type TBehaviourID = u16;
+pub type TPathID = u16;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum BehaviourNodes {
+    // composites
+    Sequence(Vec<BehaviourNodes>), // each in turn, stops at the first that does not succeed
+    Selector(Vec<BehaviourNodes>), // each in turn, stops at the first that does not fail
+    // conditions
+    HealthBelowPercent(u8),
+    // actions
+    FollowPath {
+        path_id: TPathID, // Running until at its last waypoint, then Success
+    },
+    AttackNearestStructure {
+        sight: u16, // in cells; Failure when there is none (or it is unarmed), else Running
+    },
+    Flee {
+        distance: u16, // runs from the nearest enemy within; Failure if there is none, or cornered
+    },
+    Retarget {
+        interval_millis: u128, // forgets the target every so often (while ticked); always Success
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum BehaviourStatus {
+    Success,
+    Failure,
+    Running,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Behaviour {
+    pub id: TBehaviourID,
+    pub name: String,
+    pub root: BehaviourNodes,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CreepPath {
+    pub id: TPathID,
+    pub waypoints: Vec<(u16, u16)>, // walked in order, the way between them is up to pathfinding
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Agent {
+    pub entity_id: TEntityID,
+    pub behaviour_id: TBehaviourID,
+    pub target: Option<TEntityID>, // kept until it dies, leaves sight or is Retarget'ed
+    pub waypoint_index: usize,
+    retarget_millis: u128,
+    move_millis: u128,
+    #[serde(default)] // appended after the fact, agents saved before it start without a path
+    path: Vec<(u16, u16)>, // cells still to walk, the next one last (see find_path())
+    #[serde(default)]
+    path_goal: Option<(u16, u16)>, // where the path leads to
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct BehaviourSystem {
+    behaviours: Vec<Behaviour>, // sorted by id
+    paths: Vec<CreepPath>,      // sorted by id
+    agents: Vec<Agent>,         // sorted by entity_id
+}
+
+// what the tree is ticked with; actions leave where the agent should head to in destination
+struct Blackboard<'a> {
+    map: &'a Map,
+    diplomacy: &'a Diplomacy,
+    paths: &'a Vec<CreepPath>,
+    tick_millis: u128,
+    entity: Entity,
+    destination: Option<(u16, u16)>,
+}
+
+// blocking, unlike entity_system::try_get(), so that a busy lock never changes what agents do
+fn get_entity(entity_id: TEntityID) -> Option<Entity> {
+    return entity_system::modify(&entity_id, |e| *e).ok();
+}
+
+fn tick_node(node: &BehaviourNodes, agent: &mut Agent, board: &mut Blackboard) -> BehaviourStatus {
+    let position = (board.entity.map_x, board.entity.map_y);
+    match node {
+        BehaviourNodes::Sequence(children) => {
+            for child in children.iter() {
+                let status = tick_node(child, agent, board);
+                if status != BehaviourStatus::Success {
+                    return status;
+                }
+            }
+            return BehaviourStatus::Success;
+        }
+        BehaviourNodes::Selector(children) => {
+            for child in children.iter() {
+                let status = tick_node(child, agent, board);
+                if status != BehaviourStatus::Failure {
+                    return status;
+                }
+            }
+            return BehaviourStatus::Failure;
+        }
+        BehaviourNodes::HealthBelowPercent(percent) => {
+            let entity = &board.entity;
+            let is_below = entity.is_destructible()
+                && (entity.health_points as u32 * 100)
+                    < (entity.max_health_points as u32 * *percent as u32);
+            return match is_below {
+                true => BehaviourStatus::Success,
+                false => BehaviourStatus::Failure,
+            };
+        }
+        BehaviourNodes::FollowPath { path_id } => {
+            let waypoints = match board.paths.binary_search_by(|p| p.id.cmp(path_id)) {
+                Ok(index) => &board.paths[index].waypoints,
+                Err(_) => return BehaviourStatus::Failure, // see BehaviourSystem::new()
+            };
+            while agent.waypoint_index < waypoints.len()
+                && waypoints[agent.waypoint_index] == position
+            {
+                agent.waypoint_index += 1;
+            }
+            return match waypoints.get(agent.waypoint_index) {
+                Some(waypoint) => {
+                    board.destination = Some(*waypoint);
+                    BehaviourStatus::Running
+                }
+                None => BehaviourStatus::Success,
+            };
+        }
+        BehaviourNodes::AttackNearestStructure { sight } => {
+            if board.entity.weapon.is_armed() == false {
+                return BehaviourStatus::Failure;
+            }
+            let range = *sight as u32 * *sight as u32;
+            let owner = board.entity.owner;
+            let is_valid = |target: &Entity| {
+                target.is_alive()
+                    && target.is_structure()
+                    && board.diplomacy.can_attack(owner, &target.id)
+                    && get_distance_squared(position, (target.map_x, target.map_y)) <= range
+            };
+            let target = match agent.target.and_then(get_entity) {
+                Some(t) if is_valid(&t) => Some(t),
+                _ => board
+                    .diplomacy
+                    .get_nearest_enemy_where(
+                        board.map,
+                        &agent.entity_id,
+                        *sight,
+                        Entity::is_structure,
+                    )
+                    .and_then(get_entity),
+            };
+            agent.target = target.map(|t| t.id);
+            let target = match target {
+                Some(t) => t,
+                None => return BehaviourStatus::Failure,
+            };
+            let weapon_range = board.entity.weapon.range as u32;
+            if get_distance_squared(position, (target.map_x, target.map_y))
+                <= weapon_range * weapon_range
+            {
+                unit_system::fire(&agent.entity_id, &target.id);
+            } else {
+                board.destination = Some((target.map_x, target.map_y));
+            }
+            return BehaviourStatus::Running;
+        }
+        BehaviourNodes::Flee { distance } => {
+            let threat = match board
+                .diplomacy
+                .get_nearest_enemy(board.map, &agent.entity_id, *distance)
+                .and_then(get_entity)
+            {
+                Some(t) => (t.map_x, t.map_y),
+                None => return BehaviourStatus::Failure,
+            };
+            // whichever neighbour gets it furthest away, if any gets it further at all
+            let mut best = (get_distance_squared(position, threat), position);
+            for cell in pathfinding_system::get_neighbours(board.map, position.0, position.1) {
+                let distance = get_distance_squared(cell, threat);
+                if distance > best.0 && pathfinding_system::is_passable(board.map, cell.0, cell.1) {
+                    best = (distance, cell);
+                }
+            }
+            if best.1 == position {
+                return BehaviourStatus::Failure; // cornered
+            }
+            board.destination = Some(best.1);
+            return BehaviourStatus::Running;
+        }
+        BehaviourNodes::Retarget { interval_millis } => {
+            agent.retarget_millis += board.tick_millis;
+            if agent.retarget_millis >= *interval_millis {
+                agent.retarget_millis = 0;
+                agent.target = None;
+            }
+            return BehaviourStatus::Success;
+        }
+    }
+}
+
+// the agent's path is only searched for again when it leads somewhere else, or the next cell
+// on it is no longer next to the agent or got blocked since (i.e. a structure was placed)
+fn is_path_stale(map: &Map, agent: &Agent, position: (u16, u16), destination: (u16, u16)) -> bool {
+    if agent.path_goal != Some(destination) {
+        return true;
+    }
+    return match agent.path.last() {
+        Some(next) => {
+            get_distance_squared(*next, position) != 1
+                || (*next != destination
+                    && pathfinding_system::is_passable(map, next.0, next.1) == false)
+        }
+        None => true,
+    };
+}
+
+// every path a tree references must exist, and composites must have something to run
+fn validate_node(node: &BehaviourNodes, paths: &Vec<CreepPath>) -> Result<(), String> {
+    match node {
+        BehaviourNodes::Sequence(children) | BehaviourNodes::Selector(children) => {
+            if children.is_empty() {
+                return Err("a Sequence/Selector has no children".to_owned());
+            }
+            for child in children.iter() {
+                validate_node(child, paths)?;
+            }
+        }
+        BehaviourNodes::FollowPath { path_id } => {
+            if paths.binary_search_by(|p| p.id.cmp(path_id)).is_err() {
+                return Err(format!("pathID={} does not exist", path_id));
+            }
+        }
+        _ => {}
+    }
+    return Ok(());
+}
+
+impl BehaviourSystem {
+    pub fn new(
+        behaviours: Vec<Behaviour>,
+        paths: Vec<CreepPath>,
+    ) -> Result<BehaviourSystem, String> {
+        let mut paths = paths;
+        paths.sort_by_key(|p| p.id);
+        for pair in paths.windows(2) {
+            if pair[0].id == pair[1].id {
+                return Err(format!("pathID={} is defined more than once", pair[0].id));
+            }
+        }
+        if let Some(path) = paths.iter().find(|p| p.waypoints.is_empty()) {
+            return Err(format!("pathID={} has no waypoints", path.id));
+        }
+        let mut behaviours = behaviours;
+        behaviours.sort_by_key(|b| b.id);
+        for pair in behaviours.windows(2) {
+            if pair[0].id == pair[1].id {
+                return Err(format!(
+                    "behaviourID={} is defined more than once",
+                    pair[0].id
+                ));
+            }
+        }
+        for behaviour in behaviours.iter() {
+            if let Err(e) = validate_node(&behaviour.root, &paths) {
+                return Err(format!("behaviour '{}': {}", behaviour.name, e));
+            }
+        }
+        return Ok(BehaviourSystem {
+            behaviours,
+            paths,
+            agents: Vec::new(),
+        });
+    }
+
+    pub fn get_behaviour(self: &Self, behaviour_id: TBehaviourID) -> Option<&Behaviour> {
+        return match self
+            .behaviours
+            .binary_search_by(|b| b.id.cmp(&behaviour_id))
+        {
+            Ok(index) => Some(&self.behaviours[index]),
+            Err(_) => None,
+        };
+    }
+    pub fn get_agents(self: &Self) -> &Vec<Agent> {
+        return &self.agents;
+    }
+    pub fn get_agent(self: &Self, entity_id: &TEntityID) -> Option<&Agent> {
+        return match self.agents.binary_search_by(|a| a.entity_id.cmp(entity_id)) {
+            Ok(index) => Some(&self.agents[index]),
+            Err(_) => None,
+        };
+    }
+
+    // i.e. just spawned by a wave; Ok(false) when its prototype has no behaviour
+    pub fn add_agent(self: &mut Self, entity_id: &TEntityID) -> Result<bool, String> {
+        let behaviour_id = match entity_system::modify(entity_id, |e| e.prototype_id)?
+            .and_then(|id| prototype_system::get(&id))
+            .and_then(|p| p.behaviour_id)
+        {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        if self.get_behaviour(behaviour_id).is_none() {
+            return Err(format!("behaviourID={} does not exist", behaviour_id));
+        }
+        match self.agents.binary_search_by(|a| a.entity_id.cmp(entity_id)) {
+            Ok(_) => return Err(format!("entityID={} already has a behaviour", entity_id)),
+            Err(index) => self.agents.insert(
+                index,
+                Agent {
+                    entity_id: *entity_id,
+                    behaviour_id,
+                    target: None,
+                    waypoint_index: 0,
+                    retarget_millis: 0,
+                    move_millis: 0,
+                    path: Vec::new(),
+                    path_goal: None,
+                },
+            ),
+        }
+        return Ok(true);
+    }
+    pub fn remove(self: &mut Self, entity_id: &TEntityID) {
+        self.agents.retain(|a| a.entity_id != *entity_id);
+    }
+
+    // ticks every agent's tree, then walks them; kills only queue DeathEvents (same as units)
+    pub fn update(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        map: &mut Map,
+        diplomacy: &Diplomacy,
+    ) {
+        for agent in self.agents.iter_mut() {
+            let entity = match get_entity(agent.entity_id) {
+                Some(e) if e.is_alive() => e,
+                _ => continue, // dying this tick, taken off by the Simulation
+            };
+            let root = match self
+                .behaviours
+                .binary_search_by(|b| b.id.cmp(&agent.behaviour_id))
+            {
+                Ok(index) => &self.behaviours[index].root,
+                Err(_) => continue,
+            };
+            let mut board = Blackboard {
+                map,
+                diplomacy,
+                paths: &self.paths,
+                tick_millis: last_frame_delta_millis,
+                entity,
+                destination: None,
+            };
+            tick_node(root, agent, &mut board);
+            let destination = match board.destination {
+                Some(d) if d != (entity.map_x, entity.map_y) => d,
+                _ => {
+                    agent.move_millis = 0;
+                    continue;
+                }
+            };
+
+            // same pace as units (see unit_system), but around whatever is in the way
+            let speed_percent = entity.movement_speed_percent() as u128;
+            if entity.physics_info.max_velocity == 0 || speed_percent == 0 {
+                agent.move_millis = 0;
+                continue;
+            }
+            agent.move_millis += last_frame_delta_millis;
+            let millis_per_cell = 1000 * BASE_PERCENT as u128
+                / (entity.physics_info.max_velocity as u128 * speed_percent);
+            let (mut map_x, mut map_y) = (entity.map_x, entity.map_y);
+            while agent.move_millis >= millis_per_cell && (map_x, map_y) != destination {
+                if is_path_stale(map, agent, (map_x, map_y), destination) {
+                    agent.path =
+                        match pathfinding_system::find_path(map, (map_x, map_y), destination) {
+                            Some(path) => path.into_iter().rev().collect(),
+                            None => Vec::new(),
+                        };
+                    agent.path_goal = Some(destination);
+                }
+                let next = match agent.path.last() {
+                    Some(n) => *n,
+                    None => break, // no way there, wait for one to open up
+                };
+                if pathfinding_system::is_passable(map, next.0, next.1) == false {
+                    break; // only the destination itself can be blocked, i.e. a structure
+                }
+                if map
+                    .move_entity((map_x, map_y), next, agent.entity_id)
+                    .is_err()
+                {
+                    break; // no free layer on the cell, stays where it is
+                }
+                agent.path.pop();
+                agent.move_millis -= millis_per_cell;
+                (map_x, map_y) = next;
+            }
+            if (map_x, map_y) == (entity.map_x, entity.map_y) {
+                agent.move_millis = agent.move_millis.min(millis_per_cell);
+                continue;
+            }
+            let _ = entity_system::modify(&agent.entity_id, |e| {
+                e.map_x = map_x;
+                e.map_y = map_y;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage_system::{self, Damage, DamageTypes};
+    use crate::diplomacy_system::CREEP_PLAYER_ID;
+    use crate::prototype_system::{EntityPrototype, TPrototypeID};
+    use crate::simulation::Simulation;
+    use crate::test_helpers::{add_prototype, new_simulation, TestWorld};
+
+    // a fast creep that follows path 7 (see follow_path_behaviour)
+    fn add_runner() -> TPrototypeID {
+        return add_prototype("test runner", |p| {
+            p.max_health_points = 10;
+            p.max_velocity = 30; // a cell per tick
+            p.behaviour_id = Some(1);
+        });
+    }
+
+    fn follow_path_behaviour() -> Behaviour {
+        return Behaviour {
+            id: 1,
+            name: "runner".to_owned(),
+            root: BehaviourNodes::FollowPath { path_id: 7 },
+        };
+    }
+
+    fn spawn_agent(
+        simulation: &mut Simulation,
+        prototype_id: TPrototypeID,
+        x: u16,
+        y: u16,
+    ) -> TEntityID {
+        let entity_id = prototype_system::spawn(&prototype_id, x, y).unwrap();
+        simulation.map.place_entity(x, y, entity_id).unwrap();
+        simulation.behaviours.add_agent(&entity_id).unwrap();
+        return entity_id;
+    }
+
+    fn get_position(entity_id: TEntityID) -> (u16, u16) {
+        let entity = entity_system::try_get(entity_id).unwrap();
+        return (entity.map_x, entity.map_y);
+    }
+
+    #[test]
+    fn test_behaviours_must_reference_known_paths() {
+        let path = CreepPath {
+            id: 7,
+            waypoints: vec![(12, 2)],
+        };
+        let missing = Behaviour {
+            id: 3,
+            name: "lost".to_owned(),
+            root: BehaviourNodes::FollowPath { path_id: 8 },
+        };
+        assert!(BehaviourSystem::new(vec![missing], vec![path.clone()]).is_err());
+        assert!(BehaviourSystem::new(vec![follow_path_behaviour()], vec![path]).is_ok());
+    }
+
+    #[test]
+    fn test_only_entities_with_a_behaviour_are_agents() {
+        let _world = TestWorld::new();
+        let runner_id = add_runner();
+        let wall_id = add_prototype("test wall", |_| {});
+        let path = CreepPath {
+            id: 7,
+            waypoints: vec![(3, 1)],
+        };
+        let mut simulation = new_simulation(4, 4);
+        simulation.behaviours =
+            BehaviourSystem::new(vec![follow_path_behaviour()], vec![path]).unwrap();
+        let runner = prototype_system::spawn(&runner_id, 1, 1).unwrap();
+        let wall = prototype_system::spawn(&wall_id, 2, 2).unwrap();
+        assert_eq!(simulation.behaviours.add_agent(&runner), Ok(true));
+        assert_eq!(simulation.behaviours.add_agent(&wall), Ok(false));
+    }
+
+    #[test]
+    fn test_runner_and_raider_behave_differently() {
+        let _world = TestWorld::new();
+        let setup_attacker = |p: &mut EntityPrototype, behaviour_id| {
+            p.max_health_points = 10;
+            p.max_velocity = 30;
+            p.weapon.damage = 5;
+            p.weapon.range = 1;
+            p.weapon.fire_interval_millis = 100;
+            p.behaviour_id = Some(behaviour_id);
+        };
+        let runner_id = add_prototype("test runner", |p| setup_attacker(p, 1));
+        let raider_id = add_prototype("test raider", |p| setup_attacker(p, 2));
+        let wall_id = add_prototype("test wall", |_| {});
+        let tower_id = add_prototype("test tower", |p| p.max_health_points = 1000);
+
+        let path = CreepPath {
+            id: 7,
+            waypoints: vec![(12, 2)],
+        };
+        let follow = BehaviourNodes::FollowPath { path_id: 7 };
+        let behaviours = vec![
+            Behaviour {
+                id: 1,
+                name: "runner".to_owned(),
+                root: follow.clone(),
+            },
+            Behaviour {
+                id: 2,
+                name: "raider".to_owned(),
+                root: BehaviourNodes::Selector(vec![
+                    BehaviourNodes::Sequence(vec![
+                        BehaviourNodes::HealthBelowPercent(50),
+                        BehaviourNodes::Flee { distance: 3 },
+                    ]),
+                    BehaviourNodes::Sequence(vec![
+                        BehaviourNodes::Retarget {
+                            interval_millis: 1000,
+                        },
+                        BehaviourNodes::AttackNearestStructure { sight: 8 },
+                    ]),
+                    follow,
+                ]),
+            },
+        ];
+        let mut simulation = new_simulation(16, 8);
+        simulation.behaviours = BehaviourSystem::new(behaviours, vec![path]).unwrap();
+        let mut spawn = |prototype_id: TPrototypeID, map_x: u16, map_y: u16, owner| {
+            let entity_id = prototype_system::spawn(&prototype_id, map_x, map_y).unwrap();
+            entity_system::modify(&entity_id, |e| e.owner = owner).unwrap();
+            simulation
+                .map
+                .place_entity(map_x, map_y, entity_id)
+                .unwrap();
+            return entity_id;
+        };
+        // a wall down x=5 with a gap at the bottom
+        for map_y in 0..7 {
+            spawn(wall_id, 5, map_y, 0);
+        }
+        let tower = spawn(tower_id, 8, 6, 1);
+        let runner = spawn(runner_id, 1, 2, CREEP_PLAYER_ID);
+        let raider = spawn(raider_id, 1, 3, CREEP_PLAYER_ID);
+        simulation.behaviours.add_agent(&runner).unwrap();
+        simulation.behaviours.add_agent(&raider).unwrap();
+
+        // the runner goes around the wall to the end of its path and stays there, the raider
+        // goes for the tower it comes across on the way
+        for _ in 0..40 {
+            simulation.step_once();
+        }
+        assert_eq!(get_position(runner), (12, 2));
+        let tower_health = entity_system::try_get(tower).unwrap().health_points;
+        assert!(tower_health < 1000);
+        assert_eq!(
+            simulation.behaviours.get_agent(&raider).unwrap().target,
+            Some(tower)
+        );
+        let next_to_tower = get_distance_squared(get_position(raider), (8, 6)) <= 1;
+        assert!(next_to_tower);
+
+        // hurt, it runs away from the tower instead
+        damage_system::apply_damage(&raider, &Damage::new(6, DamageTypes::Physical, None)).unwrap();
+        for _ in 0..3 {
+            simulation.step_once();
+        }
+        assert!(get_distance_squared(get_position(raider), (8, 6)) > 2 * 2);
+    }
+
+    #[test]
+    fn test_agents_wait_at_crowded_cells() {
+        let _world = TestWorld::new();
+        let runner_id = add_runner();
+        let path = CreepPath {
+            id: 7,
+            waypoints: vec![(6, 2)],
+        };
+        let mut simulation = new_simulation(8, 4);
+        simulation.behaviours =
+            BehaviourSystem::new(vec![follow_path_behaviour()], vec![path]).unwrap();
+        // walkable, but with no room left for anyone else
+        while simulation.map.get_cell(3, 2).unwrap().layers.len() < 15 {
+            let entity_id = prototype_system::spawn(&runner_id, 3, 2).unwrap();
+            simulation.map.place_entity(3, 2, entity_id).unwrap();
+        }
+        let runner = spawn_agent(&mut simulation, runner_id, 1, 2);
+        for _ in 0..10 {
+            simulation.step_once();
+        }
+        assert_eq!(get_position(runner), (2, 2));
+        let cells: Vec<(u16, u16)> = simulation
+            .map
+            .iter_cells()
+            .filter(|(_, _, cell)| cell.contains_entity(&runner))
+            .map(|(map_x, map_y, _)| (map_x, map_y))
+            .collect();
+        assert_eq!(cells, vec![(2, 2)]);
+    }
+
+    fn start_long_walk() -> (Simulation, TEntityID) {
+        let runner_id = add_runner();
+        let path = CreepPath {
+            id: 7,
+            waypoints: vec![(10, 1)],
+        };
+        let mut simulation = new_simulation(12, 4);
+        simulation.behaviours =
+            BehaviourSystem::new(vec![follow_path_behaviour()], vec![path]).unwrap();
+        let runner = spawn_agent(&mut simulation, runner_id, 1, 1);
+        simulation.step_once();
+        return (simulation, runner);
+    }
+
+    #[test]
+    fn test_paths_are_kept_while_they_lead_to_the_goal() {
+        let _world = TestWorld::new();
+        let (simulation, runner) = start_long_walk();
+        let agent = simulation.behaviours.get_agent(&runner).unwrap();
+        assert_eq!(agent.path_goal, Some((10, 1)));
+        assert_eq!(agent.path.first(), Some(&(10, 1)));
+        let here = get_position(runner);
+        assert!(is_path_stale(&simulation.map, agent, here, (10, 1)) == false);
+        assert!(is_path_stale(&simulation.map, agent, here, (10, 2))); // somewhere else
+    }
+
+    #[test]
+    fn test_blocked_paths_are_found_again() {
+        let _world = TestWorld::new();
+        let (mut simulation, runner) = start_long_walk();
+        assert!(simulation
+            .behaviours
+            .get_agent(&runner)
+            .unwrap()
+            .path
+            .contains(&(5, 1)));
+
+        // a wall goes up on the way, which sends it around
+        let wall_id = add_prototype("test wall", |_| {});
+        let wall = prototype_system::spawn(&wall_id, 5, 1).unwrap();
+        simulation.map.place_entity(5, 1, wall).unwrap();
+        for _ in 0..3 {
+            simulation.step_once();
+        }
+        let agent = simulation.behaviours.get_agent(&runner).unwrap();
+        assert!(agent.path.contains(&(5, 1)) == false);
+        for _ in 0..15 {
+            simulation.step_once();
+        }
+        assert_eq!(get_position(runner), (10, 1));
+    }
+}
@@ -0,0 +1,96 @@
+// Runs a match in its own process and serves it over Cap'n Proto RPC (see rpc_system), so that
+// any number of clients can join it as one of the scenario's players.
+//
+//   $ cargo run --release --features rpc --bin game_server -- --scenario my.scenario --listen tcp:127.0.0.1:7777
+//
+// 'headless_sim --write-scenario <file>' is a quick way to get a scenario to start from
+use lib_tower_defense::map::Map;
+use lib_tower_defense::resource_system::read_resource;
+use lib_tower_defense::rpc_system::{self, ListenAddresses};
+use lib_tower_defense::scenario_system;
+use std::env;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "tcp:127.0.0.1:7777";
+
+const USAGE: &str = "usage: game_server --scenario <file> [options]
+    --scenario <file>   scenario to run
+    --map <file>        map to run it on (default: generated from the seed)
+    --seed <n>          overrides the scenario's seed
+    --listen <address>  tcp:<host:port> or unix:<path> (default: tcp:127.0.0.1:7777)";
+
+#[derive(Debug, Default)]
+struct Options {
+    scenario_file: String,
+    map_file: Option<String>,
+    seed: Option<u64>,
+    listen_address: String,
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        listen_address: DEFAULT_LISTEN_ADDRESS.to_owned(),
+        ..Default::default()
+    };
+    let mut args_iter = args.into_iter();
+    while let Some(arg) = args_iter.next() {
+        let mut value = || match args_iter.next() {
+            Some(v) => Ok(v),
+            None => Err(format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--scenario" => options.scenario_file = value()?,
+            "--map" => options.map_file = Some(value()?),
+            "--seed" => {
+                options.seed = Some(value()?.parse().map_err(|e| format!("--seed: {}", e))?)
+            }
+            "--listen" => options.listen_address = value()?,
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    if options.scenario_file.is_empty() {
+        return Err("--scenario is required".to_owned());
+    }
+    return Ok(options);
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut scenario = read_resource(
+        &options.scenario_file,
+        scenario_system::deserialize_scenario_for_load,
+    )?;
+    if let Some(seed) = options.seed {
+        scenario.seed = seed;
+    }
+    let map = match &options.map_file {
+        Some(file_paths) => Some(read_resource(file_paths, Map::deserialize_for_load)?),
+        None => None,
+    };
+    let address = ListenAddresses::parse(&options.listen_address)?;
+    let simulation = scenario.create_simulation(map)?;
+    println!(
+        "serving '{}' (seed {}) on {}",
+        scenario.name, scenario.seed, options.listen_address
+    );
+
+    // capnp-rpc is not Send, so everything runs on this one thread
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+    return tokio::task::LocalSet::new()
+        .block_on(&runtime, rpc_system::serve(simulation, address))
+        .map_err(|e| e.to_string());
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let result = parse_options(args).and_then(run);
+    if let Err(e) = result {
+        eprintln!("error: {}\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,573 @@
+// Headless simulation runner: no display, no terminal tricks, just the Simulation, so that it can
+// run on CI boxes for soak tests and for AI-vs-AI balance runs.
+//
+//   $ cargo run --release --bin headless_sim -- --scenario my.scenario --ticks 54000 --stats out.txt
+//   $ cargo run --release --bin headless_sim -- --skirmish --ai 1:hard --ai 2:easy --stats out.txt
+//
+// Without --scenario it runs a built-in one (see --write-scenario to get it as a starting point)
+use lib_tower_defense::computer_player_system::{ComputerSetup, Difficulties, Strategy};
+use lib_tower_defense::economy_system::{ItemStack, ItemTypes, TPlayerID};
+use lib_tower_defense::entity_system;
+use lib_tower_defense::lockstep_system::{LockstepConfig, LockstepMessages, LockstepPeer};
+use lib_tower_defense::map::Map;
+use lib_tower_defense::prediction_system::{AuthoritativeServer, ReconciliationMessages};
+use lib_tower_defense::prototype_system::EntityPrototype;
+use lib_tower_defense::replay_system::{self, Replay};
+use lib_tower_defense::resource_system::{read_resource, write_resource};
+use lib_tower_defense::savegame_system;
+use lib_tower_defense::scenario_system::{self, PlayerSetup, Scenario};
+use lib_tower_defense::simulation::{get_tick_millis, Simulation, TickReport, TICKS_PER_SECOND};
+use lib_tower_defense::transport_system::{LatencyTransport, TcpTransport};
+use lib_tower_defense::wave_system::{SpawnGroup, WaveDefinition, WaveEvents};
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+const DEFAULT_MAX_TICKS: u64 = 10 * 60 * TICKS_PER_SECOND; // 10 minutes of game time
+
+const USAGE: &str = "usage: headless_sim [options]
+    --scenario <file>        scenario to run (default: built-in)
+    --map <file>             map to run it on (default: generated from the seed)
+    --seed <n>               overrides the scenario's seed
+    --ticks <n>              stops after this many ticks (default: 10 minutes of game time)
+    --realtime               runs at wall-clock speed rather than as fast as possible
+    --stats <file>           also writes the final stats (key=value per line) to the file
+    --replay <file>          records the match as a replay
+    --save <file>            saves the game at the end
+    --write-scenario <file>  writes the built-in scenario and exits
+    --skirmish               built-in scenario is two computer players fighting it out, without waves
+    --ai <id>:<difficulty>   overrides a computer player's difficulty (easy, normal or hard), the
+                             same on every lockstep peer
+    --lockstep-host <addr>   plays in lockstep as the scenario's first player (but the computer
+                             players), waits for the others to join
+    --lockstep-join <addr>   plays in lockstep as --player, joining the host at <host:port>
+    --player <id>            which of the scenario's players this peer is (default: the first)
+    --input-delay <ticks>    lockstep input delay, the same on every peer (default: 3)
+    --serve <addr>           runs as the authoritative server for predicting clients (in real-time),
+                             waits for one client (see tui_client) per scenario player to join
+    --latency-ms <n>         holds back what the server sends and receives, to test prediction";
+
+#[derive(Debug, Default)]
+struct Options {
+    scenario_file: Option<String>,
+    map_file: Option<String>,
+    seed: Option<u64>,
+    max_ticks: u64,
+    is_realtime: bool,
+    stats_file: Option<String>,
+    replay_file: Option<String>,
+    save_file: Option<String>,
+    write_scenario_file: Option<String>,
+    lockstep_host_address: Option<String>,
+    lockstep_join_address: Option<String>,
+    player_id: Option<u8>,
+    input_delay_ticks: Option<u64>,
+    serve_address: Option<String>,
+    latency_millis: u64,
+    is_skirmish: bool,
+    difficulties: Vec<(TPlayerID, Difficulties)>,
+}
+
+#[derive(Debug, Default)]
+struct MatchStats {
+    ticks: u64,
+    wall_millis: u128,
+    waves_started: usize,
+    waves_cleared: usize,
+    spawned: usize,
+    spawn_failures: usize,
+    deaths: usize,
+    commands: usize,
+    rejected_commands: usize,
+    delivered_items: usize,
+    is_all_waves_cleared: bool,
+}
+
+impl MatchStats {
+    fn add(self: &mut Self, report: &TickReport) {
+        self.ticks = report.tick + 1;
+        for event in report.wave_events.iter() {
+            match event {
+                WaveEvents::WaveStarted { wave_index, .. } => {
+                    self.waves_started += 1;
+                    println!("[tick {}] wave {} started", report.tick, wave_index);
+                }
+                WaveEvents::EntitySpawned { .. } => self.spawned += 1,
+                WaveEvents::SpawnFailed { reason, .. } => {
+                    self.spawn_failures += 1;
+                    eprintln!("[tick {}] spawn failed: {}", report.tick, reason);
+                }
+                WaveEvents::WaveCleared { wave_index } => {
+                    self.waves_cleared += 1;
+                    println!("[tick {}] wave {} cleared", report.tick, wave_index);
+                }
+                WaveEvents::AllWavesCleared => self.is_all_waves_cleared = true,
+            }
+        }
+        self.deaths += report.death_events.len();
+        for commands in [&report.commands, &report.computer_commands] {
+            self.commands += commands.len();
+            self.rejected_commands += commands.iter().filter(|(_, r)| r.is_err()).count();
+        }
+        self.delivered_items += report.delivered.len();
+    }
+
+    fn to_lines(self: &Self, simulation: &Simulation) -> Vec<String> {
+        let ticks_per_second = match self.wall_millis {
+            0 => 0,
+            millis => self.ticks as u128 * 1000 / millis,
+        };
+        let mut lines = vec![
+            format!("ticks={}", self.ticks),
+            format!("game_millis={}", simulation.get_game_time_millis()),
+            format!("wall_millis={}", self.wall_millis),
+            format!("ticks_per_second={}", ticks_per_second),
+            format!("waves_started={}", self.waves_started),
+            format!("waves_cleared={}", self.waves_cleared),
+            format!("all_waves_cleared={}", self.is_all_waves_cleared),
+            format!("spawned={}", self.spawned),
+            format!("spawn_failures={}", self.spawn_failures),
+            format!("deaths={}", self.deaths),
+            format!("entities={}", entity_system::snapshot().len()),
+            format!("commands={}", self.commands),
+            format!("rejected_commands={}", self.rejected_commands),
+            format!("delivered_items={}", self.delivered_items),
+        ];
+        let entities = entity_system::snapshot();
+        for ledger in simulation.economy.get_ledgers() {
+            let owned = entities.iter().filter(|e| e.owner == ledger.player_id);
+            let units = simulation
+                .units
+                .get_units()
+                .iter()
+                .filter(|u| u.owner == ledger.player_id)
+                .count();
+            lines.push(format!("player{}.units={}", ledger.player_id, units));
+            lines.push(format!(
+                "player{}.structures={}",
+                ledger.player_id,
+                owned.filter(|e| e.physics_info.max_velocity == 0).count()
+            ));
+            for item in ItemTypes::ALL {
+                if ledger.get_total_earned(item) > 0 || ledger.get_balance(item) > 0 {
+                    lines.push(format!(
+                        "player{}.{:?}={} (earned {}, spent {})",
+                        ledger.player_id,
+                        item,
+                        ledger.get_balance(item),
+                        ledger.get_total_earned(item),
+                        ledger.get_total_spent(item)
+                    ));
+                }
+            }
+        }
+        return lines;
+    }
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        max_ticks: DEFAULT_MAX_TICKS,
+        ..Default::default()
+    };
+    let mut args_iter = args.into_iter();
+    while let Some(arg) = args_iter.next() {
+        let mut value = || match args_iter.next() {
+            Some(v) => Ok(v),
+            None => Err(format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--scenario" => options.scenario_file = Some(value()?),
+            "--map" => options.map_file = Some(value()?),
+            "--seed" => {
+                options.seed = Some(value()?.parse().map_err(|e| format!("--seed: {}", e))?)
+            }
+            "--ticks" => {
+                options.max_ticks = value()?.parse().map_err(|e| format!("--ticks: {}", e))?
+            }
+            "--realtime" => options.is_realtime = true,
+            "--stats" => options.stats_file = Some(value()?),
+            "--replay" => options.replay_file = Some(value()?),
+            "--save" => options.save_file = Some(value()?),
+            "--write-scenario" => options.write_scenario_file = Some(value()?),
+            "--lockstep-host" => options.lockstep_host_address = Some(value()?),
+            "--lockstep-join" => options.lockstep_join_address = Some(value()?),
+            "--player" => {
+                options.player_id = Some(value()?.parse().map_err(|e| format!("--player: {}", e))?)
+            }
+            "--serve" => options.serve_address = Some(value()?),
+            "--skirmish" => options.is_skirmish = true,
+            "--ai" => {
+                let value = value()?;
+                let (player_id, difficulty) = match value.split_once(':') {
+                    Some(pair) => pair,
+                    None => return Err(format!("--ai: '{}' is not <id>:<difficulty>", value)),
+                };
+                options.difficulties.push((
+                    player_id.parse().map_err(|e| format!("--ai: {}", e))?,
+                    Difficulties::from_name(difficulty).map_err(|e| format!("--ai: {}", e))?,
+                ));
+            }
+            "--latency-ms" => {
+                options.latency_millis = value()?
+                    .parse()
+                    .map_err(|e| format!("--latency-ms: {}", e))?
+            }
+            "--input-delay" => {
+                options.input_delay_ticks = Some(
+                    value()?
+                        .parse()
+                        .map_err(|e| format!("--input-delay: {}", e))?,
+                )
+            }
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    return Ok(options);
+}
+
+// a handful of ever-growing waves of grunts, enough to soak test spawning and the entity churn
+fn make_default_scenario() -> Scenario {
+    let mut grunt = EntityPrototype::new(1, "grunt", 0);
+    grunt.max_health_points = 20;
+    grunt.max_velocity = 1;
+    grunt.bounty = vec![ItemStack::new(ItemTypes::Gold, 1)];
+    let waves = (0..5)
+        .map(|wave_index| WaveDefinition {
+            delay_millis: 15_000,
+            early_call_bonus_per_second: 2,
+            groups: vec![SpawnGroup {
+                prototype_id: grunt.id,
+                count: 5 + wave_index * 5,
+                spacing_millis: 500,
+                delay_millis: 0,
+                spawn_x: 2,
+                spawn_y: 2,
+                spawn_radius: 2,
+            }],
+        })
+        .collect();
+    return Scenario {
+        name: "built-in soak test".to_owned(),
+        seed: 0,
+        map_width: 64,
+        map_height: 48,
+        prototypes: vec![grunt],
+        recipes: Vec::new(),
+        waves,
+        players: vec![PlayerSetup {
+            player_id: 1,
+            starting_items: vec![ItemStack::new(ItemTypes::Gold, 100)],
+            team: None,
+            computer: None,
+        }],
+        upgrades: Vec::new(),
+        researches: Vec::new(),
+        prototype_prerequisites: Vec::new(),
+        team_relations: Vec::new(),
+        behaviours: Vec::new(),
+        paths: Vec::new(),
+    };
+}
+
+// two computer players in opposite corners of a generated map, each mining, building barracks and
+// sending soldiers at the other; no waves, so it runs until --ticks.  Soldiers are paid for with
+// sand, the most common ore, so that only the starting gold is needed to get going
+fn make_skirmish_scenario() -> Scenario {
+    let gold = |amount| vec![ItemStack::new(ItemTypes::Gold, amount)];
+    let mut drill = EntityPrototype::new(1, "drill", 0);
+    drill.max_health_points = 100;
+    drill.mining_millis_per_item = 1000;
+    drill.build_costs = gold(20);
+    let mut barracks = EntityPrototype::new(2, "barracks", 0);
+    barracks.max_health_points = 400;
+    barracks.build_costs = gold(60);
+    barracks.trains = vec![3];
+    barracks.supply_provided = 10;
+    let mut soldier = EntityPrototype::new(3, "soldier", 0);
+    soldier.max_health_points = 40;
+    soldier.max_velocity = 2;
+    soldier.weapon.damage = 5;
+    soldier.weapon.range = 1;
+    soldier.weapon.fire_interval_millis = 1000;
+    soldier.build_costs = vec![ItemStack::new(ItemTypes::Sand, 20)]; // mined, see below
+    soldier.production_millis = 5000;
+    soldier.supply_cost = 1;
+    let strategy = Strategy {
+        drill_id: Some(drill.id),
+        build_order: vec![barracks.id, barracks.id],
+        unit_ids: vec![soldier.id],
+        is_researching: false,
+    };
+    let (width, height) = (64, 48);
+    let computer = |player_id: TPlayerID, home_x: u16, home_y: u16| PlayerSetup {
+        player_id,
+        starting_items: gold(200),
+        team: None,
+        computer: Some(ComputerSetup {
+            difficulty: Difficulties::Normal,
+            home_x,
+            home_y,
+            strategy: strategy.clone(),
+        }),
+    };
+    return Scenario {
+        name: "built-in skirmish".to_owned(),
+        seed: 0,
+        map_width: width,
+        map_height: height,
+        prototypes: vec![drill, barracks, soldier],
+        recipes: Vec::new(),
+        waves: Vec::new(),
+        players: vec![computer(1, 4, 4), computer(2, width - 5, height - 5)],
+        upgrades: Vec::new(),
+        researches: Vec::new(),
+        prototype_prerequisites: Vec::new(),
+        team_relations: Vec::new(),
+        behaviours: Vec::new(),
+        paths: Vec::new(),
+    };
+}
+
+// --ai overrides the difficulty of the scenario's computer players
+fn set_difficulties(options: &Options, simulation: &mut Simulation) -> Result<(), String> {
+    let computers = &mut simulation.computers;
+    for (player_id, difficulty) in options.difficulties.iter() {
+        match computers.iter_mut().find(|c| c.player_id == *player_id) {
+            Some(computer) => computer.setup.difficulty = *difficulty,
+            None => {
+                return Err(format!(
+                    "--ai: playerID={} is not a computer player in the scenario",
+                    player_id
+                ))
+            }
+        }
+    }
+    return Ok(());
+}
+
+// None unless playing lockstep; blocks until a peer per player_ids is connected
+fn make_lockstep_peer(
+    options: &Options,
+    player_ids: Vec<TPlayerID>,
+    start_tick: u64,
+) -> Result<Option<LockstepPeer<TcpTransport<LockstepMessages>>>, String> {
+    let transport = match (
+        &options.lockstep_host_address,
+        &options.lockstep_join_address,
+    ) {
+        (None, None) => return Ok(None),
+        (Some(address), None) => {
+            let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+            println!(
+                "waiting for {} peer(s) on {}",
+                player_ids.len().saturating_sub(1),
+                address
+            );
+            TcpTransport::host(&listener, player_ids.len().saturating_sub(1))?
+        }
+        (None, Some(address)) => TcpTransport::join(address)?,
+        (Some(_), Some(_)) => return Err("cannot both host and join".to_owned()),
+    };
+    let player_id = match options.player_id.or(player_ids.first().copied()) {
+        Some(id) => id,
+        None => return Err("the scenario has no players".to_owned()),
+    };
+    let mut config = LockstepConfig::default();
+    if let Some(delay) = options.input_delay_ticks {
+        config.input_delay_ticks = delay;
+    }
+    let peer = LockstepPeer::new(player_id, player_ids, config, transport, start_tick)?;
+    return Ok(Some(peer));
+}
+
+type TServerTransport =
+    LatencyTransport<ReconciliationMessages, TcpTransport<ReconciliationMessages>>;
+
+// None unless serving; blocks until a client per player_ids is connected
+fn make_authoritative_server(
+    options: &Options,
+    player_ids: Vec<TPlayerID>,
+    simulation: &Simulation,
+) -> Result<Option<AuthoritativeServer<TServerTransport>>, String> {
+    let address = match &options.serve_address {
+        Some(a) => a,
+        None => return Ok(None),
+    };
+    if options.lockstep_host_address.is_some() || options.lockstep_join_address.is_some() {
+        return Err("cannot both serve and play in lockstep".to_owned());
+    }
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    println!("waiting for {} client(s) on {}", player_ids.len(), address);
+    let mut transport = TcpTransport::host(&listener, player_ids.len())?;
+    transport.set_relaying(false); // clients only talk to the server
+    let transport = LatencyTransport::new(transport, Duration::from_millis(options.latency_millis));
+    return Ok(Some(AuthoritativeServer::new(
+        simulation, transport, player_ids,
+    )?));
+}
+
+// the --replay and --save files, at the end of the match or when it is cut short
+fn write_outputs(
+    options: &Options,
+    simulation: &Simulation,
+    replay: &Option<Replay>,
+) -> Result<(), String> {
+    if let (Some(file_paths), Some(r)) = (&options.replay_file, replay) {
+        write_resource(file_paths, replay_system::serialize_replay_for_save(r))?;
+    }
+    if let Some(file_paths) = &options.save_file {
+        savegame_system::save_game(simulation, file_paths)?;
+        println!("saved the game to {}", file_paths);
+    }
+    return Ok(());
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut scenario = match &options.scenario_file {
+        Some(file_paths) => {
+            read_resource(file_paths, scenario_system::deserialize_scenario_for_load)?
+        }
+        None if options.is_skirmish => make_skirmish_scenario(),
+        None => make_default_scenario(),
+    };
+    if let Some(file_paths) = &options.write_scenario_file {
+        write_resource(
+            file_paths,
+            scenario_system::serialize_scenario_for_save(&scenario),
+        )?;
+        println!("wrote scenario '{}' to {}", scenario.name, file_paths);
+        return Ok(());
+    }
+    if let Some(seed) = options.seed {
+        scenario.seed = seed;
+    }
+    let map = match &options.map_file {
+        Some(file_paths) => Some(read_resource(file_paths, Map::deserialize_for_load)?),
+        None => None,
+    };
+    let mut simulation = scenario.create_simulation(map)?;
+    set_difficulties(&options, &mut simulation)?;
+    let mut replay = options
+        .replay_file
+        .as_ref()
+        .map(|_| Replay::new(&simulation));
+    println!(
+        "running '{}' (seed {}) on a {}x{} map for up to {} ticks{}",
+        scenario.name,
+        scenario.seed,
+        simulation.map.get_width(),
+        simulation.map.get_height(),
+        options.max_ticks,
+        if options.is_realtime {
+            " in real-time"
+        } else {
+            ""
+        }
+    );
+
+    for computer in simulation.computers.iter() {
+        println!(
+            "playerID={} is a computer player ({:?})",
+            computer.player_id, computer.setup.difficulty
+        );
+    }
+    // the computer players are played by the simulation (on every peer), everyone else needs a
+    // lockstep peer or a client
+    let human_player_ids: Vec<TPlayerID> = scenario
+        .players
+        .iter()
+        .map(|p| p.player_id)
+        .filter(|player_id| {
+            simulation
+                .computers
+                .iter()
+                .all(|c| c.player_id != *player_id)
+        })
+        .collect();
+    let mut lockstep =
+        make_lockstep_peer(&options, human_player_ids.clone(), simulation.get_tick())?;
+    let mut server = make_authoritative_server(&options, human_player_ids, &simulation)?;
+    let is_realtime = options.is_realtime || server.is_some();
+
+    let mut stats = MatchStats::default();
+    let start_time = Instant::now();
+    let mut last_frame_time = Instant::now();
+    while simulation.get_tick() < options.max_ticks && stats.is_all_waves_cleared == false {
+        if let Some(peer) = lockstep.as_mut() {
+            let report = match peer.step(&mut simulation) {
+                Ok(Some(r)) => r,
+                Ok(None) => {
+                    thread::sleep(Duration::from_millis(1)); // waiting on the other peers
+                    continue;
+                }
+                Err(e) => {
+                    // a desync is only worth reporting with the games to diff, so save first
+                    if let Err(save_error) = write_outputs(&options, &simulation, &replay) {
+                        eprintln!("error: {}", save_error);
+                    }
+                    return Err(e);
+                }
+            };
+            if options.is_realtime {
+                thread::sleep(Duration::from_millis(get_tick_millis(report.tick) as u64));
+            }
+            stats.add(&report);
+            if let Some(r) = replay.as_mut() {
+                r.record(&report);
+                r.record_hash(&simulation);
+            }
+            continue;
+        }
+        if let Some(s) = server.as_mut() {
+            s.poll(&mut simulation)?;
+        }
+        let reports = match is_realtime {
+            true => {
+                thread::sleep(simulation.get_time_until_next_tick());
+                let now = Instant::now();
+                let reports = simulation.advance(now - last_frame_time);
+                last_frame_time = now;
+                reports
+            }
+            false => vec![simulation.step_once()],
+        };
+        for report in reports.iter() {
+            stats.add(report);
+            if let Some(r) = replay.as_mut() {
+                r.record(report);
+            }
+        }
+        if let Some(r) = replay.as_mut() {
+            r.record_hash(&simulation);
+        }
+        if let Some(s) = server.as_mut() {
+            s.broadcast(&simulation)?;
+        }
+    }
+    stats.wall_millis = start_time.elapsed().as_millis();
+
+    write_outputs(&options, &simulation, &replay)?;
+    let lines = stats.to_lines(&simulation);
+    for line in lines.iter() {
+        println!("{}", line);
+    }
+    if let Some(file_paths) = &options.stats_file {
+        fs::write(file_paths, lines.join("\n") + "\n")
+            .map_err(|e| format!("{}: {}", file_paths, e))?;
+    }
+    return Ok(());
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let result = parse_options(args).and_then(run);
+    if let Err(e) = result {
+        eprintln!("error: {}\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+}
@@ -1,7 +1,7 @@
 use specs::prelude::*;
-use rand::prelude::*;
 
 use crate::components::*;
+use lib_tower_defense::random::{RandomStreams, RngStreamTypes};
 
 const ENEMY_MOVEMENT_SPEED: i32 = 10;
 
@@ -11,12 +11,13 @@ impl<'a> System<'a> for AI {
     type SystemData = (
         ReadStorage<'a, Enemy>,
         WriteStorage<'a, Velocity>,
+        Write<'a, RandomStreams>, // seeded, so that the same seed makes the same enemies wander the same way
     );
 
     fn run(&mut self, mut data: Self::SystemData) {
         //TODO: This code can be made nicer and more idiomatic using more pattern matching.
         // Look up "rust irrefutable patterns" and use them here.
-        let mut rng = thread_rng();
+        let rng = data.2.stream(RngStreamTypes::Ai);
         for (_, vel) in (&data.0, &mut data.1).join() {
             if rng.gen_range(0, 10) == 0 {
                 vel.speed = ENEMY_MOVEMENT_SPEED;
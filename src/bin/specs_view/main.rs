@@ -130,6 +130,8 @@ fn main() -> Result<(), String> {
     // Initialize resource
     let movement_command: Option<MovementCommand> = None;
     world.add_resource(movement_command);
+    // AI rolls come from here; the same seed makes the enemies wander the same way
+    world.add_resource(lib_tower_defense::random::RandomStreams::new(0));
 
     let textures = [
         texture_creator.load_texture("assets/raptor.png")?,
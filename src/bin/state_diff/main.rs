@@ -0,0 +1,155 @@
+// Dumps what differs between two game states, i.e. once lockstep peers (or a live run and its
+// replay) report different state hashes:
+//
+//   $ cargo run --bin state_diff -- peer1.save peer2.save
+//   $ cargo run --bin state_diff -- --scenario my.scenario --replay match.replay live.save
+//
+// The latter plays the replay back up to the tick of the save game and compares against that.
+// Exits with 1 when the states differ, like diff(1)
+use lib_tower_defense::replay_system::{self, ReplayPlayer};
+use lib_tower_defense::resource_system::read_resource;
+use lib_tower_defense::savegame_system;
+use lib_tower_defense::scenario_system;
+use lib_tower_defense::state_hash_system::{self, StateDifferences, StateHash};
+use lib_tower_defense::sync_system::ClientState;
+use std::env;
+
+const USAGE: &str = "usage: state_diff <left.save> <right.save>
+       state_diff --scenario <file> --replay <file> <right.save>
+    --scenario <file>  the scenario the replay was recorded with (for its prototypes)
+    --replay <file>    plays it back to the tick of <right.save> as the left side";
+
+#[derive(Debug, Default)]
+struct Options {
+    scenario_file: Option<String>,
+    replay_file: Option<String>,
+    save_files: Vec<String>,
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args_iter = args.into_iter();
+    while let Some(arg) = args_iter.next() {
+        let mut value = || match args_iter.next() {
+            Some(v) => Ok(v),
+            None => Err(format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--scenario" => options.scenario_file = Some(value()?),
+            "--replay" => options.replay_file = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ => options.save_files.push(arg),
+        }
+    }
+    let expected_saves = match options.replay_file {
+        Some(_) => 1,
+        None => 2,
+    };
+    if options.save_files.len() != expected_saves {
+        return Err(format!("expected {} save game(s)", expected_saves));
+    }
+    if options.replay_file.is_some() && options.scenario_file.is_none() {
+        return Err("--replay needs --scenario".to_owned());
+    }
+    return Ok(options);
+}
+
+fn read_save(file_paths: &String) -> Result<ClientState, String> {
+    let savegame = read_resource(file_paths, savegame_system::deserialize_savegame_for_load)?;
+    return Ok(ClientState::from_snapshot(&savegame.snapshot));
+}
+
+fn print_hash(label: &str, hash: &StateHash) {
+    println!(
+        "{} tick={} total={:016x} map={:016x} entities={:016x} economy={:016x}",
+        label, hash.tick, hash.total, hash.map, hash.entities, hash.economy
+    );
+}
+
+fn print_difference(difference: &StateDifferences) {
+    match difference {
+        StateDifferences::Tick { left, right } => println!("tick: {} / {}", left, right),
+        StateDifferences::MapSize { left, right } => {
+            println!("map size: {}x{} / {}x{}", left.0, left.1, right.0, right.1)
+        }
+        StateDifferences::Cell {
+            map_x,
+            map_y,
+            left,
+            right,
+        } => {
+            println!("cell ({}, {}):", map_x, map_y);
+            println!("  left:  {:?}", left);
+            println!("  right: {:?}", right);
+        }
+        StateDifferences::Entity {
+            entity_id,
+            left,
+            right,
+        } => {
+            println!("entityID={}:", entity_id);
+            println!("  left:  {:?}", left);
+            println!("  right: {:?}", right);
+        }
+        StateDifferences::Ledger {
+            player_id,
+            left,
+            right,
+        } => {
+            println!("playerID={} ledger:", player_id);
+            println!("  left:  {:?}", left);
+            println!("  right: {:?}", right);
+        }
+    }
+}
+
+// Ok(true) when both states are the same
+fn run(options: Options) -> Result<bool, String> {
+    let right = read_save(&options.save_files[options.save_files.len() - 1])?;
+    let left = match (&options.scenario_file, &options.replay_file) {
+        (Some(scenario_file), Some(replay_file)) => {
+            let scenario = read_resource(
+                scenario_file,
+                scenario_system::deserialize_scenario_for_load,
+            )?;
+            scenario.create_simulation(None)?; // only for its prototypes and recipes
+            let replay = read_resource(replay_file, replay_system::deserialize_replay_for_load)?;
+            let mut player = ReplayPlayer::new(replay)?;
+            player.seek(right.tick);
+            if let Some((recorded, actual)) = player.get_desync() {
+                println!(
+                    "NOTE: the replay itself no longer plays out as recorded from tick {} ({})",
+                    recorded.tick,
+                    recorded.get_differing_parts(&actual).join(", ")
+                );
+            }
+            ClientState::from_simulation(player.get_simulation())
+        }
+        _ => read_save(&options.save_files[0])?,
+    };
+
+    print_hash("left: ", &StateHash::from_client_state(&left));
+    print_hash("right:", &StateHash::from_client_state(&right));
+    let differences = state_hash_system::diff(&left, &right);
+    for difference in differences.iter() {
+        print_difference(difference);
+    }
+    println!("{} difference(s)", differences.len());
+    return Ok(differences.is_empty());
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    match parse_options(args).and_then(run) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    }
+}
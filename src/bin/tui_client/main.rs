@@ -0,0 +1,236 @@
+// Terminal client for `headless_sim --serve`: shows the server's state plus the predicted outcome
+// of its own commands (see prediction_system), so that placing and selling shows up right away
+// even over a slow link, and keeps updating while a command is being typed:
+//
+//   $ cargo run --release --bin headless_sim -- --scenario my.scenario --serve 127.0.0.1:7000 --latency-ms 200
+//   $ cargo run --release --bin tui_client -- --connect 127.0.0.1:7000 --player 1 --scenario my.scenario
+//
+// The scenario is only needed for its prototypes (to predict with); without it commands simply
+// show once the server has applied them.  Commands are typed one per line, see HELP
+use lib_tower_defense::command_system::PlayerCommands;
+use lib_tower_defense::economy_system::{ItemTypes, TPlayerID};
+use lib_tower_defense::entity_system::TEntityID;
+use lib_tower_defense::prediction_system::{
+    PredictingClient, ReconciliationMessages, PREDICTED_ENTITY_ID_BASE,
+};
+use lib_tower_defense::prototype_system;
+use lib_tower_defense::resource_system::read_resource;
+use lib_tower_defense::scenario_system;
+use lib_tower_defense::sync_system::ClientState;
+use lib_tower_defense::transport_system::{LatencyTransport, TcpTransport};
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::Duration;
+use std::{env, thread};
+
+const USAGE: &str = "usage: tui_client --connect <host:port> --player <id> [options]
+    --connect <addr>   the headless_sim --serve to play on
+    --player <id>      which of the scenario's players to play
+    --scenario <file>  the scenario being served, for its prototypes (to predict with)
+    --latency-ms <n>   holds back what the client sends and receives, to test prediction";
+const HELP: &str = "place <prototype id> <x> <y> | sell <entity id> | wave | quit";
+
+const VIEW_WIDTH: u16 = 64;
+const VIEW_HEIGHT: u16 = 24;
+const FRAME_MILLIS: u64 = 10;
+
+#[derive(Debug, Default)]
+struct Options {
+    connect_address: String,
+    player_id: Option<TPlayerID>,
+    scenario_file: Option<String>,
+    latency_millis: u64,
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args_iter = args.into_iter();
+    while let Some(arg) = args_iter.next() {
+        let mut value = || match args_iter.next() {
+            Some(v) => Ok(v),
+            None => Err(format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--connect" => options.connect_address = value()?,
+            "--player" => {
+                options.player_id = Some(value()?.parse().map_err(|e| format!("--player: {}", e))?)
+            }
+            "--scenario" => options.scenario_file = Some(value()?),
+            "--latency-ms" => {
+                options.latency_millis = value()?
+                    .parse()
+                    .map_err(|e| format!("--latency-ms: {}", e))?
+            }
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    if options.connect_address.is_empty() {
+        return Err("--connect is required".to_owned());
+    }
+    if options.player_id.is_none() {
+        return Err("--player is required".to_owned());
+    }
+    return Ok(options);
+}
+
+// Ok(None) to quit
+fn parse_command(line: &str) -> Result<Option<PlayerCommands>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let number = |index: usize| -> Result<u16, String> {
+        match words.get(index) {
+            Some(word) => word.parse().map_err(|e| format!("'{}': {}", word, e)),
+            None => Err(HELP.to_owned()),
+        }
+    };
+    let command = match words.first() {
+        Some(&"place") => PlayerCommands::PlaceStructure {
+            prototype_id: number(1)?,
+            map_x: number(2)?,
+            map_y: number(3)?,
+        },
+        Some(&"sell") => PlayerCommands::SellStructure {
+            entity_id: number(1)?,
+        },
+        Some(&"wave") => PlayerCommands::CallWaveEarly,
+        Some(&"quit") => return Ok(None),
+        _ => return Err(HELP.to_owned()),
+    };
+    return Ok(Some(command));
+}
+
+type TClientTransport =
+    LatencyTransport<ReconciliationMessages, TcpTransport<ReconciliationMessages>>;
+
+// '#' own structure, 'X' someone else's, 'o'/'x' the same for units and creeps, '?' predicted
+// (not confirmed by the server yet), ',' ore
+fn get_cell_char(
+    state: &ClientState,
+    player_id: TPlayerID,
+    pending_count: usize,
+    map_x: u16,
+    map_y: u16,
+) -> char {
+    let cell = match state.map.get_cell_ref(map_x, map_y) {
+        Some(c) => c,
+        None => return ' ',
+    };
+    let entity = cell
+        .layers
+        .iter()
+        .filter_map(|layer| state.get_entity(&layer.entity))
+        .min_by_key(|e| e.layer_weight);
+    return match entity {
+        // predicted structures count down from the base, one ID per pending command
+        Some(e) if e.id > PREDICTED_ENTITY_ID_BASE - pending_count as TEntityID => '?',
+        Some(e) if e.is_structure() && e.owner == player_id => '#',
+        Some(e) if e.is_structure() => 'X',
+        Some(e) if e.owner == player_id => 'o',
+        Some(_) => 'x',
+        None if cell.deposit.is_some() => ',',
+        None => '.',
+    };
+}
+
+fn render(client: &PredictingClient<TClientTransport>, player_id: TPlayerID, status: &String) {
+    print!("\x1b[2J\x1b[H"); // clear, then home
+    let state = match client.get_state() {
+        Some(s) => s,
+        None => {
+            println!("waiting for the server...");
+            return;
+        }
+    };
+    let items: Vec<String> = match state.ledgers.iter().find(|l| l.player_id == player_id) {
+        Some(ledger) => ItemTypes::ALL
+            .iter()
+            .filter(|item| ledger.get_balance(**item) > 0)
+            .map(|item| format!("{:?}={}", item, ledger.get_balance(*item)))
+            .collect(),
+        None => Vec::new(),
+    };
+    println!(
+        "tick {} playerID={} pending={} {}",
+        state.tick,
+        player_id,
+        client.get_pending_count(),
+        items.join(" ")
+    );
+    let height = VIEW_HEIGHT.min(state.map.get_height());
+    let width = VIEW_WIDTH.min(state.map.get_width());
+    for map_y in 0..height {
+        let line: String = (0..width)
+            .map(|map_x| get_cell_char(state, player_id, client.get_pending_count(), map_x, map_y))
+            .collect();
+        println!("{}", line);
+    }
+    println!("{}\n{}", status, HELP);
+}
+
+fn run(options: Options) -> Result<(), String> {
+    if let Some(file_paths) = &options.scenario_file {
+        let scenario = read_resource(file_paths, scenario_system::deserialize_scenario_for_load)?;
+        for prototype in scenario.prototypes.into_iter() {
+            prototype_system::add(prototype);
+        }
+    }
+    let player_id = options.player_id.unwrap_or_default();
+    let transport = LatencyTransport::new(
+        TcpTransport::join(&options.connect_address)?,
+        Duration::from_millis(options.latency_millis),
+    );
+    let mut client: PredictingClient<TClientTransport> =
+        PredictingClient::new(player_id, transport);
+
+    // stdin blocks, so it is read on its own thread to keep rendering what the server sends
+    let (line_sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let is_sent = match line {
+                Ok(line) => line_sender.send(line).is_ok(),
+                Err(_) => false,
+            };
+            if is_sent == false {
+                break;
+            }
+        }
+    });
+
+    let mut status = format!("connected to {}", options.connect_address);
+    let mut is_changed = true;
+    loop {
+        is_changed |= client.update()?;
+        match lines.try_recv() {
+            Ok(line) => {
+                status = match parse_command(&line) {
+                    Ok(Some(command)) => match client.issue(command) {
+                        Ok(sequence) => format!("sent #{}: {}", sequence, line.trim()),
+                        Err(e) => e,
+                    },
+                    Ok(None) => return Ok(()),
+                    Err(e) => e,
+                };
+                is_changed = true;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => return Ok(()), // end of input
+        }
+        if is_changed {
+            render(&client, player_id, &status);
+            is_changed = false;
+        }
+        thread::sleep(Duration::from_millis(FRAME_MILLIS));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let result = parse_options(args).and_then(run);
+    if let Err(e) = result {
+        eprintln!("error: {}\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,291 @@
+// Everything a player can do to the match goes through here as a PlayerCommands, queued on the
+// Simulation and applied at the start of the next tick.  Since commands are plain data, they can
+// be recorded (see replay_system) and later fed back in to reproduce the match.  This is the one
+// input path for every frontend, the network (lockstep_system, prediction_system, rpc_system)
+// and replays alike, so none of them should touch the Map or entities directly
+use crate::conveyor_system::ConveyorTypes;
+use crate::diplomacy_system;
+use crate::economy_system::{ItemStack, ItemTypes, TPlayerID, FULL_REFUND_PERCENT};
+use crate::entity_system::{self, TEntityID};
+use crate::placement_system;
+use crate::prototype_system::{self, TPrototypeID};
+use crate::simulation::{Simulation, TTick};
+use crate::tech_system::TResearchID;
+use crate::unit_system::UnitOrders;
+use crate::upgrade_system::TUpgradeID;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum PlayerCommands {
+    PlaceStructure {
+        prototype_id: TPrototypeID,
+        map_x: u16,
+        map_y: u16,
+    },
+    SellStructure {
+        entity_id: TEntityID,
+    },
+    UpgradeStructure {
+        entity_id: TEntityID,
+        upgrade_id: TUpgradeID,
+    },
+    // units are trained at structures (see production_system)
+    QueueUnit {
+        entity_id: TEntityID,
+        prototype_id: TPrototypeID,
+    },
+    CancelUnit {
+        entity_id: TEntityID,
+        queue_index: u8,
+    },
+    // where the units it produces head to
+    SetRallyPoint {
+        entity_id: TEntityID,
+        map_x: u16,
+        map_y: u16,
+    },
+    PlaceConveyor {
+        map_x: u16,
+        map_y: u16,
+        block: ConveyorTypes,
+    },
+    RemoveConveyor {
+        map_x: u16,
+        map_y: u16,
+    },
+    CallWaveEarly,
+    // one at a time per player (see tech_system)
+    StartResearch {
+        research_id: TResearchID,
+    },
+    CancelResearch,
+    // unit orders, each given to all of the listed units or to none of them (see unit_system)
+    MoveUnits {
+        entity_ids: Vec<TEntityID>,
+        map_x: u16,
+        map_y: u16,
+    },
+    AttackTarget {
+        entity_ids: Vec<TEntityID>,
+        target_id: TEntityID,
+    },
+    Patrol {
+        entity_ids: Vec<TEntityID>, // between where each one is now and (map_x, map_y)
+        map_x: u16,
+        map_y: u16,
+    },
+    HoldPosition {
+        entity_ids: Vec<TEntityID>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct IssuedCommand {
+    pub tick: TTick, // the tick it was applied at
+    pub player_id: TPlayerID,
+    pub command: PlayerCommands,
+}
+
+/// Applies the command to the simulation; a rejected command (i.e. cannot afford) leaves the
+/// simulation untouched
+pub fn apply(
+    simulation: &mut Simulation,
+    player_id: TPlayerID,
+    command: &PlayerCommands,
+) -> Result<(), String> {
+    match command {
+        PlayerCommands::PlaceStructure {
+            prototype_id,
+            map_x,
+            map_y,
+        } => {
+            simulation.tech.is_available(player_id, *prototype_id)?;
+            let entity_id = placement_system::place_structure(
+                &mut simulation.map,
+                &mut simulation.economy,
+                player_id,
+                prototype_id,
+                *map_x,
+                *map_y,
+            )?;
+            if let Err(e) = simulation.add_structure(player_id, &entity_id) {
+                // i.e. a factory whose recipe does not exist; take it back down at full refund
+                simulation.remove_structure(&entity_id);
+                simulation.map.remove_entity(&entity_id);
+                entity_system::remove(&entity_id)?;
+                if let Some(prototype) = prototype_system::get(prototype_id) {
+                    simulation.economy.refund(
+                        player_id,
+                        &prototype.build_costs,
+                        FULL_REFUND_PERCENT,
+                    )?;
+                }
+                return Err(e);
+            }
+        }
+        PlayerCommands::SellStructure { entity_id } => {
+            // validate all of it up front, taking it off the other systems cannot be undone
+            placement_system::can_sell(&simulation.economy, player_id, entity_id)?;
+            diplomacy_system::check_owner(entity_id, player_id)?;
+            simulation.remove_structure(entity_id);
+            placement_system::sell_structure(
+                &mut simulation.map,
+                &mut simulation.economy,
+                player_id,
+                entity_id,
+            )?;
+        }
+        PlayerCommands::UpgradeStructure {
+            entity_id,
+            upgrade_id,
+        } => {
+            diplomacy_system::check_owner(entity_id, player_id)?;
+            if let Some(upgrade) = simulation.upgrades.get_upgrade(*upgrade_id) {
+                simulation
+                    .tech
+                    .is_available(player_id, upgrade.to_prototype_id)?;
+            }
+            // meant for towers, so the structure stays registered with whichever systems it was
+            // part of (the footprint cannot change, see upgrade_system)
+            simulation.upgrades.apply_upgrade(
+                &mut simulation.economy,
+                player_id,
+                entity_id,
+                *upgrade_id,
+            )?;
+            // the new prototype's stats replaced the old ones along with their research bonuses
+            simulation
+                .tech
+                .apply_completed_modifiers(entity_id, player_id);
+        }
+        PlayerCommands::QueueUnit {
+            entity_id,
+            prototype_id,
+        } => {
+            simulation.tech.is_available(player_id, *prototype_id)?;
+            simulation.production.queue(
+                &mut simulation.economy,
+                &simulation.units,
+                player_id,
+                entity_id,
+                *prototype_id,
+            )?;
+        }
+        PlayerCommands::CancelUnit {
+            entity_id,
+            queue_index,
+        } => {
+            simulation.production.cancel(
+                &mut simulation.economy,
+                player_id,
+                entity_id,
+                *queue_index as usize,
+            )?;
+        }
+        PlayerCommands::SetRallyPoint {
+            entity_id,
+            map_x,
+            map_y,
+        } => {
+            diplomacy_system::check_owner(entity_id, player_id)?;
+            simulation
+                .units
+                .set_rally_point(&simulation.map, entity_id, *map_x, *map_y)?;
+        }
+        PlayerCommands::PlaceConveyor {
+            map_x,
+            map_y,
+            block,
+        } => {
+            simulation.conveyors.add_block(
+                &mut simulation.map,
+                player_id,
+                *map_x,
+                *map_y,
+                *block,
+            )?;
+        }
+        PlayerCommands::RemoveConveyor { map_x, map_y } => {
+            match simulation.conveyors.get_entity_id(*map_x, *map_y) {
+                Some(entity_id) => diplomacy_system::check_owner(&entity_id, player_id)?,
+                None => return Err(format!("({}, {}) has no conveyor", map_x, map_y)),
+            }
+            if simulation
+                .conveyors
+                .remove_block(&mut simulation.map, *map_x, *map_y)
+                .is_none()
+            {
+                return Err(format!("({}, {}) has no conveyor", map_x, map_y));
+            }
+        }
+        PlayerCommands::CallWaveEarly => {
+            let bonus = simulation.waves.call_next_wave_early()?;
+            // a player without a ledger (i.e. spectator) can still call it, just without the bonus
+            let _ = simulation
+                .economy
+                .earn(player_id, &vec![ItemStack::new(ItemTypes::Gold, bonus)]);
+        }
+        PlayerCommands::StartResearch { research_id } => {
+            simulation
+                .tech
+                .start_research(&mut simulation.economy, player_id, *research_id)?;
+        }
+        PlayerCommands::CancelResearch => {
+            simulation
+                .tech
+                .cancel_research(&mut simulation.economy, player_id)?;
+        }
+        PlayerCommands::MoveUnits {
+            entity_ids,
+            map_x,
+            map_y,
+        } => {
+            let order = UnitOrders::Move {
+                map_x: *map_x,
+                map_y: *map_y,
+            };
+            simulation
+                .units
+                .issue(&simulation.map, player_id, entity_ids, order)?;
+        }
+        PlayerCommands::AttackTarget {
+            entity_ids,
+            target_id,
+        } => {
+            if simulation.diplomacy.can_attack(player_id, target_id) == false {
+                return Err(format!(
+                    "entityID={} cannot be attacked by playerID={}",
+                    target_id, player_id
+                ));
+            }
+            let order = UnitOrders::AttackTarget {
+                target_id: *target_id,
+            };
+            simulation
+                .units
+                .issue(&simulation.map, player_id, entity_ids, order)?;
+        }
+        PlayerCommands::Patrol {
+            entity_ids,
+            map_x,
+            map_y,
+        } => {
+            let order = UnitOrders::Patrol {
+                from_x: *map_x, // replaced by each unit's own position
+                from_y: *map_y,
+                to_x: *map_x,
+                to_y: *map_y,
+            };
+            simulation
+                .units
+                .issue(&simulation.map, player_id, entity_ids, order)?;
+        }
+        PlayerCommands::HoldPosition { entity_ids } => {
+            simulation
+                .units
+                .issue(&simulation.map, player_id, entity_ids, UnitOrders::Hold)?;
+        }
+    }
+    return Ok(());
+}
@@ -0,0 +1,486 @@
+// Computer opponents for skirmish: a scripted player that mines, builds, trains and attacks.  It
+// plays by the same rules as everyone else, looking at the Simulation and answering with the
+// PlayerCommands a person would have given.  It is played by the Simulation itself at the start
+// of every tick, so replays and lockstep peers play it again rather than sending its commands
+// around.  What it builds and trains is data (Strategy, per player in the Scenario); the
+// difficulty decides how quickly it acts and how hard it pushes
+use crate::command_system::PlayerCommands;
+use crate::diplomacy_system::{Relations, CREEP_PLAYER_ID, NEUTRAL_PLAYER_ID};
+use crate::economy_system::{Economy, TPlayerID};
+use crate::entity_system::{self, Entity};
+use crate::map::get_distance_squared;
+use crate::placement_system;
+use crate::production_system::MAX_QUEUE_LENGTH;
+use crate::prototype_system::{self, EntityPrototype, TPrototypeID};
+use crate::simulation::{get_tick_start_millis, Simulation, TTick};
+use crate::unit_system::UnitOrders;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+pub const BUILD_RADIUS: u16 = 12; // in cells around home, it does not expand further than that
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Difficulties {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulties {
+    pub fn from_name(name: &str) -> Result<Difficulties, String> {
+        return match name.to_lowercase().as_str() {
+            "easy" => Ok(Difficulties::Easy),
+            "normal" => Ok(Difficulties::Normal),
+            "hard" => Ok(Difficulties::Hard),
+            _ => Err(format!("unknown difficulty '{}'", name)),
+        };
+    }
+
+    // how often it gets to act
+    pub fn get_think_interval_millis(self: &Self) -> u128 {
+        return match self {
+            Difficulties::Easy => 4000,
+            Difficulties::Normal => 2000,
+            Difficulties::Hard => 500,
+        };
+    }
+    pub fn get_max_drills(self: &Self) -> usize {
+        return match self {
+            Difficulties::Easy => 1,
+            Difficulties::Normal => 3,
+            Difficulties::Hard => 6,
+        };
+    }
+    // units queued per structure at a time
+    pub fn get_max_queued(self: &Self) -> usize {
+        return match self {
+            Difficulties::Easy => 1,
+            Difficulties::Normal => 2,
+            Difficulties::Hard => MAX_QUEUE_LENGTH,
+        };
+    }
+    // idle units it waits for before sending them all at the enemy
+    pub fn get_attack_group_size(self: &Self) -> usize {
+        return match self {
+            Difficulties::Easy => 10,
+            Difficulties::Normal => 6,
+            Difficulties::Hard => 3,
+        };
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Strategy {
+    pub drill_id: Option<TPrototypeID>, // placed on ore deposits, as many as the difficulty allows
+    pub build_order: Vec<TPrototypeID>, // structures built around home in this order, once per copy
+    pub unit_ids: Vec<TPrototypeID>,    // trained in turn, wherever they can be
+    #[serde(default)]
+    pub is_researching: bool, // starts whichever research is available whenever it is idle
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ComputerSetup {
+    pub difficulty: Difficulties,
+    pub home_x: u16, // where it builds around
+    pub home_y: u16,
+    pub strategy: Strategy,
+}
+
+// part of the Simulation (and so of save games), which thinks for it every tick
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ComputerPlayer {
+    pub player_id: TPlayerID,
+    pub setup: ComputerSetup,
+    next_think_millis: u128, // game time
+    next_unit_index: usize,  // into strategy.unit_ids
+}
+
+fn count_owned(structures: &Vec<Entity>, prototype_id: TPrototypeID) -> usize {
+    return structures
+        .iter()
+        .filter(|e| e.prototype_id == Some(prototype_id))
+        .count();
+}
+
+impl ComputerPlayer {
+    pub fn new(player_id: TPlayerID, setup: ComputerSetup) -> ComputerPlayer {
+        ComputerPlayer {
+            player_id,
+            setup,
+            next_think_millis: 0,
+            next_unit_index: 0,
+        }
+    }
+
+    /// Whatever it decided to do at the start of the tick, as commands to apply as its player;
+    /// empty between thinks, so it is called every tick (see Simulation::step())
+    pub fn think(self: &mut Self, simulation: &Simulation, tick: TTick) -> Vec<PlayerCommands> {
+        let now = get_tick_start_millis(tick);
+        if now < self.next_think_millis {
+            return Vec::new();
+        }
+        self.next_think_millis = now + self.setup.difficulty.get_think_interval_millis();
+        let structures: Vec<Entity> = entity_system::snapshot()
+            .into_iter()
+            .filter(|e| e.owner == self.player_id && e.is_structure() && e.is_alive())
+            .collect();
+        // spent as it goes, so that it does not plan more than it can pay for
+        let mut economy = simulation.economy.clone();
+        let mut commands = Vec::new();
+
+        // one new structure per think: a drill while it wants more, else the next in the build
+        // order (waiting for it to become affordable rather than skipping ahead)
+        let wanted = match self.setup.strategy.drill_id {
+            Some(id) if count_owned(&structures, id) < self.setup.difficulty.get_max_drills() => {
+                prototype_system::get(&id).and_then(|p| {
+                    self.find_site(simulation, &p, true)
+                        .map(|(map_x, map_y)| (p, map_x, map_y))
+                })
+            }
+            _ => None,
+        };
+        let wanted = wanted.or_else(|| {
+            let build_order = &self.setup.strategy.build_order;
+            let next = (0..build_order.len()).find(|i| {
+                let copies = build_order[..=*i]
+                    .iter()
+                    .filter(|id| **id == build_order[*i])
+                    .count();
+                count_owned(&structures, build_order[*i]) < copies
+            });
+            let prototype = next.and_then(|i| prototype_system::get(&build_order[i]))?;
+            let (map_x, map_y) = self.find_site(simulation, &prototype, false)?;
+            return Some((prototype, map_x, map_y));
+        });
+        if let Some((prototype, map_x, map_y)) = wanted {
+            if self.can_get(simulation, &mut economy, &prototype) {
+                commands.push(PlayerCommands::PlaceStructure {
+                    prototype_id: prototype.id,
+                    map_x,
+                    map_y,
+                });
+            }
+        }
+
+        if self.setup.strategy.is_researching {
+            let is_idle = match simulation.tech.get_player(self.player_id) {
+                Some(tech) => tech.in_progress.is_none(),
+                None => true,
+            };
+            let research = simulation
+                .tech
+                .get_available_researches(self.player_id)
+                .into_iter()
+                .find(|r| economy.can_afford(self.player_id, &r.costs));
+            if let (true, Some(r)) = (is_idle, research) {
+                let _ = economy.spend(self.player_id, &r.costs);
+                commands.push(PlayerCommands::StartResearch { research_id: r.id });
+            }
+        }
+
+        commands.extend(self.train(simulation, &mut economy));
+        commands.extend(self.attack(simulation));
+        return commands;
+    }
+
+    // unlocked, affordable (then spent from the planning economy)
+    fn can_get(
+        self: &Self,
+        simulation: &Simulation,
+        economy: &mut Economy,
+        prototype: &EntityPrototype,
+    ) -> bool {
+        return simulation
+            .tech
+            .is_available(self.player_id, prototype.id)
+            .is_ok()
+            && economy
+                .spend(self.player_id, &prototype.build_costs)
+                .is_ok();
+    }
+
+    // the free spot closest to home (within BUILD_RADIUS): on ore for drills, else off it and on
+    // every other row and column, so that there are always lanes left between its structures
+    fn find_site(
+        self: &Self,
+        simulation: &Simulation,
+        prototype: &EntityPrototype,
+        is_on_ore: bool,
+    ) -> Option<(u16, u16)> {
+        let map = &simulation.map;
+        let (home_x, home_y) = (self.setup.home_x, self.setup.home_y);
+        for radius in 0..=BUILD_RADIUS {
+            let low_x = home_x.saturating_sub(radius);
+            let low_y = home_y.saturating_sub(radius);
+            for map_y in low_y..=home_y.saturating_add(radius) {
+                for map_x in low_x..=home_x.saturating_add(radius) {
+                    let is_on_ring =
+                        map_x.abs_diff(home_x) == radius || map_y.abs_diff(home_y) == radius;
+                    if is_on_ring == false {
+                        continue;
+                    }
+                    let has_ore = match map.get_cell(map_x, map_y) {
+                        Ok(cell) => cell.deposit.is_some(),
+                        Err(_) => continue, // off the map
+                    };
+                    let is_on_grid = map_x % 2 == home_x % 2 && map_y % 2 == home_y % 2;
+                    if has_ore != is_on_ore || (is_on_ore == false && is_on_grid == false) {
+                        continue;
+                    }
+                    if placement_system::can_place(map, prototype, map_x, map_y).is_ok() {
+                        return Some((map_x, map_y));
+                    }
+                }
+            }
+        }
+        return None;
+    }
+
+    // tops up the queues of its structures, taking the strategy's units in turn
+    fn train(
+        self: &mut Self,
+        simulation: &Simulation,
+        economy: &mut Economy,
+    ) -> Vec<PlayerCommands> {
+        let mut commands = Vec::new();
+        let unit_ids = self.setup.strategy.unit_ids.clone();
+        if unit_ids.is_empty() {
+            return commands;
+        }
+        let (mut used, cap) = simulation
+            .production
+            .get_supply(&simulation.units, self.player_id);
+        for producer in simulation.production.get_producers() {
+            if producer.owner != self.player_id {
+                continue;
+            }
+            let trains = entity_system::modify(&producer.entity_id, |e| e.prototype_id)
+                .ok()
+                .flatten()
+                .and_then(|id| prototype_system::get(&id))
+                .map(|p| p.trains)
+                .unwrap_or_default();
+            let mut queued = producer.get_queue().len();
+            // give every unit of the strategy a go, starting where it last left off
+            for _ in 0..unit_ids.len() {
+                if queued >= self.setup.difficulty.get_max_queued() {
+                    break;
+                }
+                let prototype_id = unit_ids[self.next_unit_index % unit_ids.len()];
+                let prototype = match prototype_system::get(&prototype_id) {
+                    Some(p) if trains.contains(&prototype_id) => p,
+                    _ => {
+                        self.next_unit_index += 1;
+                        continue;
+                    }
+                };
+                if used as u32 + prototype.supply_cost as u32 > cap as u32
+                    && prototype.supply_cost > 0
+                {
+                    break; // needs more supply first
+                }
+                if self.can_get(simulation, economy, &prototype) == false {
+                    break; // saving up for it
+                }
+                commands.push(PlayerCommands::QueueUnit {
+                    entity_id: producer.entity_id,
+                    prototype_id,
+                });
+                used += prototype.supply_cost;
+                queued += 1;
+                self.next_unit_index += 1;
+            }
+        }
+        return commands;
+    }
+
+    // once enough units stand around, sends them all at the enemy closest to home, structures
+    // first
+    fn attack(self: &Self, simulation: &Simulation) -> Vec<PlayerCommands> {
+        let idle: Vec<_> = simulation
+            .units
+            .get_units()
+            .iter()
+            .filter(|u| u.owner == self.player_id && u.order == UnitOrders::Idle)
+            .map(|u| u.entity_id)
+            .collect();
+        if idle.len() < self.setup.difficulty.get_attack_group_size() {
+            return Vec::new();
+        }
+        let home = (self.setup.home_x, self.setup.home_y);
+        let target = entity_system::snapshot()
+            .into_iter()
+            .filter(|e| e.owner != NEUTRAL_PLAYER_ID && e.owner != CREEP_PLAYER_ID)
+            .filter(|e| e.is_destructible() && e.is_alive())
+            .filter(|e| {
+                simulation.diplomacy.get_relation(self.player_id, e.owner) == Relations::Enemy
+            })
+            .min_by_key(|e| {
+                let distance = get_distance_squared((e.map_x, e.map_y), home);
+                (e.is_structure() == false, distance, e.id)
+            });
+        return match target {
+            Some(t) => vec![PlayerCommands::AttackTarget {
+                entity_ids: idle,
+                target_id: t.id,
+            }],
+            None => Vec::new(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage_system::DeathEvent;
+    use crate::economy_system::{ItemStack, ItemTypes};
+    use crate::map::OreDeposit;
+    use crate::replay_system::{Replay, ReplayPlayer};
+    use crate::savegame_system::{self, SaveGame};
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+
+    struct Match {
+        simulation: Simulation,
+        drill_id: TPrototypeID,
+        rejected: usize,
+        deaths: Vec<DeathEvent>,
+        replay: Replay,
+    }
+
+    // a hard computer against an easy one, from opposite corners
+    fn play_match(ticks: usize) -> Match {
+        let gold = |amount| vec![ItemStack::new(ItemTypes::Gold, amount)];
+        let drill_id = add_prototype("test drill", |p| {
+            p.max_health_points = 50;
+            p.mining_millis_per_item = 500;
+            p.build_costs = gold(10);
+        });
+        let soldier_id = add_prototype("test soldier", |p| {
+            p.max_health_points = 30;
+            p.max_velocity = 10;
+            p.weapon.damage = 5;
+            p.weapon.range = 1;
+            p.weapon.fire_interval_millis = 500;
+            p.build_costs = gold(5);
+            p.production_millis = 500;
+            p.supply_cost = 1;
+        });
+        let barracks_id = add_prototype("test barracks", |p| {
+            p.max_health_points = 200;
+            p.build_costs = gold(20);
+            p.trains = vec![soldier_id];
+            p.production_millis = 500;
+            p.supply_provided = 10;
+        });
+        let mut simulation = new_simulation(32, 16);
+        let ore = Some(OreDeposit {
+            item: ItemTypes::Copper,
+            remaining: None,
+        });
+        simulation.map.set_deposit(4, 4, ore).unwrap();
+        simulation.map.set_deposit(27, 11, ore).unwrap();
+        let strategy = Strategy {
+            drill_id: Some(drill_id),
+            build_order: vec![barracks_id],
+            unit_ids: vec![soldier_id],
+            is_researching: false,
+        };
+        for (player_id, difficulty, home_x, home_y) in [
+            (1, Difficulties::Hard, 2, 2),
+            (2, Difficulties::Easy, 29, 13),
+        ] {
+            add_player(&mut simulation, player_id, 100);
+            let setup = ComputerSetup {
+                difficulty,
+                home_x,
+                home_y,
+                strategy: strategy.clone(),
+            };
+            simulation
+                .computers
+                .push(ComputerPlayer::new(player_id, setup));
+        }
+
+        let mut rejected = 0;
+        let mut deaths = Vec::new();
+        let mut replay = Replay::new(&simulation);
+        for _ in 0..ticks {
+            let report = simulation.step_once();
+            replay.record(&report);
+            rejected += report
+                .computer_commands
+                .iter()
+                .filter(|(_, r)| r.is_err())
+                .count();
+            deaths.extend(report.death_events);
+        }
+        return Match {
+            simulation,
+            drill_id,
+            rejected,
+            deaths,
+            replay,
+        };
+    }
+
+    #[test]
+    fn test_computer_players_only_ask_for_what_they_can_do() {
+        let _world = TestWorld::new();
+        let played = play_match(30 * 30);
+        assert_eq!(played.rejected, 0);
+        let drills = entity_system::snapshot()
+            .iter()
+            .filter(|e| e.owner == 1 && e.prototype_id == Some(played.drill_id))
+            .count();
+        assert_eq!(drills, 1); // only one ore cell nearby
+        let copper = played.simulation.economy.get_balance(1, ItemTypes::Copper);
+        assert!(copper > 0);
+    }
+
+    #[test]
+    fn test_harder_computer_players_attack_sooner() {
+        let _world = TestWorld::new();
+        let played = play_match(30 * 30);
+        // the hard one has been attacking for a while, the easy one is still gathering an army
+        assert!(played.deaths.iter().any(|d| d.entity.owner == 2));
+    }
+
+    #[test]
+    fn test_computer_players_play_the_same_in_replays() {
+        let _world = TestWorld::new();
+        let played = play_match(10 * 30);
+        assert!(played.replay.commands.is_empty()); // they are played again instead
+        let mut player = ReplayPlayer::new(played.replay).unwrap();
+        while player.step().is_some() {}
+        let replayed = player.get_simulation();
+        assert_eq!(replayed.computers, played.simulation.computers);
+        assert_eq!(replayed.economy, played.simulation.economy);
+    }
+
+    #[test]
+    fn test_computer_players_are_saved() {
+        let _world = TestWorld::new();
+        let mut simulation = new_simulation(8, 8);
+        add_player(&mut simulation, 1, 0);
+        let setup = ComputerSetup {
+            difficulty: Difficulties::Normal,
+            home_x: 2,
+            home_y: 2,
+            strategy: Strategy {
+                drill_id: None,
+                build_order: Vec::new(),
+                unit_ids: Vec::new(),
+                is_researching: false,
+            },
+        };
+        simulation.computers.push(ComputerPlayer::new(1, setup));
+        simulation.step_once();
+        let thought_at = simulation.computers[0].next_think_millis;
+        assert!(thought_at > 0);
+
+        let savegame = SaveGame::new(&simulation);
+        let bin = savegame_system::serialize_savegame_for_save(&savegame).unwrap();
+        let loaded = savegame_system::deserialize_savegame_for_load(&bin).unwrap();
+        assert_eq!(loaded.snapshot.simulation.computers, simulation.computers);
+    }
+}
@@ -0,0 +1,761 @@
+// Mindustry-style item transport on the map grid; belts carry items along, junctions let two lines
+// cross, routers spread items to every other side, sorters split by item type, and bridges hop
+// over a few cells.  Sinks and ports are where items leave the network (a core, a factory's input).
+// Everything happens in fixed ticks (the Simulation's, see update()), cells processed row by row,
+// so that the same inputs always give the same results (see tick())
+use crate::economy_system::{ItemTypes, TPlayerID, ITEM_TYPES_COUNT};
+use crate::entity_system::{self, PhysicsObjectCollisionTypes, TEntityID};
+use crate::map::{Directions, Map};
+use crate::simulation::get_tick_millis;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub const BELT_LENGTH: u16 = 120; // distance an item travels to cross a cell, multiple of the speeds
+pub const ITEM_SPACING: u16 = 40; // minimum distance between items travelling the same way
+pub const BELT_SPEED: u16 = 8; // per tick, so one item per 5 ticks (6 per second) at best
+pub const JUNCTION_SPEED: u16 = 30;
+pub const ROUTER_SPEED: u16 = 30;
+pub const BRIDGE_SPEED: u16 = 20;
+pub const JUNCTION_CAPACITY_PER_DIRECTION: usize = 2;
+pub const BRIDGE_CAPACITY: usize = 4;
+pub const MAX_BRIDGE_RANGE: u16 = 4; // cells, the bridge links to the first bridge within range
+pub const CONVEYOR_LAYER_WEIGHT: u8 = 0xF0; // heavy, so that whatever is on top is drawn over it
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum ConveyorTypes {
+    Belt { direction: Directions },
+    Junction, // items pass straight through, in whichever way they entered
+    Router,   // round-robins items to every side but the one they came from
+    Sorter { filter: ItemTypes }, // filter passes straight, others alternate left/right
+    Bridge { direction: Directions },
+    Sink,                      // accepts anything, see take_delivered()
+    Port { owner: TEntityID }, // a structure's cell, accepts only what it wants (see set_port_wants())
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct BeltItem {
+    pub item: ItemTypes,
+    pub direction: Directions, // which way it is travelling
+    pub progress: u16,         // 0..=BELT_LENGTH
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ConveyorCell {
+    pub block: ConveyorTypes,
+    items: Vec<BeltItem>, // oldest first
+    next_output: u8,      // routers round-robin, sorters alternate
+    #[serde(default)] // None for ports (the structure is what occupies their cell), and old saves
+    entity_id: Option<TEntityID>, // what marks the cell as taken on the Map, see add_block()
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct DeliveredItem {
+    pub map_x: u16, // the sink (or port) it got delivered to
+    pub map_y: u16,
+    pub item: ItemTypes,
+    pub port_owner: Option<TEntityID>,
+}
+
+impl ConveyorCell {
+    fn new(block: ConveyorTypes) -> ConveyorCell {
+        ConveyorCell {
+            block,
+            items: Vec::new(),
+            next_output: 0,
+            entity_id: None,
+        }
+    }
+
+    fn get_speed(self: &Self) -> u16 {
+        return match self.block {
+            ConveyorTypes::Belt { .. } => BELT_SPEED,
+            ConveyorTypes::Junction => JUNCTION_SPEED,
+            ConveyorTypes::Router | ConveyorTypes::Sorter { .. } => ROUTER_SPEED,
+            ConveyorTypes::Bridge { .. } => BRIDGE_SPEED,
+            ConveyorTypes::Sink | ConveyorTypes::Port { .. } => BELT_LENGTH,
+        };
+    }
+
+    // the way an item entering (travelling in item_direction) will travel within this cell
+    fn get_travel_direction(self: &Self, item_direction: Directions) -> Directions {
+        return match self.block {
+            ConveyorTypes::Belt { direction } | ConveyorTypes::Bridge { direction } => direction,
+            _ => item_direction,
+        };
+    }
+
+    // spacing is only between items travelling the same way (i.e. crossing a junction)
+    fn has_room(self: &Self, travel_direction: Directions) -> bool {
+        return match self
+            .items
+            .iter()
+            .rev()
+            .find(|i| i.direction == travel_direction)
+        {
+            Some(newest) => newest.progress >= ITEM_SPACING,
+            None => true,
+        };
+    }
+
+    fn can_accept(self: &Self, item_direction: Directions) -> bool {
+        let travel_direction = self.get_travel_direction(item_direction);
+        return match self.block {
+            ConveyorTypes::Belt { direction } => {
+                // cannot be fed from the end it outputs to
+                item_direction != direction.opposite() && self.has_room(travel_direction)
+            }
+            ConveyorTypes::Junction => {
+                self.items
+                    .iter()
+                    .filter(|i| i.direction == travel_direction)
+                    .count()
+                    < JUNCTION_CAPACITY_PER_DIRECTION
+                    && self.has_room(travel_direction)
+            }
+            ConveyorTypes::Router | ConveyorTypes::Sorter { .. } => self.items.is_empty(),
+            ConveyorTypes::Bridge { direction } => {
+                item_direction != direction.opposite()
+                    && self.items.len() < BRIDGE_CAPACITY
+                    && self.has_room(travel_direction)
+            }
+            ConveyorTypes::Sink => true,
+            ConveyorTypes::Port { .. } => false, // see ConveyorNetwork::can_accept()
+        };
+    }
+
+    fn advance(self: &mut Self) {
+        let speed = self.get_speed();
+        for index in 0..self.items.len() {
+            let travel_direction = self.items[index].direction;
+            let limit = match self.items[..index]
+                .iter()
+                .rev()
+                .find(|i| i.direction == travel_direction)
+            {
+                Some(ahead) => ahead.progress.saturating_sub(ITEM_SPACING),
+                None => BELT_LENGTH,
+            };
+            let item = &mut self.items[index];
+            item.progress = item.progress.max((item.progress + speed).min(limit));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ConveyorNetwork {
+    cells: BTreeMap<(u16, u16), ConveyorCell>, // keyed by (map_y, map_x), so iterated row by row
+    delivered: Vec<DeliveredItem>,
+    port_wants: BTreeMap<TEntityID, [u32; ITEM_TYPES_COUNT]>, // how many more of each a port owner takes
+    tick_count: u64,
+    pending_millis: u128, // leftover from update() that did not add up to a whole tick yet
+}
+
+// row by row, see ConveyorNetwork::cells
+fn to_key(map_x: u16, map_y: u16) -> (u16, u16) {
+    return (map_y, map_x);
+}
+
+impl ConveyorNetwork {
+    pub fn new() -> ConveyorNetwork {
+        ConveyorNetwork {
+            cells: BTreeMap::new(),
+            delivered: Vec::new(),
+            port_wants: BTreeMap::new(),
+            tick_count: 0,
+            pending_millis: 0,
+        }
+    }
+
+    // conveyors cannot be built on cells occupied by entities (towers, drills, units), and each
+    // one is placed on the Map as an entity of its own so that nothing gets built on top of it
+    // either (see placement_system::can_place()); walkers still cross it, see Floor
+    pub fn add_block(
+        self: &mut Self,
+        map: &mut Map,
+        owner: TPlayerID,
+        map_x: u16,
+        map_y: u16,
+        block: ConveyorTypes,
+    ) -> Result<(), String> {
+        if map.is_in_bounds(map_x, map_y) == false {
+            return Err(format!("({}, {}) is out of bounds", map_x, map_y));
+        }
+        if map.get_cell(map_x, map_y)?.layers.is_empty() == false {
+            return Err(format!("({}, {}) is occupied", map_x, map_y));
+        }
+        if self.cells.contains_key(&to_key(map_x, map_y)) {
+            return Err(format!("({}, {}) already has a conveyor", map_x, map_y));
+        }
+        let entity_id = entity_system::add(&0, CONVEYOR_LAYER_WEIGHT)?;
+        entity_system::modify(&entity_id, |e| {
+            e.map_x = map_x;
+            e.map_y = map_y;
+            e.owner = owner;
+            e.physics_info.collision_type = PhysicsObjectCollisionTypes::Floor;
+        })?;
+        if let Err(e) = map.place_entity(map_x, map_y, entity_id) {
+            let _ = entity_system::remove(&entity_id);
+            return Err(e);
+        }
+        let mut cell = ConveyorCell::new(block);
+        cell.entity_id = Some(entity_id);
+        self.cells.insert(to_key(map_x, map_y), cell);
+        return Ok(());
+    }
+
+    // turns the cells of a placed structure (i.e. a factory) into ports, so that belts can feed it
+    pub fn add_ports(
+        self: &mut Self,
+        map: &Map,
+        owner: &TEntityID,
+        footprint: &Vec<(u16, u16)>,
+    ) -> Result<(), String> {
+        for (map_x, map_y) in footprint.iter() {
+            if map.is_in_bounds(*map_x, *map_y) == false
+                || map.get_cell(*map_x, *map_y)?.contains_entity(owner) == false
+            {
+                return Err(format!(
+                    "entityID={} is not placed on ({}, {})",
+                    owner, map_x, map_y
+                ));
+            }
+            if self.cells.contains_key(&to_key(*map_x, *map_y)) {
+                return Err(format!("({}, {}) already has a conveyor", map_x, map_y));
+            }
+        }
+        for (map_x, map_y) in footprint.iter() {
+            self.cells.insert(
+                to_key(*map_x, *map_y),
+                ConveyorCell::new(ConveyorTypes::Port { owner: *owner }),
+            );
+        }
+        self.port_wants.insert(*owner, [0; ITEM_TYPES_COUNT]);
+        return Ok(());
+    }
+    pub fn remove_ports(self: &mut Self, owner: &TEntityID) {
+        self.cells
+            .retain(|_, cell| cell.block != ConveyorTypes::Port { owner: *owner });
+        self.port_wants.remove(owner);
+    }
+    // how many more of each item the owner's ports will take (i.e. room in a factory's buffer)
+    pub fn set_port_wants(self: &mut Self, owner: &TEntityID, wants: [u32; ITEM_TYPES_COUNT]) {
+        if let Some(port_wants) = self.port_wants.get_mut(owner) {
+            *port_wants = wants;
+        }
+    }
+
+    // returns the items that were on it, None if there was no conveyor there (ports are taken
+    // off along with their structure, see remove_ports())
+    pub fn remove_block(
+        self: &mut Self,
+        map: &mut Map,
+        map_x: u16,
+        map_y: u16,
+    ) -> Option<Vec<ItemTypes>> {
+        let key = to_key(map_x, map_y);
+        if let Some(ConveyorTypes::Port { .. }) | None = self.cells.get(&key).map(|c| c.block) {
+            return None;
+        }
+        let cell = self.cells.remove(&key)?;
+        if let Some(entity_id) = cell.entity_id {
+            map.remove_entity(&entity_id);
+            let _ = entity_system::remove(&entity_id);
+        }
+        return Some(cell.items.iter().map(|i| i.item).collect());
+    }
+
+    pub fn get_block(self: &Self, map_x: u16, map_y: u16) -> Option<ConveyorTypes> {
+        return self.cells.get(&to_key(map_x, map_y)).map(|c| c.block);
+    }
+
+    // the entity marking the block's cell, which carries who owns it (None for ports)
+    pub fn get_entity_id(self: &Self, map_x: u16, map_y: u16) -> Option<TEntityID> {
+        return self.cells.get(&to_key(map_x, map_y))?.entity_id;
+    }
+
+    pub fn get_items(self: &Self, map_x: u16, map_y: u16) -> Vec<BeltItem> {
+        return match self.cells.get(&to_key(map_x, map_y)) {
+            Some(cell) => cell.items.clone(),
+            None => Vec::new(),
+        };
+    }
+
+    pub fn get_item_count(self: &Self) -> usize {
+        return self.cells.values().map(|c| c.items.len()).sum();
+    }
+
+    pub fn get_tick_count(self: &Self) -> u64 {
+        return self.tick_count;
+    }
+
+    // feeds an item in from outside of the network (drills, factories), item_direction being the
+    // way it is travelling as it enters.  False if the cell is full or has no conveyor
+    pub fn insert_item(
+        self: &mut Self,
+        map_x: u16,
+        map_y: u16,
+        item: ItemTypes,
+        item_direction: Directions,
+    ) -> bool {
+        let key = to_key(map_x, map_y);
+        let accepts = self.can_accept(key, item, item_direction);
+        if accepts {
+            self.accept(key, item, item_direction);
+        }
+        return accepts;
+    }
+
+    // feeds an item into the first conveyor next to the footprint (cells of a structure, see
+    // placement_system::get_footprint()) which will take it
+    pub fn insert_adjacent(self: &mut Self, footprint: &Vec<(u16, u16)>, item: ItemTypes) -> bool {
+        for (cell_x, cell_y) in footprint.iter() {
+            for direction in Directions::ALL {
+                if let Some((x, y)) = direction.step(*cell_x, *cell_y) {
+                    if footprint.contains(&(x, y)) == false
+                        && self.insert_item(x, y, item, direction)
+                    {
+                        return true;
+                    }
+                }
+            }
+        }
+        return false;
+    }
+
+    // items which have reached sinks/ports since the last call, in the order they arrived
+    pub fn take_delivered(self: &mut Self) -> Vec<DeliveredItem> {
+        return std::mem::take(&mut self.delivered);
+    }
+    // only those delivered to the owner's ports, leaving the rest for take_delivered()
+    pub fn take_delivered_to(self: &mut Self, owner: &TEntityID) -> Vec<ItemTypes> {
+        let mut taken = Vec::new();
+        self.delivered
+            .retain(|d| match d.port_owner == Some(*owner) {
+                true => {
+                    taken.push(d.item);
+                    false
+                }
+                false => true,
+            });
+        return taken;
+    }
+
+    fn can_accept(
+        self: &Self,
+        key: (u16, u16),
+        item: ItemTypes,
+        item_direction: Directions,
+    ) -> bool {
+        return match self.cells.get(&key) {
+            Some(ConveyorCell {
+                block: ConveyorTypes::Port { owner },
+                ..
+            }) => match self.port_wants.get(owner) {
+                Some(wants) => wants[item.index()] > 0,
+                None => false,
+            },
+            Some(cell) => cell.can_accept(item_direction),
+            None => false,
+        };
+    }
+
+    // the caller must have checked can_accept() already
+    fn accept(self: &mut Self, key: (u16, u16), item: ItemTypes, item_direction: Directions) {
+        let cell = self.cells.get_mut(&key).unwrap();
+        match cell.block {
+            ConveyorTypes::Sink | ConveyorTypes::Port { .. } => {
+                let port_owner = match cell.block {
+                    ConveyorTypes::Port { owner } => Some(owner),
+                    _ => None,
+                };
+                if let Some(wants) = port_owner.and_then(|owner| self.port_wants.get_mut(&owner)) {
+                    wants[item.index()] -= 1;
+                }
+                self.delivered.push(DeliveredItem {
+                    map_x: key.1,
+                    map_y: key.0,
+                    item,
+                    port_owner,
+                });
+                return;
+            }
+            _ => {}
+        }
+        let direction = cell.get_travel_direction(item_direction);
+        cell.items.push(BeltItem {
+            item,
+            direction,
+            progress: 0,
+        });
+    }
+
+    // where the item may go next, in order of preference (key of the target and the way the item
+    // will be travelling as it enters it)
+    fn get_targets(
+        self: &Self,
+        key: (u16, u16),
+        cell: &ConveyorCell,
+        item: &BeltItem,
+    ) -> Vec<((u16, u16), Directions)> {
+        let (map_y, map_x) = key;
+        let step = |direction: Directions| {
+            direction
+                .step(map_x, map_y)
+                .map(|(x, y)| (to_key(x, y), direction))
+        };
+        return match cell.block {
+            ConveyorTypes::Belt { .. } | ConveyorTypes::Junction => {
+                step(item.direction).into_iter().collect()
+            }
+            ConveyorTypes::Router => (0..4)
+                .map(|i| Directions::ALL[(cell.next_output as usize + i) % 4])
+                .filter(|d| *d != item.direction.opposite()) // not back where it came from
+                .filter_map(step)
+                .collect(),
+            ConveyorTypes::Sorter { filter } => {
+                if item.item == filter {
+                    step(item.direction).into_iter().collect()
+                } else if cell.next_output % 2 == 0 {
+                    [item.direction.turn_left(), item.direction.turn_right()]
+                        .into_iter()
+                        .filter_map(step)
+                        .collect()
+                } else {
+                    [item.direction.turn_right(), item.direction.turn_left()]
+                        .into_iter()
+                        .filter_map(step)
+                        .collect()
+                }
+            }
+            ConveyorTypes::Bridge { direction } => {
+                // hop to the linked bridge if there is one in range, else it is the exit end
+                let mut position = (map_x, map_y);
+                for _ in 0..MAX_BRIDGE_RANGE {
+                    position = match direction.step(position.0, position.1) {
+                        Some(p) => p,
+                        None => break,
+                    };
+                    let linked_key = to_key(position.0, position.1);
+                    if let Some(linked) = self.cells.get(&linked_key) {
+                        if linked.block == (ConveyorTypes::Bridge { direction }) {
+                            return vec![(linked_key, direction)];
+                        }
+                    }
+                }
+                step(direction).into_iter().collect()
+            }
+            ConveyorTypes::Sink | ConveyorTypes::Port { .. } => Vec::new(),
+        };
+    }
+
+    /// Advances everything by a single tick; items first move within their cells, then the ones
+    /// which reached the end of their cell are handed over (row by row) to the next.  An item
+    /// handed over starts at the beginning of its new cell, so it cannot move twice in one tick
+    pub fn tick(self: &mut Self) {
+        for cell in self.cells.values_mut() {
+            cell.advance();
+        }
+        let keys: Vec<(u16, u16)> = self.cells.keys().copied().collect();
+        for key in keys {
+            let mut index = 0;
+            loop {
+                let cell = &self.cells[&key];
+                if index >= cell.items.len() {
+                    break;
+                }
+                let item = cell.items[index];
+                if item.progress < BELT_LENGTH {
+                    index += 1;
+                    continue;
+                }
+                let target = self.get_targets(key, cell, &item).into_iter().find(
+                    |(target_key, direction)| self.can_accept(*target_key, item.item, *direction),
+                );
+                match target {
+                    Some((target_key, direction)) => {
+                        let cell = self.cells.get_mut(&key).unwrap();
+                        cell.items.remove(index);
+                        match cell.block {
+                            ConveyorTypes::Router => {
+                                cell.next_output = (direction as u8 + 1) % 4;
+                            }
+                            ConveyorTypes::Sorter { .. } if direction != item.direction => {
+                                // alternate, so that the next one goes the other way
+                                cell.next_output = match direction == item.direction.turn_left() {
+                                    true => 1,
+                                    false => 0,
+                                };
+                            }
+                            _ => {}
+                        }
+                        self.accept(target_key, item.item, direction);
+                    }
+                    None => index += 1, // blocked, try again next tick
+                }
+            }
+        }
+        self.tick_count += 1;
+    }
+
+    // runs as many whole ticks as the elapsed time adds up to, returns how many were run; ticks
+    // are as long as the Simulation's (see get_tick_millis()), so stepped along with it that is
+    // exactly one per Simulation tick
+    pub fn update(self: &mut Self, last_frame_delta_millis: u128) -> u128 {
+        self.pending_millis += last_frame_delta_millis;
+        let mut ticks = 0;
+        while self.pending_millis >= get_tick_millis(self.tick_count) {
+            self.pending_millis -= get_tick_millis(self.tick_count);
+            self.tick();
+            ticks += 1;
+        }
+        return ticks;
+    }
+}
+
+pub fn serialize_conveyors_for_save(network: &ConveyorNetwork) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match network.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_conveyors_for_load(bin_data: &Vec<u8>) -> Result<ConveyorNetwork, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    return rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathfinding_system;
+    use crate::placement_system;
+    use crate::prototype_system::EntityPrototype;
+    use crate::simulation::TICKS_PER_SECOND;
+    use crate::test_helpers::TestWorld;
+
+    fn run_straight_line(ticks: u32) -> (ConveyorNetwork, u32, Vec<DeliveredItem>) {
+        entity_system::reset(); // so that the belts' entities get the same IDs every run
+        let mut the_map = Map::create(8, 1).unwrap();
+        let mut network = ConveyorNetwork::new();
+        for x in 0..6 {
+            let east = ConveyorTypes::Belt {
+                direction: Directions::East,
+            };
+            network.add_block(&mut the_map, 1, x, 0, east).unwrap();
+        }
+        network
+            .add_block(&mut the_map, 1, 6, 0, ConveyorTypes::Sink)
+            .unwrap();
+        let mut inserted = 0;
+        let mut delivered = Vec::new();
+        for _ in 0..ticks {
+            if network.insert_item(0, 0, ItemTypes::Copper, Directions::East) {
+                inserted += 1;
+            }
+            network.tick();
+            delivered.extend(network.take_delivered());
+        }
+        return (network, inserted, delivered);
+    }
+
+    #[test]
+    fn test_belt_throughput() {
+        let _world = TestWorld::new();
+        let (network, inserted, delivered) = run_straight_line(400);
+        // nothing gets lost, and the line runs at (close to) full belt speed of 6 per second
+        assert_eq!(
+            inserted as usize,
+            delivered.len() + network.get_item_count()
+        );
+        assert_eq!(inserted, 400 / (ITEM_SPACING / BELT_SPEED) as u32);
+        assert!(delivered.len() >= 60); // minus the ~90 ticks it takes to cross 6 belts
+        assert!(delivered.iter().all(|d| (d.map_x, d.map_y) == (6, 0)));
+    }
+
+    #[test]
+    fn test_same_inputs_same_results() {
+        let _world = TestWorld::new();
+        let (network, _, delivered) = run_straight_line(400);
+        let (other_network, _, other_delivered) = run_straight_line(400);
+        assert_eq!(network, other_network);
+        assert_eq!(delivered, other_delivered);
+    }
+
+    #[test]
+    fn test_networks_are_saved() {
+        let _world = TestWorld::new();
+        let (network, _, _) = run_straight_line(100);
+        let bin = serialize_conveyors_for_save(&network).unwrap();
+        assert_eq!(deserialize_conveyors_for_load(&bin).unwrap(), network);
+    }
+
+    #[test]
+    fn test_one_tick_per_simulation_tick() {
+        let mut network = ConveyorNetwork::new();
+        for tick in 0..3 * TICKS_PER_SECOND {
+            assert_eq!(network.update(get_tick_millis(tick)), 1);
+        }
+        // and no time is lost when stepped with odd amounts
+        assert_eq!(network.update(50), 1);
+        assert_eq!(network.update(50), 2);
+        assert_eq!(network.get_tick_count(), 3 * TICKS_PER_SECOND + 3);
+    }
+
+    fn belt(direction: Directions) -> ConveyorTypes {
+        return ConveyorTypes::Belt { direction };
+    }
+
+    // feeds the given items in (taking turns, one a tick) at the given cells for a second, then
+    // lets the network deliver all of it
+    fn deliver(
+        network: &mut ConveyorNetwork,
+        inputs: &[(u16, u16, Directions, &[ItemTypes])],
+    ) -> Vec<DeliveredItem> {
+        let mut delivered = Vec::new();
+        for tick in 0..200 {
+            if tick < 30 {
+                for (map_x, map_y, direction, items) in inputs {
+                    let item = items[tick % items.len()];
+                    network.insert_item(*map_x, *map_y, item, *direction);
+                }
+            }
+            network.tick();
+            delivered.extend(network.take_delivered());
+        }
+        assert_eq!(network.get_item_count(), 0);
+        return delivered;
+    }
+
+    fn get_delivered_at(delivered: &[DeliveredItem], map_x: u16, map_y: u16) -> Vec<ItemTypes> {
+        return delivered
+            .iter()
+            .filter(|d| (d.map_x, d.map_y) == (map_x, map_y))
+            .map(|d| d.item)
+            .collect();
+    }
+
+    #[test]
+    fn test_sorter_sends_the_rest_aside() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(4, 4).unwrap();
+        let mut network = ConveyorNetwork::new();
+        let mut add = |x, y, block| network.add_block(&mut the_map, 1, x, y, block).unwrap();
+        add(0, 1, belt(Directions::East));
+        add(
+            1,
+            1,
+            ConveyorTypes::Sorter {
+                filter: ItemTypes::Copper,
+            },
+        );
+        for (x, y) in [(2, 1), (1, 0), (1, 2)] {
+            add(x, y, ConveyorTypes::Sink);
+        }
+        let mixed = [ItemTypes::Copper, ItemTypes::Lead];
+        let delivered = deliver(&mut network, &[(0, 1, Directions::East, &mixed)]);
+        // copper straight through, lead to either side
+        let straight = get_delivered_at(&delivered, 2, 1);
+        assert!(straight.len() > 0 && straight.iter().all(|i| *i == ItemTypes::Copper));
+        for (x, y) in [(1, 0), (1, 2)] {
+            let aside = get_delivered_at(&delivered, x, y);
+            assert!(aside.len() > 0 && aside.iter().all(|i| *i == ItemTypes::Lead));
+        }
+    }
+
+    #[test]
+    fn test_bridge_over_empty_cells() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(8, 1).unwrap();
+        let mut network = ConveyorNetwork::new();
+        let mut add = |x, block| network.add_block(&mut the_map, 1, x, 0, block).unwrap();
+        let bridge = ConveyorTypes::Bridge {
+            direction: Directions::East,
+        };
+        add(0, belt(Directions::East));
+        add(1, bridge);
+        add(4, bridge);
+        add(5, ConveyorTypes::Sink);
+        let delivered = deliver(
+            &mut network,
+            &[(0, 0, Directions::East, &[ItemTypes::Sand])],
+        );
+        assert!(get_delivered_at(&delivered, 5, 0).len() > 0);
+        assert_eq!(delivered.len(), get_delivered_at(&delivered, 5, 0).len());
+    }
+
+    #[test]
+    fn test_junction_crosses_lines() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(4, 4).unwrap();
+        let mut network = ConveyorNetwork::new();
+        let mut add = |x, y, block| network.add_block(&mut the_map, 1, x, y, block).unwrap();
+        // crossed by a line going east and one going south
+        add(0, 1, belt(Directions::East));
+        add(1, 0, belt(Directions::South));
+        add(1, 1, ConveyorTypes::Junction);
+        add(2, 1, ConveyorTypes::Sink);
+        add(1, 2, ConveyorTypes::Sink);
+        let delivered = deliver(
+            &mut network,
+            &[
+                (0, 1, Directions::East, &[ItemTypes::Coal]),
+                (1, 0, Directions::South, &[ItemTypes::Lead]),
+            ],
+        );
+        let east = get_delivered_at(&delivered, 2, 1);
+        assert!(east.len() > 0 && east.iter().all(|i| *i == ItemTypes::Coal));
+        let south = get_delivered_at(&delivered, 1, 2);
+        assert!(south.len() > 0 && south.iter().all(|i| *i == ItemTypes::Lead));
+    }
+
+    #[test]
+    fn test_router_spreads_evenly() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(4, 4).unwrap();
+        let mut network = ConveyorNetwork::new();
+        let mut add = |x, y, block| network.add_block(&mut the_map, 1, x, y, block).unwrap();
+        // over the 3 other sides
+        add(0, 1, belt(Directions::East));
+        add(1, 1, ConveyorTypes::Router);
+        for (x, y) in [(2, 1), (1, 0), (1, 2)] {
+            add(x, y, ConveyorTypes::Sink);
+        }
+        let delivered = deliver(
+            &mut network,
+            &[(0, 1, Directions::East, &[ItemTypes::Titanium])],
+        );
+        let routed =
+            [(2, 1), (1, 0), (1, 2)].map(|(x, y)| get_delivered_at(&delivered, x, y).len());
+        assert!(routed.iter().max().unwrap() - routed.iter().min().unwrap() <= 1);
+        assert!(network
+            .add_block(&mut the_map, 1, 1, 1, ConveyorTypes::Sink)
+            .is_err());
+    }
+
+    #[test]
+    fn test_belts_take_up_their_cell() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(4, 4).unwrap();
+        let mut network = ConveyorNetwork::new();
+        let east = belt(Directions::East);
+        network.add_block(&mut the_map, 1, 1, 1, east).unwrap();
+        let tower = EntityPrototype::new(0, "test tower", 0);
+        assert!(placement_system::can_place(&the_map, &tower, 1, 1).is_err());
+        assert!(pathfinding_system::is_passable(&the_map, 1, 1)); // walked over
+        assert!(network.add_block(&mut the_map, 1, 1, 1, east).is_err());
+
+        network.insert_item(1, 1, ItemTypes::Copper, Directions::East);
+        assert_eq!(
+            network.remove_block(&mut the_map, 1, 1),
+            Some(vec![ItemTypes::Copper])
+        );
+        assert!(the_map.get_cell(1, 1).unwrap().layers.is_empty());
+        assert!(entity_system::snapshot().is_empty());
+        assert!(placement_system::can_place(&the_map, &tower, 1, 1).is_ok());
+        assert_eq!(network.remove_block(&mut the_map, 1, 1), None);
+    }
+}
@@ -0,0 +1,332 @@
+// Factories (i.e. graphite press, silicon smelter) which turn input items into output items over
+// time, according to data-driven recipes.  Inputs arrive through conveyor ports on the factory's
+// own cells (see ConveyorNetwork::add_ports()), and outputs are pushed onto adjacent conveyors
+use crate::conveyor_system::ConveyorNetwork;
+use crate::economy_system::{ItemStack, ItemTypes, ITEM_TYPES_COUNT};
+use crate::entity_system::{self, TEntityID};
+use crate::map::Map;
+use crate::placement_system;
+use crate::power_system::FULL_POWER_PERMILLE;
+use crate::prototype_system;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub type TRecipeID = u16;
+
+pub const BUFFERED_CRAFTS: u32 = 2; // buffers hold enough inputs/outputs for this many crafts
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Recipe {
+    pub id: TRecipeID,
+    pub name: String,
+    pub inputs: Vec<ItemStack>,
+    pub outputs: Vec<ItemStack>,
+    pub craft_millis: u128, // at full power
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Factory {
+    pub entity_id: TEntityID,
+    pub recipe_id: TRecipeID,
+    pub footprint: Vec<(u16, u16)>,
+    input_buffer: [u32; ITEM_TYPES_COUNT],
+    output_buffer: [u32; ITEM_TYPES_COUNT],
+    is_crafting: bool, // inputs are consumed when a craft starts
+    progress_millis: u128,
+}
+
+impl Factory {
+    pub fn get_input(self: &Self, item: ItemTypes) -> u32 {
+        return self.input_buffer[item.index()];
+    }
+    pub fn get_output(self: &Self, item: ItemTypes) -> u32 {
+        return self.output_buffer[item.index()];
+    }
+    pub fn is_crafting(self: &Self) -> bool {
+        return self.is_crafting;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CraftingSystem {
+    recipes: Vec<Recipe>, // sorted by id
+    factories: BTreeMap<TEntityID, Factory>,
+}
+
+impl CraftingSystem {
+    pub fn new(recipes: Vec<Recipe>) -> Result<CraftingSystem, String> {
+        let mut sorted = recipes;
+        sorted.sort_by_key(|r| r.id);
+        for pair in sorted.windows(2) {
+            if pair[0].id == pair[1].id {
+                return Err(format!("recipeID={} is defined more than once", pair[0].id));
+            }
+        }
+        for recipe in sorted.iter() {
+            if recipe.outputs.is_empty() || recipe.craft_millis == 0 {
+                return Err(format!(
+                    "recipe '{}' needs outputs and a craft time",
+                    recipe.name
+                ));
+            }
+        }
+        Ok(CraftingSystem {
+            recipes: sorted,
+            factories: BTreeMap::new(),
+        })
+    }
+
+    pub fn get_recipe(self: &Self, recipe_id: TRecipeID) -> Option<&Recipe> {
+        return match self.recipes.binary_search_by(|r| r.id.cmp(&recipe_id)) {
+            Ok(index) => Some(&self.recipes[index]),
+            Err(_) => None,
+        };
+    }
+    pub fn get_factory(self: &Self, entity_id: &TEntityID) -> Option<&Factory> {
+        return self.factories.get(entity_id);
+    }
+    pub fn get_factories(self: &Self) -> Vec<&Factory> {
+        return self.factories.values().collect();
+    }
+
+    /// Registers an already placed (see placement_system) factory, based on its prototype's
+    /// crafting_recipe_id, and opens up its cells as conveyor ports
+    pub fn add_factory(
+        self: &mut Self,
+        map: &Map,
+        conveyors: &mut ConveyorNetwork,
+        entity_id: &TEntityID,
+    ) -> Result<(), String> {
+        if self.factories.contains_key(entity_id) {
+            return Err(format!("entityID={} is already a factory", entity_id));
+        }
+        let entity = entity_system::modify(entity_id, |e| *e)?;
+        let prototype = match entity
+            .prototype_id
+            .and_then(|id| prototype_system::get(&id))
+        {
+            Some(p) => p,
+            None => return Err(format!("entityID={} has no prototype", entity_id)),
+        };
+        let recipe_id = match prototype.crafting_recipe_id {
+            Some(id) if self.get_recipe(id).is_some() => id,
+            Some(id) => return Err(format!("recipeID={} does not exist", id)),
+            None => return Err(format!("'{}' is not a factory", prototype.name)),
+        };
+        let footprint = placement_system::get_footprint(&prototype, entity.map_x, entity.map_y);
+        conveyors.add_ports(map, entity_id, &footprint)?;
+        self.factories.insert(
+            *entity_id,
+            Factory {
+                entity_id: *entity_id,
+                recipe_id,
+                footprint,
+                input_buffer: [0; ITEM_TYPES_COUNT],
+                output_buffer: [0; ITEM_TYPES_COUNT],
+                is_crafting: false,
+                progress_millis: 0,
+            },
+        );
+        return Ok(());
+    }
+
+    // returns the factory (and whatever was left in its buffers)
+    pub fn remove_factory(
+        self: &mut Self,
+        conveyors: &mut ConveyorNetwork,
+        entity_id: &TEntityID,
+    ) -> Option<Factory> {
+        conveyors.remove_ports(entity_id);
+        return self.factories.remove(entity_id);
+    }
+
+    /// Per factory (in entity order): takes in what was delivered to its ports, crafts (slower
+    /// when under-powered), pushes outputs onto adjacent conveyors, then tells its ports how much
+    /// more input there is room for
+    pub fn update(self: &mut Self, last_frame_delta_millis: u128, conveyors: &mut ConveyorNetwork) {
+        for factory in self.factories.values_mut() {
+            let recipe = match self
+                .recipes
+                .binary_search_by(|r| r.id.cmp(&factory.recipe_id))
+            {
+                Ok(index) => &self.recipes[index],
+                Err(_) => continue,
+            };
+            for item in conveyors.take_delivered_to(&factory.entity_id) {
+                factory.input_buffer[item.index()] += 1;
+            }
+
+            let power_permille = entity_system::modify(&factory.entity_id, |e| e.power_permille)
+                .unwrap_or(FULL_POWER_PERMILLE);
+            let mut elapsed_millis =
+                last_frame_delta_millis * power_permille as u128 / FULL_POWER_PERMILLE as u128;
+            loop {
+                if factory.is_crafting == false {
+                    let has_inputs = recipe
+                        .inputs
+                        .iter()
+                        .all(|stack| factory.input_buffer[stack.item.index()] >= stack.amount);
+                    if has_inputs == false {
+                        factory.progress_millis = 0;
+                        break;
+                    }
+                    for stack in recipe.inputs.iter() {
+                        factory.input_buffer[stack.item.index()] -= stack.amount;
+                    }
+                    factory.is_crafting = true;
+                }
+                let needed_millis = recipe.craft_millis - factory.progress_millis;
+                if elapsed_millis < needed_millis {
+                    factory.progress_millis += elapsed_millis;
+                    break;
+                }
+                // done, but it stalls until there is room for the outputs
+                let has_room = recipe.outputs.iter().all(|stack| {
+                    factory.output_buffer[stack.item.index()] + stack.amount
+                        <= stack.amount * BUFFERED_CRAFTS
+                });
+                if has_room == false {
+                    factory.progress_millis = recipe.craft_millis;
+                    break;
+                }
+                elapsed_millis -= needed_millis;
+                for stack in recipe.outputs.iter() {
+                    factory.output_buffer[stack.item.index()] += stack.amount;
+                }
+                factory.is_crafting = false;
+                factory.progress_millis = 0;
+            }
+
+            for stack in recipe.outputs.iter() {
+                while factory.output_buffer[stack.item.index()] > 0
+                    && conveyors.insert_adjacent(&factory.footprint, stack.item)
+                {
+                    factory.output_buffer[stack.item.index()] -= 1;
+                }
+            }
+
+            let mut wants = [0; ITEM_TYPES_COUNT];
+            for stack in recipe.inputs.iter() {
+                wants[stack.item.index()] = (stack.amount * BUFFERED_CRAFTS)
+                    .saturating_sub(factory.input_buffer[stack.item.index()]);
+            }
+            conveyors.set_port_wants(&factory.entity_id, wants);
+        }
+    }
+}
+
+pub fn serialize_crafting_for_save(crafting: &CraftingSystem) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match crafting.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_crafting_for_load(bin_data: &Vec<u8>) -> Result<CraftingSystem, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    let crafting: CraftingSystem =
+        rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string())?;
+    let mut validated = CraftingSystem::new(crafting.recipes)?;
+    validated.factories = crafting.factories;
+    return Ok(validated);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conveyor_system::ConveyorTypes;
+    use crate::economy_system::Economy;
+    use crate::map::Directions;
+    use crate::simulation::get_tick_millis;
+    use crate::test_helpers::{add_prototype, TestWorld};
+
+    fn graphite_recipe() -> Recipe {
+        return Recipe {
+            id: 1,
+            name: "graphite".to_owned(),
+            inputs: vec![ItemStack::new(ItemTypes::Coal, 2)],
+            outputs: vec![ItemStack::new(ItemTypes::Graphite, 1)],
+            craft_millis: 500,
+        };
+    }
+
+    // coal -> belt -> belt -> press -> belt -> sink
+    fn build_press_line() -> (Map, ConveyorNetwork, CraftingSystem, TEntityID) {
+        let mut crafting = CraftingSystem::new(vec![graphite_recipe()]).unwrap();
+        let press_prototype_id = add_prototype("test press", |p| p.crafting_recipe_id = Some(1));
+        let mut the_map = Map::create(6, 1).unwrap();
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        let press_id = placement_system::place_structure(
+            &mut the_map,
+            &mut economy,
+            1,
+            &press_prototype_id,
+            2,
+            0,
+        )
+        .unwrap();
+        let mut conveyors = ConveyorNetwork::new();
+        let east = ConveyorTypes::Belt {
+            direction: Directions::East,
+        };
+        for (x, block) in [(0, east), (1, east), (3, east), (4, ConveyorTypes::Sink)] {
+            conveyors.add_block(&mut the_map, 1, x, 0, block).unwrap();
+        }
+        crafting
+            .add_factory(&the_map, &mut conveyors, &press_id)
+            .unwrap();
+        return (the_map, conveyors, crafting, press_id);
+    }
+
+    #[test]
+    fn test_recipe_ids_are_unique() {
+        let recipes = vec![graphite_recipe(), graphite_recipe()];
+        assert!(CraftingSystem::new(recipes).is_err());
+    }
+
+    #[test]
+    fn test_factory_on_conveyors() {
+        let _world = TestWorld::new();
+        let (_, mut conveyors, mut crafting, press_id) = build_press_line();
+        let mut delivered = Vec::new();
+        let mut is_lead_inserted = false;
+        for tick in 0..400 {
+            // a single lead in between, which the press never takes
+            if tick >= 100 && is_lead_inserted == false {
+                is_lead_inserted = conveyors.insert_item(0, 0, ItemTypes::Lead, Directions::East);
+            } else {
+                conveyors.insert_item(0, 0, ItemTypes::Coal, Directions::East);
+            }
+            conveyors.tick();
+            crafting.update(get_tick_millis(tick), &mut conveyors);
+            delivered.extend(conveyors.take_delivered());
+        }
+        // the lead ends up stuck in front of the press, starving it
+        assert!(delivered.iter().all(|d| d.item == ItemTypes::Graphite));
+        assert!(delivered.len() >= 3 && delivered.len() <= 20);
+        assert_eq!(conveyors.get_items(1, 0)[0].item, ItemTypes::Lead);
+        let factory = crafting.get_factory(&press_id).unwrap();
+        assert!(factory.get_input(ItemTypes::Coal) <= 2 * BUFFERED_CRAFTS);
+    }
+
+    #[test]
+    fn test_factories_are_saved_and_removed() {
+        let _world = TestWorld::new();
+        let (_, mut conveyors, mut crafting, press_id) = build_press_line();
+        for tick in 0..50 {
+            conveyors.insert_item(0, 0, ItemTypes::Coal, Directions::East);
+            conveyors.tick();
+            crafting.update(get_tick_millis(tick), &mut conveyors);
+        }
+        let bin = serialize_crafting_for_save(&crafting).unwrap();
+        assert_eq!(deserialize_crafting_for_load(&bin).unwrap(), crafting);
+        crafting.remove_factory(&mut conveyors, &press_id).unwrap();
+        assert_eq!(conveyors.get_block(2, 0), None);
+        assert!(crafting.get_factory(&press_id).is_none());
+    }
+}
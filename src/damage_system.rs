@@ -0,0 +1,313 @@
+// Damage, armor and health model.  The per-entity values (HP, shields, armor and resistances)
+// live on the Entity itself so that they get serialized along with it, while this system only
+// owns the on-death events that are waiting to be processed (see remove_dead())
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::Map;
+use crate::status_effect_system::BASE_PERCENT;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+// Note: No need to drop/deconstruct/destroy once it's created
+static DAMAGE_SINGLETON: Lazy<Mutex<DamageFactory>> =
+    Lazy::new(|| Mutex::new(DamageFactory::new()));
+struct DamageFactory {
+    death_events: Vec<DeathEvent>, // queued in the order they died, drained by remove_dead()
+}
+
+impl DamageFactory {
+    fn new() -> DamageFactory {
+        DamageFactory {
+            death_events: Vec::new(),
+        }
+    }
+}
+
+pub const DAMAGE_TYPES_COUNT: usize = 6; // make sure to update this if DamageTypes changes
+pub const MAX_RESISTANCE_PERCENT: u8 = 100; // 100% means immune to that damage type
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum DamageTypes {
+    Physical, // the only type that armor reduces
+    Magic,
+    Fire,
+    Frost,
+    Poison,
+    Electric,
+}
+impl DamageTypes {
+    // used as index into DefenseInfo::resistances
+    pub fn index(self: &Self) -> usize {
+        return *self as usize;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Damage {
+    pub amount: u16,
+    pub damage_type: DamageTypes,
+    pub source: Option<TEntityID>, // who dealt it (i.e. tower), None for environmental damages
+}
+impl Damage {
+    pub fn new(amount: u16, damage_type: DamageTypes, source: Option<TEntityID>) -> Damage {
+        Damage {
+            amount,
+            damage_type,
+            source,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct DefenseInfo {
+    pub armor: u16, // flat reduction applied to Physical damages only
+    pub resistances: [u8; DAMAGE_TYPES_COUNT], // percentage (0..=100) reduction per DamageTypes::index()
+    pub shield_points: u16,                    // absorbs damages prior to health_points
+    pub max_shield_points: u16,
+}
+impl DefenseInfo {
+    pub fn new() -> DefenseInfo {
+        DefenseInfo {
+            armor: 0,
+            resistances: [0; DAMAGE_TYPES_COUNT],
+            shield_points: 0,
+            max_shield_points: 0,
+        }
+    }
+    // how much of the damage gets through the armor and resistances (but not shields)
+    pub fn mitigate(self: &Self, damage: &Damage) -> u16 {
+        let resistance = self.resistances[damage.damage_type.index()].min(MAX_RESISTANCE_PERCENT);
+        if resistance >= MAX_RESISTANCE_PERCENT || damage.amount == 0 {
+            return 0; // immune
+        }
+        let mut amount = damage.amount as u32;
+        if damage.damage_type == DamageTypes::Physical {
+            // armor can never fully negate a hit, at least 1 point will always get through
+            amount = amount.saturating_sub(self.armor as u32).max(1);
+        }
+        amount =
+            amount * (MAX_RESISTANCE_PERCENT - resistance) as u32 / MAX_RESISTANCE_PERCENT as u32;
+        return amount.max(1) as u16;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct WeaponInfo {
+    pub damage: u16, // per shot, 0 means the entity is unarmed
+    pub damage_type: DamageTypes,
+    pub range: u16,                 // in grids
+    pub fire_interval_millis: u128, // reset value for cooldown_millis
+    pub cooldown_millis: u128,      // ready to fire when it reaches 0
+}
+impl WeaponInfo {
+    pub fn new() -> WeaponInfo {
+        WeaponInfo {
+            damage: 0,
+            damage_type: DamageTypes::Physical,
+            range: 0,
+            fire_interval_millis: 0,
+            cooldown_millis: 0,
+        }
+    }
+    pub fn is_armed(self: &Self) -> bool {
+        return self.damage > 0;
+    }
+    pub fn is_ready(self: &Self) -> bool {
+        return self.is_armed() && self.cooldown_millis == 0;
+    }
+    // fire_rate_percent of 200 cools down twice as fast, while 0 (i.e. stunned) does not cool down at all
+    pub fn update(self: &mut Self, last_frame_delta_millis: u128, fire_rate_percent: u16) {
+        let scaled_delta =
+            last_frame_delta_millis * fire_rate_percent as u128 / BASE_PERCENT as u128;
+        self.cooldown_millis = self.cooldown_millis.saturating_sub(scaled_delta);
+    }
+    // returns the damage to be dealt and restarts the cooldown, None if not ready
+    pub fn fire(self: &mut Self, source: TEntityID) -> Option<Damage> {
+        if self.is_ready() == false {
+            return None;
+        }
+        self.cooldown_millis = self.fire_interval_millis;
+        return Some(Damage::new(self.damage, self.damage_type, Some(source)));
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct DamageOutcome {
+    pub absorbed_by_shield: u16,
+    pub health_damage: u16,
+    pub is_killed: bool, // true only on the hit that brought health_points to 0
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct DeathEvent {
+    pub entity: Entity, // copy of the entity at the moment of death, since it will be removed from entity_system
+    pub killer: Option<TEntityID>,
+    pub damage_type: DamageTypes,
+}
+
+impl Entity {
+    // entities with max_health_points of 0 (i.e. terrain, decorations) are indestructible
+    pub fn is_destructible(self: &Self) -> bool {
+        return self.max_health_points > 0;
+    }
+    pub fn is_alive(self: &Self) -> bool {
+        return self.is_destructible() == false || self.health_points > 0;
+    }
+    pub fn set_max_health_points(self: &mut Self, max_health_points: u16) {
+        self.max_health_points = max_health_points;
+        self.health_points = max_health_points;
+    }
+
+    // pure calculation, does not queue DeathEvent (see damage_system::apply_damage())
+    pub fn take_damage(self: &mut Self, damage: &Damage) -> DamageOutcome {
+        let mut outcome = DamageOutcome {
+            absorbed_by_shield: 0,
+            health_damage: 0,
+            is_killed: false,
+        };
+        if self.is_destructible() == false || self.is_alive() == false {
+            return outcome; // cannot kill what's already dead
+        }
+        let mut amount = (self.defense.mitigate(damage) as u32 * self.damage_taken_percent() as u32
+            / BASE_PERCENT as u32)
+            .min(u16::MAX as u32) as u16;
+        outcome.absorbed_by_shield = amount.min(self.defense.shield_points);
+        self.defense.shield_points -= outcome.absorbed_by_shield;
+        amount -= outcome.absorbed_by_shield;
+
+        outcome.health_damage = amount.min(self.health_points);
+        self.health_points -= outcome.health_damage;
+        outcome.is_killed = self.health_points == 0;
+        return outcome;
+    }
+
+    // returns the actual amount healed (capped at max_health_points); dead entities cannot be healed
+    pub fn heal(self: &mut Self, amount: u16) -> u16 {
+        if self.is_destructible() == false || self.is_alive() == false {
+            return 0;
+        }
+        let healed = amount.min(self.max_health_points - self.health_points);
+        self.health_points += healed;
+        return healed;
+    }
+    pub fn recharge_shield(self: &mut Self, amount: u16) -> u16 {
+        let recharged = amount.min(
+            self.defense
+                .max_shield_points
+                .saturating_sub(self.defense.shield_points),
+        );
+        self.defense.shield_points += recharged;
+        return recharged;
+    }
+}
+
+pub fn apply_damage(target: &TEntityID, damage: &Damage) -> Result<DamageOutcome, String> {
+    let (outcome, entity) =
+        entity_system::modify(target, |entity| (entity.take_damage(damage), *entity))?;
+    if outcome.is_killed {
+        push_death_event(DeathEvent {
+            entity,
+            killer: damage.source,
+            damage_type: damage.damage_type,
+        });
+    }
+    return Ok(outcome);
+}
+
+pub fn heal(target: &TEntityID, amount: u16) -> Result<u16, String> {
+    return entity_system::modify(target, |entity| entity.heal(amount));
+}
+
+// for systems (i.e. damage over time ticked inside entity_system::update) that have already
+// applied Entity::take_damage() on their own and only need to report the death
+pub fn push_death_event(event: DeathEvent) {
+    let mut singleton = DAMAGE_SINGLETON.lock().unwrap();
+    singleton.death_events.push(event);
+}
+
+pub fn take_death_events() -> Vec<DeathEvent> {
+    let mut singleton = DAMAGE_SINGLETON.lock().unwrap();
+    return std::mem::take(&mut singleton.death_events);
+}
+
+/// Drains all pending on-death events, removing each dead entity from entity_system as well
+/// as from every CellLayer on the map that references it.  The events are returned so that the
+/// caller can react to them (bounties, wave progression, etc)
+pub fn remove_dead(map: &mut Map) -> Vec<DeathEvent> {
+    let events = take_death_events();
+    for event in events.iter() {
+        // it may have already been removed by someone else, which is fine
+        let _ = entity_system::remove(&event.entity.id);
+        map.remove_entity(&event.entity.id);
+    }
+    return events;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestWorld;
+
+    fn make_test_entity(max_hp: u16) -> Entity {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        entity.set_max_health_points(max_hp);
+        return entity;
+    }
+
+    #[test]
+    fn test_armor_and_resistances() {
+        let mut entity = make_test_entity(100);
+        entity.defense.armor = 5;
+        entity.defense.resistances[DamageTypes::Fire.index()] = 50;
+        entity.defense.resistances[DamageTypes::Poison.index()] = MAX_RESISTANCE_PERCENT;
+
+        let physical = entity.take_damage(&Damage::new(15, DamageTypes::Physical, None));
+        assert_eq!(physical.health_damage, 10); // 15 - 5 armor
+        let fire = entity.take_damage(&Damage::new(20, DamageTypes::Fire, None));
+        assert_eq!(fire.health_damage, 10); // armor does not apply, 50% resisted
+        let poison = entity.take_damage(&Damage::new(20, DamageTypes::Poison, None));
+        assert_eq!(poison.health_damage, 0); // immune
+        assert_eq!(entity.health_points, 80);
+    }
+
+    #[test]
+    fn test_shield_heal_and_death() {
+        let mut entity = make_test_entity(10);
+        entity.defense.max_shield_points = 5;
+        entity.recharge_shield(100);
+        assert_eq!(entity.defense.shield_points, 5);
+
+        let hit = entity.take_damage(&Damage::new(8, DamageTypes::Magic, None));
+        assert_eq!(hit.absorbed_by_shield, 5);
+        assert_eq!(hit.health_damage, 3);
+        assert_eq!(entity.heal(100), 3);
+
+        let killing_blow = entity.take_damage(&Damage::new(50, DamageTypes::Magic, None));
+        assert!(killing_blow.is_killed);
+        assert!(entity.is_alive() == false);
+        assert_eq!(entity.heal(5), 0);
+        let overkill = entity.take_damage(&Damage::new(50, DamageTypes::Magic, None));
+        assert!(overkill.is_killed == false); // only report death once
+    }
+
+    #[test]
+    fn test_remove_dead_from_map() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(8, 8).unwrap();
+        let entity_id = entity_system::add(&0, 0x80).unwrap();
+        entity_system::modify(&entity_id, |e| e.set_max_health_points(10)).unwrap();
+        let mut cell = the_map.get_cell(2, 3).unwrap();
+        cell.set(0, entity_id).unwrap();
+        the_map.set(2, 3, cell).unwrap();
+
+        apply_damage(&entity_id, &Damage::new(9, DamageTypes::Physical, None)).unwrap();
+        assert!(entity_system::modify(&entity_id, |e| e.is_alive()).unwrap());
+        let outcome = apply_damage(&entity_id, &Damage::new(9, DamageTypes::Physical, None));
+        assert!(outcome.unwrap().is_killed);
+
+        let events = remove_dead(&mut the_map);
+        assert!(events.iter().any(|ev| ev.entity.id == entity_id));
+        assert!(entity_system::modify(&entity_id, |e| e.id).is_err());
+        assert!(the_map.get_cell(2, 3).unwrap().layers.is_empty());
+    }
+}
@@ -0,0 +1,363 @@
+// Who is friends with whom.  Every entity is owned by a player (see Entity::owner): structures
+// by whoever placed them, units by whoever trained them, wave creeps by CREEP_PLAYER_ID and
+// everything else (terrain, props, the TUI editor's entities) by NEUTRAL_PLAYER_ID.  Players are
+// grouped into teams, and teams are allied, neutral or at war with each other; targeting,
+// ownership checks and shared vision all go through get_relation()
+use crate::economy_system::TPlayerID;
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::{get_distance_squared, Map};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub type TTeamID = u8;
+
+pub const NEUTRAL_PLAYER_ID: TPlayerID = 0; // nobody's, default for Entity::owner
+pub const CREEP_PLAYER_ID: TPlayerID = TPlayerID::MAX; // wave enemies, at war with everyone
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Relations {
+    Ally,    // never attacked, shares vision
+    Neutral, // only attacked when explicitly ordered to
+    Enemy,   // attacked on sight
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct TeamRelation {
+    pub team_a: TTeamID,
+    pub team_b: TTeamID,
+    pub relation: Relations,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Diplomacy {
+    teams: BTreeMap<TPlayerID, TTeamID>, // players not listed are on a team of their own
+    relations: BTreeMap<(TTeamID, TTeamID), Relations>, // lower team first, Enemy when not listed
+}
+
+impl Diplomacy {
+    pub fn new() -> Diplomacy {
+        Diplomacy {
+            teams: BTreeMap::new(),
+            relations: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_team(self: &Self, player_id: TPlayerID) -> Option<TTeamID> {
+        return self.teams.get(&player_id).copied();
+    }
+    pub fn set_team(self: &mut Self, player_id: TPlayerID, team_id: TTeamID) -> Result<(), String> {
+        if player_id == NEUTRAL_PLAYER_ID || player_id == CREEP_PLAYER_ID {
+            return Err(format!("playerID={} cannot join a team", player_id));
+        }
+        self.teams.insert(player_id, team_id);
+        return Ok(());
+    }
+    pub fn set_relation(self: &mut Self, relation: &TeamRelation) -> Result<(), String> {
+        if relation.team_a == relation.team_b {
+            return Err(format!(
+                "teamID={} is always allied with itself",
+                relation.team_a
+            ));
+        }
+        let key = (
+            relation.team_a.min(relation.team_b),
+            relation.team_a.max(relation.team_b),
+        );
+        self.relations.insert(key, relation.relation);
+        return Ok(());
+    }
+
+    // symmetric: a player is always its own ally, and on the same team as its allies
+    pub fn get_relation(self: &Self, player_id: TPlayerID, other_id: TPlayerID) -> Relations {
+        if player_id == other_id {
+            return Relations::Ally;
+        }
+        if player_id == NEUTRAL_PLAYER_ID || other_id == NEUTRAL_PLAYER_ID {
+            return Relations::Neutral;
+        }
+        let (team, other_team) = match (self.get_team(player_id), self.get_team(other_id)) {
+            (Some(t), Some(o)) => (t, o),
+            _ => return Relations::Enemy, // includes creeps, who never have a team
+        };
+        if team == other_team {
+            return Relations::Ally;
+        }
+        return match self
+            .relations
+            .get(&(team.min(other_team), team.max(other_team)))
+        {
+            Some(relation) => *relation,
+            None => Relations::Enemy,
+        };
+    }
+
+    // the player's entities are attacked unless they are allied with the attacking one; this is
+    // for explicit orders, auto-targeting only picks Enemy (see get_nearest_enemy())
+    pub fn can_attack(self: &Self, player_id: TPlayerID, target_id: &TEntityID) -> bool {
+        return match entity_system::modify(target_id, |e| *e).ok() {
+            Some(target) => {
+                target.is_destructible()
+                    && self.get_relation(player_id, target.owner) != Relations::Ally
+            }
+            None => false,
+        };
+    }
+
+    // everyone whose vision the player sees with (itself included), i.e. for fog of war
+    pub fn get_vision_sharers(self: &Self, player_id: TPlayerID) -> Vec<TPlayerID> {
+        let mut sharers: Vec<TPlayerID> = self
+            .teams
+            .keys()
+            .copied()
+            .filter(|other| self.get_relation(player_id, *other) == Relations::Ally)
+            .collect();
+        if sharers.contains(&player_id) == false {
+            sharers.push(player_id);
+            sharers.sort();
+        }
+        return sharers;
+    }
+
+    /// The closest living entity (within range, in cells) that the entity's owner is at war
+    /// with, lowest entityID on ties; what idle units shoot at (see unit_system)
+    pub fn get_nearest_enemy(
+        self: &Self,
+        map: &Map,
+        entity_id: &TEntityID,
+        range: u16,
+    ) -> Option<TEntityID> {
+        return self.get_nearest_enemy_where(map, entity_id, range, |_| true);
+    }
+    // same, but only among those the filter accepts (i.e. structures)
+    pub fn get_nearest_enemy_where(
+        self: &Self,
+        map: &Map,
+        entity_id: &TEntityID,
+        range: u16,
+        filter: impl Fn(&Entity) -> bool,
+    ) -> Option<TEntityID> {
+        let entity = entity_system::modify(entity_id, |e| *e).ok()?;
+        let mut nearest: Option<(u32, TEntityID)> = None;
+        for map_y in entity.map_y.saturating_sub(range)..=entity.map_y.saturating_add(range) {
+            for map_x in entity.map_x.saturating_sub(range)..=entity.map_x.saturating_add(range) {
+                let cell = match map.get_cell(map_x, map_y) {
+                    Ok(c) => c,
+                    Err(_) => continue, // off the map
+                };
+                for layer in cell.layers.iter() {
+                    let other = match entity_system::modify(&layer.entity, |e| *e).ok() {
+                        Some(o) => o,
+                        None => continue,
+                    };
+                    if other.is_destructible() == false
+                        || other.is_alive() == false
+                        || self.get_relation(entity.owner, other.owner) != Relations::Enemy
+                        || filter(&other) == false
+                    {
+                        continue;
+                    }
+                    let distance =
+                        get_distance_squared((map_x, map_y), (entity.map_x, entity.map_y));
+                    if distance > range as u32 * range as u32 {
+                        continue;
+                    }
+                    if nearest.map_or(true, |n| (distance, other.id) < n) {
+                        nearest = Some((distance, other.id));
+                    }
+                }
+            }
+        }
+        return nearest.map(|(_, id)| id);
+    }
+}
+
+// for commands on one's own entities (i.e. selling or upgrading a structure)
+pub fn check_owner(entity_id: &TEntityID, player_id: TPlayerID) -> Result<(), String> {
+    let owner = entity_system::modify(entity_id, |e| e.owner)?;
+    if owner != player_id {
+        return Err(format!(
+            "entityID={} is not owned by playerID={}",
+            entity_id, player_id
+        ));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::conveyor_system::ConveyorTypes;
+    use crate::map::Directions;
+    use crate::prototype_system::{self, TPrototypeID};
+    use crate::simulation::Simulation;
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+
+    fn add_unit_prototype() -> TPrototypeID {
+        return add_prototype("test unit", |p| {
+            p.max_health_points = 100;
+            p.max_velocity = 10;
+            p.weapon.damage = 5;
+            p.weapon.range = 2;
+            p.weapon.fire_interval_millis = 100;
+        });
+    }
+
+    // 1 and 2 are a team, allied with 3's, at war with 4 (not on any team)
+    fn set_teams(diplomacy: &mut Diplomacy) {
+        diplomacy.set_team(1, 10).unwrap();
+        diplomacy.set_team(2, 10).unwrap();
+        diplomacy.set_team(3, 20).unwrap();
+        let allied = TeamRelation {
+            team_a: 20,
+            team_b: 10,
+            relation: Relations::Ally,
+        };
+        diplomacy.set_relation(&allied).unwrap();
+    }
+
+    fn spawn_unit(
+        simulation: &mut Simulation,
+        prototype_id: TPrototypeID,
+        player_id: TPlayerID,
+        map_x: u16,
+    ) -> TEntityID {
+        let entity_id = prototype_system::spawn(&prototype_id, map_x, 5).unwrap();
+        simulation.map.place_entity(map_x, 5, entity_id).unwrap();
+        simulation.units.add_unit(&entity_id, player_id).unwrap();
+        return entity_id;
+    }
+
+    fn get_health(entity_id: TEntityID) -> u16 {
+        return entity_system::try_get(entity_id).unwrap().health_points;
+    }
+
+    #[test]
+    fn test_relations_follow_the_teams() {
+        let mut diplomacy = Diplomacy::new();
+        set_teams(&mut diplomacy);
+        assert_eq!(diplomacy.get_relation(3, 1), Relations::Ally);
+        assert_eq!(diplomacy.get_relation(1, 2), Relations::Ally);
+        assert_eq!(diplomacy.get_relation(1, 4), Relations::Enemy);
+        assert_eq!(
+            diplomacy.get_relation(1, NEUTRAL_PLAYER_ID),
+            Relations::Neutral
+        );
+        assert_eq!(diplomacy.get_vision_sharers(2), vec![1, 2, 3]);
+        assert_eq!(diplomacy.get_vision_sharers(4), vec![4]);
+    }
+
+    #[test]
+    fn test_creeps_cannot_join_a_team() {
+        let mut diplomacy = Diplomacy::new();
+        assert!(diplomacy.set_team(CREEP_PLAYER_ID, 10).is_err());
+        assert_eq!(diplomacy.get_relation(CREEP_PLAYER_ID, 1), Relations::Enemy);
+    }
+
+    #[test]
+    fn test_idle_units_shoot_only_enemies() {
+        let _world = TestWorld::new();
+        let unit_id = add_unit_prototype();
+        let mut simulation = new_simulation(16, 16);
+        set_teams(&mut simulation.diplomacy);
+        let mine = spawn_unit(&mut simulation, unit_id, 1, 5);
+        let ally = spawn_unit(&mut simulation, unit_id, 3, 6);
+        let enemy = spawn_unit(&mut simulation, unit_id, 4, 3); // out of the ally's range
+
+        // even with an ally closer
+        assert_eq!(
+            simulation
+                .diplomacy
+                .get_nearest_enemy(&simulation.map, &mine, 2),
+            Some(enemy)
+        );
+        for _ in 0..10 {
+            simulation.step_once();
+        }
+        assert_eq!(get_health(ally), 100);
+        assert!(get_health(enemy) < 100);
+        assert!(get_health(mine) < 100); // shot back
+    }
+
+    #[test]
+    fn test_allies_cannot_be_attacked_nor_others_sold() {
+        let _world = TestWorld::new();
+        let unit_id = add_unit_prototype();
+        let mut simulation = new_simulation(16, 16);
+        set_teams(&mut simulation.diplomacy);
+        add_player(&mut simulation, 1, 1);
+        let mine = spawn_unit(&mut simulation, unit_id, 1, 5);
+        let ally = spawn_unit(&mut simulation, unit_id, 3, 6);
+        let enemy = spawn_unit(&mut simulation, unit_id, 4, 12);
+        simulation.queue_command(
+            1,
+            PlayerCommands::AttackTarget {
+                entity_ids: vec![mine],
+                target_id: ally,
+            },
+        );
+        simulation.queue_command(1, PlayerCommands::SellStructure { entity_id: enemy });
+        let report = simulation.step_once();
+        assert_eq!(report.commands.len(), 2);
+        assert!(report.commands.iter().all(|(_, r)| r.is_err()));
+        assert!(entity_system::try_get(enemy).is_some());
+    }
+
+    #[test]
+    fn test_only_the_owner_removes_belts() {
+        let _world = TestWorld::new();
+        let mut simulation = new_simulation(8, 8);
+        let belt = ConveyorTypes::Belt {
+            direction: Directions::East,
+        };
+        let place = PlayerCommands::PlaceConveyor {
+            map_x: 2,
+            map_y: 2,
+            block: belt,
+        };
+        let remove = PlayerCommands::RemoveConveyor { map_x: 2, map_y: 2 };
+        simulation.queue_command(1, place);
+        simulation.queue_command(2, remove.clone()); // someone else's belt
+        let report = simulation.step_once();
+        assert!(report.commands[0].1.is_ok());
+        assert!(report.commands[1].1.is_err());
+        assert_eq!(simulation.conveyors.get_block(2, 2), Some(belt));
+
+        simulation.queue_command(1, remove);
+        assert!(simulation.step_once().commands[0].1.is_ok());
+        assert_eq!(simulation.conveyors.get_block(2, 2), None);
+    }
+
+    #[test]
+    fn test_towers_skip_allies() {
+        let _world = TestWorld::new();
+        let tower_id = add_prototype("test tower", |p| {
+            p.max_health_points = 100;
+            p.weapon.damage = 5;
+            p.weapon.range = 3;
+            p.weapon.fire_interval_millis = 100;
+        });
+        let unit_id = add_unit_prototype();
+        let mut simulation = new_simulation(16, 16);
+        simulation.diplomacy.set_team(1, 10).unwrap();
+        simulation.diplomacy.set_team(2, 10).unwrap();
+        let mut spawn = |prototype_id: TPrototypeID, owner: TPlayerID, map_x: u16| {
+            let entity_id = prototype_system::spawn(&prototype_id, map_x, 5).unwrap();
+            entity_system::modify(&entity_id, |e| e.owner = owner).unwrap();
+            simulation.map.place_entity(map_x, 5, entity_id).unwrap();
+            return entity_id;
+        };
+        let tower = spawn(tower_id, 1, 5);
+        let ally = spawn(unit_id, 2, 6); // closest, but on the same team
+        let enemy = spawn(unit_id, 3, 3);
+        let out_of_range = spawn(unit_id, 3, 9);
+        for _ in 0..10 {
+            simulation.step_once();
+        }
+        assert_eq!(get_health(ally), 100);
+        assert_eq!(get_health(out_of_range), 100);
+        assert!(get_health(enemy) < 100);
+        assert_eq!(get_health(tower), 100);
+    }
+}
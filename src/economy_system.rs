@@ -0,0 +1,411 @@
+// Per-player economy ledger.  Like Mindustry, the items (copper, lead, etc) that are mined and
+// transported are also the currency that structures are paid with, while Gold is the classic
+// tower-defense currency earned from kills and early wave calls
+use crate::damage_system::DeathEvent;
+use crate::entity_system;
+use crate::prototype_system;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+pub type TPlayerID = u8;
+
+pub const ITEM_TYPES_COUNT: usize = 8; // make sure to update this if ItemTypes changes
+pub const FULL_REFUND_PERCENT: u8 = 100;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum ItemTypes {
+    Gold,
+    Copper,
+    Lead,
+    Coal,
+    Sand,
+    Titanium,
+    Graphite,
+    Silicon,
+}
+impl ItemTypes {
+    pub const ALL: [ItemTypes; ITEM_TYPES_COUNT] = [
+        ItemTypes::Gold,
+        ItemTypes::Copper,
+        ItemTypes::Lead,
+        ItemTypes::Coal,
+        ItemTypes::Sand,
+        ItemTypes::Titanium,
+        ItemTypes::Graphite,
+        ItemTypes::Silicon,
+    ];
+    pub fn index(self: &Self) -> usize {
+        return *self as usize;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ItemStack {
+    pub item: ItemTypes,
+    pub amount: u32,
+}
+impl ItemStack {
+    pub fn new(item: ItemTypes, amount: u32) -> ItemStack {
+        ItemStack { item, amount }
+    }
+}
+
+// percentage of the costs, rounded down (i.e. refunds when selling); done in u64 since
+// amount * percent does not fit in u32 for large stacks
+pub fn scale_costs(costs: &Vec<ItemStack>, percent: u8) -> Vec<ItemStack> {
+    return costs
+        .iter()
+        .map(|stack| {
+            let amount = stack.amount as u64 * percent as u64 / FULL_REFUND_PERCENT as u64;
+            ItemStack::new(stack.item, amount.min(u32::MAX as u64) as u32)
+        })
+        .filter(|stack| stack.amount > 0)
+        .collect();
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PlayerLedger {
+    pub player_id: TPlayerID,
+    balances: [u32; ITEM_TYPES_COUNT],
+    total_earned: [u32; ITEM_TYPES_COUNT], // for end of game stats
+    total_spent: [u32; ITEM_TYPES_COUNT],
+}
+impl PlayerLedger {
+    fn new(player_id: TPlayerID) -> PlayerLedger {
+        PlayerLedger {
+            player_id,
+            balances: [0; ITEM_TYPES_COUNT],
+            total_earned: [0; ITEM_TYPES_COUNT],
+            total_spent: [0; ITEM_TYPES_COUNT],
+        }
+    }
+    // i.e. rebuilding a ledger received over the wire (see rpc_system), indexed by ItemTypes
+    pub fn from_totals(
+        player_id: TPlayerID,
+        balances: [u32; ITEM_TYPES_COUNT],
+        total_earned: [u32; ITEM_TYPES_COUNT],
+        total_spent: [u32; ITEM_TYPES_COUNT],
+    ) -> PlayerLedger {
+        PlayerLedger {
+            player_id,
+            balances,
+            total_earned,
+            total_spent,
+        }
+    }
+    pub fn get_balance(self: &Self, item: ItemTypes) -> u32 {
+        return self.balances[item.index()];
+    }
+    pub fn get_total_earned(self: &Self, item: ItemTypes) -> u32 {
+        return self.total_earned[item.index()];
+    }
+    pub fn get_total_spent(self: &Self, item: ItemTypes) -> u32 {
+        return self.total_spent[item.index()];
+    }
+    pub fn can_afford(self: &Self, costs: &Vec<ItemStack>) -> bool {
+        // the same item may be listed more than once, so total them up first
+        let mut totals = [0u64; ITEM_TYPES_COUNT];
+        for stack in costs {
+            totals[stack.item.index()] += stack.amount as u64;
+        }
+        return totals
+            .iter()
+            .enumerate()
+            .all(|(i, total)| *total <= self.balances[i] as u64);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Economy {
+    ledgers: Vec<PlayerLedger>, // sorted by player_id
+}
+
+impl Economy {
+    pub fn new() -> Economy {
+        Economy {
+            ledgers: Vec::new(),
+        }
+    }
+
+    // i.e. to work on the ledgers of a ClientState (see prediction_system)
+    pub fn from_ledgers(mut ledgers: Vec<PlayerLedger>) -> Economy {
+        ledgers.sort_by_key(|l| l.player_id);
+        ledgers.dedup_by_key(|l| l.player_id);
+        Economy { ledgers }
+    }
+
+    pub fn add_player(self: &mut Self, player_id: TPlayerID) -> Result<(), String> {
+        match self
+            .ledgers
+            .binary_search_by(|l| l.player_id.cmp(&player_id))
+        {
+            Ok(_) => Err(format!("playerID={} already has a ledger", player_id)),
+            Err(index) => {
+                self.ledgers.insert(index, PlayerLedger::new(player_id));
+                Ok(())
+            }
+        }
+    }
+    pub fn get_ledger(self: &Self, player_id: TPlayerID) -> Option<&PlayerLedger> {
+        return match self
+            .ledgers
+            .binary_search_by(|l| l.player_id.cmp(&player_id))
+        {
+            Ok(index) => Some(&self.ledgers[index]),
+            Err(_) => None,
+        };
+    }
+    pub fn get_ledgers(self: &Self) -> &Vec<PlayerLedger> {
+        return &self.ledgers;
+    }
+    fn get_ledger_mut(self: &mut Self, player_id: TPlayerID) -> Result<&mut PlayerLedger, String> {
+        return match self
+            .ledgers
+            .binary_search_by(|l| l.player_id.cmp(&player_id))
+        {
+            Ok(index) => Ok(&mut self.ledgers[index]),
+            Err(_) => Err(format!("playerID={} has no ledger", player_id)),
+        };
+    }
+
+    pub fn get_balance(self: &Self, player_id: TPlayerID, item: ItemTypes) -> u32 {
+        return match self.get_ledger(player_id) {
+            Some(ledger) => ledger.get_balance(item),
+            None => 0,
+        };
+    }
+    pub fn can_afford(self: &Self, player_id: TPlayerID, costs: &Vec<ItemStack>) -> bool {
+        return match self.get_ledger(player_id) {
+            Some(ledger) => ledger.can_afford(costs),
+            None => false,
+        };
+    }
+
+    // all or nothing; the balances are untouched if the player cannot afford all of it
+    pub fn spend(
+        self: &mut Self,
+        player_id: TPlayerID,
+        costs: &Vec<ItemStack>,
+    ) -> Result<(), String> {
+        let ledger = self.get_ledger_mut(player_id)?;
+        if ledger.can_afford(costs) == false {
+            let missing: Vec<String> = costs
+                .iter()
+                .filter(|stack| ledger.get_balance(stack.item) < stack.amount)
+                .map(|stack| {
+                    format!(
+                        "{:?} {}/{}",
+                        stack.item,
+                        ledger.get_balance(stack.item),
+                        stack.amount
+                    )
+                })
+                .collect();
+            return Err(format!(
+                "playerID={} cannot afford it, insufficient: {}",
+                player_id,
+                missing.join(", ")
+            ));
+        }
+        for stack in costs {
+            ledger.balances[stack.item.index()] -= stack.amount;
+            ledger.total_spent[stack.item.index()] =
+                ledger.total_spent[stack.item.index()].saturating_add(stack.amount);
+        }
+        return Ok(());
+    }
+
+    pub fn earn(
+        self: &mut Self,
+        player_id: TPlayerID,
+        items: &Vec<ItemStack>,
+    ) -> Result<(), String> {
+        let ledger = self.get_ledger_mut(player_id)?;
+        for stack in items {
+            let i = stack.item.index();
+            ledger.balances[i] = ledger.balances[i].saturating_add(stack.amount);
+            ledger.total_earned[i] = ledger.total_earned[i].saturating_add(stack.amount);
+        }
+        return Ok(());
+    }
+
+    // gives back percentage of the costs (i.e. when selling a structure), returns what was refunded
+    pub fn refund(
+        self: &mut Self,
+        player_id: TPlayerID,
+        costs: &Vec<ItemStack>,
+        percent: u8,
+    ) -> Result<Vec<ItemStack>, String> {
+        let refunded = scale_costs(costs, percent.min(FULL_REFUND_PERCENT));
+        let ledger = self.get_ledger_mut(player_id)?;
+        for stack in refunded.iter() {
+            let i = stack.item.index();
+            ledger.balances[i] = ledger.balances[i].saturating_add(stack.amount);
+            // undo the spending rather than counting it as income
+            ledger.total_spent[i] = ledger.total_spent[i].saturating_sub(stack.amount);
+        }
+        return Ok(refunded);
+    }
+
+    // income from kills, based on the bounty of the prototype the dead entity was spawned from,
+    // paid to whoever owns the killer; returns who got what.  The killer may have died in the
+    // same batch (already gone from entity_system), so its owner is also looked up among the
+    // events.  Kills by nobody (i.e. poison without a source) or by players without a ledger
+    // (creeps, neutral) are not paid out
+    pub fn award_kill_bounties(
+        self: &mut Self,
+        death_events: &Vec<DeathEvent>,
+    ) -> Vec<(TPlayerID, ItemStack)> {
+        let mut awarded: Vec<(TPlayerID, ItemStack)> = Vec::new();
+        for event in death_events {
+            let killer_id = match event.killer {
+                Some(id) => id,
+                None => continue,
+            };
+            let owner = match entity_system::modify(&killer_id, |e| e.owner).ok() {
+                Some(owner) => owner,
+                None => match death_events.iter().find(|e| e.entity.id == killer_id) {
+                    Some(e) => e.entity.owner,
+                    None => continue,
+                },
+            };
+            if owner == event.entity.owner {
+                continue; // no reward for finishing off one's own
+            }
+            if let Some(prototype) = event
+                .entity
+                .prototype_id
+                .and_then(|id| prototype_system::get(&id))
+            {
+                if self.earn(owner, &prototype.bounty).is_ok() {
+                    awarded.extend(prototype.bounty.iter().map(|stack| (owner, *stack)));
+                }
+            }
+        }
+        return awarded;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage_system::DamageTypes;
+    use crate::diplomacy_system::CREEP_PLAYER_ID;
+    use crate::entity_system::{Entity, TEntityID};
+    use crate::test_helpers::{add_prototype, TestWorld};
+
+    // 100 gold and 10 copper for player 1
+    fn new_test_economy() -> Economy {
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        economy
+            .earn(
+                1,
+                &vec![
+                    ItemStack::new(ItemTypes::Gold, 100),
+                    ItemStack::new(ItemTypes::Copper, 10),
+                ],
+            )
+            .unwrap();
+        return economy;
+    }
+
+    #[test]
+    fn test_players_are_added_once() {
+        let mut economy = new_test_economy();
+        assert!(economy.add_player(1).is_err());
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 100); // not reset
+    }
+
+    #[test]
+    fn test_spend_all_or_nothing() {
+        let mut economy = new_test_economy();
+        let costs = vec![
+            ItemStack::new(ItemTypes::Gold, 60),
+            ItemStack::new(ItemTypes::Copper, 15),
+        ];
+        assert!(economy.spend(1, &costs).is_err());
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 100); // untouched
+        assert!(economy.spend(2, &costs).is_err()); // no such player
+
+        let costs = vec![
+            ItemStack::new(ItemTypes::Gold, 60),
+            ItemStack::new(ItemTypes::Copper, 5),
+        ];
+        economy.spend(1, &costs).unwrap();
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 40);
+        assert_eq!(economy.get_balance(1, ItemTypes::Copper), 5);
+    }
+
+    #[test]
+    fn test_refund_rounds_down() {
+        let mut economy = new_test_economy();
+        let costs = vec![
+            ItemStack::new(ItemTypes::Gold, 60),
+            ItemStack::new(ItemTypes::Copper, 5),
+        ];
+        economy.spend(1, &costs).unwrap();
+        let refunded = economy.refund(1, &costs, 50).unwrap();
+        assert_eq!(
+            refunded,
+            vec![
+                ItemStack::new(ItemTypes::Gold, 30),
+                ItemStack::new(ItemTypes::Copper, 2)
+            ]
+        );
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 70);
+        assert_eq!(economy.get_balance(1, ItemTypes::Copper), 7);
+    }
+
+    #[test]
+    fn test_scale_costs_of_large_stacks() {
+        let costs = vec![ItemStack::new(ItemTypes::Copper, u32::MAX)];
+        assert_eq!(
+            scale_costs(&costs, 50),
+            vec![ItemStack::new(ItemTypes::Copper, u32::MAX / 2)]
+        );
+        assert_eq!(scale_costs(&costs, 200), costs); // capped rather than wrapped
+    }
+
+    #[test]
+    fn test_bounties_go_to_the_killers_owner() {
+        let _world = TestWorld::new();
+        let creep_id = add_prototype("test creep", |p| {
+            p.bounty = vec![ItemStack::new(ItemTypes::Gold, 5)];
+        });
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        economy.add_player(2).unwrap();
+        let spawn = |owner: TPlayerID| {
+            let entity_id = prototype_system::spawn(&creep_id, 0, 0).unwrap();
+            entity_system::modify(&entity_id, |e| e.owner = owner).unwrap();
+            return entity_system::try_get(entity_id).unwrap();
+        };
+        let killed_by = |entity: Entity, killer: Option<TEntityID>| DeathEvent {
+            entity,
+            killer,
+            damage_type: DamageTypes::Physical,
+        };
+        let tower = spawn(1);
+        let other_tower = spawn(2);
+        let creeps: Vec<Entity> = (0..4).map(|_| spawn(CREEP_PLAYER_ID)).collect();
+        let events = vec![
+            killed_by(creeps[0], Some(tower.id)),
+            killed_by(creeps[1], Some(other_tower.id)),
+            killed_by(other_tower, Some(creeps[3].id)), // creeps get nothing
+            killed_by(creeps[2], None),                 // i.e. poison of nobody's
+            killed_by(tower, Some(tower.id)),           // nor does finishing off one's own
+        ];
+        // other_tower died in the same batch and is gone by now, its kill still counts
+        entity_system::remove(&other_tower.id).unwrap();
+        assert_eq!(
+            economy.award_kill_bounties(&events),
+            vec![
+                (1, ItemStack::new(ItemTypes::Gold, 5)),
+                (2, ItemStack::new(ItemTypes::Gold, 5))
+            ]
+        );
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 5);
+        assert_eq!(economy.get_balance(2, ItemTypes::Gold), 5);
+    }
+}
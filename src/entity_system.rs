@@ -1,4 +1,9 @@
+use crate::damage_system::{self, Damage, DeathEvent, DefenseInfo, WeaponInfo};
+use crate::economy_system::TPlayerID;
+use crate::power_system::FULL_POWER_PERMILLE;
+use crate::prototype_system::TPrototypeID;
 use crate::sprite_system;
+use crate::status_effect_system::{StatusEffect, MAX_STATUS_EFFECTS_PER_ENTITY};
 
 pub use super::sprite_system::*;
 use once_cell::sync::Lazy;
@@ -37,17 +42,7 @@ pub fn add(sprite_group_id: &TSpriteSubGroupID, layer_weight: u8) -> Result<TEnt
         _ => 0, // edge-case, list is empty
     };
 
-    let new_entity = Entity {
-        id: max_id + 1,
-        sprites: *sprite_group_id,
-        layer_weight: layer_weight,
-        current_sprite_index: 0,
-        sprite_update_interval_reset: 24 * 1000,
-        last_sprite_update_millis: 0,
-        health_points: 0,
-        mana_points: 0,
-        physics_info: PhysicsObject::new(),
-    };
+    let new_entity = Entity::new(&(max_id + 1), sprite_group_id, &layer_weight);
     singleton.entities.push(new_entity);
 
     return Ok(new_entity.id);
@@ -84,6 +79,42 @@ pub fn try_get(entity_id: TEntityID) -> Option<Entity> {
         Err(_) => None,
     }
 }
+// blocking counterpart of try_get() for systems that need to mutate the entity in-place (i.e.
+// damage_system); the closure is called while the singleton is locked, so do NOT call back
+// into entity_system from within it or else it will deadlock
+pub fn modify<TR>(
+    entity_id: &TEntityID,
+    func: impl FnOnce(&mut Entity) -> TR,
+) -> Result<TR, String> {
+    let mut singleton = ENTITY_SINGLETON.lock().unwrap();
+    match singleton
+        .entities
+        .binary_search_by(|entity| entity.id.cmp(entity_id))
+    {
+        Ok(entity_index) => Ok(func(&mut singleton.entities[entity_index])),
+        Err(_) => Err(format!("entityID={} does not exist", entity_id)),
+    }
+}
+// whether every one of the entities that still exists passes the check, locking the singleton
+// once rather than once per try_get(); the same as modify(), do NOT call back into entity_system
+// from within the check
+pub fn all_of<'a>(
+    entity_ids: impl Iterator<Item = &'a TEntityID>,
+    check: impl Fn(&Entity) -> bool,
+) -> bool {
+    let singleton = ENTITY_SINGLETON.lock().unwrap();
+    for entity_id in entity_ids {
+        if let Ok(index) = singleton
+            .entities
+            .binary_search_by(|entity| entity.id.cmp(entity_id))
+        {
+            if check(&singleton.entities[index]) == false {
+                return false;
+            }
+        }
+    }
+    return true;
+}
 // See: Instant::now() and Instant::elapsed() for more details on how to pass deltaT
 // if max time slice is 0, will process entire list
 pub fn update(last_frame_delta_millis: u128, max_time_slice: u128) {
@@ -97,6 +128,14 @@ pub fn update(last_frame_delta_millis: u128, max_time_slice: u128) {
             == false
     };
 
+    if singleton.entities.is_empty() {
+        return;
+    }
+    if singleton.next_entity_to_update >= singleton.entities.len() {
+        // entities were removed since last update
+        singleton.next_entity_to_update = 0;
+    }
+
     let mut _exit_update = false; // even though it's used, rust-analyzer complains that this variable is never read, so use _var to shut compiler up...
     let mut processed_entity_count = 0;
     loop {
@@ -104,7 +143,15 @@ pub fn update(last_frame_delta_millis: u128, max_time_slice: u128) {
         // TODO: update each entity
         format!("Updating: {:?}:", singleton.entities[entity_index]);
 
-        singleton.entities[entity_index].update(last_frame_delta_millis);
+        if let Some(killing_blow) = singleton.entities[entity_index].update(last_frame_delta_millis)
+        {
+            // i.e. died of poison; removal is deferred to damage_system::remove_dead()
+            damage_system::push_death_event(DeathEvent {
+                entity: singleton.entities[entity_index],
+                killer: killing_blow.source,
+                damage_type: killing_blow.damage_type,
+            });
+        }
 
         if is_collidable(singleton.entities[entity_index]) {
             // test collisions against others (exclude self)
@@ -147,11 +194,39 @@ pub fn update(last_frame_delta_millis: u128, max_time_slice: u128) {
 pub fn reset() {
     let mut singleton = ENTITY_SINGLETON.lock().unwrap();
     singleton.entities.clear();
+    singleton.next_entity_to_update = 0;
+}
+
+// copy of every entity (sorted by id), i.e. to compare/persist the state of the simulation
+pub fn snapshot() -> Vec<Entity> {
+    let singleton = ENTITY_SINGLETON.lock().unwrap();
+    return singleton.entities.clone();
+}
+
+// replaces every entity with the given snapshot() (i.e. loading a save or seeking a replay)
+pub fn restore(entities: Vec<Entity>) {
+    let mut singleton = ENTITY_SINGLETON.lock().unwrap();
+    singleton.entities = entities;
+    singleton.entities.sort_by_key(|e| e.id); // binary_search relies on it
+    singleton.next_entity_to_update = 0;
+}
+
+// tests run in parallel but share the singleton, so any test that creates entities, or that
+// update()s/reset()s all of them, must hold this for its duration
+#[cfg(test)]
+static TEST_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+#[cfg(test)]
+pub(crate) fn lock_for_test() -> std::sync::MutexGuard<'static, ()> {
+    // a failed (panicked) test should not fail the rest of them
+    return TEST_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum PhysicsObjectCollisionTypes {
     NotCollidable, // i.e. smoke, vapor, etc
+    Floor,         // built on the ground and walked over, i.e. conveyor belts
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
@@ -180,31 +255,55 @@ impl PhysicsObject {
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub struct Entity {
     pub id: TEntityID,
+    pub prototype_id: Option<TPrototypeID>, // None for entities that were not spawned from a prototype (i.e. TUI editor)
+    pub map_x: u16, // position on the Map, which should match the cell(s) whose CellLayer references this entity
+    pub map_y: u16,
     pub sprites: TSpriteSubGroupID, // no need to track ResourceID for this SpriteID, since Sprite system internally tracks resources associated to it
     pub current_sprite_index: usize, // Assumes there are no more than 255 sprites in sprite groups
     pub layer_weight: u8, // lighter the weight, it bubbles towards the top when stacked (255 means most heaviest, 0 is lightest)
     pub sprite_update_interval_reset: u128, // reset timer value
     pub last_sprite_update_millis: u128, // duration as_millis() returns u128
     pub health_points: u16, // max of 65535 HP
+    pub max_health_points: u16, // 0 means indestructible (see damage_system)
     pub mana_points: u16, // max of 65535 MP
+    pub defense: DefenseInfo, // armor, resistances and shields
+    pub weapon: WeaponInfo,
+    pub status_effects: [Option<StatusEffect>; MAX_STATUS_EFFECTS_PER_ENTITY], // fixed array so that Entity can remain Copy
+    pub power_permille: u16, // how well powered it is, FULL_POWER_PERMILLE also for those that need no power (see power_system)
     pub physics_info: PhysicsObject,
+    #[serde(default)] // last, so that saves from before owners still load (as neutral)
+    pub owner: TPlayerID, // see diplomacy_system
 }
 impl Entity {
     pub fn new(id: &TEntityID, sid: &TSpriteSubGroupID, weight: &u8) -> Entity {
         // private, must call add_entity instead!
         Entity {
             id: *id,
+            prototype_id: None,
+            map_x: 0,
+            map_y: 0,
             sprites: *sid,
             layer_weight: *weight, // mid-weight is 0x80
             current_sprite_index: 0,
             sprite_update_interval_reset: 24 * 1000,
             last_sprite_update_millis: 0,
             health_points: 0,
+            max_health_points: 0,
             mana_points: 0,
+            defense: DefenseInfo::new(),
+            weapon: WeaponInfo::new(),
+            status_effects: [None; MAX_STATUS_EFFECTS_PER_ENTITY],
+            power_permille: FULL_POWER_PERMILLE,
             physics_info: PhysicsObject::new(),
+            owner: 0, // diplomacy_system::NEUTRAL_PLAYER_ID
         }
     }
-    pub fn update(self: &mut Self, last_frame_delta_millis: u128) {
+    // spawned from a prototype and unable to move, i.e. towers, drills, walls
+    pub fn is_structure(self: &Self) -> bool {
+        return self.prototype_id.is_some() && self.physics_info.max_velocity == 0;
+    }
+    // returns the damage that killed this entity (i.e. poison) if it died during this update
+    pub fn update(self: &mut Self, last_frame_delta_millis: u128) -> Option<Damage> {
         // make sure to update with elapsed time (animation)
         let time_left = self.last_sprite_update_millis as i128 - last_frame_delta_millis as i128;
         if time_left > 0 {
@@ -214,12 +313,27 @@ impl Entity {
             let sprites = sprite_system::try_get_sprites(&self.sprites);
             // based on current SubGroupID, because the SpriteID is sequentially assumed, move to next SpriteID
             self.current_sprite_index += 1;
-            if self.current_sprite_index as usize > sprites.len() {
+            if self.current_sprite_index as usize >= sprites.len() {
                 self.current_sprite_index = 0; // reset to loop back
             }
-            sprite_system::add_sprite_for_update(sprites[self.current_sprite_index].id);
+            if let Some(sprite) = sprites.get(self.current_sprite_index) {
+                // TUI has no sprites (or sprite system was locked), in which case we just reset the clock
+                sprite_system::add_sprite_for_update(sprite.id);
+            }
             self.last_sprite_update_millis = self.sprite_update_interval_reset;
         }
+
+        let mut killing_blow = None;
+        for damage in self.update_status_effects(last_frame_delta_millis) {
+            if self.take_damage(&damage).is_killed {
+                killing_blow = Some(damage);
+            }
+        }
+        // under-powered structures fire proportionally slower
+        let fire_rate_percent = (self.fire_rate_percent() as u32 * self.power_permille as u32
+            / FULL_POWER_PERMILLE as u32) as u16;
+        self.weapon
+            .update(last_frame_delta_millis, fire_rate_percent);
         if self.physics_info.current_velocity_x > 0
             || self.physics_info.current_velocity_y > 0
             || self.physics_info.current_acceleration_x > 0
@@ -227,6 +341,7 @@ impl Entity {
         {
             // update position if moving
         }
+        return killing_blow;
     }
 }
 
@@ -243,6 +358,7 @@ mod tests {
 
     #[test]
     fn test_create_and_remove() {
+        let _guard = lock_for_test();
         let sprite_id = 5;
         let layer_weight = 12;
         let new_entity = add(&sprite_id, layer_weight).unwrap();
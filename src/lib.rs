@@ -1,9 +1,46 @@
+pub mod damage_system;
+pub mod economy_system;
 pub mod entity_system;
 pub mod map;
+pub mod mining_system;
+pub mod prototype_system;
 pub mod resource_system;
 pub mod ai;
+pub mod behaviour_system;
+pub mod command_system;
 pub mod components;
+pub mod computer_player_system;
+pub mod conveyor_system;
+pub mod crafting_system;
+pub mod diplomacy_system;
+pub mod lockstep_system;
+pub mod pathfinding_system;
 pub mod physics;
+pub mod placement_system;
+pub mod power_system;
+pub mod prediction_system;
+pub mod production_system;
+pub mod random;
+pub mod replay_system;
+#[cfg(feature = "rpc")]
+pub mod rpc_system;
 
 pub mod sample_lib;
+pub mod savegame_system;
+pub mod scenario_system;
+pub mod selection_system;
+pub mod simulation;
 pub mod sprite_system;
+pub mod state_hash_system;
+pub mod status_effect_system;
+pub mod sync_system;
+pub mod tech_system;
+#[cfg(test)]
+mod test_helpers;
+#[cfg(feature = "rpc")]
+#[allow(clippy::all, dead_code)] // generated
+pub mod tower_defense_capnp;
+pub mod transport_system;
+pub mod unit_system;
+pub mod upgrade_system;
+pub mod wave_system;
@@ -0,0 +1,340 @@
+// Deterministic lockstep, Starcraft style: every peer runs the whole Simulation and only the
+// players' commands go over the wire.  Commands issued now are scheduled input_delay_ticks ahead
+// (so that they have time to reach the other peers), and a tick is only run once every player's
+// commands for it are in, which means a slow peer stalls everyone rather than drifting apart.
+// Every checksum_interval_ticks the peers also exchange a checksum of their state, since a desync
+// would otherwise go unnoticed until the matches visibly differ.
+use crate::command_system::PlayerCommands;
+use crate::economy_system::TPlayerID;
+use crate::simulation::{Simulation, TTick, TickReport, TICKS_PER_SECOND};
+use crate::state_hash_system::StateHash;
+use crate::transport_system::Transport;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub const DEFAULT_INPUT_DELAY_TICKS: TTick = 3; // 100ms, about a LAN round trip plus a frame
+pub const DEFAULT_CHECKSUM_INTERVAL_TICKS: TTick = TICKS_PER_SECOND;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum LockstepMessages {
+    // a player's commands for a tick; sent exactly once per tick, even when empty
+    Commands {
+        player_id: TPlayerID,
+        tick: TTick,
+        commands: Vec<PlayerCommands>,
+    },
+    // the state hash after hash.tick ticks have been run
+    Checksum {
+        player_id: TPlayerID,
+        hash: StateHash,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct LockstepConfig {
+    pub input_delay_ticks: TTick,       // has to be the same on every peer
+    pub checksum_interval_ticks: TTick, // 0 disables the checksums
+}
+
+impl Default for LockstepConfig {
+    fn default() -> LockstepConfig {
+        LockstepConfig {
+            input_delay_ticks: DEFAULT_INPUT_DELAY_TICKS,
+            checksum_interval_ticks: DEFAULT_CHECKSUM_INTERVAL_TICKS,
+        }
+    }
+}
+
+/// One player's end of a lockstep match; the Simulation is passed in rather than owned so that
+/// the host can keep using it as usual (rendering, replays, saving...)
+/// NOTE: commands must only be issued through the peer, never queued on the Simulation directly
+pub struct LockstepPeer<T: Transport<LockstepMessages>> {
+    local_player_id: TPlayerID,
+    player_ids: Vec<TPlayerID>, // every player of the match, this one included
+    config: LockstepConfig,
+    transport: T,
+    local_commands: Vec<PlayerCommands>, // issued since the last turn was sent
+    next_tick_to_send: TTick,
+    turns: BTreeMap<TTick, BTreeMap<TPlayerID, Vec<PlayerCommands>>>,
+    checksums: BTreeMap<TTick, BTreeMap<TPlayerID, StateHash>>, // until every player's has been compared
+}
+
+impl<T: Transport<LockstepMessages>> LockstepPeer<T> {
+    pub fn new(
+        local_player_id: TPlayerID,
+        player_ids: Vec<TPlayerID>,
+        config: LockstepConfig,
+        transport: T,
+        start_tick: TTick,
+    ) -> Result<LockstepPeer<T>, String> {
+        if player_ids.contains(&local_player_id) == false {
+            return Err(format!(
+                "playerID={} is not one of the players {:?}",
+                local_player_id, player_ids
+            ));
+        }
+        // nobody could have issued anything for the ticks within the input delay
+        let mut turns = BTreeMap::new();
+        for tick in start_tick..start_tick + config.input_delay_ticks {
+            let empty: BTreeMap<TPlayerID, Vec<PlayerCommands>> =
+                player_ids.iter().map(|id| (*id, Vec::new())).collect();
+            turns.insert(tick, empty);
+        }
+        return Ok(LockstepPeer {
+            local_player_id,
+            player_ids,
+            config,
+            transport,
+            local_commands: Vec::new(),
+            next_tick_to_send: start_tick + config.input_delay_ticks,
+            turns,
+            checksums: BTreeMap::new(),
+        });
+    }
+
+    pub fn get_local_player_id(self: &Self) -> TPlayerID {
+        return self.local_player_id;
+    }
+
+    // goes out with the next turn, so it runs input_delay_ticks from now on every peer
+    pub fn issue(self: &mut Self, command: PlayerCommands) {
+        self.local_commands.push(command);
+    }
+
+    // true when the next tick is held up by a peer whose commands have not arrived yet
+    pub fn is_waiting(self: &Self, simulation: &Simulation) -> bool {
+        return match self.turns.get(&simulation.get_tick()) {
+            Some(turn) => turn.len() < self.player_ids.len(),
+            None => true,
+        };
+    }
+
+    // runs the next tick if every player's commands for it are in, Ok(None) while waiting;
+    // an Err is either the transport failing, a misbehaving peer or a desync
+    pub fn step(
+        self: &mut Self,
+        simulation: &mut Simulation,
+    ) -> Result<Option<TickReport>, String> {
+        self.send_turns(simulation.get_tick())?;
+        while let Some(message) = self.transport.receive()? {
+            self.handle(message, simulation.get_tick())?;
+        }
+        if self.is_waiting(simulation) {
+            return Ok(None);
+        }
+        let turn = self
+            .turns
+            .remove(&simulation.get_tick())
+            .unwrap_or_default();
+        for (player_id, commands) in turn {
+            for command in commands {
+                simulation.queue_command(player_id, command);
+            }
+        }
+        let report = simulation.step_once();
+
+        let tick = simulation.get_tick();
+        if self.config.checksum_interval_ticks > 0
+            && tick % self.config.checksum_interval_ticks == 0
+        {
+            let hash = StateHash::from_simulation(simulation);
+            self.transport.send(&LockstepMessages::Checksum {
+                player_id: self.local_player_id,
+                hash,
+            })?;
+            self.add_checksum(self.local_player_id, hash)?;
+        }
+        self.send_turns(simulation.get_tick())?;
+        return Ok(Some(report));
+    }
+
+    // every turn up to input_delay_ticks ahead of the simulation
+    fn send_turns(self: &mut Self, current_tick: TTick) -> Result<(), String> {
+        while self.next_tick_to_send < current_tick + self.config.input_delay_ticks.max(1) {
+            let commands = std::mem::take(&mut self.local_commands);
+            self.transport.send(&LockstepMessages::Commands {
+                player_id: self.local_player_id,
+                tick: self.next_tick_to_send,
+                commands: commands.clone(),
+            })?;
+            self.turns
+                .entry(self.next_tick_to_send)
+                .or_default()
+                .insert(self.local_player_id, commands);
+            self.next_tick_to_send += 1;
+        }
+        return Ok(());
+    }
+
+    fn handle(
+        self: &mut Self,
+        message: LockstepMessages,
+        current_tick: TTick,
+    ) -> Result<(), String> {
+        match message {
+            LockstepMessages::Commands {
+                player_id,
+                tick,
+                commands,
+            } => {
+                if self.player_ids.contains(&player_id) == false
+                    || player_id == self.local_player_id
+                {
+                    return Err(format!("commands from unexpected playerID={}", player_id));
+                }
+                if tick < current_tick {
+                    return Err(format!(
+                        "playerID={} sent commands for tick {} which already ran",
+                        player_id, tick
+                    ));
+                }
+                let turn = self.turns.entry(tick).or_default();
+                if turn.insert(player_id, commands).is_some() {
+                    return Err(format!(
+                        "playerID={} sent commands for tick {} twice",
+                        player_id, tick
+                    ));
+                }
+            }
+            LockstepMessages::Checksum { player_id, hash } => {
+                if self.player_ids.contains(&player_id) == false
+                    || player_id == self.local_player_id
+                {
+                    return Err(format!("checksum from unexpected playerID={}", player_id));
+                }
+                self.add_checksum(player_id, hash)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn add_checksum(self: &mut Self, player_id: TPlayerID, hash: StateHash) -> Result<(), String> {
+        let checksums = self.checksums.entry(hash.tick).or_default();
+        checksums.insert(player_id, hash);
+        if let Some((other_player_id, other_hash)) = checksums.iter().find(|(_, h)| **h != hash) {
+            return Err(format!(
+                "desync after tick {}: playerID={} and playerID={} differ in {} (save both games and see state_diff)",
+                hash.tick,
+                other_player_id,
+                player_id,
+                other_hash.get_differing_parts(&hash).join(", ")
+            ));
+        }
+        if checksums.len() == self.player_ids.len() {
+            self.checksums.remove(&hash.tick);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::{ItemStack, ItemTypes};
+    use crate::simulation::SimulationSnapshot;
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+    use crate::transport_system::ChannelTransport;
+
+    type TTestPeer = LockstepPeer<ChannelTransport<LockstepMessages>>;
+
+    const CONFIG: LockstepConfig = LockstepConfig {
+        input_delay_ticks: 2,
+        checksum_interval_ticks: 5,
+    };
+
+    // peers of players 1 and 2, with the (same) match each of them starts from
+    fn create_peers() -> (TTestPeer, TTestPeer, SimulationSnapshot) {
+        let mut transports = ChannelTransport::create_mesh(2);
+        let peer_b =
+            LockstepPeer::new(2, vec![1, 2], CONFIG, transports.pop().unwrap(), 0).unwrap();
+        let peer_a =
+            LockstepPeer::new(1, vec![1, 2], CONFIG, transports.pop().unwrap(), 0).unwrap();
+        let mut simulation = new_simulation(8, 8);
+        for player_id in [1, 2] {
+            add_player(&mut simulation, player_id, 50);
+        }
+        return (peer_a, peer_b, simulation.snapshot());
+    }
+
+    // both peers live in this one process, so each swaps its entities in before stepping
+    fn step_peer(
+        peer: &mut TTestPeer,
+        snapshot: &mut SimulationSnapshot,
+    ) -> Result<Option<TickReport>, String> {
+        let mut simulation = Simulation::restore(snapshot);
+        let report = peer.step(&mut simulation);
+        *snapshot = simulation.snapshot();
+        return report;
+    }
+
+    #[test]
+    fn test_peers_wait_for_each_other() {
+        let _world = TestWorld::new();
+        let (mut peer_a, _peer_b, mut snapshot_a) = create_peers();
+        // B lags behind, so A has to wait for it once it is input_delay_ticks ahead
+        for _ in 0..CONFIG.input_delay_ticks {
+            assert!(step_peer(&mut peer_a, &mut snapshot_a).unwrap().is_some());
+        }
+        assert!(step_peer(&mut peer_a, &mut snapshot_a).unwrap().is_none());
+        assert_eq!(snapshot_a.simulation.get_tick(), CONFIG.input_delay_ticks);
+    }
+
+    #[test]
+    fn test_peers_apply_each_others_commands() {
+        let _world = TestWorld::new();
+        let tower_id = add_prototype("test tower", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+        });
+        let (mut peer_a, mut peer_b, mut snapshot_a) = create_peers();
+        let mut snapshot_b = snapshot_a.clone();
+        peer_a.issue(PlayerCommands::PlaceStructure {
+            prototype_id: tower_id,
+            map_x: 1,
+            map_y: 1,
+        });
+        peer_b.issue(PlayerCommands::PlaceStructure {
+            prototype_id: tower_id,
+            map_x: 5,
+            map_y: 5,
+        });
+        while snapshot_a.simulation.get_tick() < 20 || snapshot_b.simulation.get_tick() < 20 {
+            step_peer(&mut peer_b, &mut snapshot_b).unwrap();
+            step_peer(&mut peer_a, &mut snapshot_a).unwrap();
+        }
+        // A is input_delay_ticks ahead, once B catches up both towers are on both peers
+        let mut behind = Simulation::restore(&snapshot_b);
+        while behind.get_tick() < snapshot_a.simulation.get_tick() {
+            peer_b.step(&mut behind).unwrap();
+        }
+        assert_eq!(behind.snapshot(), snapshot_a);
+        assert_eq!(snapshot_a.entities.len(), 2);
+        assert_eq!(behind.economy.get_balance(2, ItemTypes::Gold), 40);
+    }
+
+    #[test]
+    fn test_desync_is_detected_at_the_next_checksum() {
+        let _world = TestWorld::new();
+        let (mut peer_a, mut peer_b, mut snapshot_a) = create_peers();
+        let mut snapshot_b = snapshot_a.clone();
+        snapshot_b
+            .simulation
+            .economy
+            .earn(2, &vec![ItemStack::new(ItemTypes::Gold, 1)])
+            .unwrap();
+        let mut desync = None;
+        for _ in 0..20 {
+            if let Err(e) = step_peer(&mut peer_a, &mut snapshot_a) {
+                desync = Some(e);
+                break;
+            }
+            if let Err(e) = step_peer(&mut peer_b, &mut snapshot_b) {
+                desync = Some(e);
+                break;
+            }
+        }
+        let desync = desync.unwrap();
+        assert!(desync.starts_with("desync after tick 5:"));
+        assert!(desync.contains("differ in economy"));
+    }
+}
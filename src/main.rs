@@ -1,11 +1,17 @@
 include!(concat!(env!("OUT_DIR"), "/hello.rs")); // see build.rs
 use device_query::{DeviceQuery, DeviceState, Keycode};
 //use lib_tower_defense::{entity_system, resource_system, sprite_system};
-use lib_tower_defense::{entity_system, sprite_system};
+use lib_tower_defense::command_system::PlayerCommands;
+use lib_tower_defense::crafting_system::CraftingSystem;
+use lib_tower_defense::economy_system::TPlayerID;
+use lib_tower_defense::entity_system;
+use lib_tower_defense::prototype_system::{self, EntityPrototype, TPrototypeID};
+use lib_tower_defense::savegame_system;
+use lib_tower_defense::simulation::Simulation;
+use lib_tower_defense::wave_system::WaveScheduler;
 
 use std::{
-    collections::HashSet,
-    io,
+    path::Path,
     process::{Command, Output},
     thread, time,
 };
@@ -14,7 +20,6 @@ use terminal_size::{terminal_size, Height, Width}; // need this to clear screen
 pub use lib_tower_defense::entity_system::*;
 pub use lib_tower_defense::map::*;
 pub use lib_tower_defense::resource_system::*;
-pub use lib_tower_defense::sprite_system::*;
 
 const SAMPLE_VIEW_WIDTH: u8 = 80;
 const SAMPLE_VIEW_HEIGHT: u8 = 40;
@@ -22,6 +27,11 @@ const SAMPLE_MAP_WIDTH: u16 = SAMPLE_VIEW_WIDTH as u16 * 5;
 const SAMPLE_MAP_HEIGHT: u16 = SAMPLE_VIEW_HEIGHT as u16 * 5;
 const LAYER_CHARS: [u8; 16] = *b" .,-~:;&=!*[#QW@"; // for debuggin, replace ' ' (space) with '.' if needed
 const STR_ESCAPE: &str = "\x1b";
+// the editor is a match of its own, played by this one player so that every edit is a command
+const EDITOR_PLAYER_ID: TPlayerID = 1;
+// what 'space' cycles a cell through, one after the other before it is empty again
+const FIRST_EDITOR_PROTOTYPE_ID: TPrototypeID = 1000;
+const EDITOR_PROTOTYPE_COUNT: TPrototypeID = LAYER_CHARS.len() as TPrototypeID - 1;
 
 // NOTE: Probably not a useful function because on Windows, it defaults to PowerShell and so doing commands like 'ls -ltArh'
 // will not work.  Also, we cannot tell if bash.exe is installed on the Windows,  and even so, passing as command with args:
@@ -247,87 +257,71 @@ enum BreakLoopType {
     SaveAndExit = 2,
     ApplicationError,
 }
-fn make_fake_sprite_resource() -> TResourceID {
-    let temp_sprite_resource_id = 42;
-
-    return temp_sprite_resource_id;
+fn add_editor_prototypes() {
+    for index in 0..EDITOR_PROTOTYPE_COUNT {
+        // free to build and sold at no refund, NO sprites for TUI version
+        let name = format!("editor block {}", index);
+        prototype_system::add(EntityPrototype::new(
+            FIRST_EDITOR_PROTOTYPE_ID + index,
+            &name,
+            0,
+        ));
+    }
 }
-fn make_fake_sprites(temp_sprite_resource_id: TResourceID) -> HashSet<TSpriteSubGroupID> {
-    return sprite_system::add(&temp_sprite_resource_id, deserialize_sprite).unwrap();
+fn create_editor_simulation() -> Result<Simulation, String> {
+    let mut simulation = Simulation::new(
+        Map::create(SAMPLE_MAP_WIDTH, SAMPLE_MAP_HEIGHT)?,
+        WaveScheduler::new(Vec::new())?,
+        CraftingSystem::new(Vec::new())?,
+        0,
+    );
+    simulation.economy.add_player(EDITOR_PLAYER_ID)?;
+    return Ok(simulation);
 }
-fn deserialize_sprite(temp_sprite_resource_id: &TResourceID) -> Vec<Sprite> {
-    return Vec::<Sprite>::new(); // NO sprites for TUI version, return empty list?
+// sells whichever editor block is on top of the cell and places the next one (if any), or places
+// the first one on an empty cell; cells with anything else on them are left alone
+fn make_cycle_commands(the_map: &Map, map_x: u16, map_y: u16) -> Vec<PlayerCommands> {
+    let place = |prototype_id| PlayerCommands::PlaceStructure {
+        prototype_id,
+        map_x,
+        map_y,
+    };
+    let entity_id = match the_map.get_cell(map_x, map_y).map(|c| c.first()) {
+        Ok(Some(layer)) => layer.entity,
+        Ok(None) => return vec![place(FIRST_EDITOR_PROTOTYPE_ID)],
+        Err(_) => return Vec::new(),
+    };
+    let editor_prototype_ids =
+        FIRST_EDITOR_PROTOTYPE_ID..FIRST_EDITOR_PROTOTYPE_ID + EDITOR_PROTOTYPE_COUNT;
+    let prototype_id = match entity_system::modify(&entity_id, |e| e.prototype_id) {
+        Ok(Some(id)) if editor_prototype_ids.contains(&id) => id,
+        _ => return Vec::new(),
+    };
+    let mut commands = vec![PlayerCommands::SellStructure { entity_id }];
+    if editor_prototype_ids.contains(&(prototype_id + 1)) {
+        commands.push(place(prototype_id + 1));
+    }
+    return commands;
 }
 
 fn main() {
     clear_screen();
-    //let ms = time::Duration::from_millis(300); // 1/3 second
-    let ms = time::Duration::from_millis(50);
-
-    let temp_sprite_resource_id = make_fake_sprite_resource();
-    //let temp_sprite_id = make_fake_sprite(temp_sprite_resource_id); // when we have a sprite as a resource, update this...
+    let mut last_frame_time = time::Instant::now();
 
     let file_paths = "./test.save.bin".to_owned();
-    let mut map_from_local_life: Map;
-    let (map_resource, the_map) = match Resource::new(file_paths.clone(), false) {
-        Ok(res_id) => {
-            let res_result = Resource::try_get(res_id);
-            let ret_tuple_result: Result<(Option<Resource>, &mut Map), String> = match res_result {
-                Some(res) => match res.read_data(Map::deserialize_for_load) {
-                    Ok(m) => {
-                        if m.get_width() != SAMPLE_MAP_WIDTH {
-                            panic!("Invalid data");
-                        }
-                        if m.get_height() != SAMPLE_MAP_HEIGHT {
-                            panic!("Invalid data");
-                        }
-                        map_from_local_life = m;
-                        Ok((Some(res), &mut map_from_local_life))
-                    }
-                    Err(e) => {
-                        // if file exists, throw a panic!(), else assume bin data does not exist, and create a brand new map
-                        println!("Error: {}", e);
-                        Err(e)
-                    }
-                },
-                None => {
-                    let ret_err = format!(
-                        "Was able to locate file '{}' (resource ID={})",
-                        file_paths.clone(),
-                        res_id
-                    );
-                    println!("{}", ret_err);
-                    Err(ret_err)
-                }
-            };
-            ret_tuple_result
-        }
-        Err(io_error) => {
-            // if file exists, throw a panic!(), else assume bin data does not exist, and create a brand new map
-            let str_io_error = io_error.to_string();
-            let ret_err = Err::<(_, _), String>(str_io_error.to_owned());
-            println!("Error: {}", str_io_error);
-
-            let ret_error = match io_error.downcast_ref::<io::Error>() {
-                Some(io_casted_error) => match io_casted_error.kind() {
-                    io::ErrorKind::NotFound => {
-                        // create a NEW map instead since we could not load it...
-                        match Map::create(SAMPLE_MAP_WIDTH, SAMPLE_MAP_HEIGHT) {
-                            Ok(new_map) => {
-                                map_from_local_life = new_map;
-                                Ok((None, &mut map_from_local_life))
-                            }
-                            Err(e_map) => Err(e_map),
-                        }
-                    }
-                    _ => ret_err,
-                },
-                _ => ret_err,
-            };
-            ret_error
-        }
+    add_editor_prototypes();
+    let mut simulation = match Path::new(&file_paths).exists() {
+        true => savegame_system::load_game(&file_paths),
+        false => create_editor_simulation(), // nothing saved yet, start with a brand new map
     }
     .unwrap();
+    if simulation.map.get_width() != SAMPLE_MAP_WIDTH {
+        panic!("Invalid data");
+    }
+    if simulation.map.get_height() != SAMPLE_MAP_HEIGHT {
+        panic!("Invalid data");
+    }
+    let mut last_rejected = String::new(); // the last edit that did not go through, and why
     let mut view_x: u16 = 0;
     let mut view_y: u16 = 0;
     let mut cursor_x: u8 = 0;
@@ -353,8 +347,9 @@ fn main() {
 
     let mut break_loop = BreakLoopType::NoBreak;
     'main_game_outer_loop: loop {
-        let view = the_map
-            .build_view(0, 0, SAMPLE_VIEW_WIDTH, SAMPLE_VIEW_HEIGHT)
+        let view = simulation
+            .map
+            .build_view_at(view_x, view_y, SAMPLE_VIEW_WIDTH, SAMPLE_VIEW_HEIGHT)
             .unwrap();
         if view.len() == 0 {
             break_loop = BreakLoopType::ApplicationError;
@@ -382,13 +377,13 @@ fn main() {
                         // Prompt to save data
                         println!("escape");
                         break_loop = BreakLoopType::SaveAndExit;
-                        break 'main_game_outer_loop;
+                        break; // handled once the frame is done, see below
                     }
                     Keycode::Q => {
                         // Prompt to quit without save
                         println!("quit");
                         break_loop = BreakLoopType::QuitWithoutSave;
-                        break 'main_game_outer_loop;
+                        break; // handled once the frame is done, see below
                     }
                     Keycode::PageUp => {
                         if view_y > move_step_y as u16 {
@@ -407,7 +402,7 @@ fn main() {
                         let top_y = view_y + move_step_y as u16;
                         let bot_y = top_y + SAMPLE_VIEW_HEIGHT as u16;
 
-                        if bot_y < the_map.get_height() {
+                        if bot_y < simulation.map.get_height() {
                             view_y = top_y;
                         }
                     }
@@ -417,7 +412,7 @@ fn main() {
 
                         if cursor_y < SAMPLE_VIEW_HEIGHT {
                             cursor_y = cursor_y + 1;
-                        } else if bot_y < the_map.get_height() {
+                        } else if bot_y < simulation.map.get_height() {
                             view_y = top_y;
                         }
                     }
@@ -438,7 +433,7 @@ fn main() {
                         let left_x = view_x + move_step_x as u16;
                         let right_x = left_x + SAMPLE_VIEW_WIDTH as u16;
 
-                        if right_x < the_map.get_width() {
+                        if right_x < simulation.map.get_width() {
                             view_x = left_x;
                         }
                     }
@@ -448,7 +443,7 @@ fn main() {
 
                         if cursor_x < SAMPLE_VIEW_WIDTH {
                             cursor_x = cursor_x + 1;
-                        } else if right_x < the_map.get_width() {
+                        } else if right_x < simulation.map.get_width() {
                             view_x = left_x;
                         }
                     }
@@ -467,95 +462,39 @@ fn main() {
                     Keycode::Space => {
                         let pos_x = view_x + cursor_x as u16;
                         let pos_y = view_y + cursor_y as u16;
-                        let mut cursor_position_cell =
-                            match the_map.get_cell(pos_x, pos_y).unwrap().first().is_some() {
-                                true => the_map.get_cell(pos_x, pos_y).unwrap(),
-                                false => {
-                                    let sprites_added = sprite_system::add(
-                                        &temp_sprite_resource_id,
-                                        deserialize_sprite,
-                                    )
-                                    .to_owned()
-                                    .unwrap();
-
-                                    MapCell {
-                                        layers: sprites_added
-                                            .iter()
-                                            .map(|sid_added| {
-                                                CellLayer::new(
-                                                    0,
-                                                    entity_system::add(sid_added, 0x80).unwrap(),
-                                                )
-                                            })
-                                            .collect(),
-                                    }
-                                }
-                            };
-
-                        for layer in cursor_position_cell.layers.clone() {
-                            let temp_cycle_the_value_on_space =
-                                match (layer.entity + 1) as usize > LAYER_CHARS.len() {
-                                    false => layer.entity + 1,
-                                    true => 0,
-                                };
-                            // Note: set() should add if missing, but in this case, we're iterating through existing Layers, so it assumes it's always an update/repleace
-                            cursor_position_cell
-                                .set(layer.id, temp_cycle_the_value_on_space)
-                                .unwrap();
+                        // applied along with the next tick, like any other player's commands
+                        for command in make_cycle_commands(&simulation.map, pos_x, pos_y) {
+                            simulation.queue_command(EDITOR_PLAYER_ID, command);
                         }
-
-                        the_map.set(pos_x, pos_y, cursor_position_cell).unwrap();
                     }
                     _ => (),
                 }
             }
-            // update map position based on key pressed
-            the_map.set_upper_left(view_x, view_y);
         }
 
         // for now, only update text if key is pressed
-        let possibleLayerTopmost =
-            match the_map.get_cell(view_x + cursor_x as u16, view_y + cursor_y as u16) {
-                Ok(c) => {
-                    let c_layers = c.layers.into_iter();
-                    c_layers
-                        // sort based on min layer_weight is lighter (bubbles towards top)
-                        .min_by_key(|c| match entity_system::try_get(c.entity) {
-                            Some(e) => e.layer_weight, // if tied, will only return the first encountered!
-                            None => panic!(
+        let possibleLayerTopmost = match simulation
+            .map
+            .get_cell(view_x + cursor_x as u16, view_y + cursor_y as u16)
+        {
+            Ok(c) => {
+                let c_layers = c.layers.into_iter();
+                c_layers
+                    // sort based on min layer_weight is lighter (bubbles towards top)
+                    .min_by_key(|c| match entity_system::try_get(c.entity) {
+                        Some(e) => e.layer_weight, // if tied, will only return the first encountered!
+                        None => panic!(
                             "either entity_system deadlocked or entity_id={eid} no longer exists",
                             eid = c.entity
                         ), // should never happen (unless try_get was going to deadlocked and returned None), so will panic instead of returning u8::MAX
-                        })
-                }
-                Err(e) => None,
-            };
-
-        let make_new_entity = || -> Vec<Entity> {
-            // deal with it like an editor, add/create new element here
-            let added_sprite_groups: HashSet<TSpriteSubGroupID> =
-                make_fake_sprites(temp_sprite_resource_id);
-            let entities = added_sprite_groups
-                .into_iter() // convert
-                .map(|sub_group_id| {
-                    let added_entity_id = entity_system::add(&sub_group_id, 0x80).unwrap();
-                    let r = entity_system::try_get(added_entity_id).unwrap();
-                    return r.to_owned();
-                });
-            return entities.collect();
+                    })
+            }
+            Err(e) => None,
         };
-        let entity_as_the_val = match possibleLayerTopmost
+
+        let entity_as_the_val = possibleLayerTopmost
             .map(|cl| entity_system::try_get(cl.entity))
-            .flatten()
-        {
-            Some(e) => Some(e.clone()),
-            None =>
-            // deal with it like an editor, add/create new element here
-            {
-                make_new_entity().first().map(|e| *e)
-            }
-        }
-        .unwrap();
+            .flatten();
 
         let mut keys_input = "[".to_owned();
         for k in test_keys {
@@ -563,33 +502,36 @@ fn main() {
         }
         keys_input.push_str("]");
         let status = format!(
-            "Map:{:?} - World:({}, {}) Cursor:({}, {}) Pos:({}, {}) Val:(EID:{}; SID:{})- Mouse:{:?} - Keys:{}\nCursor keys, PgUp, PgDn, '[', ']', 'space', 'Q', and Esc",
-            the_map.get_upper_left(),
+            "World:({}, {}) Cursor:({}, {}) Pos:({}, {}) Val:(EID:{:?}; SID:{:?})- Mouse:{:?} - Keys:{}\nRejected:{}\nCursor keys, PgUp, PgDn, '[', ']', 'space', 'Q', and Esc",
             view_x,
             view_y,
             cursor_x,
             cursor_y,
             view_x + cursor_x as u16,
             view_y + cursor_y as u16,
-            entity_as_the_val.id,
-            entity_as_the_val.sprites,
+            entity_as_the_val.map(|e| e.id),
+            entity_as_the_val.map(|e| e.sprites),
             mouse.coords,
-            keys_input
+            keys_input,
+            last_rejected
         );
+        let now = time::Instant::now();
+        for report in simulation.advance(now - last_frame_time) {
+            for (issued, result) in report.commands {
+                if let Err(e) = result {
+                    last_rejected = format!("{:?}: {}", issued.command, e);
+                }
+            }
+        }
+        last_frame_time = now;
         // sleep mainly so that we can yield the app and let other processes run...
-        thread::sleep(ms);
+        thread::sleep(simulation.get_time_until_next_tick());
         match break_loop {
             BreakLoopType::QuitWithoutSave => break 'main_game_outer_loop,
             BreakLoopType::SaveAndExit => {
-                // update data and quit
-                let _bytes_written = match map_resource {
-                    Some(mut m) => m.write_data(|| the_map.serialize_for_save()).unwrap(),
-                    _ =>
-                    // try to create new resource and attempt to save it?
-                    {
-                        Vec::new()
-                    }
-                };
+                // update data and quit; the whole game, since the map alone does not hold the
+                // entities placed on it
+                savegame_system::save_game(&simulation, &file_paths).unwrap();
                 break 'main_game_outer_loop;
             }
             BreakLoopType::ApplicationError => break 'main_game_outer_loop,
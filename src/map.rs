@@ -1,5 +1,7 @@
 //extern crate serde;
+use crate::economy_system::ItemTypes;
 use crate::entity_system::*;
+use crate::random::Rng;
 use serde::Serialize;
 use serde_derive::Deserialize;
 //use crate::resource_system::Resource;
@@ -41,9 +43,53 @@ impl CellLayer {
     //}
 }
 
+// ore/resource under the cell, which drills (see mining_system) extract from
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct OreDeposit {
+    pub item: ItemTypes,
+    pub remaining: Option<u32>, // None means infinite (i.e. sand), else depletes to nothing
+}
+
+// grid directions, north being towards map_y == 0
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Directions {
+    North,
+    East,
+    South,
+    West,
+}
+impl Directions {
+    pub const ALL: [Directions; 4] = [
+        Directions::North,
+        Directions::East,
+        Directions::South,
+        Directions::West,
+    ];
+    pub fn opposite(self: &Self) -> Directions {
+        return Directions::ALL[(*self as usize + 2) % 4];
+    }
+    pub fn turn_left(self: &Self) -> Directions {
+        return Directions::ALL[(*self as usize + 3) % 4];
+    }
+    pub fn turn_right(self: &Self) -> Directions {
+        return Directions::ALL[(*self as usize + 1) % 4];
+    }
+    // the adjacent position, None if it would go below 0 (upper bounds are up to the Map)
+    pub fn step(self: &Self, map_x: u16, map_y: u16) -> Option<(u16, u16)> {
+        return match self {
+            Directions::North => map_y.checked_sub(1).map(|y| (map_x, y)),
+            Directions::East => map_x.checked_add(1).map(|x| (x, map_y)),
+            Directions::South => map_y.checked_add(1).map(|y| (map_x, y)),
+            Directions::West => map_x.checked_sub(1).map(|x| (x, map_y)),
+        };
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct MapCell {
     pub layers: Vec<CellLayer>, // this means we cannot derive Copy, use .clone()
+    #[serde(default)] // so that maps saved prior to deposits can still be loaded
+    pub deposit: Option<OreDeposit>,
 }
 
 //impl Serialize for MapCell {
@@ -82,6 +128,33 @@ impl MapCell {
         return Ok(());
     }
 
+    // adds the entity on the lowest unused layer ID (so that i.e. several units can share a cell),
+    // returns the layer ID that was used
+    pub fn add_entity(self: &mut Self, entity_id: TEntityID) -> Result<TCellID, String> {
+        if self.layers.len() + 1 >= MAX_LAYERS_PER_CELL {
+            return Err(format!(
+                "Cannot add entity {}, cell already has {} layers",
+                entity_id,
+                self.layers.len()
+            ));
+        }
+        let free_id = (0..MAX_LAYERS_PER_CELL as TCellID)
+            .find(|id| self.layers.iter().any(|layer| layer.id == *id) == false)
+            .unwrap(); // safe, since we've already checked that there are less layers than MAX
+        self.layers.push(CellLayer::new(free_id, entity_id));
+        return Ok(free_id);
+    }
+    pub fn contains_entity(self: &Self, entity_id: &TEntityID) -> bool {
+        return self.layers.iter().any(|layer| layer.entity == *entity_id);
+    }
+
+    // removes every layer referencing the entity, returns true if any was removed
+    pub fn remove_entity(self: &mut Self, entity_id: &TEntityID) -> bool {
+        let layer_count = self.layers.len();
+        self.layers.retain(|layer| layer.entity != *entity_id);
+        return self.layers.len() != layer_count;
+    }
+
     pub fn get(self: &Self) -> Vec<CellLayer> {
         return self.layers.clone(); // make a copy
     }
@@ -144,6 +217,14 @@ pub struct Map {
 //        state.end()
 //    }
 //}
+
+// between two cells, squared so that it stays an integer (compare against range * range)
+pub fn get_distance_squared(from: (u16, u16), to: (u16, u16)) -> u32 {
+    let dx = from.0.abs_diff(to.0) as u32;
+    let dy = from.1.abs_diff(to.1) as u32;
+    return dx * dx + dy * dy;
+}
+
 impl Map {
     pub fn get_width(self: &Self) -> u16 {
         return self.width;
@@ -169,6 +250,7 @@ impl Map {
             vec![
                 MapCell {
                     layers: Vec::new(), // start with Empty (flat plain cell)
+                    deposit: None,
                 };
                 width as usize
             ];
@@ -201,14 +283,29 @@ impl Map {
         return (new_x, new_y);
     }
 
+    pub fn is_in_bounds(self: &Self, map_x: u16, map_y: u16) -> bool {
+        return map_x < self.width && map_y < self.height;
+    }
+
+    pub fn get_neighbor(
+        self: &Self,
+        map_x: u16,
+        map_y: u16,
+        direction: Directions,
+    ) -> Option<(u16, u16)> {
+        return direction
+            .step(map_x, map_y)
+            .filter(|(x, y)| self.is_in_bounds(*x, *y));
+    }
+
     pub fn get_cell(self: &Self, map_x: u16, map_y: u16) -> Result<MapCell, String> {
-        if map_x > self.width {
+        if map_x >= self.width {
             return Err(format!(
                 "Cannot assign MapCell to position ([{}], {}) for its X position exceeds {}",
                 map_x, map_y, self.width
             ));
         }
-        if map_y > self.height {
+        if map_y >= self.height {
             return Err(format!(
                 "Cannot assign MapCell to position ({}, [{}]) for its Y position exceeds {}",
                 map_x, map_y, self.height
@@ -216,6 +313,13 @@ impl Map {
         }
         return Ok(self.grid[map_y as usize][map_x as usize].clone()); // need to clone() since we cannot copy()
     }
+    // same as get_cell() but without the clone, i.e. for lookups done for every cell in a search
+    pub fn get_cell_ref(self: &Self, map_x: u16, map_y: u16) -> Option<&MapCell> {
+        if self.is_in_bounds(map_x, map_y) == false {
+            return None;
+        }
+        return Some(&self.grid[map_y as usize][map_x as usize]);
+    }
     pub fn get_cell_view(
         self: &Self,
         view_offset_x: u8,
@@ -243,13 +347,13 @@ impl Map {
                 width, self.width
             ));
         }
-        if map_y > self.height {
+        if map_y >= self.height {
             return Err(format!(
                 "Map Y {} exceeds the boundary of max height is {}",
                 map_y, self.height
             ));
         }
-        if map_x > self.width {
+        if map_x >= self.width {
             return Err(format!(
                 "Map X {} exceeds the boundary of max width is {}",
                 map_x, self.width
@@ -267,13 +371,13 @@ impl Map {
     }
 
     pub fn set(self: &mut Self, map_x: u16, map_y: u16, cell: MapCell) -> Result<(), String> {
-        if map_x > self.width {
+        if map_x >= self.width {
             return Err(format!(
                 "Cannot assign MapCell to position ([{}], {}) for its X position exceeds {}",
                 map_x, map_y, self.width
             ));
         }
-        if map_y > self.height {
+        if map_y >= self.height {
             return Err(format!(
                 "Cannot assign MapCell to position ({}, [{}]) for its Y position exceeds {}",
                 map_x, map_y, self.height
@@ -323,6 +427,131 @@ impl Map {
         return Ok(());
     }
 
+    pub fn place_entity(
+        self: &mut Self,
+        map_x: u16,
+        map_y: u16,
+        entity_id: TEntityID,
+    ) -> Result<TCellID, String> {
+        if self.is_in_bounds(map_x, map_y) == false {
+            return Err(format!(
+                "Cannot place entity {} at ({}, {}), map is {}x{}",
+                entity_id, map_x, map_y, self.width, self.height
+            ));
+        }
+        return self.grid[map_y as usize][map_x as usize].add_entity(entity_id);
+    }
+
+    // from one cell to the other, only taking it off the old cell once the new one has room for
+    // it, so that it is never left off the map
+    pub fn move_entity(
+        self: &mut Self,
+        from: (u16, u16),
+        to: (u16, u16),
+        entity_id: TEntityID,
+    ) -> Result<(), String> {
+        self.place_entity(to.0, to.1, entity_id)?;
+        if self.is_in_bounds(from.0, from.1) {
+            self.grid[from.1 as usize][from.0 as usize].remove_entity(&entity_id);
+        }
+        return Ok(());
+    }
+
+    pub fn get_deposit(self: &Self, map_x: u16, map_y: u16) -> Option<OreDeposit> {
+        if self.is_in_bounds(map_x, map_y) == false {
+            return None;
+        }
+        return self.grid[map_y as usize][map_x as usize].deposit;
+    }
+    pub fn set_deposit(
+        self: &mut Self,
+        map_x: u16,
+        map_y: u16,
+        deposit: Option<OreDeposit>,
+    ) -> Result<(), String> {
+        if self.is_in_bounds(map_x, map_y) == false {
+            return Err(format!(
+                "Cannot set deposit at ({}, {}), map is {}x{}",
+                map_x, map_y, self.width, self.height
+            ));
+        }
+        self.grid[map_y as usize][map_x as usize].deposit = deposit;
+        return Ok(());
+    }
+    // takes up to amount out of the deposit, returns what was actually extracted; finite deposits
+    // are removed from the cell once depleted
+    pub fn extract_deposit(
+        self: &mut Self,
+        map_x: u16,
+        map_y: u16,
+        amount: u32,
+    ) -> Option<(ItemTypes, u32)> {
+        if self.is_in_bounds(map_x, map_y) == false {
+            return None;
+        }
+        let cell = &mut self.grid[map_y as usize][map_x as usize];
+        let deposit = cell.deposit.as_mut()?;
+        let extracted = match deposit.remaining.as_mut() {
+            Some(remaining) => {
+                let extracted = amount.min(*remaining);
+                *remaining -= extracted;
+                extracted
+            }
+            None => amount,
+        };
+        let item = deposit.item;
+        if deposit.remaining == Some(0) {
+            cell.deposit = None;
+        }
+        return Some((item, extracted));
+    }
+
+    // sweeps the entire grid, since cells do not index back to entities; returns the number of
+    // cells the entity was removed from
+    pub fn remove_entity(self: &mut Self, entity_id: &TEntityID) -> usize {
+        let mut removed_count = 0;
+        for row in self.grid.iter_mut() {
+            for cell in row.iter_mut() {
+                if cell.remove_entity(entity_id) {
+                    removed_count += 1;
+                }
+            }
+        }
+        return removed_count;
+    }
+
+    // every cell, row by row
+    pub fn iter_cells(self: &Self) -> impl Iterator<Item = (u16, u16, &MapCell)> {
+        return self.grid.iter().enumerate().flat_map(|(map_y, row)| {
+            row.iter()
+                .enumerate()
+                .map(move |(map_x, cell)| (map_x as u16, map_y as u16, cell))
+        });
+    }
+
+    // cells (row by row) that differ from those of the previous map, i.e. to send only what
+    // changed to clients; apply them on the other end with set()
+    pub fn get_changed_cells(
+        self: &Self,
+        previous: &Map,
+    ) -> Result<Vec<(u16, u16, MapCell)>, String> {
+        if self.width != previous.width || self.height != previous.height {
+            return Err(format!(
+                "Cannot compare a {}x{} map against a {}x{} one",
+                self.width, self.height, previous.width, previous.height
+            ));
+        }
+        let mut changed = Vec::new();
+        for (map_y, (row, previous_row)) in self.grid.iter().zip(previous.grid.iter()).enumerate() {
+            for (map_x, (cell, previous_cell)) in row.iter().zip(previous_row.iter()).enumerate() {
+                if cell != previous_cell {
+                    changed.push((map_x as u16, map_y as u16, cell.clone()));
+                }
+            }
+        }
+        return Ok(changed);
+    }
+
     // convert 2D to single array strided
     pub fn build_view(
         self: &Self,
@@ -330,18 +559,32 @@ impl Map {
         view_offset_y: u8,
         view_width: u8,
         view_height: u8,
+    ) -> Result<Vec<Option<TEntityID>>, String> {
+        return self.build_view_at(
+            self.current_x + view_offset_x as u16,
+            self.current_y + view_offset_y as u16,
+            view_width,
+            view_height,
+        );
+    }
+    // same as build_view(), but from the given upper left rather than the map's own, so that a
+    // frontend can scroll about without changing the map
+    pub fn build_view_at(
+        self: &Self,
+        map_x: u16,
+        map_y: u16,
+        view_width: u8,
+        view_height: u8,
     ) -> Result<Vec<Option<TEntityID>>, String> {
         // Map:((5, 205)) - World:(5, 205) Cursor:(0, 0) Pos:(5, 205) Val:0 - Mouse:(1017, 618) - Keys:[PageDown]
         // thread 'main' panicked at 'called `Result::unwrap()` on an `Err` value: "Map Y 205 exceeds the boundary of max height is 200"', src\map.rs:313:75
-        let map_x = self.current_x + view_offset_x as u16;
-        let map_y = self.current_y + view_offset_y as u16;
-        if map_x > self.width {
+        if map_x >= self.width {
             return Err(format!(
                 "ViewXTop={} exceeds max width dimension {}",
                 map_x, self.width
             ));
         }
-        if map_y > self.height {
+        if map_y >= self.height {
             return Err(format!(
                 "ViewYTop={} exceeds max height dimension {}",
                 map_y, self.height
@@ -490,9 +733,42 @@ impl Map {
         };
     }
 
-    pub fn auto_generate(self: &Self) -> Result<Map, String> {
-        // TODO: based on map dimension, update grid[][]
-        return Err("CODE ME!".to_owned());
+    // A fresh map of the same dimension, scattered with ore patches; draw from the simulation's
+    // RngStreamTypes::MapGeneration stream so that the same seed makes the same map
+    pub fn auto_generate(self: &Self, rng: &mut Rng) -> Result<Map, String> {
+        const PATCH_ORES: [(ItemTypes, Option<u32>); 5] = [
+            (ItemTypes::Copper, Some(400)),
+            (ItemTypes::Lead, Some(400)),
+            (ItemTypes::Coal, Some(250)),
+            (ItemTypes::Titanium, Some(150)),
+            (ItemTypes::Sand, None),
+        ];
+        let mut generated = Map::create(self.width, self.height)?;
+        // roughly a patch per 64 cells, each a blob of radius 1..=2 with ragged edges
+        let patch_count = (self.width as u32 * self.height as u32) / 64;
+        for _ in 0..patch_count {
+            let (item, remaining) = *rng.choose(&PATCH_ORES).unwrap();
+            let center_x = rng.gen_range(0, self.width as u32) as i32;
+            let center_y = rng.gen_range(0, self.height as u32) as i32;
+            let radius = rng.gen_range(1, 3) as i32;
+            for y in (center_y - radius)..=(center_y + radius) {
+                for x in (center_x - radius)..=(center_x + radius) {
+                    let is_edge = (x - center_x).abs() == radius || (y - center_y).abs() == radius;
+                    if is_edge && rng.gen_permille(500) {
+                        continue;
+                    }
+                    if x < 0 || y < 0 || generated.is_in_bounds(x as u16, y as u16) == false {
+                        continue;
+                    }
+                    generated.set_deposit(
+                        x as u16,
+                        y as u16,
+                        Some(OreDeposit { item, remaining }),
+                    )?;
+                }
+            }
+        }
+        return Ok(generated);
     }
 
     pub fn load(_file_path: &String) -> Result<Map, String> {
@@ -516,6 +792,26 @@ mod tests {
         assert_eq!(the_map.grid[0][0].layers.len(), 0); // when freshly creaed, each/any layers are empty
     }
 
+    #[test]
+    fn auto_generate_is_seeded() {
+        use crate::random::{RandomStreams, RngStreamTypes};
+        let template = Map::create(32, 32).unwrap();
+        let mut streams = RandomStreams::new(42);
+        let the_map = template
+            .auto_generate(streams.stream(RngStreamTypes::MapGeneration))
+            .unwrap();
+        let mut same_seed = RandomStreams::new(42);
+        let same_map = template
+            .auto_generate(same_seed.stream(RngStreamTypes::MapGeneration))
+            .unwrap();
+        assert_eq!(the_map, same_map);
+        assert!(the_map
+            .grid
+            .iter()
+            .flatten()
+            .any(|cell| cell.deposit.is_some()));
+    }
+
     #[test]
     fn can_update_layer() {
         let mut the_map = Map::create(64, 128).unwrap(); // gotta make it mutable if we're going to allow update
@@ -543,6 +839,21 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_far_edge_is_out_of_bounds() {
+        // the last valid cell is (width - 1, height - 1); width/height themselves used to slip
+        // through and index past the end of the grid
+        let mut the_map = Map::create(16, 32).unwrap();
+        let cell = the_map.get_cell(15, 31).unwrap();
+        assert!(the_map.get_cell(16, 0).is_err());
+        assert!(the_map.get_cell(0, 32).is_err());
+        assert!(the_map.set(16, 0, cell.clone()).is_err());
+        assert!(the_map.set(0, 32, cell.clone()).is_err());
+        assert!(the_map.get_cell_row(16, 0, 1).is_err());
+        assert!(the_map.get_cell_row(0, 32, 1).is_err());
+        assert!(the_map.set(15, 31, cell).is_ok());
+    }
+
     #[test]
     fn test_view_for_rendering() {
         let map_width = 16;
@@ -571,6 +882,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_view_at_leaves_the_map_as_is() {
+        let mut the_map = Map::create(16, 32).unwrap();
+        the_map.grid[20][10].set(0, 7).unwrap();
+        let view = the_map.build_view_at(8, 18, 4, 4).unwrap();
+        assert_eq!(view[2 * 4 + 2], Some(7));
+        assert_eq!(the_map.get_upper_left(), (0, 0));
+
+        the_map.set_upper_left(8, 18);
+        assert_eq!(the_map.build_view(0, 0, 4, 4).unwrap(), view);
+        assert!(the_map.build_view_at(14, 0, 4, 4).is_err());
+    }
+
     #[test]
     fn test_serialize_deserialize() {
         let mut the_map = Map::create(64, 128).unwrap(); // gotta make it mutable if we're going to allow update
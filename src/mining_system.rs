@@ -0,0 +1,230 @@
+// Mindustry-style drills which extract from the OreDeposits under their footprint, the more ore
+// cells a drill covers the faster it mines
+use crate::economy_system::{Economy, ItemStack, TPlayerID};
+use crate::entity_system::{self, TEntityID};
+use crate::map::Map;
+use crate::placement_system;
+use crate::prototype_system;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Drill {
+    pub entity_id: TEntityID,
+    pub owner: TPlayerID, // whose economy the mined items go to
+    pub footprint: Vec<(u16, u16)>,
+    pub millis_per_item: u128, // per ore cell under the footprint
+    progress_millis: u128,
+    next_cell_index: usize, // round-robins through the ore cells so that they deplete evenly
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MiningSystem {
+    drills: Vec<Drill>, // sorted by entity_id so that updates are in a deterministic order
+}
+
+impl MiningSystem {
+    pub fn new() -> MiningSystem {
+        MiningSystem { drills: Vec::new() }
+    }
+
+    // registers an already placed (see placement_system) drill structure
+    pub fn add_drill(
+        self: &mut Self,
+        entity_id: &TEntityID,
+        owner: TPlayerID,
+    ) -> Result<(), String> {
+        let entity = entity_system::modify(entity_id, |e| *e)?;
+        let prototype = match entity
+            .prototype_id
+            .and_then(|id| prototype_system::get(&id))
+        {
+            Some(p) => p,
+            None => return Err(format!("entityID={} has no prototype", entity_id)),
+        };
+        if prototype.mining_millis_per_item == 0 {
+            return Err(format!("'{}' is not a drill", prototype.name));
+        }
+        match self.drills.binary_search_by(|d| d.entity_id.cmp(entity_id)) {
+            Ok(_) => Err(format!("entityID={} is already a drill", entity_id)),
+            Err(index) => {
+                self.drills.insert(
+                    index,
+                    Drill {
+                        entity_id: *entity_id,
+                        owner,
+                        footprint: placement_system::get_footprint(
+                            &prototype,
+                            entity.map_x,
+                            entity.map_y,
+                        ),
+                        millis_per_item: prototype.mining_millis_per_item,
+                        progress_millis: 0,
+                        next_cell_index: 0,
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+    pub fn remove_drill(self: &mut Self, entity_id: &TEntityID) -> Option<Drill> {
+        return match self.drills.binary_search_by(|d| d.entity_id.cmp(entity_id)) {
+            Ok(index) => Some(self.drills.remove(index)),
+            Err(_) => None,
+        };
+    }
+    pub fn get_drills(self: &Self) -> &Vec<Drill> {
+        return &self.drills;
+    }
+
+    // items per minute the drill is currently capable of (0 if there's nothing left to mine)
+    pub fn get_mining_rate_per_minute(self: &Self, map: &Map, entity_id: &TEntityID) -> u32 {
+        return match self.drills.binary_search_by(|d| d.entity_id.cmp(entity_id)) {
+            Ok(index) => {
+                let drill = &self.drills[index];
+                let ore_cells = drill.get_ore_cells(map).len() as u128;
+                (60 * 1000 * ore_cells / drill.millis_per_item.max(1)) as u32
+            }
+            Err(_) => 0,
+        };
+    }
+
+    /// Mines and returns what each drill has extracted this frame (per drill, in drill order);
+    /// it is up to the caller to deliver them (see update_into_economy() for the simple case)
+    pub fn update(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        map: &mut Map,
+    ) -> Vec<(TEntityID, TPlayerID, Vec<ItemStack>)> {
+        let mut mined = Vec::new();
+        for drill in self.drills.iter_mut() {
+            let ore_cells = drill.get_ore_cells(map);
+            if ore_cells.is_empty() {
+                drill.progress_millis = 0; // nothing left to mine, idle
+                continue;
+            }
+            drill.progress_millis += last_frame_delta_millis * ore_cells.len() as u128;
+            let mut items: Vec<ItemStack> = Vec::new();
+            while drill.progress_millis >= drill.millis_per_item {
+                drill.progress_millis -= drill.millis_per_item;
+                let ore_cells = drill.get_ore_cells(map); // may have been depleted by the last extraction
+                if ore_cells.is_empty() {
+                    drill.progress_millis = 0;
+                    break;
+                }
+                let (cell_x, cell_y) = ore_cells[drill.next_cell_index % ore_cells.len()];
+                drill.next_cell_index = (drill.next_cell_index + 1) % ore_cells.len();
+                if let Some((item, amount)) = map.extract_deposit(cell_x, cell_y, 1) {
+                    match items.iter_mut().find(|stack| stack.item == item) {
+                        Some(stack) => stack.amount += amount,
+                        None => items.push(ItemStack::new(item, amount)),
+                    }
+                }
+            }
+            if items.is_empty() == false {
+                mined.push((drill.entity_id, drill.owner, items));
+            }
+        }
+        return mined;
+    }
+
+    // for when there is no transport network, mined items go straight into the owner's ledger
+    pub fn update_into_economy(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        map: &mut Map,
+        economy: &mut Economy,
+    ) -> Result<(), String> {
+        for (_drill_id, owner, items) in self.update(last_frame_delta_millis, map) {
+            economy.earn(owner, &items)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Drill {
+    fn get_ore_cells(self: &Self, map: &Map) -> Vec<(u16, u16)> {
+        return self
+            .footprint
+            .iter()
+            .filter(|(x, y)| map.get_deposit(*x, *y).is_some())
+            .copied()
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::ItemTypes;
+    use crate::map::OreDeposit;
+    use crate::test_helpers::{add_prototype, TestWorld};
+
+    // a 2x2 drill of player 1 over 3 copper cells (holding 3, 1 and infinite ore) and a bare one
+    fn place_drill() -> (Map, Economy, MiningSystem, TEntityID) {
+        let drill_prototype_id = add_prototype("test drill", |p| {
+            p.footprint_width = 2;
+            p.footprint_height = 2;
+            p.mining_millis_per_item = 1000;
+        });
+        let mut the_map = Map::create(8, 8).unwrap();
+        let copper = |remaining| OreDeposit {
+            item: ItemTypes::Copper,
+            remaining,
+        };
+        the_map.set_deposit(3, 3, Some(copper(Some(3)))).unwrap();
+        the_map.set_deposit(4, 3, Some(copper(Some(1)))).unwrap();
+        the_map.set_deposit(4, 4, Some(copper(None))).unwrap();
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        let drill_id = placement_system::place_structure(
+            &mut the_map,
+            &mut economy,
+            1,
+            &drill_prototype_id,
+            3,
+            3,
+        )
+        .unwrap();
+        let mut mining = MiningSystem::new();
+        mining.add_drill(&drill_id, 1).unwrap();
+        return (the_map, economy, mining, drill_id);
+    }
+
+    #[test]
+    fn test_drills_are_added_once() {
+        let _world = TestWorld::new();
+        let (_, _, mut mining, drill_id) = place_drill();
+        assert!(mining.add_drill(&drill_id, 1).is_err());
+    }
+
+    #[test]
+    fn test_drills_mine_every_ore_cell_below() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, mut mining, drill_id) = place_drill();
+        assert_eq!(mining.get_mining_rate_per_minute(&the_map, &drill_id), 180);
+        // 3 ore cells, so 3 items per second, which depletes the 1 remaining
+        mining
+            .update_into_economy(1000, &mut the_map, &mut economy)
+            .unwrap();
+        assert_eq!(economy.get_balance(1, ItemTypes::Copper), 3);
+        assert!(the_map.get_deposit(4, 3).is_none());
+        assert_eq!(the_map.get_deposit(3, 3).unwrap().remaining, Some(1));
+    }
+
+    #[test]
+    fn test_drills_deplete_deposits() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, mut mining, drill_id) = place_drill();
+        for _ in 0..11 {
+            mining
+                .update_into_economy(1000, &mut the_map, &mut economy)
+                .unwrap();
+        }
+        // only the infinite one remains
+        assert!(the_map.get_deposit(3, 3).is_none());
+        assert!(the_map.get_deposit(4, 3).is_none());
+        assert_eq!(mining.get_mining_rate_per_minute(&the_map, &drill_id), 60);
+        assert!(economy.get_balance(1, ItemTypes::Copper) >= 13);
+    }
+}
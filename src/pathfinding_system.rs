@@ -0,0 +1,74 @@
+// Grid pathfinding for anything that walks the Map (see behaviour_system).  Structures, walls and
+// any other entity that cannot move block their cells; whatever can move (units, creeps) or is
+// built to be walked over (conveyors) is walked through, same as unit_system does.  Plain breadth-first search over the 4 neighbours in
+// a fixed order, so the same map always gives the same path
+use crate::entity_system::{self, PhysicsObjectCollisionTypes};
+use crate::map::Map;
+use std::collections::VecDeque;
+
+// up, left, right, down: the order in which ties are broken
+const NEIGHBOURS: [(i32, i32); 4] = [(0, -1), (-1, 0), (1, 0), (0, 1)];
+
+pub fn is_passable(map: &Map, map_x: u16, map_y: u16) -> bool {
+    return match map.get_cell_ref(map_x, map_y) {
+        Some(cell) => entity_system::all_of(cell.layers.iter().map(|layer| &layer.entity), |e| {
+            e.physics_info.max_velocity > 0
+                || e.physics_info.collision_type == PhysicsObjectCollisionTypes::Floor
+        }),
+        None => false, // off the map
+    };
+}
+
+pub fn get_neighbours(map: &Map, map_x: u16, map_y: u16) -> Vec<(u16, u16)> {
+    let mut neighbours = Vec::new();
+    for (dx, dy) in NEIGHBOURS.iter() {
+        let x = map_x as i32 + dx;
+        let y = map_y as i32 + dy;
+        if x >= 0 && y >= 0 && map.is_in_bounds(x as u16, y as u16) {
+            neighbours.push((x as u16, y as u16));
+        }
+    }
+    return neighbours;
+}
+
+/// Shortest walk from one cell to the other, excluding the start and including the goal; the
+/// goal itself may be blocked (i.e. a structure being attacked), the cells on the way may not.
+/// None when there is no way there
+pub fn find_path(map: &Map, from: (u16, u16), to: (u16, u16)) -> Option<Vec<(u16, u16)>> {
+    if from == to {
+        return Some(Vec::new());
+    }
+    if map.is_in_bounds(from.0, from.1) == false || map.is_in_bounds(to.0, to.1) == false {
+        return None;
+    }
+    let width = map.get_width() as usize;
+    let index = |cell: (u16, u16)| cell.1 as usize * width + cell.0 as usize;
+    let mut came_from: Vec<Option<(u16, u16)>> = vec![None; width * map.get_height() as usize];
+    let mut to_visit = VecDeque::new();
+    came_from[index(from)] = Some(from);
+    to_visit.push_back(from);
+    while let Some(current) = to_visit.pop_front() {
+        for next in get_neighbours(map, current.0, current.1) {
+            if came_from[index(next)].is_some() {
+                continue;
+            }
+            if next != to && is_passable(map, next.0, next.1) == false {
+                continue;
+            }
+            came_from[index(next)] = Some(current);
+            if next == to {
+                // walk it back to the start
+                let mut path = vec![to];
+                let mut cell = current;
+                while cell != from {
+                    path.push(cell);
+                    cell = came_from[index(cell)].unwrap();
+                }
+                path.reverse();
+                return Some(path);
+            }
+            to_visit.push_back(next);
+        }
+    }
+    return None;
+}
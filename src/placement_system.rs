@@ -0,0 +1,237 @@
+// Building and selling of structures (towers, drills, etc) on the Map, which is where the
+// economy gets charged/refunded.  Everything is validated up front so that a failed placement
+// leaves the map, entity_system and the ledger untouched
+use crate::economy_system::{Economy, ItemStack, TPlayerID, FULL_REFUND_PERCENT};
+use crate::entity_system::{self, TEntityID};
+use crate::map::Map;
+use crate::prototype_system::{self, EntityPrototype, TPrototypeID};
+
+// the cells (upper-left first, row by row) the prototype would occupy if placed at map_x/map_y
+pub fn get_footprint(prototype: &EntityPrototype, map_x: u16, map_y: u16) -> Vec<(u16, u16)> {
+    let mut cells = Vec::new();
+    for offset_y in 0..prototype.footprint_height.max(1) as u16 {
+        for offset_x in 0..prototype.footprint_width.max(1) as u16 {
+            cells.push((
+                map_x.saturating_add(offset_x),
+                map_y.saturating_add(offset_y),
+            ));
+        }
+    }
+    return cells;
+}
+
+// Ok(()) if the footprint is within the map and none of its cells are occupied
+pub fn can_place(
+    map: &Map,
+    prototype: &EntityPrototype,
+    map_x: u16,
+    map_y: u16,
+) -> Result<(), String> {
+    for (cell_x, cell_y) in get_footprint(prototype, map_x, map_y) {
+        if map.is_in_bounds(cell_x, cell_y) == false {
+            return Err(format!(
+                "'{}' at ({}, {}) does not fit within the map",
+                prototype.name, map_x, map_y
+            ));
+        }
+        if map.get_cell(cell_x, cell_y)?.layers.is_empty() == false {
+            return Err(format!(
+                "'{}' at ({}, {}) overlaps occupied cell ({}, {})",
+                prototype.name, map_x, map_y, cell_x, cell_y
+            ));
+        }
+    }
+    return Ok(());
+}
+
+/// Charges the player, spawns the structure and places it on every cell of its footprint.
+/// Fails (without side effects) if it cannot be placed there or the player cannot afford it
+pub fn place_structure(
+    map: &mut Map,
+    economy: &mut Economy,
+    player_id: TPlayerID,
+    prototype_id: &TPrototypeID,
+    map_x: u16,
+    map_y: u16,
+) -> Result<TEntityID, String> {
+    let prototype = match prototype_system::get(prototype_id) {
+        Some(p) => p,
+        None => return Err(format!("prototypeID={} does not exist", prototype_id)),
+    };
+    can_place(map, &prototype, map_x, map_y)?;
+    economy.spend(player_id, &prototype.build_costs)?;
+
+    let entity_id = match prototype_system::spawn(prototype_id, map_x, map_y)
+        .and_then(|id| entity_system::modify(&id, |e| e.owner = player_id).map(|_| id))
+    {
+        Ok(id) => id,
+        Err(e) => {
+            economy.refund(player_id, &prototype.build_costs, FULL_REFUND_PERCENT)?;
+            return Err(e);
+        }
+    };
+    for (cell_x, cell_y) in get_footprint(&prototype, map_x, map_y) {
+        if let Err(e) = map.place_entity(cell_x, cell_y, entity_id) {
+            // should not happen since can_place() passed, but roll back just in case
+            map.remove_entity(&entity_id);
+            let _ = entity_system::remove(&entity_id);
+            economy.refund(player_id, &prototype.build_costs, FULL_REFUND_PERCENT)?;
+            return Err(e);
+        }
+    }
+    return Ok(entity_id);
+}
+
+// Ok(prototype) if the entity is a structure (not a unit) and the player has a ledger to be
+// refunded to, i.e. sell_structure() would go through
+pub fn can_sell(
+    economy: &Economy,
+    player_id: TPlayerID,
+    entity_id: &TEntityID,
+) -> Result<EntityPrototype, String> {
+    let entity = entity_system::modify(entity_id, |e| *e)?;
+    let prototype = match entity
+        .prototype_id
+        .and_then(|id| prototype_system::get(&id))
+    {
+        Some(p) if entity.is_structure() => p,
+        _ => return Err(format!("entityID={} is not a structure", entity_id)),
+    };
+    if economy.get_ledger(player_id).is_none() {
+        return Err(format!("playerID={} has no ledger", player_id));
+    }
+    return Ok(prototype);
+}
+
+/// Removes the structure from the map and entity_system, refunding the player based on the
+/// prototype's sell_refund_percent.  Returns what was refunded
+pub fn sell_structure(
+    map: &mut Map,
+    economy: &mut Economy,
+    player_id: TPlayerID,
+    entity_id: &TEntityID,
+) -> Result<Vec<ItemStack>, String> {
+    let prototype = can_sell(economy, player_id, entity_id)?;
+    map.remove_entity(entity_id);
+    entity_system::remove(entity_id)?;
+    return economy.refund(
+        player_id,
+        &prototype.build_costs,
+        prototype.sell_refund_percent,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::ItemTypes;
+    use crate::test_helpers::{add_prototype, new_prototype_id, TestWorld};
+
+    // a 2x2 tower for 100 gold, on an empty map, with 150 gold for player 1
+    fn setup() -> (Map, Economy, TPrototypeID) {
+        let tower_id = add_prototype("test tower", |p| {
+            p.footprint_width = 2;
+            p.footprint_height = 2;
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 100)];
+            p.sell_refund_percent = 75;
+        });
+        let the_map = Map::create(8, 8).unwrap();
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        economy
+            .earn(1, &vec![ItemStack::new(ItemTypes::Gold, 150)])
+            .unwrap();
+        return (the_map, economy, tower_id);
+    }
+
+    // a failed placement neither charges the player nor leaves anything behind
+    fn assert_nothing_placed(the_map: &Map, economy: &Economy, gold: u32, entity_count: usize) {
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), gold);
+        assert_eq!(entity_system::snapshot().len(), entity_count);
+        let cells_taken = the_map
+            .iter_cells()
+            .filter(|(_, _, cell)| cell.layers.is_empty() == false)
+            .count();
+        assert_eq!(cells_taken, 4 * entity_count);
+    }
+
+    #[test]
+    fn test_place_and_sell() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, tower_id) = setup();
+        let entity_id = place_structure(&mut the_map, &mut economy, 1, &tower_id, 1, 1).unwrap();
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 50);
+        assert!(the_map.get_cell(2, 2).unwrap().contains_entity(&entity_id));
+
+        let refunded = sell_structure(&mut the_map, &mut economy, 1, &entity_id).unwrap();
+        assert_eq!(refunded, vec![ItemStack::new(ItemTypes::Gold, 75)]);
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 125);
+        assert!(the_map.get_cell(1, 1).unwrap().layers.is_empty());
+        assert!(entity_system::modify(&entity_id, |e| e.id).is_err());
+    }
+
+    #[test]
+    fn test_overlapping_placement_fails() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, tower_id) = setup();
+        economy
+            .earn(1, &vec![ItemStack::new(ItemTypes::Gold, 100)])
+            .unwrap();
+        place_structure(&mut the_map, &mut economy, 1, &tower_id, 1, 1).unwrap();
+        assert!(place_structure(&mut the_map, &mut economy, 1, &tower_id, 2, 2).is_err());
+        assert_nothing_placed(&the_map, &economy, 150, 1);
+    }
+
+    #[test]
+    fn test_placement_out_of_bounds_fails() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, tower_id) = setup();
+        assert!(place_structure(&mut the_map, &mut economy, 1, &tower_id, 7, 0).is_err());
+        assert_nothing_placed(&the_map, &economy, 150, 0);
+    }
+
+    #[test]
+    fn test_unaffordable_placement_fails() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, tower_id) = setup();
+        economy
+            .spend(1, &vec![ItemStack::new(ItemTypes::Gold, 51)])
+            .unwrap();
+        assert!(place_structure(&mut the_map, &mut economy, 1, &tower_id, 4, 4).is_err());
+        assert_nothing_placed(&the_map, &economy, 99, 0);
+    }
+
+    #[test]
+    fn test_placing_unknown_prototypes_fails() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, _) = setup();
+        let unknown_id = new_prototype_id();
+        assert!(place_structure(&mut the_map, &mut economy, 1, &unknown_id, 4, 4).is_err());
+        assert_nothing_placed(&the_map, &economy, 150, 0);
+    }
+
+    #[test]
+    fn test_selling_without_a_ledger_fails() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, tower_id) = setup();
+        let entity_id = place_structure(&mut the_map, &mut economy, 1, &tower_id, 1, 1).unwrap();
+        assert!(sell_structure(&mut the_map, &mut economy, 2, &entity_id).is_err());
+        assert!(the_map.get_cell(1, 1).unwrap().contains_entity(&entity_id));
+        assert!(entity_system::try_get(entity_id).is_some());
+    }
+
+    #[test]
+    fn test_units_cannot_be_sold() {
+        let _world = TestWorld::new();
+        let (mut the_map, mut economy, _) = setup();
+        let soldier_id = add_prototype("test soldier", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 100)];
+            p.max_velocity = 10;
+        });
+        let entity_id = prototype_system::spawn(&soldier_id, 1, 1).unwrap();
+        the_map.place_entity(1, 1, entity_id).unwrap();
+        assert!(sell_structure(&mut the_map, &mut economy, 1, &entity_id).is_err());
+        assert!(the_map.get_cell(1, 1).unwrap().contains_entity(&entity_id));
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 150);
+    }
+}
@@ -0,0 +1,450 @@
+// Power grids; structures with a PowerRoles (see EntityPrototype::power) are connected when their
+// footprints touch, or when a power node's laser range reaches them.  Each connected group is a
+// PowerNetwork which, every update, balances its producers against its consumers, with batteries
+// soaking up the surplus and covering the shortfall.  Consumers that get less than they need are
+// throttled (see Entity::power_permille, i.e. towers fire slower)
+use crate::entity_system::{self, TEntityID};
+use crate::map::Map;
+use crate::placement_system;
+use crate::prototype_system;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub const FULL_POWER_PERMILLE: u16 = 1000;
+
+pub type TPowerNetworkID = u32;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum PowerRoles {
+    Producer { output: u32 },  // power units per second
+    Consumer { demand: u32 },  // power units per second
+    Battery { capacity: u32 }, // power units
+    Node { range: u8 },        // in cells, connects to anything within it
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PowerMember {
+    pub entity_id: TEntityID,
+    pub role: PowerRoles,
+    pub footprint: Vec<(u16, u16)>,
+    pub network_id: TPowerNetworkID,
+    stored_unit_millis: u64, // batteries only, 1000 of it is one power unit
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PowerNetwork {
+    pub id: TPowerNetworkID,
+    pub members: Vec<TEntityID>,    // sorted
+    pub production: u32,            // per second
+    pub demand: u32,                // per second
+    pub capacity: u32,              // of all batteries
+    pub satisfaction_permille: u16, // as of the last update
+}
+
+impl PowerNetwork {
+    fn new(id: TPowerNetworkID) -> PowerNetwork {
+        PowerNetwork {
+            id,
+            members: Vec::new(),
+            production: 0,
+            demand: 0,
+            capacity: 0,
+            satisfaction_permille: FULL_POWER_PERMILLE,
+        }
+    }
+}
+
+impl PowerMember {
+    fn get_range(self: &Self) -> u32 {
+        return match self.role {
+            PowerRoles::Node { range } => range as u32,
+            _ => 0,
+        };
+    }
+
+    // touching footprints, or within range of a node (whichever reaches further)
+    fn is_connected_to(self: &Self, other: &PowerMember) -> bool {
+        let range = self.get_range().max(other.get_range());
+        return self.footprint.iter().any(|(x, y)| {
+            other.footprint.iter().any(|(other_x, other_y)| {
+                let dx = (*x as i32 - *other_x as i32).unsigned_abs();
+                let dy = (*y as i32 - *other_y as i32).unsigned_abs();
+                dx + dy == 1 || (range > 0 && dx * dx + dy * dy <= range * range)
+            })
+        });
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PowerGrid {
+    members: BTreeMap<TEntityID, PowerMember>,
+    networks: BTreeMap<TPowerNetworkID, PowerNetwork>,
+    next_network_id: TPowerNetworkID,
+}
+
+impl PowerGrid {
+    pub fn new() -> PowerGrid {
+        PowerGrid {
+            members: BTreeMap::new(),
+            networks: BTreeMap::new(),
+            next_network_id: 1,
+        }
+    }
+
+    pub fn get_member(self: &Self, entity_id: &TEntityID) -> Option<&PowerMember> {
+        return self.members.get(entity_id);
+    }
+    pub fn get_network(self: &Self, network_id: TPowerNetworkID) -> Option<&PowerNetwork> {
+        return self.networks.get(&network_id);
+    }
+    pub fn get_network_of(self: &Self, entity_id: &TEntityID) -> Option<&PowerNetwork> {
+        return self
+            .members
+            .get(entity_id)
+            .and_then(|m| self.networks.get(&m.network_id));
+    }
+    pub fn get_networks(self: &Self) -> Vec<&PowerNetwork> {
+        return self.networks.values().collect();
+    }
+    // in power units, 0 for anything but batteries
+    pub fn get_stored(self: &Self, entity_id: &TEntityID) -> u32 {
+        return match self.members.get(entity_id) {
+            Some(member) => (member.stored_unit_millis / 1000) as u32,
+            None => 0,
+        };
+    }
+
+    /// Joins an already placed structure (see placement_system) to the grid, merging whichever
+    /// networks it connects together; only the networks it touches get recomputed
+    pub fn add_structure(self: &mut Self, map: &Map, entity_id: &TEntityID) -> Result<(), String> {
+        if self.members.contains_key(entity_id) {
+            return Err(format!(
+                "entityID={} is already on the power grid",
+                entity_id
+            ));
+        }
+        let entity = entity_system::modify(entity_id, |e| *e)?;
+        let prototype = match entity
+            .prototype_id
+            .and_then(|id| prototype_system::get(&id))
+        {
+            Some(p) => p,
+            None => return Err(format!("entityID={} has no prototype", entity_id)),
+        };
+        let role = match prototype.power {
+            Some(role) => role,
+            None => return Err(format!("'{}' does not use power", prototype.name)),
+        };
+        let footprint = placement_system::get_footprint(&prototype, entity.map_x, entity.map_y);
+        for (cell_x, cell_y) in footprint.iter() {
+            if map.is_in_bounds(*cell_x, *cell_y) == false
+                || map.get_cell(*cell_x, *cell_y)?.contains_entity(entity_id) == false
+            {
+                return Err(format!(
+                    "entityID={} is not placed on ({}, {})",
+                    entity_id, cell_x, cell_y
+                ));
+            }
+        }
+        let mut member = PowerMember {
+            entity_id: *entity_id,
+            role,
+            footprint,
+            network_id: 0,
+            stored_unit_millis: 0,
+        };
+
+        let mut connected_networks: Vec<TPowerNetworkID> = self
+            .members
+            .values()
+            .filter(|other| member.is_connected_to(other))
+            .map(|other| other.network_id)
+            .collect();
+        connected_networks.sort();
+        connected_networks.dedup();
+        member.network_id = match connected_networks.first() {
+            Some(id) => *id, // the oldest one absorbs the rest
+            None => self.new_network(),
+        };
+        for merged_id in connected_networks.iter().skip(1) {
+            self.networks.remove(merged_id);
+            for other in self.members.values_mut() {
+                if other.network_id == *merged_id {
+                    other.network_id = member.network_id;
+                }
+            }
+        }
+        let network_id = member.network_id;
+        self.members.insert(*entity_id, member);
+        self.recompute_network(network_id);
+        return Ok(());
+    }
+
+    /// Takes the structure off the grid (i.e. sold or destroyed), splitting its network if it was
+    /// the only link in between.  Returns the member that was removed
+    pub fn remove_structure(self: &mut Self, entity_id: &TEntityID) -> Option<PowerMember> {
+        let removed = self.members.remove(entity_id)?;
+        // in case it is only being disconnected rather than destroyed
+        let _ = entity_system::modify(entity_id, |e| e.power_permille = FULL_POWER_PERMILLE);
+
+        // flood fill what is left of the network, first group keeps the id, the rest get new ones
+        let mut remaining: Vec<TEntityID> = self
+            .networks
+            .remove(&removed.network_id)
+            .map(|n| n.members)
+            .unwrap_or_default();
+        remaining.retain(|id| id != entity_id);
+        let mut groups: Vec<Vec<TEntityID>> = Vec::new();
+        while let Some(first) = remaining.first().copied() {
+            let mut group = vec![first];
+            remaining.retain(|id| *id != first);
+            let mut index = 0;
+            while index < group.len() {
+                let current = &self.members[&group[index]];
+                let (connected, rest): (Vec<TEntityID>, Vec<TEntityID>) = remaining
+                    .iter()
+                    .partition(|id| current.is_connected_to(&self.members[id]));
+                group.extend(connected);
+                remaining = rest;
+                index += 1;
+            }
+            groups.push(group);
+        }
+        let mut network_ids = Vec::new();
+        for (group_index, group) in groups.iter().enumerate() {
+            let network_id = match group_index {
+                0 => {
+                    let id = removed.network_id;
+                    self.networks.insert(id, PowerNetwork::new(id));
+                    id
+                }
+                _ => self.new_network(),
+            };
+            for id in group.iter() {
+                self.members.get_mut(id).unwrap().network_id = network_id;
+            }
+            network_ids.push(network_id);
+        }
+        // only once every group has its id, for recompute goes by network_id
+        for network_id in network_ids {
+            self.recompute_network(network_id);
+        }
+        return Some(removed);
+    }
+
+    fn new_network(self: &mut Self) -> TPowerNetworkID {
+        let id = self.next_network_id;
+        self.next_network_id += 1;
+        self.networks.insert(id, PowerNetwork::new(id));
+        return id;
+    }
+
+    fn recompute_network(self: &mut Self, network_id: TPowerNetworkID) {
+        let network = match self.networks.get_mut(&network_id) {
+            Some(n) => n,
+            None => return,
+        };
+        network.members.clear();
+        network.production = 0;
+        network.demand = 0;
+        network.capacity = 0;
+        for member in self.members.values().filter(|m| m.network_id == network_id) {
+            network.members.push(member.entity_id); // members is a BTreeMap, so already sorted
+            match member.role {
+                PowerRoles::Producer { output } => network.production += output,
+                PowerRoles::Consumer { demand } => network.demand += demand,
+                PowerRoles::Battery { capacity } => network.capacity += capacity,
+                PowerRoles::Node { .. } => {}
+            }
+        }
+    }
+
+    /// Balances supply against demand for every network, charging/draining batteries (in member
+    /// order) and setting each consumer's power_permille
+    pub fn update(self: &mut Self, last_frame_delta_millis: u128) {
+        let delta = last_frame_delta_millis as u64;
+        let mut throttles: Vec<(TEntityID, u16)> = Vec::new();
+        for network in self.networks.values_mut() {
+            let produced = network.production as u64 * delta;
+            let demanded = network.demand as u64 * delta;
+            let batteries = network.members.iter().filter_map(|id| {
+                match self.members.get(id).map(|m| m.role) {
+                    Some(PowerRoles::Battery { capacity }) => Some((*id, capacity)),
+                    _ => None,
+                }
+            });
+            let batteries: Vec<(TEntityID, u32)> = batteries.collect();
+
+            if produced >= demanded {
+                let mut surplus = produced - demanded;
+                for (id, capacity) in batteries {
+                    let battery = self.members.get_mut(&id).unwrap();
+                    let room = (capacity as u64 * 1000).saturating_sub(battery.stored_unit_millis);
+                    let charge = room.min(surplus);
+                    battery.stored_unit_millis += charge;
+                    surplus -= charge;
+                }
+                network.satisfaction_permille = FULL_POWER_PERMILLE;
+            } else {
+                let mut shortfall = demanded - produced;
+                for (id, _capacity) in batteries {
+                    let battery = self.members.get_mut(&id).unwrap();
+                    let drain = battery.stored_unit_millis.min(shortfall);
+                    battery.stored_unit_millis -= drain;
+                    shortfall -= drain;
+                }
+                network.satisfaction_permille =
+                    ((demanded - shortfall) * FULL_POWER_PERMILLE as u64 / demanded) as u16;
+            }
+            for id in network.members.iter() {
+                if let Some(PowerRoles::Consumer { .. }) = self.members.get(id).map(|m| m.role) {
+                    throttles.push((*id, network.satisfaction_permille));
+                }
+            }
+        }
+        for (entity_id, permille) in throttles {
+            // structures that got destroyed are expected to be removed by the caller, skip them
+            let _ = entity_system::modify(&entity_id, |e| e.power_permille = permille);
+        }
+    }
+}
+
+pub fn serialize_power_for_save(grid: &PowerGrid) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match grid.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_power_for_load(bin_data: &Vec<u8>) -> Result<PowerGrid, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    return rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::Economy;
+    use crate::test_helpers::{add_prototype, TestWorld};
+
+    struct TestGrid {
+        the_map: Map,
+        grid: PowerGrid,
+        battery_id: TEntityID,
+        node_id: TEntityID,
+        tower_id: TEntityID,
+    }
+
+    // a generator (100) and an empty battery, with a tower (wanting 200) only reachable through
+    // the node's laser; all but the node are in the grid
+    fn build_grid() -> TestGrid {
+        let add_power_prototype = |role| add_prototype("test power", |p| p.power = Some(role));
+        let generator_prototype_id = add_power_prototype(PowerRoles::Producer { output: 100 });
+        let node_prototype_id = add_power_prototype(PowerRoles::Node { range: 5 });
+        let tower_prototype_id = add_power_prototype(PowerRoles::Consumer { demand: 200 });
+        let battery_prototype_id = add_power_prototype(PowerRoles::Battery { capacity: 100 });
+        let mut the_map = Map::create(16, 4).unwrap();
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        let mut place = |prototype_id, x, y| {
+            placement_system::place_structure(&mut the_map, &mut economy, 1, &prototype_id, x, y)
+                .unwrap()
+        };
+        let generator_id = place(generator_prototype_id, 0, 0);
+        let battery_id = place(battery_prototype_id, 0, 1);
+        let node_id = place(node_prototype_id, 1, 0);
+        let tower_id = place(tower_prototype_id, 5, 0);
+        let mut grid = PowerGrid::new();
+        for id in [tower_id, generator_id, battery_id] {
+            grid.add_structure(&the_map, &id).unwrap();
+        }
+        return TestGrid {
+            the_map,
+            grid,
+            battery_id,
+            node_id,
+            tower_id,
+        };
+    }
+
+    fn get_satisfaction(grid: &PowerGrid, entity_id: &TEntityID) -> u16 {
+        return grid
+            .get_network_of(entity_id)
+            .unwrap()
+            .satisfaction_permille;
+    }
+
+    #[test]
+    fn test_nodes_join_networks() {
+        let _world = TestWorld::new();
+        let mut test = build_grid();
+        assert_eq!(test.grid.get_networks().len(), 2); // tower is on its own until the node is built
+        test.grid
+            .add_structure(&test.the_map, &test.node_id)
+            .unwrap();
+        assert_eq!(test.grid.get_networks().len(), 1);
+        assert!(test
+            .grid
+            .add_structure(&test.the_map, &test.node_id)
+            .is_err());
+        test.grid.remove_structure(&test.node_id).unwrap();
+        assert_eq!(test.grid.get_networks().len(), 2);
+    }
+
+    #[test]
+    fn test_shortfalls_throttle_consumers() {
+        let _world = TestWorld::new();
+        let mut test = build_grid();
+        test.grid
+            .add_structure(&test.the_map, &test.node_id)
+            .unwrap();
+        // 100 produced against 200 demanded, nothing stored, so half speed
+        test.grid.update(1000);
+        assert_eq!(get_satisfaction(&test.grid, &test.tower_id), 500);
+        entity_system::modify(&test.tower_id, |e| {
+            e.weapon.fire_interval_millis = 1000;
+            e.weapon.cooldown_millis = 1000;
+            e.update(400);
+            assert_eq!(e.weapon.cooldown_millis, 800);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_batteries_cover_shortfalls() {
+        let _world = TestWorld::new();
+        let mut test = build_grid();
+        // without the tower on its network, the battery charges up
+        test.grid.update(2000);
+        assert_eq!(test.grid.get_stored(&test.battery_id), 100);
+        assert_eq!(get_satisfaction(&test.grid, &test.tower_id), 0);
+
+        // connected, it covers the shortfall for a second
+        test.grid
+            .add_structure(&test.the_map, &test.node_id)
+            .unwrap();
+        test.grid.update(1000);
+        assert_eq!(
+            get_satisfaction(&test.grid, &test.tower_id),
+            FULL_POWER_PERMILLE
+        );
+        assert_eq!(test.grid.get_stored(&test.battery_id), 0);
+        assert_eq!(
+            entity_system::modify(&test.tower_id, |e| e.power_permille).unwrap(),
+            FULL_POWER_PERMILLE
+        );
+    }
+
+    #[test]
+    fn test_grids_are_saved() {
+        let _world = TestWorld::new();
+        let mut test = build_grid();
+        test.grid.update(500);
+        let bin = serialize_power_for_save(&test.grid).unwrap();
+        assert_eq!(deserialize_power_for_load(&bin).unwrap(), test.grid);
+    }
+}
@@ -0,0 +1,538 @@
+// Authoritative server with client-side prediction, the alternative to lockstep for when clients
+// should not have to run (nor wait on) the whole simulation.  The server runs the Simulation and
+// broadcasts a StateDelta (see sync_system) per tick, along with the last command of each player
+// it has applied.  A client shows its own commands right away by predicting their outcome on top
+// of the last state from the server, and reconciles whenever an update comes in: the update
+// replaces the state, acknowledged commands are dropped and those still in flight are predicted
+// again on top of it.  A misprediction (i.e. the server rejected the placement) simply vanishes
+// with the next update.
+use crate::command_system::PlayerCommands;
+use crate::economy_system::{Economy, TPlayerID};
+use crate::entity_system::{Entity, TEntityID};
+use crate::placement_system;
+use crate::prototype_system;
+use crate::simulation::Simulation;
+use crate::sync_system::{ClientState, StateDelta};
+use crate::tech_system::TechSystem;
+use crate::transport_system::{TSourceID, Transport};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub type TSequence = u32; // per client, starts at 1 so that 0 means nothing was acknowledged yet
+
+// predicted structures get IDs counting down from here, out of the way of the server's
+pub const PREDICTED_ENTITY_ID_BASE: TEntityID = TEntityID::MAX;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum ReconciliationMessages {
+    // client -> server
+    Command {
+        player_id: TPlayerID,
+        sequence: TSequence,
+        command: PlayerCommands,
+    },
+    // server -> clients, once when the match starts; the tech tree is not part of the ClientState
+    // but clients need it to predict what they may build
+    FullState {
+        state: ClientState,
+        tech: TechSystem,
+    },
+    // server -> clients, every tick; acks are the last sequence applied per player, and the tech
+    // tree is only sent when it has changed
+    Update {
+        delta: StateDelta,
+        acks: Vec<(TPlayerID, TSequence)>,
+        tech: Option<TechSystem>,
+    },
+}
+
+/// Server end; call poll() before and broadcast() after advancing the simulation every frame, so
+/// that the acks sent never get ahead of the ticks that applied the commands
+pub struct AuthoritativeServer<T: Transport<ReconciliationMessages>> {
+    transport: T,
+    last_sent: ClientState,
+    last_sent_tech: TechSystem,
+    acks: BTreeMap<TPlayerID, TSequence>,
+    player_ids: Vec<TPlayerID>, // the ones clients may play, sorted
+    // each connection plays the player it first sent a command for, and only that one
+    bound_players: BTreeMap<TSourceID, TPlayerID>,
+    rejected_count: usize,
+}
+
+impl<T: Transport<ReconciliationMessages>> AuthoritativeServer<T> {
+    pub fn new(
+        simulation: &Simulation,
+        mut transport: T,
+        mut player_ids: Vec<TPlayerID>,
+    ) -> Result<AuthoritativeServer<T>, String> {
+        let last_sent = ClientState::from_simulation(simulation);
+        transport.send(&ReconciliationMessages::FullState {
+            state: last_sent.clone(),
+            tech: simulation.tech.clone(),
+        })?;
+        player_ids.sort();
+        player_ids.dedup();
+        return Ok(AuthoritativeServer {
+            transport,
+            last_sent,
+            last_sent_tech: simulation.tech.clone(),
+            acks: BTreeMap::new(),
+            player_ids,
+            bound_players: BTreeMap::new(),
+            rejected_count: 0,
+        });
+    }
+
+    // commands dropped for coming from a connection that does not play that player
+    pub fn get_rejected_count(self: &Self) -> usize {
+        return self.rejected_count;
+    }
+
+    // the player the connection plays, binding it on its first command; None when it claims
+    // someone else's (or a player no client may play)
+    fn bind_player(
+        self: &mut Self,
+        source_id: TSourceID,
+        player_id: TPlayerID,
+    ) -> Option<TPlayerID> {
+        if let Some(bound_player_id) = self.bound_players.get(&source_id) {
+            return match *bound_player_id == player_id {
+                true => Some(player_id),
+                false => None,
+            };
+        }
+        let is_taken = self.bound_players.values().any(|p| *p == player_id);
+        if is_taken || self.player_ids.binary_search(&player_id).is_err() {
+            return None;
+        }
+        self.bound_players.insert(source_id, player_id);
+        return Some(player_id);
+    }
+
+    // queues whatever commands came in for the next tick
+    pub fn poll(self: &mut Self, simulation: &mut Simulation) -> Result<(), String> {
+        while let Some((source_id, message)) = self.transport.receive_from()? {
+            match message {
+                ReconciliationMessages::Command {
+                    player_id,
+                    sequence,
+                    command,
+                } => {
+                    let player_id = match self.bind_player(source_id, player_id) {
+                        Some(p) => p,
+                        None => {
+                            self.rejected_count += 1;
+                            continue;
+                        }
+                    };
+                    let ack = self.acks.entry(player_id).or_insert(0);
+                    if sequence <= *ack {
+                        continue; // duplicate, already applied
+                    }
+                    *ack = sequence;
+                    simulation.queue_command(player_id, command);
+                }
+                _ => return Err("clients only send commands".to_owned()),
+            }
+        }
+        return Ok(());
+    }
+
+    // sends what changed since the last broadcast, if any tick ran since
+    pub fn broadcast(self: &mut Self, simulation: &Simulation) -> Result<(), String> {
+        if simulation.get_tick() == self.last_sent.tick {
+            return Ok(());
+        }
+        let current = ClientState::from_simulation(simulation);
+        let delta = self.last_sent.get_delta_to(&current)?;
+        let tech = match simulation.tech == self.last_sent_tech {
+            true => None,
+            false => Some(simulation.tech.clone()),
+        };
+        self.transport.send(&ReconciliationMessages::Update {
+            delta,
+            acks: self.acks.iter().map(|(p, s)| (*p, *s)).collect(),
+            tech: tech.clone(),
+        })?;
+        self.last_sent = current;
+        if let Some(tech) = tech {
+            self.last_sent_tech = tech;
+        }
+        return Ok(());
+    }
+}
+
+/// Client end, i.e. what a TUI renders from instead of a local Simulation
+pub struct PredictingClient<T: Transport<ReconciliationMessages>> {
+    player_id: TPlayerID,
+    transport: T,
+    authoritative: Option<ClientState>, // None until the server's FullState arrives
+    predicted: Option<ClientState>,
+    tech: TechSystem,                          // the server's latest
+    pending: Vec<(TSequence, PlayerCommands)>, // sent but not acknowledged yet, in order
+    next_sequence: TSequence,
+}
+
+impl<T: Transport<ReconciliationMessages>> PredictingClient<T> {
+    pub fn new(player_id: TPlayerID, transport: T) -> PredictingClient<T> {
+        PredictingClient {
+            player_id,
+            transport,
+            authoritative: None,
+            predicted: None,
+            tech: TechSystem::default(),
+            pending: Vec::new(),
+            next_sequence: 1,
+        }
+    }
+
+    // what to show: the server's latest state plus the predicted outcome of pending commands
+    pub fn get_state(self: &Self) -> Option<&ClientState> {
+        return self.predicted.as_ref();
+    }
+    pub fn get_authoritative_state(self: &Self) -> Option<&ClientState> {
+        return self.authoritative.as_ref();
+    }
+    pub fn get_pending_count(self: &Self) -> usize {
+        return self.pending.len();
+    }
+
+    // sends the command and shows its predicted outcome right away
+    pub fn issue(self: &mut Self, command: PlayerCommands) -> Result<TSequence, String> {
+        let sequence = self.next_sequence;
+        self.transport.send(&ReconciliationMessages::Command {
+            player_id: self.player_id,
+            sequence,
+            command: command.clone(),
+        })?;
+        self.next_sequence += 1;
+        if let Some(state) = self.predicted.as_mut() {
+            // not predictable (i.e. conveyors, which clients do not see) is fine, the server decides
+            let _ = predict(
+                state,
+                &self.tech,
+                self.player_id,
+                self.pending.len(),
+                &command,
+            );
+        }
+        self.pending.push((sequence, command));
+        return Ok(sequence);
+    }
+
+    // applies whatever the server sent, returns true if the state to show has changed
+    pub fn update(self: &mut Self) -> Result<bool, String> {
+        let mut is_changed = false;
+        while let Some(message) = self.transport.receive()? {
+            match message {
+                ReconciliationMessages::FullState { state, tech } => {
+                    self.authoritative = Some(state);
+                    self.tech = tech;
+                }
+                ReconciliationMessages::Update { delta, acks, tech } => {
+                    match self.authoritative.as_mut() {
+                        Some(state) => state.apply(&delta)?,
+                        None => return Err("update received before the full state".to_owned()),
+                    }
+                    if let Some(tech) = tech {
+                        self.tech = tech;
+                    }
+                    if let Some((_, ack)) = acks.iter().find(|(p, _)| *p == self.player_id) {
+                        self.pending.retain(|(sequence, _)| sequence > ack);
+                    }
+                }
+                ReconciliationMessages::Command { .. } => {
+                    return Err("only the server sends to clients".to_owned())
+                }
+            }
+            is_changed = true;
+        }
+        if is_changed {
+            self.reconcile();
+        }
+        return Ok(is_changed);
+    }
+
+    fn reconcile(self: &mut Self) {
+        self.predicted = self.authoritative.clone();
+        if let Some(state) = self.predicted.as_mut() {
+            for (index, (_, command)) in self.pending.iter().enumerate() {
+                let _ = predict(state, &self.tech, self.player_id, index, command);
+            }
+        }
+    }
+}
+
+// the visible outcome of the command, same validation as command_system::apply(); pending_index
+// keeps the IDs of predicted structures apart
+fn predict(
+    state: &mut ClientState,
+    tech: &TechSystem,
+    player_id: TPlayerID,
+    pending_index: usize,
+    command: &PlayerCommands,
+) -> Result<(), String> {
+    match command {
+        PlayerCommands::PlaceStructure {
+            prototype_id,
+            map_x,
+            map_y,
+        } => {
+            tech.is_available_with(player_id, *prototype_id, &|entity_id| {
+                state.get_entity(entity_id).and_then(|e| e.prototype_id)
+            })?;
+            let prototype = match prototype_system::get(prototype_id) {
+                Some(p) => p,
+                None => return Err(format!("prototypeID={} does not exist", prototype_id)),
+            };
+            placement_system::can_place(&state.map, &prototype, *map_x, *map_y)?;
+            let mut economy = Economy::from_ledgers(state.ledgers.clone());
+            economy.spend(player_id, &prototype.build_costs)?;
+
+            let entity_id = PREDICTED_ENTITY_ID_BASE - pending_index as TEntityID;
+            let mut entity = Entity::new(&entity_id, &prototype.sprites, &prototype.layer_weight);
+            prototype.apply_stats(&mut entity);
+            entity.health_points = prototype.max_health_points;
+            entity.map_x = *map_x;
+            entity.map_y = *map_y;
+            entity.owner = player_id;
+            for (cell_x, cell_y) in placement_system::get_footprint(&prototype, *map_x, *map_y) {
+                state.map.place_entity(cell_x, cell_y, entity_id)?;
+            }
+            match state.entities.binary_search_by(|e| e.id.cmp(&entity_id)) {
+                Ok(index) => state.entities[index] = entity,
+                Err(index) => state.entities.insert(index, entity),
+            }
+            state.ledgers = economy.get_ledgers().clone();
+        }
+        PlayerCommands::SellStructure { entity_id } => {
+            let entity = match state.get_entity(entity_id) {
+                Some(e) => e,
+                None => return Err(format!("entityID={} does not exist", entity_id)),
+            };
+            let prototype = match entity
+                .prototype_id
+                .and_then(|id| prototype_system::get(&id))
+            {
+                Some(p) => p,
+                None => return Err(format!("entityID={} is not a structure", entity_id)),
+            };
+            if entity.owner != player_id {
+                return Err(format!(
+                    "entityID={} is not owned by playerID={}",
+                    entity_id, player_id
+                ));
+            }
+            let mut economy = Economy::from_ledgers(state.ledgers.clone());
+            economy.refund(
+                player_id,
+                &prototype.build_costs,
+                prototype.sell_refund_percent,
+            )?;
+            state.map.remove_entity(entity_id);
+            state.entities.retain(|e| e.id != *entity_id);
+            state.ledgers = economy.get_ledgers().clone();
+        }
+        // conveyors and waves are not part of what clients see, and upgrades, production,
+        // research and unit orders only show once the server has carried them out
+        PlayerCommands::PlaceConveyor { .. }
+        | PlayerCommands::RemoveConveyor { .. }
+        | PlayerCommands::CallWaveEarly
+        | PlayerCommands::UpgradeStructure { .. }
+        | PlayerCommands::QueueUnit { .. }
+        | PlayerCommands::CancelUnit { .. }
+        | PlayerCommands::StartResearch { .. }
+        | PlayerCommands::CancelResearch
+        | PlayerCommands::SetRallyPoint { .. }
+        | PlayerCommands::MoveUnits { .. }
+        | PlayerCommands::AttackTarget { .. }
+        | PlayerCommands::Patrol { .. }
+        | PlayerCommands::HoldPosition { .. } => {}
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::{ItemStack, ItemTypes};
+    use crate::prototype_system::TPrototypeID;
+    use crate::tech_system::Research;
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+    use crate::transport_system::{ChannelTransport, LatencyTransport};
+    use std::time::Duration;
+
+    type TTestClient = PredictingClient<
+        LatencyTransport<ReconciliationMessages, ChannelTransport<ReconciliationMessages>>,
+    >;
+
+    fn add_tower_prototype() -> TPrototypeID {
+        return add_prototype("test tower", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+        });
+    }
+
+    fn place(prototype_id: TPrototypeID, map_x: u16) -> PlayerCommands {
+        return PlayerCommands::PlaceStructure {
+            prototype_id,
+            map_x,
+            map_y: 0,
+        };
+    }
+
+    // a server of player 1 (holding 15 gold) and its client, which has the first update already
+    fn connect() -> (
+        Simulation,
+        AuthoritativeServer<ChannelTransport<ReconciliationMessages>>,
+        TTestClient,
+    ) {
+        let mut simulation = new_simulation(8, 8);
+        add_player(&mut simulation, 1, 15);
+        let mut transports = ChannelTransport::create_mesh(2);
+        let latency = Duration::from_millis(40);
+        let mut client =
+            PredictingClient::new(1, LatencyTransport::new(transports.pop().unwrap(), latency));
+        let server =
+            AuthoritativeServer::new(&simulation, transports.pop().unwrap(), vec![1]).unwrap();
+        while client.get_state().is_none() {
+            client.update().unwrap();
+        }
+        return (simulation, server, client);
+    }
+
+    #[test]
+    fn test_commands_are_predicted_right_away() {
+        let _world = TestWorld::new();
+        let tower_id = add_tower_prototype();
+        let (_simulation, _server, mut client) = connect();
+        // shown long before the server even got it
+        client.issue(place(tower_id, 1)).unwrap();
+        let predicted = client.get_state().unwrap();
+        assert_eq!(predicted.entities.len(), 1);
+        assert_eq!(predicted.entities[0].id, PREDICTED_ENTITY_ID_BASE);
+        assert_eq!(predicted.entities[0].owner, 1);
+        assert_eq!(predicted.ledgers[0].get_balance(ItemTypes::Gold), 5);
+        let authoritative = client.get_authoritative_state().unwrap();
+        assert!(authoritative.entities.is_empty());
+    }
+
+    #[test]
+    fn test_failing_commands_are_not_predicted() {
+        let _world = TestWorld::new();
+        let tower_id = add_tower_prototype();
+        let (_simulation, _server, mut client) = connect();
+        client.issue(place(tower_id, 1)).unwrap();
+        client.issue(place(tower_id, 1)).unwrap(); // occupied
+        client.issue(place(tower_id, 4)).unwrap(); // unaffordable after the first one
+        assert_eq!(client.get_pending_count(), 3); // still up to the server
+        let predicted = client.get_state().unwrap();
+        assert_eq!(predicted.entities.len(), 1);
+        assert_eq!(predicted.ledgers[0].get_balance(ItemTypes::Gold), 5);
+    }
+
+    #[test]
+    fn test_predictions_are_replaced_by_the_servers_state() {
+        let _world = TestWorld::new();
+        let tower_id = add_tower_prototype();
+        let (mut simulation, mut server, mut client) = connect();
+        client.issue(place(tower_id, 1)).unwrap();
+        client.issue(place(tower_id, 4)).unwrap();
+
+        // the server catches up, and once its updates arrive only the real tower is left
+        while client.get_pending_count() > 0 {
+            server.poll(&mut simulation).unwrap();
+            simulation.step_once();
+            server.broadcast(&simulation).unwrap();
+            client.update().unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        while client.get_authoritative_state().unwrap().tick < simulation.get_tick() {
+            client.update().unwrap();
+        }
+        let reconciled = client.get_state().unwrap();
+        assert_eq!(reconciled, client.get_authoritative_state().unwrap());
+        assert_eq!(reconciled.entities.len(), 1);
+        assert!(reconciled.entities[0].id < PREDICTED_ENTITY_ID_BASE - 2);
+        assert_eq!(reconciled.ledgers[0].get_balance(ItemTypes::Gold), 5);
+        assert_eq!(reconciled, &ClientState::from_simulation(&simulation));
+    }
+
+    // players 1 and 2 with 30 gold each
+    fn new_client_state() -> ClientState {
+        let mut simulation = new_simulation(8, 8);
+        for player_id in [1, 2] {
+            add_player(&mut simulation, player_id, 30);
+        }
+        return ClientState::from_simulation(&simulation);
+    }
+
+    #[test]
+    fn test_locked_prototypes_are_not_predicted() {
+        let _world = TestWorld::new();
+        let locked_tower_id = add_prototype("test locked tower", |_| {});
+        let research = Research {
+            id: 1,
+            name: "test research".to_owned(),
+            costs: Vec::new(),
+            research_millis: 1000,
+            prerequisites: Vec::new(),
+            unlocks: vec![locked_tower_id],
+            modifiers: Vec::new(),
+        };
+        let tech = TechSystem::new(vec![research], Vec::new()).unwrap();
+        let mut state = new_client_state();
+        let locked = predict(&mut state, &tech, 1, 0, &place(locked_tower_id, 0));
+        assert!(locked.unwrap_err().contains("needs research"));
+        assert!(state.entities.is_empty());
+    }
+
+    #[test]
+    fn test_only_the_owner_sells() {
+        let _world = TestWorld::new();
+        let tower_id = add_tower_prototype();
+        let tech = TechSystem::default();
+        let mut state = new_client_state();
+        predict(&mut state, &tech, 2, 0, &place(tower_id, 0)).unwrap();
+        let their_tower = state.entities[0];
+        assert_eq!(their_tower.owner, 2);
+        let sell = PlayerCommands::SellStructure {
+            entity_id: their_tower.id,
+        };
+        let sold = predict(&mut state, &tech, 1, 1, &sell);
+        assert!(sold.unwrap_err().contains("is not owned by playerID=1"));
+        assert_eq!(state.entities.len(), 1);
+        predict(&mut state, &tech, 2, 1, &sell).unwrap();
+        assert!(state.entities.is_empty());
+    }
+
+    #[test]
+    fn test_connections_only_play_their_own_player() {
+        let mut simulation = new_simulation(4, 4);
+        let mut transports = ChannelTransport::create_mesh(3);
+        let mut mallory = transports.pop().unwrap();
+        let mut alice = transports.pop().unwrap();
+        let mut server =
+            AuthoritativeServer::new(&simulation, transports.pop().unwrap(), vec![1, 2]).unwrap();
+        let command = |player_id, sequence| ReconciliationMessages::Command {
+            player_id,
+            sequence,
+            command: PlayerCommands::CallWaveEarly,
+        };
+        alice.send(&command(1, 1)).unwrap();
+        server.poll(&mut simulation).unwrap();
+        assert_eq!(server.get_rejected_count(), 0);
+
+        mallory.send(&command(1, 2)).unwrap(); // already played by alice
+        mallory.send(&command(3, 1)).unwrap(); // not a player clients may play
+        alice.send(&command(2, 2)).unwrap(); // bound to player 1 already
+        server.poll(&mut simulation).unwrap();
+        assert_eq!(server.get_rejected_count(), 3);
+        assert_eq!(server.acks.get(&1), Some(&1));
+
+        mallory.send(&command(2, 1)).unwrap();
+        server.poll(&mut simulation).unwrap();
+        assert_eq!(server.get_rejected_count(), 3);
+        assert_eq!(server.acks.get(&2), Some(&1));
+    }
+}
@@ -0,0 +1,461 @@
+// Structures that train units over time (i.e. barracks), Starcraft style.  A player queues units
+// at one of their structures, paying the unit's build_costs and reserving its supply up front,
+// and the structure trains them one after another (slower when under-powered).  A trained unit
+// comes out on a free cell next to the footprint, on the side of the structure's rally point,
+// and then heads to the rally point (see unit_system).  Cancelling refunds in full, and so does
+// losing the structure with units still queued
+use crate::economy_system::{Economy, ItemStack, TPlayerID, FULL_REFUND_PERCENT};
+use crate::entity_system::{self, TEntityID};
+use crate::map::{get_distance_squared, Map};
+use crate::placement_system;
+use crate::power_system::FULL_POWER_PERMILLE;
+use crate::prototype_system::{self, TPrototypeID};
+use crate::unit_system::{UnitOrders, UnitSystem};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub const MAX_QUEUE_LENGTH: usize = 5;
+pub const MAX_SUPPLY: u16 = 200; // no matter how many supply structures a player has
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct QueuedUnit {
+    pub prototype_id: TPrototypeID,
+    pub costs: Vec<ItemStack>, // what was charged, in case it gets cancelled
+    pub supply_cost: u16,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Producer {
+    pub entity_id: TEntityID,
+    pub owner: TPlayerID,
+    pub footprint: Vec<(u16, u16)>,
+    pub supply_provided: u16,
+    queue: Vec<QueuedUnit>, // the first one is being trained
+    progress_millis: u128,
+}
+
+impl Producer {
+    pub fn get_queue(self: &Self) -> &Vec<QueuedUnit> {
+        return &self.queue;
+    }
+    pub fn get_progress_millis(self: &Self) -> u128 {
+        return self.progress_millis;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct ProductionSystem {
+    producers: BTreeMap<TEntityID, Producer>,
+}
+
+// the empty cell around the footprint that is nearest to the rally point, or else the first one
+// (row by row)
+fn find_spawn_cell(
+    map: &Map,
+    footprint: &Vec<(u16, u16)>,
+    rally_point: Option<(u16, u16)>,
+) -> Option<(u16, u16)> {
+    let left = footprint.iter().map(|c| c.0).min()? as i32 - 1;
+    let right = footprint.iter().map(|c| c.0).max()? as i32 + 1;
+    let top = footprint.iter().map(|c| c.1).min()? as i32 - 1;
+    let bottom = footprint.iter().map(|c| c.1).max()? as i32 + 1;
+    let mut free_cells = Vec::new();
+    for map_y in top..=bottom {
+        for map_x in left..=right {
+            if map_x < 0 || map_y < 0 || footprint.contains(&(map_x as u16, map_y as u16)) {
+                continue;
+            }
+            let is_empty = match map.get_cell(map_x as u16, map_y as u16) {
+                Ok(cell) => cell.layers.is_empty(),
+                Err(_) => false, // off the map
+            };
+            if is_empty {
+                free_cells.push((map_x as u16, map_y as u16));
+            }
+        }
+    }
+    return match rally_point {
+        Some(point) => free_cells
+            .into_iter()
+            .enumerate()
+            .min_by_key(|(i, c)| (get_distance_squared(*c, point), *i))
+            .map(|(_, c)| c),
+        None => free_cells.first().copied(),
+    };
+}
+
+impl ProductionSystem {
+    pub fn new() -> ProductionSystem {
+        ProductionSystem {
+            producers: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_producer(self: &Self, entity_id: &TEntityID) -> Option<&Producer> {
+        return self.producers.get(entity_id);
+    }
+    pub fn get_producers(self: &Self) -> Vec<&Producer> {
+        return self.producers.values().collect();
+    }
+
+    // the structure must already be placed, and its prototype train units or provide supply
+    pub fn add_structure(
+        self: &mut Self,
+        entity_id: &TEntityID,
+        owner: TPlayerID,
+    ) -> Result<(), String> {
+        let entity = entity_system::modify(entity_id, |e| *e)?;
+        let prototype = match entity
+            .prototype_id
+            .and_then(|id| prototype_system::get(&id))
+        {
+            Some(p) => p,
+            None => return Err(format!("entityID={} has no prototype", entity_id)),
+        };
+        if prototype.trains.is_empty() && prototype.supply_provided == 0 {
+            return Err(format!(
+                "'{}' neither trains units nor provides supply",
+                prototype.name
+            ));
+        }
+        self.producers.insert(
+            *entity_id,
+            Producer {
+                entity_id: *entity_id,
+                owner,
+                footprint: placement_system::get_footprint(&prototype, entity.map_x, entity.map_y),
+                supply_provided: prototype.supply_provided,
+                queue: Vec::new(),
+                progress_millis: 0,
+            },
+        );
+        return Ok(());
+    }
+
+    // returns the owner and everything that was charged for its queue, to be refunded
+    pub fn remove_structure(
+        self: &mut Self,
+        entity_id: &TEntityID,
+    ) -> Option<(TPlayerID, Vec<ItemStack>)> {
+        let producer = self.producers.remove(entity_id)?;
+        let costs = producer
+            .queue
+            .into_iter()
+            .flat_map(|queued| queued.costs.into_iter())
+            .collect();
+        return Some((producer.owner, costs));
+    }
+
+    /// Supply (used, cap) of the player: used counts their units as well as what is queued, the
+    /// cap is what their structures provide (up to MAX_SUPPLY)
+    pub fn get_supply(self: &Self, units: &UnitSystem, player_id: TPlayerID) -> (u16, u16) {
+        let mut used: u32 = 0;
+        let mut cap: u32 = 0;
+        for unit in units.get_units().iter().filter(|u| u.owner == player_id) {
+            let prototype = entity_system::modify(&unit.entity_id, |e| e.prototype_id)
+                .ok()
+                .flatten()
+                .and_then(|id| prototype_system::get(&id));
+            if let Some(p) = prototype {
+                used += p.supply_cost as u32;
+            }
+        }
+        for producer in self.producers.values().filter(|p| p.owner == player_id) {
+            used += producer
+                .queue
+                .iter()
+                .map(|q| q.supply_cost as u32)
+                .sum::<u32>();
+            cap += producer.supply_provided as u32;
+        }
+        return (
+            used.min(u16::MAX as u32) as u16,
+            cap.min(MAX_SUPPLY as u32) as u16,
+        );
+    }
+
+    /// Charges the player and adds the unit to the end of the structure's queue; fails without
+    /// side effects when it is not the player's, cannot train it, is full, or there is not enough
+    /// supply or items
+    pub fn queue(
+        self: &mut Self,
+        economy: &mut Economy,
+        units: &UnitSystem,
+        player_id: TPlayerID,
+        structure_id: &TEntityID,
+        prototype_id: TPrototypeID,
+    ) -> Result<(), String> {
+        let (used, cap) = self.get_supply(units, player_id);
+        let producer = match self.producers.get_mut(structure_id) {
+            Some(p) if p.owner == player_id => p,
+            _ => {
+                return Err(format!(
+                    "entityID={} is not a structure of playerID={}",
+                    structure_id, player_id
+                ))
+            }
+        };
+        let trains = entity_system::modify(structure_id, |e| e.prototype_id)?
+            .and_then(|id| prototype_system::get(&id))
+            .map(|p| p.trains)
+            .unwrap_or_default();
+        let prototype = match prototype_system::get(&prototype_id) {
+            Some(p) if trains.contains(&prototype_id) => p,
+            _ => {
+                return Err(format!(
+                    "entityID={} cannot train prototypeID={}",
+                    structure_id, prototype_id
+                ))
+            }
+        };
+        if prototype.max_velocity == 0 {
+            return Err(format!(
+                "'{}' cannot move, so it is not a unit",
+                prototype.name
+            ));
+        }
+        if producer.queue.len() >= MAX_QUEUE_LENGTH {
+            return Err(format!("entityID={} has a full queue", structure_id));
+        }
+        if prototype.supply_cost > 0 && used as u32 + prototype.supply_cost as u32 > cap as u32 {
+            return Err(format!(
+                "'{}' needs {} supply, {}/{} used",
+                prototype.name, prototype.supply_cost, used, cap
+            ));
+        }
+        economy.spend(player_id, &prototype.build_costs)?;
+        producer.queue.push(QueuedUnit {
+            prototype_id,
+            costs: prototype.build_costs.clone(),
+            supply_cost: prototype.supply_cost,
+        });
+        return Ok(());
+    }
+
+    // takes the unit out of the queue with a full refund; cancelling the one in training loses
+    // its progress
+    pub fn cancel(
+        self: &mut Self,
+        economy: &mut Economy,
+        player_id: TPlayerID,
+        structure_id: &TEntityID,
+        queue_index: usize,
+    ) -> Result<(), String> {
+        let producer = match self.producers.get_mut(structure_id) {
+            Some(p) if p.owner == player_id => p,
+            _ => {
+                return Err(format!(
+                    "entityID={} is not a structure of playerID={}",
+                    structure_id, player_id
+                ))
+            }
+        };
+        if queue_index >= producer.queue.len() {
+            return Err(format!(
+                "entityID={} has nothing queued at {}",
+                structure_id, queue_index
+            ));
+        }
+        economy.refund(
+            player_id,
+            &producer.queue[queue_index].costs,
+            FULL_REFUND_PERCENT,
+        )?;
+        producer.queue.remove(queue_index);
+        if queue_index == 0 {
+            producer.progress_millis = 0;
+        }
+        return Ok(());
+    }
+
+    /// Per structure (in entity order): trains the first unit in its queue, and once done spawns
+    /// it and sends it to the rally point.  A finished unit waits for a free cell around the
+    /// structure.  Returns the units that came out
+    pub fn update(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        map: &mut Map,
+        units: &mut UnitSystem,
+    ) -> Vec<TEntityID> {
+        let mut produced = Vec::new();
+        for producer in self.producers.values_mut() {
+            let queued = match producer.queue.first() {
+                Some(q) => q.clone(),
+                None => continue,
+            };
+            let production_millis = match prototype_system::get(&queued.prototype_id) {
+                Some(p) => p.production_millis,
+                None => continue,
+            };
+            let power_permille = entity_system::modify(&producer.entity_id, |e| e.power_permille)
+                .unwrap_or(FULL_POWER_PERMILLE);
+            producer.progress_millis = (producer.progress_millis
+                + last_frame_delta_millis * power_permille as u128 / FULL_POWER_PERMILLE as u128)
+                .min(production_millis);
+            if producer.progress_millis < production_millis {
+                continue;
+            }
+
+            let rally_point = units.get_rally_point(&producer.entity_id);
+            let (map_x, map_y) = match find_spawn_cell(map, &producer.footprint, rally_point) {
+                Some(c) => c,
+                None => continue, // blocked in
+            };
+            let entity_id = match prototype_system::spawn(&queued.prototype_id, map_x, map_y) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            if map.place_entity(map_x, map_y, entity_id).is_err()
+                || units.add_unit(&entity_id, producer.owner).is_err()
+            {
+                map.remove_entity(&entity_id);
+                let _ = entity_system::remove(&entity_id);
+                continue;
+            }
+            if let Some((rally_x, rally_y)) = rally_point {
+                let order = UnitOrders::Move {
+                    map_x: rally_x,
+                    map_y: rally_y,
+                };
+                let _ = units.issue(map, producer.owner, &vec![entity_id], order);
+            }
+            producer.queue.remove(0);
+            producer.progress_millis = 0;
+            produced.push(entity_id);
+        }
+        return produced;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::economy_system::ItemTypes;
+    use crate::simulation::{Simulation, TICKS_PER_SECOND};
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+
+    // barracks of player 1 (with 100 gold left) for 2 supply of 10 gold marines, placed by command
+    fn build_barracks() -> (Simulation, TEntityID, TPrototypeID) {
+        let marine_id = add_prototype("test marine", |p| {
+            p.max_health_points = 10;
+            p.max_velocity = 30;
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+            p.production_millis = 1000;
+            p.supply_cost = 1;
+        });
+        let barracks_prototype_id = add_prototype("test barracks", |p| {
+            p.footprint_width = 2;
+            p.footprint_height = 2;
+            p.trains = vec![marine_id];
+            p.supply_provided = 2;
+        });
+        let mut simulation = new_simulation(16, 16);
+        add_player(&mut simulation, 1, 100);
+        simulation.queue_command(
+            1,
+            PlayerCommands::PlaceStructure {
+                prototype_id: barracks_prototype_id,
+                map_x: 4,
+                map_y: 4,
+            },
+        );
+        simulation.step_once();
+        let barracks_id = simulation.production.get_producers()[0].entity_id;
+        return (simulation, barracks_id, marine_id);
+    }
+
+    fn queue(barracks_id: TEntityID, marine_id: TPrototypeID) -> PlayerCommands {
+        return PlayerCommands::QueueUnit {
+            entity_id: barracks_id,
+            prototype_id: marine_id,
+        };
+    }
+
+    #[test]
+    fn test_queueing_is_limited_by_supply() {
+        let _world = TestWorld::new();
+        let (mut simulation, barracks_id, marine_id) = build_barracks();
+        for _ in 0..3 {
+            simulation.queue_command(1, queue(barracks_id, marine_id));
+        }
+        let report = simulation.step_once();
+        let results: Vec<bool> = report.commands.iter().map(|(_, r)| r.is_ok()).collect();
+        assert_eq!(results, vec![true, true, false]);
+        assert_eq!(
+            simulation.production.get_supply(&simulation.units, 1),
+            (2, 2)
+        );
+        assert_eq!(simulation.economy.get_balance(1, ItemTypes::Gold), 80);
+    }
+
+    #[test]
+    fn test_only_the_owner_queues() {
+        let _world = TestWorld::new();
+        let (mut simulation, barracks_id, marine_id) = build_barracks();
+        add_player(&mut simulation, 2, 100);
+        simulation.queue_command(2, queue(barracks_id, marine_id));
+        let report = simulation.step_once();
+        assert!(report.commands[0].1.is_err());
+        assert_eq!(simulation.economy.get_balance(2, ItemTypes::Gold), 100);
+        let producer = simulation.production.get_producer(&barracks_id).unwrap();
+        assert!(producer.get_queue().is_empty());
+    }
+
+    #[test]
+    fn test_cancelling_refunds() {
+        let _world = TestWorld::new();
+        let (mut simulation, barracks_id, marine_id) = build_barracks();
+        for _ in 0..2 {
+            simulation.queue_command(1, queue(barracks_id, marine_id));
+        }
+        simulation.step_once();
+        simulation.queue_command(
+            1,
+            PlayerCommands::CancelUnit {
+                entity_id: barracks_id,
+                queue_index: 1,
+            },
+        );
+        simulation.step_once();
+        assert_eq!(simulation.economy.get_balance(1, ItemTypes::Gold), 90);
+        assert_eq!(
+            simulation.production.get_supply(&simulation.units, 1),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn test_trained_units_go_to_the_rally_point() {
+        let _world = TestWorld::new();
+        let (mut simulation, barracks_id, marine_id) = build_barracks();
+        simulation.queue_command(1, queue(barracks_id, marine_id));
+        simulation.queue_command(
+            1,
+            PlayerCommands::SetRallyPoint {
+                entity_id: barracks_id,
+                map_x: 10,
+                map_y: 4,
+            },
+        );
+        let mut produced = Vec::new();
+        for _ in 0..2 * TICKS_PER_SECOND {
+            produced.extend(simulation.step_once().produced);
+        }
+        assert_eq!(produced.len(), 1);
+        assert_eq!(simulation.units.get_unit(&produced[0]).unwrap().owner, 1);
+        let entity = entity_system::try_get(produced[0]).unwrap();
+        assert_eq!((entity.map_x, entity.map_y), (10, 4)); // came out on the right, went on over
+        assert_eq!(
+            simulation.production.get_supply(&simulation.units, 1),
+            (1, 2)
+        );
+        assert!(simulation
+            .production
+            .get_producer(&barracks_id)
+            .unwrap()
+            .get_queue()
+            .is_empty());
+    }
+}
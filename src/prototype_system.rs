@@ -0,0 +1,191 @@
+// Data-driven entity templates (i.e. "Ogre", "Cannon Tower Lv1"), which waves, placement and
+// production spawn entities from.  IDs are defined by the data (not auto-assigned) so
+// that wave/scenario files can reference them
+use crate::behaviour_system::TBehaviourID;
+use crate::crafting_system::TRecipeID;
+use crate::damage_system::{DefenseInfo, WeaponInfo};
+use crate::economy_system::ItemStack;
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::power_system::PowerRoles;
+use crate::sprite_system::TSpriteSubGroupID;
+use crate::status_effect_system::Aura;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+// Note: No need to drop/deconstruct/destroy once it's created
+static PROTOTYPE_SINGLETON: Lazy<Mutex<PrototypeFactory>> =
+    Lazy::new(|| Mutex::new(PrototypeFactory::new()));
+struct PrototypeFactory {
+    prototypes: Vec<EntityPrototype>, // sorted by id
+}
+
+impl PrototypeFactory {
+    fn new() -> PrototypeFactory {
+        PrototypeFactory {
+            prototypes: Vec::new(),
+        }
+    }
+}
+
+pub type TPrototypeID = u16;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct EntityPrototype {
+    pub id: TPrototypeID,
+    pub name: String,
+    pub sprites: TSpriteSubGroupID,
+    pub layer_weight: u8,
+    pub max_health_points: u16,
+    pub defense: DefenseInfo,
+    pub weapon: WeaponInfo,
+    pub max_velocity: u8,    // grids per second, 0 for structures
+    pub footprint_width: u8, // number of cells it occupies when placed, upper-left being its map_x/map_y
+    pub footprint_height: u8,
+    pub build_costs: Vec<ItemStack>,
+    pub sell_refund_percent: u8,
+    pub bounty: Vec<ItemStack>,       // awarded to whoever kills it
+    pub mining_millis_per_item: u128, // drills only (0 otherwise), time to mine 1 item per ore cell under its footprint
+    pub power: Option<PowerRoles>,    // None for those that are not part of power networks
+    pub crafting_recipe_id: Option<TRecipeID>, // factories only, see crafting_system
+    // production (see production_system), last so that data from before it still loads
+    #[serde(default)]
+    pub trains: Vec<TPrototypeID>, // unit-producing structures only, which units can be queued
+    #[serde(default)]
+    pub production_millis: u128, // units only, time to train one at full power
+    #[serde(default)]
+    pub supply_cost: u16, // units only
+    #[serde(default)]
+    pub supply_provided: u16, // structures only, raises the owner's supply cap
+    #[serde(default)]
+    pub behaviour_id: Option<TBehaviourID>, // computer-controlled only (i.e. creeps), see behaviour_system
+    #[serde(default)]
+    pub aura: Option<Aura>, // applied around it every tick while alive
+}
+impl EntityPrototype {
+    pub fn new(id: TPrototypeID, name: &str, sprites: TSpriteSubGroupID) -> EntityPrototype {
+        EntityPrototype {
+            id,
+            name: name.to_owned(),
+            sprites,
+            layer_weight: 0x80,
+            max_health_points: 0,
+            defense: DefenseInfo::new(),
+            weapon: WeaponInfo::new(),
+            max_velocity: 0,
+            footprint_width: 1,
+            footprint_height: 1,
+            build_costs: Vec::new(),
+            sell_refund_percent: 50,
+            bounty: Vec::new(),
+            mining_millis_per_item: 0,
+            power: None,
+            crafting_recipe_id: None,
+            trains: Vec::new(),
+            production_millis: 0,
+            supply_cost: 0,
+            supply_provided: 0,
+            behaviour_id: None,
+            aura: None,
+        }
+    }
+
+    // swaps the stats and sprites of the entity to this prototype's, but leaves the current
+    // health_points, position and status effects alone (see upgrade_system)
+    pub fn apply_stats(self: &Self, entity: &mut Entity) {
+        entity.prototype_id = Some(self.id);
+        if entity.sprites != self.sprites {
+            entity.sprites = self.sprites;
+            entity.current_sprite_index = 0;
+        }
+        entity.layer_weight = self.layer_weight;
+        entity.max_health_points = self.max_health_points;
+        entity.health_points = entity.health_points.min(self.max_health_points);
+        entity.defense = self.defense;
+        entity.weapon = self.weapon;
+        entity.physics_info.max_velocity = self.max_velocity;
+    }
+}
+
+// adds, or replaces the prototype with the same ID (i.e. when data gets reloaded)
+pub fn add(prototype: EntityPrototype) {
+    let mut singleton = PROTOTYPE_SINGLETON.lock().unwrap();
+    match singleton
+        .prototypes
+        .binary_search_by(|p| p.id.cmp(&prototype.id))
+    {
+        Ok(index) => singleton.prototypes[index] = prototype,
+        Err(index) => singleton.prototypes.insert(index, prototype),
+    }
+}
+
+// unlike other systems, this is a blocking get(), for the registry is read-mostly data whose lock
+// is never held while calling into other systems, hence cannot take part in a deadlock
+pub fn get(prototype_id: &TPrototypeID) -> Option<EntityPrototype> {
+    let singleton = PROTOTYPE_SINGLETON.lock().unwrap();
+    return match singleton
+        .prototypes
+        .binary_search_by(|p| p.id.cmp(prototype_id))
+    {
+        Ok(index) => Some(singleton.prototypes[index].clone()),
+        Err(_) => None,
+    };
+}
+
+pub fn get_all() -> Vec<EntityPrototype> {
+    let singleton = PROTOTYPE_SINGLETON.lock().unwrap();
+    return singleton.prototypes.clone();
+}
+
+// adds a new entity (to entity_system) based on the prototype, note that it is up to the caller
+// to place it on the Map
+pub fn spawn(prototype_id: &TPrototypeID, map_x: u16, map_y: u16) -> Result<TEntityID, String> {
+    let prototype = match get(prototype_id) {
+        Some(p) => p,
+        None => return Err(format!("prototypeID={} does not exist", prototype_id)),
+    };
+    let entity_id = entity_system::add(&prototype.sprites, prototype.layer_weight)?;
+    entity_system::modify(&entity_id, |entity| {
+        prototype.apply_stats(entity);
+        entity.set_max_health_points(prototype.max_health_points);
+        entity.map_x = map_x;
+        entity.map_y = map_y;
+    })?;
+    return Ok(entity_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{new_prototype_id, TestWorld};
+
+    #[test]
+    fn test_add_replaces() {
+        let mut prototype = EntityPrototype::new(new_prototype_id(), "test ogre", 3);
+        prototype.max_health_points = 50;
+        add(prototype.clone());
+        prototype.max_health_points = 60;
+        add(prototype.clone());
+        assert_eq!(get(&prototype.id).unwrap().max_health_points, 60);
+    }
+
+    #[test]
+    fn test_spawn() {
+        let _world = TestWorld::new();
+        let mut prototype = EntityPrototype::new(new_prototype_id(), "test ogre", 3);
+        prototype.max_health_points = 60;
+        add(prototype.clone());
+        let entity_id = spawn(&prototype.id, 4, 5).unwrap();
+        let entity = entity_system::modify(&entity_id, |e| *e).unwrap();
+        assert_eq!(entity.prototype_id, Some(prototype.id));
+        assert_eq!((entity.map_x, entity.map_y), (4, 5));
+        assert_eq!(entity.health_points, 60);
+    }
+
+    #[test]
+    fn test_spawn_unknown_prototype() {
+        let _world = TestWorld::new();
+        assert!(spawn(&new_prototype_id(), 0, 0).is_err());
+        assert!(entity_system::snapshot().is_empty());
+    }
+}
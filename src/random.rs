@@ -0,0 +1,132 @@
+// Seedable, serializable random numbers owned by the simulation (rather than thread_rng()), so
+// that a saved seed reproduces the match exactly.  Each subsystem draws from its own stream, so
+// that i.e. adding a map generation step does not shift what the AI rolls afterwards
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+pub const RNG_STREAM_TYPES_COUNT: usize = 3; // make sure to update this if RngStreamTypes changes
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum RngStreamTypes {
+    Ai,
+    Spawning,
+    MapGeneration,
+}
+
+/// SplitMix64; tiny (a single u64 of state), fast, and good enough for gameplay (NOT for crypto)
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(self: &mut Self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+    pub fn next_u32(self: &mut Self) -> u32 {
+        return (self.next_u64() >> 32) as u32;
+    }
+
+    // uniform within [low, high), without modulo bias; returns low if the range is empty
+    pub fn gen_range(self: &mut Self, low: u32, high: u32) -> u32 {
+        if high <= low {
+            return low;
+        }
+        let span = (high - low) as u64;
+        let zone = u64::MAX - (u64::MAX % span); // reject the uneven tail
+        loop {
+            let value = self.next_u64();
+            if value < zone {
+                return low + (value % span) as u32;
+            }
+        }
+    }
+    // i.e. gen_permille(250) is true a quarter of the time
+    pub fn gen_permille(self: &mut Self, permille: u16) -> bool {
+        return self.gen_range(0, 1000) < permille as u32;
+    }
+    pub fn choose<'a, T>(self: &mut Self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            return None;
+        }
+        return items.get(self.gen_range(0, items.len() as u32) as usize);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct RandomStreams {
+    seed: u64,
+    streams: [Rng; RNG_STREAM_TYPES_COUNT],
+}
+
+impl RandomStreams {
+    pub fn new(seed: u64) -> RandomStreams {
+        // each stream is seeded off of its own draw from a generator of the match seed, so that
+        // they do not overlap even though they share the same seed
+        let mut seeder = Rng::new(seed);
+        let mut streams = [Rng::new(0); RNG_STREAM_TYPES_COUNT];
+        for stream in streams.iter_mut() {
+            *stream = Rng::new(seeder.next_u64());
+        }
+        RandomStreams { seed, streams }
+    }
+    pub fn get_seed(self: &Self) -> u64 {
+        return self.seed;
+    }
+    pub fn stream(self: &mut Self, stream_type: RngStreamTypes) -> &mut Rng {
+        return &mut self.streams[stream_type as usize];
+    }
+}
+
+// for specs resources, which need a Default; seed 0 is as reproducible as any other
+impl Default for RandomStreams {
+    fn default() -> Self {
+        return RandomStreams::new(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_streams() {
+        let mut streams = RandomStreams::new(1234);
+        let mut same_seed = RandomStreams::new(1234);
+        let rolls: Vec<u32> = (0..100)
+            .map(|_| streams.stream(RngStreamTypes::Ai).gen_range(10, 20))
+            .collect();
+        assert!(rolls.iter().all(|r| (10..20).contains(r)));
+        assert!(rolls.iter().any(|r| *r != rolls[0]));
+
+        // drawing from another stream does not disturb the AI's
+        same_seed.stream(RngStreamTypes::MapGeneration).next_u64();
+        let same_rolls: Vec<u32> = (0..100)
+            .map(|_| same_seed.stream(RngStreamTypes::Ai).gen_range(10, 20))
+            .collect();
+        assert_eq!(rolls, same_rolls);
+        assert_ne!(
+            RandomStreams::new(1235)
+                .stream(RngStreamTypes::Ai)
+                .next_u64(),
+            RandomStreams::new(1234)
+                .stream(RngStreamTypes::Ai)
+                .next_u64()
+        );
+
+        let bin = rmp_serde::to_vec(&streams).unwrap();
+        let mut restored: RandomStreams = rmp_serde::from_slice(&bin).unwrap();
+        assert_eq!(
+            restored.stream(RngStreamTypes::Spawning).next_u64(),
+            streams.stream(RngStreamTypes::Spawning).next_u64()
+        );
+    }
+}
@@ -0,0 +1,315 @@
+// Recording and playback of matches.  Since the simulation is deterministic, a replay is only the
+// starting state plus every command with the tick it was applied at; playback feeds them back
+// into a fresh Simulation.  Seeking re-simulates from the closest snapshot, which the player
+// takes every SNAPSHOT_INTERVAL_TICKS as it goes.  The recording host can also store a StateHash
+// every HASH_INTERVAL_TICKS, which playback checks against to catch a replay that no longer plays
+// out the same (i.e. the simulation changed without REPLAY_VERSION being bumped)
+use crate::command_system::IssuedCommand;
+use crate::simulation::{Simulation, SimulationSnapshot, TTick, TickReport, TICKS_PER_SECOND};
+use crate::state_hash_system::StateHash;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+// bump whenever the simulation changes in ways that break old replays; 2 since computer players
+// are played again on playback rather than having their commands recorded
+pub const REPLAY_VERSION: u16 = 2;
+pub const SNAPSHOT_INTERVAL_TICKS: TTick = 10 * TICKS_PER_SECOND;
+pub const HASH_INTERVAL_TICKS: TTick = TICKS_PER_SECOND;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u16,
+    pub initial: SimulationSnapshot,
+    pub commands: Vec<IssuedCommand>, // sorted by tick, then in the order they were applied
+    pub end_tick: TTick,              // the tick after the last one recorded
+    #[serde(default)] // replays recorded without hashes simply are not checked
+    pub hashes: Vec<StateHash>, // sorted by tick
+}
+
+impl Replay {
+    // starts recording from the current state of the simulation
+    pub fn new(simulation: &Simulation) -> Replay {
+        Replay {
+            version: REPLAY_VERSION,
+            initial: simulation.snapshot(),
+            commands: Vec::new(),
+            end_tick: simulation.get_tick(),
+            hashes: Vec::new(),
+        }
+    }
+
+    // call with every report, in order; rejected commands are kept as well, since they are
+    // rejected the same way on playback
+    pub fn record(self: &mut Self, report: &TickReport) {
+        for (issued, _result) in report.commands.iter() {
+            self.commands.push(issued.clone());
+        }
+        self.end_tick = report.tick + 1;
+    }
+
+    // call once the ticks are run (i.e. after record()), only keeps those on HASH_INTERVAL_TICKS
+    pub fn record_hash(self: &mut Self, simulation: &Simulation) {
+        let tick = simulation.get_tick();
+        let last_tick = self.hashes.last().map(|h| h.tick);
+        if tick % HASH_INTERVAL_TICKS == 0 && last_tick.map_or(true, |t| t < tick) {
+            self.hashes.push(StateHash::from_simulation(simulation));
+        }
+    }
+
+    pub fn get_start_tick(self: &Self) -> TTick {
+        return self.initial.simulation.get_tick();
+    }
+}
+
+pub struct ReplayPlayer {
+    replay: Replay,
+    simulation: Simulation,
+    next_command_index: usize,
+    snapshots: Vec<(usize, SimulationSnapshot)>, // with the next_command_index at the time, sorted by tick
+    desync: Option<(StateHash, StateHash)>, // the first recorded hash that did not match, with the actual one
+}
+
+impl ReplayPlayer {
+    // NOTE: replaces every entity in entity_system with those of the replay
+    pub fn new(replay: Replay) -> Result<ReplayPlayer, String> {
+        if replay.version != REPLAY_VERSION {
+            return Err(format!(
+                "replay version {} cannot be played back by version {}",
+                replay.version, REPLAY_VERSION
+            ));
+        }
+        let simulation = Simulation::restore(&replay.initial);
+        let snapshots = vec![(0, replay.initial.clone())];
+        Ok(ReplayPlayer {
+            replay,
+            simulation,
+            next_command_index: 0,
+            snapshots,
+            desync: None,
+        })
+    }
+
+    pub fn get_simulation(self: &Self) -> &Simulation {
+        return &self.simulation;
+    }
+    pub fn get_replay(self: &Self) -> &Replay {
+        return &self.replay;
+    }
+    // (recorded, played back) hashes of the first tick that played out differently, if any
+    pub fn get_desync(self: &Self) -> Option<(StateHash, StateHash)> {
+        return self.desync;
+    }
+    pub fn is_finished(self: &Self) -> bool {
+        return self.simulation.get_tick() >= self.replay.end_tick;
+    }
+
+    // plays back a single tick, None once the end of the replay is reached
+    pub fn step(self: &mut Self) -> Option<TickReport> {
+        if self.is_finished() {
+            return None;
+        }
+        let tick = self.simulation.get_tick();
+        while let Some(issued) = self.replay.commands.get(self.next_command_index) {
+            if issued.tick > tick {
+                break;
+            }
+            self.simulation
+                .queue_command(issued.player_id, issued.command.clone());
+            self.next_command_index += 1;
+        }
+        let report = self.simulation.step_once();
+
+        let next_tick = self.simulation.get_tick();
+        if self.desync.is_none() {
+            if let Ok(index) = self
+                .replay
+                .hashes
+                .binary_search_by(|h| h.tick.cmp(&next_tick))
+            {
+                let actual = StateHash::from_simulation(&self.simulation);
+                if actual != self.replay.hashes[index] {
+                    self.desync = Some((self.replay.hashes[index], actual));
+                }
+            }
+        }
+        let last_snapshot_tick = self.snapshots.last().unwrap().1.simulation.get_tick(); // never empty
+        if next_tick % SNAPSHOT_INTERVAL_TICKS == 0 && next_tick > last_snapshot_tick {
+            self.snapshots
+                .push((self.next_command_index, self.simulation.snapshot()));
+        }
+        return Some(report);
+    }
+
+    /// Jumps to the start of the given tick (capped to the end of the replay), by going back to
+    /// the closest snapshot at or before it and re-simulating from there
+    pub fn seek(self: &mut Self, tick: TTick) {
+        let target_tick = tick.min(self.replay.end_tick);
+        if target_tick < self.simulation.get_tick() {
+            let (command_index, snapshot) = self
+                .snapshots
+                .iter()
+                .rev()
+                .find(|(_, snapshot)| snapshot.simulation.get_tick() <= target_tick)
+                .unwrap_or(&self.snapshots[0]); // the initial one, in case the target is before the start
+            self.simulation = Simulation::restore(snapshot);
+            self.next_command_index = *command_index;
+        }
+        while self.simulation.get_tick() < target_tick {
+            self.step();
+        }
+    }
+}
+
+// NOTE: same as Map, no I/O here; use resource_system (i.e. Resource::write_data()) to persist it
+pub fn serialize_replay_for_save(replay: &Replay) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match replay.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_replay_for_load(bin_data: &Vec<u8>) -> Result<Replay, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    return rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::conveyor_system::ConveyorTypes;
+    use crate::crafting_system::CraftingSystem;
+    use crate::economy_system::{ItemStack, ItemTypes};
+    use crate::entity_system::{self, Entity};
+    use crate::map::{Directions, Map};
+    use crate::test_helpers::{add_player, add_prototype, TestWorld};
+    use crate::wave_system::{SpawnGroup, WaveDefinition, WaveScheduler};
+
+    type TState = (Simulation, Vec<Entity>);
+
+    fn get_state(simulation: &Simulation) -> TState {
+        return (simulation.clone(), entity_system::snapshot());
+    }
+
+    struct Recording {
+        replay: Replay,
+        recorded: TState,
+        halfway: TState, // as of SNAPSHOT_INTERVAL_TICKS + 20, in between two snapshots
+    }
+
+    // towers, a conveyor and a wave called early, by player 1
+    fn record_match() -> Recording {
+        let tower_id = add_prototype("test tower", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+            p.sell_refund_percent = 50;
+        });
+        let creep_id = add_prototype("test creep", |p| p.max_health_points = 10);
+        let waves = WaveScheduler::new(vec![WaveDefinition {
+            delay_millis: 20_000,
+            early_call_bonus_per_second: 1,
+            groups: vec![SpawnGroup {
+                prototype_id: creep_id,
+                count: 4,
+                spacing_millis: 400,
+                delay_millis: 0,
+                spawn_x: 4,
+                spawn_y: 4,
+                spawn_radius: 2,
+            }],
+        }])
+        .unwrap();
+        let mut simulation = Simulation::new(
+            Map::create(8, 8).unwrap(),
+            waves,
+            CraftingSystem::new(Vec::new()).unwrap(),
+            99,
+        );
+        add_player(&mut simulation, 1, 25);
+        let mut replay = Replay::new(&simulation);
+        let place = |x| PlayerCommands::PlaceStructure {
+            prototype_id: tower_id,
+            map_x: x,
+            map_y: 0,
+        };
+        let mut halfway = None;
+        for tick in 0..(2 * SNAPSHOT_INTERVAL_TICKS + 45) {
+            match tick {
+                5 => simulation.queue_command(1, place(0)),
+                6 => {
+                    simulation.queue_command(1, place(0)); // occupied, rejected
+                    simulation.queue_command(1, place(1));
+                }
+                40 => simulation.queue_command(
+                    1,
+                    PlayerCommands::PlaceConveyor {
+                        map_x: 3,
+                        map_y: 3,
+                        block: ConveyorTypes::Belt {
+                            direction: Directions::East,
+                        },
+                    },
+                ),
+                350 => simulation.queue_command(1, PlayerCommands::CallWaveEarly),
+                _ => (),
+            }
+            if tick == SNAPSHOT_INTERVAL_TICKS + 20 {
+                halfway = Some(get_state(&simulation));
+            }
+            let report = simulation.step_once();
+            replay.record(&report);
+            replay.record_hash(&simulation);
+        }
+        // two towers, plus 8 (whole) seconds skipped by calling the wave early
+        assert_eq!(
+            simulation.economy.get_balance(1, ItemTypes::Gold),
+            25 - 20 + 8
+        );
+        assert!(simulation.waves.get_alive_entities().len() > 0);
+        return Recording {
+            replay,
+            recorded: get_state(&simulation),
+            halfway: halfway.unwrap(),
+        };
+    }
+
+    #[test]
+    fn test_record_and_play_back() {
+        let _world = TestWorld::new();
+        let recording = record_match();
+        let bin = serialize_replay_for_save(&recording.replay).unwrap();
+        let loaded = deserialize_replay_for_load(&bin).unwrap();
+        assert_eq!(loaded, recording.replay);
+        let mut player = ReplayPlayer::new(loaded).unwrap();
+        while player.step().is_some() {}
+        assert!(player.is_finished());
+        assert_eq!(player.get_desync(), None);
+        assert_eq!(get_state(player.get_simulation()), recording.recorded);
+    }
+
+    #[test]
+    fn test_seek_both_ways() {
+        let _world = TestWorld::new();
+        let recording = record_match();
+        let mut player = ReplayPlayer::new(recording.replay.clone()).unwrap();
+        player.seek(recording.replay.end_tick);
+        // backwards onto a snapshot and re-simulated forward from there
+        player.seek(SNAPSHOT_INTERVAL_TICKS + 20);
+        assert_eq!(get_state(player.get_simulation()), recording.halfway);
+        player.seek(recording.replay.end_tick);
+        assert_eq!(get_state(player.get_simulation()), recording.recorded);
+    }
+
+    #[test]
+    fn test_desync_is_caught_at_the_first_differing_hash() {
+        let _world = TestWorld::new();
+        let recording = record_match();
+        let mut tampered = recording.replay.clone();
+        tampered.hashes[1].economy ^= 1;
+        let mut player = ReplayPlayer::new(tampered).unwrap();
+        player.seek(recording.replay.end_tick);
+        assert_eq!(player.get_desync().unwrap().0.tick, 2 * HASH_INTERVAL_TICKS);
+    }
+}
@@ -142,6 +142,16 @@ impl Resource {
         }
     }
 
+    // the factory keeps every resource (and its buffer) until it is released, so one-off
+    // loads should release theirs once read; returns None if it was not (or no longer) there
+    pub fn release(res_id: TResourceID) -> Option<Resource> {
+        let mut singleton = RESOURCE_SINGLETON.lock().unwrap();
+        return match singleton.resources.binary_search_by(|f| f.id.cmp(&res_id)) {
+            Ok(index) => Some(singleton.resources.remove(index)),
+            Err(_) => None,
+        };
+    }
+
     pub fn write_data<TF>(
         self: &mut Self,
         func_serialize_for_save: TF,
@@ -152,9 +162,7 @@ impl Resource {
         match func_serialize_for_save() {
             Ok(serialized_buffer) => {
                 //println!("Serialized {} bytes, begin writing...", result_of_T.len());
-                let file = File::create(self.paths.to_owned())?;
-                let mut writer = BufWriter::new(file);
-                match writer.write_all(&serialized_buffer) {
+                match write_file_replacing(&self.paths, &serialized_buffer) {
                     Ok(()) => {
                         self.buffer = serialized_buffer.clone(); // update last read buffer with newly (and successfully) written buffer
                         let ret_result: Result<Vec<u8>, Box<dyn std::error::Error>> =
@@ -217,3 +225,77 @@ impl Resource {
     //    println!("7 - (-3) = {}", apply_function(7, -3, diff));
     //}
 }
+
+// write to a temp file next to it, then rename over the original, so that a crash
+// (or full disk) mid-write leaves the previous file intact rather than half-written
+fn write_file_replacing(file_paths: &str, buffer: &[u8]) -> std::io::Result<()> {
+    let temp_paths = format!("{}.tmp", file_paths);
+    let file = File::create(&temp_paths)?;
+    let mut writer = BufWriter::new(file);
+    let written = writer
+        .write_all(buffer)
+        .and_then(|_| writer.flush())
+        .and_then(|_| writer.get_ref().sync_all())
+        .and_then(|_| fs::rename(&temp_paths, file_paths));
+    if written.is_err() {
+        let _ = fs::remove_file(temp_paths);
+    }
+    return written;
+}
+
+// loads the whole file and deserializes it in one go, errors are prefixed with the file_paths
+pub fn read_resource<T>(
+    file_paths: &String,
+    func_deserialize_for_load: fn(&Vec<u8>) -> Result<T, String>,
+) -> Result<T, String> {
+    let res_id =
+        Resource::new(file_paths.clone(), false).map_err(|e| format!("{}: {}", file_paths, e))?;
+    return match Resource::release(res_id) {
+        Some(resource) => resource.read_data(func_deserialize_for_load),
+        None => Err(format!("{}: resource is gone", file_paths)),
+    };
+}
+
+// the counterpart of read_resource(); unlike create() followed by write_data(), the file is never
+// truncated up front, so that a failed serialization (or write) keeps the previous file as it was
+pub fn write_resource(
+    file_paths: &String,
+    serialized: Result<Vec<u8>, String>,
+) -> Result<(), String> {
+    let buffer = serialized.map_err(|e| format!("{}: {}", file_paths, e))?;
+    return write_file_replacing(file_paths, &buffer).map_err(|e| format!("{}: {}", file_paths, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_file_paths(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("resource_system_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        return dir.join(name).to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn test_write_then_read_resource() {
+        let file_paths = get_test_file_paths("round_trip.bin");
+        write_resource(&file_paths, Ok(vec![1, 2, 3])).unwrap();
+        let read = read_resource(&file_paths, |buffer| Ok(buffer.clone())).unwrap();
+        assert_eq!(read, vec![1, 2, 3]);
+        let singleton = RESOURCE_SINGLETON.lock().unwrap();
+        assert!(singleton.resources.iter().all(|r| r.paths != file_paths)); // released
+        drop(singleton);
+        assert!(fs::metadata(format!("{}.tmp", file_paths)).is_err()); // renamed away
+        fs::remove_file(file_paths).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_keeps_the_previous_file() {
+        let file_paths = get_test_file_paths("kept.bin");
+        write_resource(&file_paths, Ok(vec![4, 5, 6])).unwrap();
+        let result = write_resource(&file_paths, Err("cannot serialize".to_owned()));
+        assert!(result.unwrap_err().ends_with("cannot serialize"));
+        assert_eq!(fs::read(&file_paths).unwrap(), vec![4, 5, 6]);
+        fs::remove_file(file_paths).unwrap();
+    }
+}
@@ -0,0 +1,1021 @@
+// Cap'n Proto RPC front of a Simulation, so that clients (TUI, SDL, Bevy) can run in their own
+// process and talk to it over TCP or a Unix socket.  A client joins as a player, issues
+// PlayerCommands through its GameSession, and subscribes to get the full state once followed by a
+// StateDelta (see sync_system) every tick.  Only built with the "rpc" feature.
+//
+// NOTE: capnp-rpc is single threaded (!Send), so serve() and connect() must be run within a
+// tokio::task::LocalSet, i.e.:
+//
+//   tokio::task::LocalSet::new().block_on(&runtime, rpc_system::serve(simulation, address))
+use crate::command_system::PlayerCommands;
+use crate::conveyor_system::ConveyorTypes;
+use crate::economy_system::{ItemStack, ItemTypes, PlayerLedger, TPlayerID, ITEM_TYPES_COUNT};
+use crate::entity_system::{Entity, TEntityID};
+use crate::map::{CellLayer, Directions, Map, MapCell, OreDeposit};
+use crate::simulation::Simulation;
+use crate::sync_system::{CellChange, ClientState, StateDelta};
+use crate::tower_defense_capnp::{
+    conveyor_block, entity, game_server, game_session, game_state, item_stack, map_cell,
+    player_command, player_ledger, state_delta, state_subscriber, subscription, Direction,
+    ItemType,
+};
+use capnp::capability::Promise;
+use capnp::primitive_list;
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use futures::AsyncReadExt;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Instant;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+pub type TSubscriberID = u64;
+pub type TConnectionID = u64;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ListenAddresses {
+    Tcp(String), // i.e. "127.0.0.1:7777"
+    #[cfg(unix)]
+    Unix(String), // socket file path
+}
+
+impl ListenAddresses {
+    // "tcp:<host:port>" or "unix:<path>"
+    pub fn parse(address: &str) -> Result<ListenAddresses, String> {
+        if let Some(host_port) = address.strip_prefix("tcp:") {
+            return Ok(ListenAddresses::Tcp(host_port.to_owned()));
+        }
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Ok(ListenAddresses::Unix(path.to_owned()));
+        }
+        return Err(format!(
+            "'{}' should be either tcp:<host:port> or unix:<path>",
+            address
+        ));
+    }
+}
+
+fn to_item_type(item: ItemTypes) -> ItemType {
+    return match item {
+        ItemTypes::Gold => ItemType::Gold,
+        ItemTypes::Copper => ItemType::Copper,
+        ItemTypes::Lead => ItemType::Lead,
+        ItemTypes::Coal => ItemType::Coal,
+        ItemTypes::Sand => ItemType::Sand,
+        ItemTypes::Titanium => ItemType::Titanium,
+        ItemTypes::Graphite => ItemType::Graphite,
+        ItemTypes::Silicon => ItemType::Silicon,
+    };
+}
+fn from_item_type(item: ItemType) -> ItemTypes {
+    return match item {
+        ItemType::Gold => ItemTypes::Gold,
+        ItemType::Copper => ItemTypes::Copper,
+        ItemType::Lead => ItemTypes::Lead,
+        ItemType::Coal => ItemTypes::Coal,
+        ItemType::Sand => ItemTypes::Sand,
+        ItemType::Titanium => ItemTypes::Titanium,
+        ItemType::Graphite => ItemTypes::Graphite,
+        ItemType::Silicon => ItemTypes::Silicon,
+    };
+}
+fn to_direction(direction: Directions) -> Direction {
+    return match direction {
+        Directions::North => Direction::North,
+        Directions::East => Direction::East,
+        Directions::South => Direction::South,
+        Directions::West => Direction::West,
+    };
+}
+fn from_direction(direction: Direction) -> Directions {
+    return match direction {
+        Direction::North => Directions::North,
+        Direction::East => Directions::East,
+        Direction::South => Directions::South,
+        Direction::West => Directions::West,
+    };
+}
+
+fn write_item_stacks(
+    mut builder: capnp::struct_list::Builder<item_stack::Owned>,
+    stacks: &[ItemStack],
+) {
+    for (i, stack) in stacks.iter().enumerate() {
+        let mut stack_builder = builder.reborrow().get(i as u32);
+        stack_builder.set_item(to_item_type(stack.item));
+        stack_builder.set_amount(stack.amount);
+    }
+}
+// indexed by ItemTypes, as PlayerLedger keeps them
+fn read_item_totals(
+    reader: capnp::struct_list::Reader<item_stack::Owned>,
+) -> capnp::Result<[u32; ITEM_TYPES_COUNT]> {
+    let mut totals = [0; ITEM_TYPES_COUNT];
+    for stack in reader.iter() {
+        let i = from_item_type(stack.get_item()?).index();
+        totals[i] = totals[i].saturating_add(stack.get_amount());
+    }
+    return Ok(totals);
+}
+
+fn write_map_cell(mut builder: map_cell::Builder, map_x: u16, map_y: u16, cell: &MapCell) {
+    builder.set_map_x(map_x);
+    builder.set_map_y(map_y);
+    {
+        let mut layers = builder.reborrow().init_layers(cell.layers.len() as u32);
+        for (i, layer) in cell.layers.iter().enumerate() {
+            let mut layer_builder = layers.reborrow().get(i as u32);
+            layer_builder.set_id(layer.id);
+            layer_builder.set_entity(layer.entity);
+        }
+    }
+    match cell.deposit {
+        None => builder.init_deposit().set_none(()),
+        Some(deposit) => {
+            let mut ore = builder.init_deposit().init_ore();
+            ore.set_item(to_item_type(deposit.item));
+            ore.set_remaining(deposit.remaining.unwrap_or(0));
+            ore.set_is_infinite(deposit.remaining.is_none());
+        }
+    }
+}
+fn read_map_cell(reader: map_cell::Reader) -> capnp::Result<CellChange> {
+    let layers = reader
+        .get_layers()?
+        .iter()
+        .map(|layer| CellLayer::new(layer.get_id(), layer.get_entity()))
+        .collect();
+    let deposit = match reader.get_deposit().which()? {
+        map_cell::deposit::None(()) => None,
+        map_cell::deposit::Ore(ore) => {
+            let ore = ore?;
+            Some(OreDeposit {
+                item: from_item_type(ore.get_item()?),
+                remaining: match ore.get_is_infinite() {
+                    true => None,
+                    false => Some(ore.get_remaining()),
+                },
+            })
+        }
+    };
+    return Ok(CellChange {
+        map_x: reader.get_map_x(),
+        map_y: reader.get_map_y(),
+        cell: MapCell { layers, deposit },
+    });
+}
+
+fn write_entity(mut builder: entity::Builder, entity: &Entity) {
+    builder.set_id(entity.id);
+    builder.set_prototype_id(entity.prototype_id.unwrap_or(0));
+    builder.set_has_prototype(entity.prototype_id.is_some());
+    builder.set_map_x(entity.map_x);
+    builder.set_map_y(entity.map_y);
+    builder.set_sprites(entity.sprites);
+    builder.set_current_sprite_index(entity.current_sprite_index as u32);
+    builder.set_layer_weight(entity.layer_weight);
+    builder.set_health_points(entity.health_points);
+    builder.set_max_health_points(entity.max_health_points);
+    builder.set_mana_points(entity.mana_points);
+    builder.set_power_permille(entity.power_permille);
+    builder.set_owner(entity.owner);
+}
+// the rest of the Entity (weapon timers, status effects...) is left at defaults on the client
+fn read_entity(reader: entity::Reader) -> Entity {
+    let mut entity = Entity::new(
+        &reader.get_id(),
+        &reader.get_sprites(),
+        &reader.get_layer_weight(),
+    );
+    entity.prototype_id = match reader.get_has_prototype() {
+        true => Some(reader.get_prototype_id()),
+        false => None,
+    };
+    entity.map_x = reader.get_map_x();
+    entity.map_y = reader.get_map_y();
+    entity.current_sprite_index = reader.get_current_sprite_index() as usize;
+    entity.health_points = reader.get_health_points();
+    entity.max_health_points = reader.get_max_health_points();
+    entity.mana_points = reader.get_mana_points();
+    entity.power_permille = reader.get_power_permille();
+    entity.owner = reader.get_owner();
+    return entity;
+}
+
+fn write_ledger(mut builder: player_ledger::Builder, ledger: &PlayerLedger) {
+    builder.set_player_id(ledger.player_id);
+    let non_zero = |get: &dyn Fn(ItemTypes) -> u32| -> Vec<ItemStack> {
+        return ItemTypes::ALL
+            .iter()
+            .map(|item| ItemStack::new(*item, get(*item)))
+            .filter(|stack| stack.amount > 0)
+            .collect();
+    };
+    let balances = non_zero(&|item| ledger.get_balance(item));
+    let earned = non_zero(&|item| ledger.get_total_earned(item));
+    let spent = non_zero(&|item| ledger.get_total_spent(item));
+    write_item_stacks(
+        builder.reborrow().init_balances(balances.len() as u32),
+        &balances,
+    );
+    write_item_stacks(
+        builder.reborrow().init_total_earned(earned.len() as u32),
+        &earned,
+    );
+    write_item_stacks(
+        builder.reborrow().init_total_spent(spent.len() as u32),
+        &spent,
+    );
+}
+fn read_ledger(reader: player_ledger::Reader) -> capnp::Result<PlayerLedger> {
+    return Ok(PlayerLedger::from_totals(
+        reader.get_player_id(),
+        read_item_totals(reader.get_balances()?)?,
+        read_item_totals(reader.get_total_earned()?)?,
+        read_item_totals(reader.get_total_spent()?)?,
+    ));
+}
+
+fn write_conveyor_block(mut builder: conveyor_block::Builder, block: &ConveyorTypes) {
+    match block {
+        ConveyorTypes::Belt { direction } => builder.set_belt(to_direction(*direction)),
+        ConveyorTypes::Junction => builder.set_junction(()),
+        ConveyorTypes::Router => builder.set_router(()),
+        ConveyorTypes::Sorter { filter } => builder.set_sorter(to_item_type(*filter)),
+        ConveyorTypes::Bridge { direction } => builder.set_bridge(to_direction(*direction)),
+        ConveyorTypes::Sink => builder.set_sink(()),
+        ConveyorTypes::Port { owner } => builder.set_port(*owner),
+    }
+}
+fn read_conveyor_block(reader: conveyor_block::Reader) -> capnp::Result<ConveyorTypes> {
+    return Ok(match reader.which()? {
+        conveyor_block::Belt(direction) => ConveyorTypes::Belt {
+            direction: from_direction(direction?),
+        },
+        conveyor_block::Junction(()) => ConveyorTypes::Junction,
+        conveyor_block::Router(()) => ConveyorTypes::Router,
+        conveyor_block::Sorter(filter) => ConveyorTypes::Sorter {
+            filter: from_item_type(filter?),
+        },
+        conveyor_block::Bridge(direction) => ConveyorTypes::Bridge {
+            direction: from_direction(direction?),
+        },
+        conveyor_block::Sink(()) => ConveyorTypes::Sink,
+        conveyor_block::Port(owner) => ConveyorTypes::Port { owner },
+    });
+}
+
+pub fn write_command(mut builder: player_command::Builder, command: &PlayerCommands) {
+    match command {
+        PlayerCommands::PlaceStructure {
+            prototype_id,
+            map_x,
+            map_y,
+        } => {
+            let mut place = builder.init_place_structure();
+            place.set_prototype_id(*prototype_id);
+            place.set_map_x(*map_x);
+            place.set_map_y(*map_y);
+        }
+        PlayerCommands::SellStructure { entity_id } => {
+            builder.init_sell_structure().set_entity_id(*entity_id);
+        }
+        PlayerCommands::PlaceConveyor {
+            map_x,
+            map_y,
+            block,
+        } => {
+            let mut place = builder.init_place_conveyor();
+            place.set_map_x(*map_x);
+            place.set_map_y(*map_y);
+            write_conveyor_block(place.init_block(), block);
+        }
+        PlayerCommands::RemoveConveyor { map_x, map_y } => {
+            let mut remove = builder.init_remove_conveyor();
+            remove.set_map_x(*map_x);
+            remove.set_map_y(*map_y);
+        }
+        PlayerCommands::CallWaveEarly => builder.set_call_wave_early(()),
+        PlayerCommands::UpgradeStructure {
+            entity_id,
+            upgrade_id,
+        } => {
+            let mut upgrade = builder.init_upgrade_structure();
+            upgrade.set_entity_id(*entity_id);
+            upgrade.set_upgrade_id(*upgrade_id);
+        }
+        PlayerCommands::SetRallyPoint {
+            entity_id,
+            map_x,
+            map_y,
+        } => {
+            let mut rally = builder.init_set_rally_point();
+            rally.set_entity_id(*entity_id);
+            rally.set_map_x(*map_x);
+            rally.set_map_y(*map_y);
+        }
+        PlayerCommands::MoveUnits {
+            entity_ids,
+            map_x,
+            map_y,
+        } => {
+            let mut move_units = builder.init_move_units();
+            write_entity_ids(
+                move_units
+                    .reborrow()
+                    .init_entity_ids(entity_ids.len() as u32),
+                entity_ids,
+            );
+            move_units.set_map_x(*map_x);
+            move_units.set_map_y(*map_y);
+        }
+        PlayerCommands::AttackTarget {
+            entity_ids,
+            target_id,
+        } => {
+            let mut attack = builder.init_attack_target();
+            write_entity_ids(
+                attack.reborrow().init_entity_ids(entity_ids.len() as u32),
+                entity_ids,
+            );
+            attack.set_target_id(*target_id);
+        }
+        PlayerCommands::Patrol {
+            entity_ids,
+            map_x,
+            map_y,
+        } => {
+            let mut patrol = builder.init_patrol();
+            write_entity_ids(
+                patrol.reborrow().init_entity_ids(entity_ids.len() as u32),
+                entity_ids,
+            );
+            patrol.set_map_x(*map_x);
+            patrol.set_map_y(*map_y);
+        }
+        PlayerCommands::HoldPosition { entity_ids } => {
+            let hold = builder.init_hold_position();
+            write_entity_ids(hold.init_entity_ids(entity_ids.len() as u32), entity_ids);
+        }
+        PlayerCommands::QueueUnit {
+            entity_id,
+            prototype_id,
+        } => {
+            let mut queue = builder.init_queue_unit();
+            queue.set_entity_id(*entity_id);
+            queue.set_prototype_id(*prototype_id);
+        }
+        PlayerCommands::CancelUnit {
+            entity_id,
+            queue_index,
+        } => {
+            let mut cancel = builder.init_cancel_unit();
+            cancel.set_entity_id(*entity_id);
+            cancel.set_queue_index(*queue_index);
+        }
+        PlayerCommands::StartResearch { research_id } => {
+            builder.init_start_research().set_research_id(*research_id);
+        }
+        PlayerCommands::CancelResearch => builder.set_cancel_research(()),
+    }
+}
+fn write_entity_ids(mut builder: primitive_list::Builder<u16>, entity_ids: &Vec<TEntityID>) {
+    for (i, entity_id) in entity_ids.iter().enumerate() {
+        builder.set(i as u32, *entity_id);
+    }
+}
+pub fn read_command(reader: player_command::Reader) -> capnp::Result<PlayerCommands> {
+    return Ok(match reader.which()? {
+        player_command::PlaceStructure(place) => PlayerCommands::PlaceStructure {
+            prototype_id: place.get_prototype_id(),
+            map_x: place.get_map_x(),
+            map_y: place.get_map_y(),
+        },
+        player_command::SellStructure(sell) => PlayerCommands::SellStructure {
+            entity_id: sell.get_entity_id(),
+        },
+        player_command::PlaceConveyor(place) => PlayerCommands::PlaceConveyor {
+            map_x: place.get_map_x(),
+            map_y: place.get_map_y(),
+            block: read_conveyor_block(place.get_block()?)?,
+        },
+        player_command::RemoveConveyor(remove) => PlayerCommands::RemoveConveyor {
+            map_x: remove.get_map_x(),
+            map_y: remove.get_map_y(),
+        },
+        player_command::CallWaveEarly(()) => PlayerCommands::CallWaveEarly,
+        player_command::UpgradeStructure(upgrade) => PlayerCommands::UpgradeStructure {
+            entity_id: upgrade.get_entity_id(),
+            upgrade_id: upgrade.get_upgrade_id(),
+        },
+        player_command::SetRallyPoint(rally) => PlayerCommands::SetRallyPoint {
+            entity_id: rally.get_entity_id(),
+            map_x: rally.get_map_x(),
+            map_y: rally.get_map_y(),
+        },
+        player_command::MoveUnits(move_units) => PlayerCommands::MoveUnits {
+            entity_ids: move_units.get_entity_ids()?.iter().collect(),
+            map_x: move_units.get_map_x(),
+            map_y: move_units.get_map_y(),
+        },
+        player_command::AttackTarget(attack) => PlayerCommands::AttackTarget {
+            entity_ids: attack.get_entity_ids()?.iter().collect(),
+            target_id: attack.get_target_id(),
+        },
+        player_command::Patrol(patrol) => PlayerCommands::Patrol {
+            entity_ids: patrol.get_entity_ids()?.iter().collect(),
+            map_x: patrol.get_map_x(),
+            map_y: patrol.get_map_y(),
+        },
+        player_command::HoldPosition(hold) => PlayerCommands::HoldPosition {
+            entity_ids: hold.get_entity_ids()?.iter().collect(),
+        },
+        player_command::QueueUnit(queue) => PlayerCommands::QueueUnit {
+            entity_id: queue.get_entity_id(),
+            prototype_id: queue.get_prototype_id(),
+        },
+        player_command::CancelUnit(cancel) => PlayerCommands::CancelUnit {
+            entity_id: cancel.get_entity_id(),
+            queue_index: cancel.get_queue_index(),
+        },
+        player_command::StartResearch(research) => PlayerCommands::StartResearch {
+            research_id: research.get_research_id(),
+        },
+        player_command::CancelResearch(()) => PlayerCommands::CancelResearch,
+    });
+}
+
+pub fn write_game_state(mut builder: game_state::Builder, state: &ClientState) {
+    builder.set_tick(state.tick);
+    {
+        let mut map = builder.reborrow().init_map();
+        map.set_width(state.map.get_width());
+        map.set_height(state.map.get_height());
+        let blank = Map::create(state.map.get_width(), state.map.get_height()).unwrap(); // same dimension as a valid map
+        let cells = state.map.get_changed_cells(&blank).unwrap_or_default();
+        let mut cells_builder = map.init_cells(cells.len() as u32);
+        for (i, (map_x, map_y, cell)) in cells.iter().enumerate() {
+            write_map_cell(cells_builder.reborrow().get(i as u32), *map_x, *map_y, cell);
+        }
+    }
+    {
+        let mut entities = builder
+            .reborrow()
+            .init_entities(state.entities.len() as u32);
+        for (i, entity) in state.entities.iter().enumerate() {
+            write_entity(entities.reborrow().get(i as u32), entity);
+        }
+    }
+    let mut ledgers = builder.init_ledgers(state.ledgers.len() as u32);
+    for (i, ledger) in state.ledgers.iter().enumerate() {
+        write_ledger(ledgers.reborrow().get(i as u32), ledger);
+    }
+}
+pub fn read_game_state(reader: game_state::Reader) -> capnp::Result<ClientState> {
+    let map_reader = reader.get_map()?;
+    let mut map = Map::create(map_reader.get_width(), map_reader.get_height())
+        .map_err(capnp::Error::failed)?;
+    for cell in map_reader.get_cells()?.iter() {
+        let change = read_map_cell(cell)?;
+        if map.is_in_bounds(change.map_x, change.map_y) == false {
+            return Err(capnp::Error::failed(format!(
+                "cell ({}, {}) is out of bounds",
+                change.map_x, change.map_y
+            )));
+        }
+        map.set(change.map_x, change.map_y, change.cell)
+            .map_err(capnp::Error::failed)?;
+    }
+    let entities = reader.get_entities()?.iter().map(read_entity).collect();
+    let mut ledgers = Vec::new();
+    for ledger in reader.get_ledgers()?.iter() {
+        ledgers.push(read_ledger(ledger)?);
+    }
+    return Ok(ClientState {
+        tick: reader.get_tick(),
+        map,
+        entities,
+        ledgers,
+    });
+}
+
+pub fn write_state_delta(mut builder: state_delta::Builder, delta: &StateDelta) {
+    builder.set_from_tick(delta.from_tick);
+    builder.set_tick(delta.tick);
+    {
+        let mut cells = builder
+            .reborrow()
+            .init_changed_cells(delta.changed_cells.len() as u32);
+        for (i, change) in delta.changed_cells.iter().enumerate() {
+            write_map_cell(
+                cells.reborrow().get(i as u32),
+                change.map_x,
+                change.map_y,
+                &change.cell,
+            );
+        }
+    }
+    {
+        let mut entities = builder
+            .reborrow()
+            .init_changed_entities(delta.changed_entities.len() as u32);
+        for (i, entity) in delta.changed_entities.iter().enumerate() {
+            write_entity(entities.reborrow().get(i as u32), entity);
+        }
+    }
+    {
+        let mut removed = builder
+            .reborrow()
+            .init_removed_entities(delta.removed_entities.len() as u32);
+        for (i, entity_id) in delta.removed_entities.iter().enumerate() {
+            removed.set(i as u32, *entity_id);
+        }
+    }
+    let mut ledgers = builder.init_changed_ledgers(delta.changed_ledgers.len() as u32);
+    for (i, ledger) in delta.changed_ledgers.iter().enumerate() {
+        write_ledger(ledgers.reborrow().get(i as u32), ledger);
+    }
+}
+pub fn read_state_delta(reader: state_delta::Reader) -> capnp::Result<StateDelta> {
+    let mut changed_cells = Vec::new();
+    for cell in reader.get_changed_cells()?.iter() {
+        changed_cells.push(read_map_cell(cell)?);
+    }
+    let mut changed_ledgers = Vec::new();
+    for ledger in reader.get_changed_ledgers()?.iter() {
+        changed_ledgers.push(read_ledger(ledger)?);
+    }
+    return Ok(StateDelta {
+        from_tick: reader.get_from_tick(),
+        tick: reader.get_tick(),
+        changed_cells,
+        changed_entities: reader
+            .get_changed_entities()?
+            .iter()
+            .map(read_entity)
+            .collect(),
+        removed_entities: reader.get_removed_entities()?.iter().collect(),
+        changed_ledgers,
+    });
+}
+
+// deltas pushed to a subscriber that it has not returned yet; one that falls this far behind is
+// dropped rather than piling up ever more requests (and memory) for it, it can subscribe again
+const MAX_IN_FLIGHT_DELTAS: usize = 16;
+
+struct Subscriber {
+    client: state_subscriber::Client,
+    in_flight: usize,
+}
+
+// everything the server side shares between connections, all on the one (local) thread
+struct ServerState {
+    simulation: Simulation,
+    last_sent: ClientState, // what every subscriber has (or is about to have) applied
+    subscribers: BTreeMap<TSubscriberID, Subscriber>,
+    next_subscriber_id: TSubscriberID,
+    joined_players: BTreeMap<TConnectionID, TPlayerID>, // the one player each connection plays
+    next_connection_id: TConnectionID,
+}
+impl ServerState {
+    // a connection sticks with the player it first joined as, and no two connections play one
+    fn bind_player(
+        self: &mut Self,
+        connection_id: TConnectionID,
+        player_id: TPlayerID,
+    ) -> Result<(), String> {
+        if let Some(bound_id) = self.joined_players.get(&connection_id) {
+            if *bound_id != player_id {
+                return Err(format!(
+                    "this connection already plays playerID={}",
+                    bound_id
+                ));
+            }
+            return Ok(());
+        }
+        if self.joined_players.values().any(|id| *id == player_id) {
+            return Err(format!(
+                "playerID={} is already played by another connection",
+                player_id
+            ));
+        }
+        if self.simulation.economy.get_ledger(player_id).is_none() {
+            return Err(format!("playerID={} is not part of this game", player_id));
+        }
+        self.joined_players.insert(connection_id, player_id);
+        return Ok(());
+    }
+}
+
+fn new_server_state(simulation: Simulation) -> Rc<RefCell<ServerState>> {
+    let last_sent = ClientState::from_simulation(&simulation);
+    return Rc::new(RefCell::new(ServerState {
+        simulation,
+        last_sent,
+        subscribers: BTreeMap::new(),
+        next_subscriber_id: 1,
+        joined_players: BTreeMap::new(),
+        next_connection_id: 1,
+    }));
+}
+
+struct SubscriptionImpl {
+    id: TSubscriberID,
+    state: Rc<RefCell<ServerState>>,
+}
+impl subscription::Server for SubscriptionImpl {}
+impl Drop for SubscriptionImpl {
+    fn drop(&mut self) {
+        self.state.borrow_mut().subscribers.remove(&self.id);
+    }
+}
+
+struct GameSessionImpl {
+    player_id: TPlayerID,
+    state: Rc<RefCell<ServerState>>,
+}
+impl game_session::Server for GameSessionImpl {
+    fn issue_command(
+        &mut self,
+        params: game_session::IssueCommandParams,
+        mut results: game_session::IssueCommandResults,
+    ) -> Promise<(), capnp::Error> {
+        let command = pry!(read_command(pry!(pry!(params.get()).get_command())));
+        let mut server = self.state.borrow_mut();
+        server.simulation.queue_command(self.player_id, command);
+        results.get().set_tick(server.simulation.get_tick());
+        return Promise::ok(());
+    }
+
+    fn subscribe(
+        &mut self,
+        params: game_session::SubscribeParams,
+        mut results: game_session::SubscribeResults,
+    ) -> Promise<(), capnp::Error> {
+        let subscriber = pry!(pry!(params.get()).get_subscriber());
+        let mut server = self.state.borrow_mut();
+        let id = server.next_subscriber_id;
+        server.next_subscriber_id += 1;
+        server.subscribers.insert(
+            id,
+            Subscriber {
+                client: subscriber,
+                in_flight: 0,
+            },
+        );
+        write_game_state(results.get().init_state(), &server.last_sent);
+        results
+            .get()
+            .set_subscription(capnp_rpc::new_client(SubscriptionImpl {
+                id,
+                state: self.state.clone(),
+            }));
+        return Promise::ok(());
+    }
+}
+
+// one per connection, so that it can be bound to the player it joins as
+struct GameServerImpl {
+    connection_id: TConnectionID,
+    state: Rc<RefCell<ServerState>>,
+}
+impl Drop for GameServerImpl {
+    fn drop(&mut self) {
+        self.state
+            .borrow_mut()
+            .joined_players
+            .remove(&self.connection_id);
+    }
+}
+
+fn new_game_server(state: &Rc<RefCell<ServerState>>) -> game_server::Client {
+    let mut server = state.borrow_mut();
+    let connection_id = server.next_connection_id;
+    server.next_connection_id += 1;
+    return capnp_rpc::new_client(GameServerImpl {
+        connection_id,
+        state: state.clone(),
+    });
+}
+
+impl game_server::Server for GameServerImpl {
+    fn join_game(
+        &mut self,
+        params: game_server::JoinGameParams,
+        mut results: game_server::JoinGameResults,
+    ) -> Promise<(), capnp::Error> {
+        let player_id = pry!(params.get()).get_player_id();
+        let bound = self
+            .state
+            .borrow_mut()
+            .bind_player(self.connection_id, player_id);
+        if let Err(e) = bound {
+            return Promise::err(capnp::Error::failed(e));
+        }
+        results
+            .get()
+            .set_session(capnp_rpc::new_client(GameSessionImpl {
+                player_id,
+                state: self.state.clone(),
+            }));
+        return Promise::ok(());
+    }
+}
+
+// runs the simulation in real-time, sending every subscriber the delta after each frame; only
+// returns if it cannot make one, as every subscriber would silently fall out of sync after that
+async fn run_simulation(state: Rc<RefCell<ServerState>>) -> Result<(), String> {
+    let mut last_frame_time = Instant::now();
+    loop {
+        let wait = state.borrow().simulation.get_time_until_next_tick();
+        tokio::time::sleep(wait).await;
+        let now = Instant::now();
+        let mut server = state.borrow_mut();
+        let reports = server.simulation.advance(now - last_frame_time);
+        last_frame_time = now;
+        if reports.is_empty() {
+            continue;
+        }
+        let current = ClientState::from_simulation(&server.simulation);
+        let delta = server
+            .last_sent
+            .get_delta_to(&current)
+            .map_err(|e| format!("cannot make a delta, {}", e))?;
+        server.last_sent = current;
+        // skipping a delta would break the chain, so a subscriber that cannot keep up is dropped
+        server
+            .subscribers
+            .retain(|_, subscriber| subscriber.in_flight < MAX_IN_FLIGHT_DELTAS);
+        for (id, subscriber) in server.subscribers.iter_mut() {
+            let mut request = subscriber.client.push_delta_request();
+            write_state_delta(request.get().init_delta(), &delta);
+            subscriber.in_flight += 1;
+            let id = *id;
+            let state_on_reply = state.clone();
+            tokio::task::spawn_local(async move {
+                let is_applied = request.send().promise.await.is_ok();
+                let mut server = state_on_reply.borrow_mut();
+                match server.subscribers.get_mut(&id) {
+                    Some(subscriber) if is_applied => subscriber.in_flight -= 1,
+                    Some(_) => {
+                        // went away, or could not apply it and would miss the ones after
+                        server.subscribers.remove(&id);
+                    }
+                    None => {} // already dropped
+                }
+            });
+        }
+    }
+}
+
+fn start_rpc_system<S>(
+    stream: S,
+    side: rpc_twoparty_capnp::Side,
+    bootstrap: Option<capnp::capability::Client>,
+) -> RpcSystem<rpc_twoparty_capnp::Side>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    let (reader, writer) = stream.compat().split();
+    let network = twoparty::VatNetwork::new(reader, writer, side, Default::default());
+    return RpcSystem::new(Box::new(network), bootstrap);
+}
+
+/// Runs the simulation (in real-time) and serves it to whoever connects, until an I/O error or
+/// the simulation can no longer be sent
+pub async fn serve(
+    simulation: Simulation,
+    address: ListenAddresses,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let state = new_server_state(simulation);
+    tokio::select! {
+        result = run_simulation(state.clone()) => return Ok(result?),
+        result = accept_connections(state, address) => return result,
+    }
+}
+
+async fn accept_connections(
+    state: Rc<RefCell<ServerState>>,
+    address: ListenAddresses,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match address {
+        ListenAddresses::Tcp(host_port) => {
+            let listener = tokio::net::TcpListener::bind(&host_port).await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                let rpc_system = start_rpc_system(
+                    stream,
+                    rpc_twoparty_capnp::Side::Server,
+                    Some(new_game_server(&state).client),
+                );
+                tokio::task::spawn_local(rpc_system);
+            }
+        }
+        #[cfg(unix)]
+        ListenAddresses::Unix(path) => {
+            let _ = std::fs::remove_file(&path); // stale socket of a previous run
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let rpc_system = start_rpc_system(
+                    stream,
+                    rpc_twoparty_capnp::Side::Server,
+                    Some(new_game_server(&state).client),
+                );
+                tokio::task::spawn_local(rpc_system);
+            }
+        }
+    }
+}
+
+/// Client side: connects to a served game, call join_game_request() on what is returned
+pub async fn connect(
+    address: ListenAddresses,
+) -> Result<game_server::Client, Box<dyn std::error::Error>> {
+    let mut rpc_system = match address {
+        ListenAddresses::Tcp(host_port) => {
+            let stream = tokio::net::TcpStream::connect(&host_port).await?;
+            stream.set_nodelay(true)?;
+            start_rpc_system(stream, rpc_twoparty_capnp::Side::Client, None)
+        }
+        #[cfg(unix)]
+        ListenAddresses::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(&path).await?;
+            start_rpc_system(stream, rpc_twoparty_capnp::Side::Client, None)
+        }
+    };
+    let server: game_server::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    tokio::task::spawn_local(rpc_system);
+    return Ok(server);
+}
+
+/// Client side: keeps a ClientState up to date with the deltas pushed to it; pass (a clone of) it
+/// to GameSession.subscribe() and seed() it with the GameState that returns.  Deltas can arrive
+/// before that, those are kept and replayed on top of the GameState once it is seeded
+#[derive(Clone, Default)]
+pub struct ClientStateMirror {
+    pub state: Rc<RefCell<Option<ClientState>>>,
+    early_deltas: Rc<RefCell<Vec<StateDelta>>>,
+}
+
+impl ClientStateMirror {
+    pub fn new() -> ClientStateMirror {
+        return ClientStateMirror::default();
+    }
+
+    pub fn seed(self: &Self, seeded: ClientState) -> Result<(), String> {
+        let mut state = seeded;
+        for delta in self.early_deltas.borrow_mut().drain(..) {
+            if delta.tick <= state.tick {
+                continue; // already part of the GameState
+            }
+            state.apply(&delta)?;
+        }
+        *self.state.borrow_mut() = Some(state);
+        return Ok(());
+    }
+
+    fn receive(self: &Self, delta: StateDelta) -> Result<(), String> {
+        return match self.state.borrow_mut().as_mut() {
+            Some(client_state) => client_state.apply(&delta),
+            None => {
+                self.early_deltas.borrow_mut().push(delta);
+                Ok(())
+            }
+        };
+    }
+}
+
+impl state_subscriber::Server for ClientStateMirror {
+    fn push_delta(
+        &mut self,
+        params: state_subscriber::PushDeltaParams,
+        _results: state_subscriber::PushDeltaResults,
+    ) -> Promise<(), capnp::Error> {
+        let delta = pry!(read_state_delta(pry!(pry!(params.get()).get_delta())));
+        return match self.receive(delta) {
+            Ok(()) => Promise::ok(()),
+            Err(e) => Promise::err(capnp::Error::failed(e)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prototype_system::TPrototypeID;
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+    use std::time::Duration;
+
+    // player 1, with just enough gold for the one (10 gold) tower
+    fn make_simulation() -> (Simulation, TPrototypeID) {
+        let tower_id = add_prototype("test tower", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+        });
+        let mut simulation = new_simulation(8, 8);
+        add_player(&mut simulation, 1, 10);
+        return (simulation, tower_id);
+    }
+
+    #[test]
+    fn test_early_deltas_are_replayed_once_seeded() {
+        let _world = TestWorld::new();
+        let (mut simulation, _) = make_simulation();
+        let seeded = ClientState::from_simulation(&simulation);
+        simulation.step_once();
+        let next = ClientState::from_simulation(&simulation);
+        simulation.step_once();
+        let last = ClientState::from_simulation(&simulation);
+
+        // the first one is already part of the GameState it gets seeded with
+        let mirror = ClientStateMirror::new();
+        mirror.receive(seeded.get_delta_to(&next).unwrap()).unwrap();
+        mirror.receive(next.get_delta_to(&last).unwrap()).unwrap();
+        assert!(mirror.state.borrow().is_none());
+        mirror.seed(next).unwrap();
+        assert_eq!(mirror.state.borrow().as_ref(), Some(&last));
+    }
+
+    #[test]
+    fn test_serve_subscribe_and_apply() {
+        let _world = TestWorld::new();
+        let (simulation, tower_id) = make_simulation();
+        let state = new_server_state(simulation);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        tokio::task::LocalSet::new().block_on(&runtime, async {
+            // the same as serve(), on a port of its own
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            tokio::task::spawn_local(run_simulation(state.clone()));
+            let accepted_state = state.clone();
+            tokio::task::spawn_local(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let rpc_system = start_rpc_system(
+                        stream,
+                        rpc_twoparty_capnp::Side::Server,
+                        Some(new_game_server(&accepted_state).client),
+                    );
+                    tokio::task::spawn_local(rpc_system);
+                }
+            });
+
+            let game_server = connect(ListenAddresses::Tcp(address.clone()))
+                .await
+                .unwrap();
+            let mut join = game_server.join_game_request();
+            join.get().set_player_id(1);
+            let response = join.send().promise.await.unwrap();
+            let session = response.get().unwrap().get_session().unwrap();
+
+            // player 1 is taken, by the connection above
+            let other_server = connect(ListenAddresses::Tcp(address)).await.unwrap();
+            let mut join = other_server.join_game_request();
+            join.get().set_player_id(1);
+            assert!(join.send().promise.await.is_err());
+
+            let mirror = ClientStateMirror::new();
+            let mut subscribe = session.subscribe_request();
+            subscribe
+                .get()
+                .set_subscriber(capnp_rpc::new_client(mirror.clone()));
+            let response = subscribe.send().promise.await.unwrap();
+            let _subscription = response.get().unwrap().get_subscription().unwrap();
+            let seeded = read_game_state(response.get().unwrap().get_state().unwrap()).unwrap();
+            mirror.seed(seeded).unwrap();
+
+            let mut issue = session.issue_command_request();
+            let place = PlayerCommands::PlaceStructure {
+                prototype_id: tower_id,
+                map_x: 2,
+                map_y: 3,
+            };
+            write_command(issue.get().init_command(), &place);
+            issue.send().promise.await.unwrap();
+
+            // caught up with the server, tower and all
+            let mut is_caught_up = false;
+            for _ in 0..200 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let mirrored = mirror.state.borrow().clone().unwrap();
+                is_caught_up = mirrored.entities.len() == 1
+                    && mirrored == state.borrow().last_sent
+                    && state.borrow().subscribers[&1].in_flight == 0;
+                if is_caught_up {
+                    break;
+                }
+            }
+            assert!(is_caught_up);
+            let mirrored = mirror.state.borrow().clone().unwrap();
+            assert_eq!(
+                (mirrored.entities[0].map_x, mirrored.entities[0].map_y),
+                (2, 3)
+            );
+        });
+    }
+}
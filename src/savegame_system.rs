@@ -0,0 +1,258 @@
+// Save games: the whole match in one file, that is the Simulation (map, economy, waves, RNG, clock
+// and every other system) plus entity_system's entities.  Sprites are not part of it since they
+// are loaded from resources up front, and each entity already carries its own animation state
+use crate::damage_system;
+use crate::entity_system::TEntityID;
+use crate::prototype_system;
+use crate::resource_system;
+use crate::simulation::{Simulation, SimulationSnapshot};
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+// bump whenever the save format changes; 2 since units, upgrades, production, tech, diplomacy,
+// behaviours and computer players became part of the Simulation
+pub const SAVEGAME_VERSION: u16 = 2;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u16,
+    pub snapshot: SimulationSnapshot,
+}
+
+impl SaveGame {
+    pub fn new(simulation: &Simulation) -> SaveGame {
+        SaveGame {
+            version: SAVEGAME_VERSION,
+            snapshot: simulation.snapshot(),
+        }
+    }
+
+    /// Makes sure that every entity ID referenced (by the map, waves and structures) exists, and
+    /// that every entity's prototype is loaded, so that a restored game never dangles
+    pub fn validate(self: &Self) -> Result<(), String> {
+        let entities = &self.snapshot.entities;
+        if let Some(pair) = entities.windows(2).find(|pair| pair[0].id >= pair[1].id) {
+            return Err(format!(
+                "entityID={} is out of order or duplicated",
+                pair[1].id
+            ));
+        }
+        let has_entity =
+            |entity_id: &TEntityID| entities.binary_search_by(|e| e.id.cmp(entity_id)).is_ok();
+        for entity in entities.iter() {
+            if let Some(prototype_id) = entity.prototype_id {
+                if prototype_system::get(&prototype_id).is_none() {
+                    return Err(format!(
+                        "entityID={} is of prototypeID={} which is not loaded",
+                        entity.id, prototype_id
+                    ));
+                }
+            }
+        }
+
+        let simulation = &self.snapshot.simulation;
+        for map_y in 0..simulation.map.get_height() {
+            for map_x in 0..simulation.map.get_width() {
+                let cell = simulation.map.get_cell(map_x, map_y)?;
+                if let Some(layer) = cell.layers.iter().find(|l| has_entity(&l.entity) == false) {
+                    return Err(format!(
+                        "map ({}, {}) references entityID={} which does not exist",
+                        map_x, map_y, layer.entity
+                    ));
+                }
+            }
+        }
+        let mut referenced: Vec<(&str, TEntityID)> = Vec::new();
+        for entity_id in simulation.waves.get_alive_entities() {
+            referenced.push(("wave", entity_id));
+        }
+        for drill in simulation.mining.get_drills() {
+            referenced.push(("drill", drill.entity_id));
+        }
+        for network in simulation.power.get_networks() {
+            for entity_id in network.members.iter() {
+                referenced.push(("power", *entity_id));
+            }
+        }
+        for factory in simulation.crafting.get_factories() {
+            referenced.push(("factory", factory.entity_id));
+        }
+        for unit in simulation.units.get_units() {
+            referenced.push(("unit", unit.entity_id));
+        }
+        for producer in simulation.production.get_producers() {
+            referenced.push(("producer", producer.entity_id));
+        }
+        if let Some((kind, entity_id)) = referenced.iter().find(|(_, id)| has_entity(id) == false) {
+            return Err(format!(
+                "{} references entityID={} which does not exist",
+                kind, entity_id
+            ));
+        }
+        return Ok(());
+    }
+
+    // NOTE: replaces every entity in entity_system, so nothing of the previous game lingers
+    pub fn restore(self: &Self) -> Result<Simulation, String> {
+        self.validate()?;
+        damage_system::take_death_events(); // deaths of the previous game, if any
+        return Ok(Simulation::restore(&self.snapshot));
+    }
+}
+
+pub fn serialize_savegame_for_save(savegame: &SaveGame) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match savegame.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_savegame_for_load(bin_data: &Vec<u8>) -> Result<SaveGame, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    // the version alone first, a save of another version would most likely fail to deserialize
+    // with a far less helpful error
+    let (version, _): (u16, serde::de::IgnoredAny) =
+        rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string())?;
+    if version != SAVEGAME_VERSION {
+        return Err(format!(
+            "save game version {} cannot be loaded by version {}",
+            version, SAVEGAME_VERSION
+        ));
+    }
+    return rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string());
+}
+
+/// Writes the game via resource_system, which replaces the file atomically (an existing save
+/// is left intact if writing fails)
+pub fn save_game(simulation: &Simulation, file_paths: &String) -> Result<(), String> {
+    let savegame = SaveGame::new(simulation);
+    return resource_system::write_resource(file_paths, serialize_savegame_for_save(&savegame));
+}
+
+// NOTE: replaces every entity in entity_system (see SaveGame::restore())
+pub fn load_game(file_paths: &String) -> Result<Simulation, String> {
+    return resource_system::read_resource(file_paths, deserialize_savegame_for_load)?.restore();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::crafting_system::CraftingSystem;
+    use crate::economy_system::{ItemStack, ItemTypes};
+    use crate::entity_system;
+    use crate::map::Map;
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+    use crate::wave_system::{SpawnGroup, WaveDefinition, WaveScheduler};
+
+    // a tower of player 1 and a wave of 3 creeps around it, over a second and a bit into the match
+    fn play_a_bit() -> Simulation {
+        let tower_id = add_prototype("test tower", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+        });
+        let creep_id = add_prototype("test creep", |p| p.max_health_points = 10);
+        let waves = WaveScheduler::new(vec![WaveDefinition {
+            delay_millis: 500,
+            early_call_bonus_per_second: 0,
+            groups: vec![SpawnGroup {
+                prototype_id: creep_id,
+                count: 3,
+                spacing_millis: 300,
+                delay_millis: 0,
+                spawn_x: 5,
+                spawn_y: 5,
+                spawn_radius: 1,
+            }],
+        }])
+        .unwrap();
+        let mut simulation = Simulation::new(
+            Map::create(8, 8).unwrap(),
+            waves,
+            CraftingSystem::new(Vec::new()).unwrap(),
+            7,
+        );
+        add_player(&mut simulation, 1, 10);
+        simulation.queue_command(
+            1,
+            PlayerCommands::PlaceStructure {
+                prototype_id: tower_id,
+                map_x: 0,
+                map_y: 0,
+            },
+        );
+        for _ in 0..40 {
+            simulation.step_once();
+        }
+        assert_eq!(entity_system::snapshot().len(), 4);
+        return simulation;
+    }
+
+    #[test]
+    fn test_save_and_load_game() {
+        let _world = TestWorld::new();
+        let simulation = play_a_bit();
+        let file_paths = "./unit_test_savegame.bin".to_owned();
+        let _ = std::fs::remove_file(file_paths.clone());
+        save_game(&simulation, &file_paths).unwrap();
+        save_game(&simulation, &file_paths).unwrap(); // over an existing one
+        assert!(std::path::Path::new(&format!("{}.tmp", file_paths)).exists() == false);
+        let entities = entity_system::snapshot();
+
+        entity_system::reset();
+        let loaded = load_game(&file_paths).unwrap();
+        std::fs::remove_file(file_paths.clone()).unwrap();
+        assert_eq!(loaded, simulation);
+        assert_eq!(entity_system::snapshot(), entities);
+    }
+
+    #[test]
+    fn test_loaded_games_carry_on_the_same() {
+        let _world = TestWorld::new();
+        let mut simulation = play_a_bit();
+        let entities = entity_system::snapshot();
+        let bin = serialize_savegame_for_save(&SaveGame::new(&simulation)).unwrap();
+
+        entity_system::reset();
+        let mut loaded = deserialize_savegame_for_load(&bin)
+            .unwrap()
+            .restore()
+            .unwrap();
+        // including the next entity ID
+        let loaded_report = loaded.step_once();
+        entity_system::restore(entities);
+        assert_eq!(simulation.step_once(), loaded_report);
+    }
+
+    #[test]
+    fn test_missing_entities_are_invalid() {
+        let _world = TestWorld::new();
+        let simulation = play_a_bit();
+        let mut savegame = SaveGame::new(&simulation);
+        assert!(savegame.validate().is_ok());
+        // a map cell referencing an entity which was not saved
+        savegame.snapshot.entities.remove(0);
+        assert!(savegame.validate().is_err());
+    }
+
+    #[test]
+    fn test_other_versions_are_rejected() {
+        let _world = TestWorld::new();
+        let mut savegame = SaveGame::new(&new_simulation(4, 4));
+        let bin = serialize_savegame_for_save(&savegame).unwrap();
+        assert_eq!(deserialize_savegame_for_load(&bin).unwrap(), savegame);
+
+        savegame.version = SAVEGAME_VERSION - 1;
+        let bin = serialize_savegame_for_save(&savegame).unwrap();
+        let error = deserialize_savegame_for_load(&bin).unwrap_err();
+        assert!(error.starts_with(&format!("save game version {}", SAVEGAME_VERSION - 1)));
+    }
+
+    #[test]
+    fn test_other_data_is_rejected() {
+        assert!(deserialize_savegame_for_load(&vec![0xc0]).is_err());
+        assert!(deserialize_savegame_for_load(&Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// Scenarios: everything needed to start a match (prototypes, waves, recipes, players and their
+// starting items, seed), as data so that it can be authored and persisted via resource_system.
+// The map is either loaded separately or generated from the seed
+use crate::behaviour_system::{Behaviour, BehaviourSystem, CreepPath};
+use crate::computer_player_system::{ComputerPlayer, ComputerSetup};
+use crate::crafting_system::{CraftingSystem, Recipe};
+use crate::diplomacy_system::{TTeamID, TeamRelation};
+use crate::economy_system::{ItemStack, TPlayerID};
+use crate::map::Map;
+use crate::prototype_system::{self, EntityPrototype};
+use crate::random::RngStreamTypes;
+use crate::simulation::Simulation;
+use crate::tech_system::{PrototypePrerequisites, Research, TechSystem};
+use crate::upgrade_system::{UpgradeDefinition, UpgradeTree};
+use crate::wave_system::{WaveDefinition, WaveScheduler};
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PlayerSetup {
+    pub player_id: TPlayerID,
+    pub starting_items: Vec<ItemStack>,
+    #[serde(default)] // None is a team of its own, at war with everyone
+    pub team: Option<TTeamID>,
+    #[serde(default)] // None when played by a person
+    pub computer: Option<ComputerSetup>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub seed: u64,
+    pub map_width: u16, // of the generated map, when no map is given
+    pub map_height: u16,
+    pub prototypes: Vec<EntityPrototype>,
+    pub recipes: Vec<Recipe>,
+    pub waves: Vec<WaveDefinition>,
+    pub players: Vec<PlayerSetup>,
+    #[serde(default)] // last, so that scenarios from before upgrades still load
+    pub upgrades: Vec<UpgradeDefinition>,
+    #[serde(default)]
+    pub researches: Vec<Research>,
+    #[serde(default)]
+    pub prototype_prerequisites: Vec<PrototypePrerequisites>,
+    #[serde(default)] // teams not listed are at war
+    pub team_relations: Vec<TeamRelation>,
+    #[serde(default)]
+    pub behaviours: Vec<Behaviour>,
+    #[serde(default)]
+    pub paths: Vec<CreepPath>,
+}
+
+impl Scenario {
+    /// Registers the scenario's prototypes and sets up a fresh Simulation (computer players
+    /// included), on the given map or else on one generated (from the seed) at map_width x
+    /// map_height
+    pub fn create_simulation(self: &Self, map: Option<Map>) -> Result<Simulation, String> {
+        for prototype in self.prototypes.iter() {
+            prototype_system::add(prototype.clone());
+        }
+        let waves = WaveScheduler::new(self.waves.clone())?;
+        let crafting = CraftingSystem::new(self.recipes.clone())?;
+        let upgrades = UpgradeTree::new(self.upgrades.clone())?;
+        let tech = TechSystem::new(
+            self.researches.clone(),
+            self.prototype_prerequisites.clone(),
+        )?;
+        let behaviours = BehaviourSystem::new(self.behaviours.clone(), self.paths.clone())?;
+        let mut simulation = match map {
+            Some(m) => Simulation::new(m, waves, crafting, self.seed),
+            None => {
+                let template = Map::create(self.map_width, self.map_height)?;
+                let mut simulation = Simulation::new(template, waves, crafting, self.seed);
+                simulation.map = simulation
+                    .map
+                    .auto_generate(simulation.rng.stream(RngStreamTypes::MapGeneration))?;
+                simulation
+            }
+        };
+        simulation.upgrades = upgrades;
+        simulation.tech = tech;
+        simulation.behaviours = behaviours;
+        for player in self.players.iter() {
+            simulation.economy.add_player(player.player_id)?;
+            simulation
+                .economy
+                .earn(player.player_id, &player.starting_items)?;
+            if let Some(team_id) = player.team {
+                simulation.diplomacy.set_team(player.player_id, team_id)?;
+            }
+        }
+        for relation in self.team_relations.iter() {
+            simulation.diplomacy.set_relation(relation)?;
+        }
+        simulation.computers = self.create_computer_players();
+        return Ok(simulation);
+    }
+
+    // the players it has set up as computer opponents, ready to play (see ComputerPlayer::think())
+    pub fn create_computer_players(self: &Self) -> Vec<ComputerPlayer> {
+        return self
+            .players
+            .iter()
+            .filter_map(|p| {
+                p.computer
+                    .clone()
+                    .map(|setup| ComputerPlayer::new(p.player_id, setup))
+            })
+            .collect();
+    }
+}
+
+// NOTE: same as Map, no I/O here; use resource_system (i.e. Resource::read_data()) to persist it
+pub fn serialize_scenario_for_save(scenario: &Scenario) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match scenario.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_scenario_for_load(bin_data: &Vec<u8>) -> Result<Scenario, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    return rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::ItemTypes;
+    use crate::prototype_system::TPrototypeID;
+    use crate::test_helpers::new_prototype_id;
+    use crate::wave_system::SpawnGroup;
+
+    // a single wave of one creep against player 1 (with 50 gold)
+    fn make_scenario(creep_id: TPrototypeID) -> Scenario {
+        let mut creep = EntityPrototype::new(creep_id, "test creep", 0);
+        creep.max_health_points = 5;
+        return Scenario {
+            name: "test".to_owned(),
+            seed: 3,
+            map_width: 24,
+            map_height: 16,
+            prototypes: vec![creep],
+            recipes: Vec::new(),
+            waves: vec![WaveDefinition {
+                delay_millis: 100,
+                early_call_bonus_per_second: 0,
+                groups: vec![SpawnGroup {
+                    prototype_id: creep_id,
+                    count: 1,
+                    spacing_millis: 0,
+                    delay_millis: 0,
+                    spawn_x: 2,
+                    spawn_y: 2,
+                    spawn_radius: 0,
+                }],
+            }],
+            players: vec![PlayerSetup {
+                player_id: 1,
+                starting_items: vec![ItemStack::new(ItemTypes::Gold, 50)],
+                team: None,
+                computer: None,
+            }],
+            upgrades: Vec::new(),
+            researches: Vec::new(),
+            prototype_prerequisites: Vec::new(),
+            team_relations: Vec::new(),
+            behaviours: Vec::new(),
+            paths: Vec::new(),
+        };
+    }
+
+    #[test]
+    fn test_scenarios_are_saved() {
+        let scenario = make_scenario(new_prototype_id());
+        let bin = serialize_scenario_for_save(&scenario).unwrap();
+        assert_eq!(deserialize_scenario_for_load(&bin).unwrap(), scenario);
+    }
+
+    #[test]
+    fn test_scenario_to_simulation() {
+        let creep_id = new_prototype_id();
+        let scenario = make_scenario(creep_id);
+        let simulation = scenario.create_simulation(None).unwrap();
+        assert!(prototype_system::get(&creep_id).is_some());
+        assert_eq!(simulation.economy.get_balance(1, ItemTypes::Gold), 50);
+        assert_eq!(simulation.map.get_width(), 24);
+        // same seed, same generated map
+        assert_eq!(
+            scenario.create_simulation(None).unwrap().map,
+            simulation.map
+        );
+    }
+}
@@ -0,0 +1,292 @@
+// What a player has selected and their numbered control groups (the 1..0 keys), so that units
+// can be box-selected, grouped and given one order as a whole.  This is frontend state, never
+// part of the Simulation: the order that comes out of a selection is a plain PlayerCommands
+// listing the units (see command_system), which is all that peers and replays ever see
+use crate::command_system::PlayerCommands;
+use crate::economy_system::TPlayerID;
+use crate::entity_system::{self, TEntityID};
+use crate::map::Map;
+use crate::prototype_system::TPrototypeID;
+use crate::unit_system::{UnitOrders, UnitSystem};
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+pub const CONTROL_GROUP_COUNT: usize = 10;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Selection {
+    pub player_id: TPlayerID,
+    selected: Vec<TEntityID>, // sorted
+    control_groups: [Vec<TEntityID>; CONTROL_GROUP_COUNT],
+}
+
+fn merge(into: &mut Vec<TEntityID>, entity_ids: &Vec<TEntityID>) {
+    into.extend(entity_ids.iter());
+    into.sort();
+    into.dedup();
+}
+
+impl Selection {
+    pub fn new(player_id: TPlayerID) -> Selection {
+        Selection {
+            player_id,
+            selected: Vec::new(),
+            control_groups: Default::default(),
+        }
+    }
+
+    pub fn get_selected(self: &Self) -> &Vec<TEntityID> {
+        return &self.selected;
+    }
+    pub fn get_control_group(self: &Self, group: usize) -> Result<&Vec<TEntityID>, String> {
+        return match self.control_groups.get(group) {
+            Some(g) => Ok(g),
+            None => Err(format!("there is no control group {}", group)),
+        };
+    }
+
+    // replaces the selection (i.e. click or box-select)
+    pub fn select(self: &mut Self, entity_ids: &Vec<TEntityID>) {
+        self.selected.clear();
+        merge(&mut self.selected, entity_ids);
+    }
+    // adds to it (i.e. shift+click)
+    pub fn add(self: &mut Self, entity_ids: &Vec<TEntityID>) {
+        merge(&mut self.selected, entity_ids);
+    }
+    pub fn clear(self: &mut Self) {
+        self.selected.clear();
+    }
+
+    // makes the selection the group (i.e. ctrl+N)
+    pub fn assign_group(self: &mut Self, group: usize) -> Result<(), String> {
+        self.get_control_group(group)?;
+        self.control_groups[group] = self.selected.clone();
+        return Ok(());
+    }
+    // adds the selection to the group (i.e. shift+N)
+    pub fn add_to_group(self: &mut Self, group: usize) -> Result<(), String> {
+        self.get_control_group(group)?;
+        merge(&mut self.control_groups[group], &self.selected);
+        return Ok(());
+    }
+    // selects the group (i.e. N)
+    pub fn recall_group(self: &mut Self, group: usize) -> Result<&Vec<TEntityID>, String> {
+        self.selected = self.get_control_group(group)?.clone();
+        return Ok(&self.selected);
+    }
+
+    // drops whatever is no longer one of the player's units (i.e. died); call once per frame
+    pub fn prune(self: &mut Self, units: &UnitSystem) {
+        let player_id = self.player_id;
+        let is_own_unit = |entity_id: &TEntityID| match units.get_unit(entity_id) {
+            Some(unit) => unit.owner == player_id,
+            None => false,
+        };
+        self.selected.retain(is_own_unit);
+        for group in self.control_groups.iter_mut() {
+            group.retain(is_own_unit);
+        }
+    }
+
+    // the command giving the order to every selected unit, None when there is nothing selected
+    // or the order is not one a player can give (Idle)
+    pub fn get_order_command(self: &Self, order: UnitOrders) -> Option<PlayerCommands> {
+        if self.selected.is_empty() {
+            return None;
+        }
+        let entity_ids = self.selected.clone();
+        return match order {
+            UnitOrders::Idle => None,
+            UnitOrders::Move { map_x, map_y } => Some(PlayerCommands::MoveUnits {
+                entity_ids,
+                map_x,
+                map_y,
+            }),
+            UnitOrders::AttackTarget { target_id } => Some(PlayerCommands::AttackTarget {
+                entity_ids,
+                target_id,
+            }),
+            UnitOrders::Patrol { to_x, to_y, .. } => Some(PlayerCommands::Patrol {
+                entity_ids,
+                map_x: to_x,
+                map_y: to_y,
+            }),
+            UnitOrders::Hold => Some(PlayerCommands::HoldPosition { entity_ids }),
+        };
+    }
+}
+
+/// The player's units on any cell within the rectangle (corners inclusive and in any order,
+/// clipped to the map), i.e. box-select
+pub fn get_units_in_rect(
+    map: &Map,
+    units: &UnitSystem,
+    player_id: TPlayerID,
+    corner: (u16, u16),
+    opposite_corner: (u16, u16),
+) -> Vec<TEntityID> {
+    let mut found = Vec::new();
+    if map.get_width() == 0 || map.get_height() == 0 {
+        return found;
+    }
+    // clipped first, so that a drag far off the map does not walk all of those cells
+    let right = corner.0.max(opposite_corner.0).min(map.get_width() - 1);
+    let bottom = corner.1.max(opposite_corner.1).min(map.get_height() - 1);
+    for map_y in corner.1.min(opposite_corner.1)..=bottom {
+        for map_x in corner.0.min(opposite_corner.0)..=right {
+            let cell = match map.get_cell_ref(map_x, map_y) {
+                Some(c) => c,
+                None => continue,
+            };
+            for layer in cell.layers.iter() {
+                if let Some(unit) = units.get_unit(&layer.entity) {
+                    if unit.owner == player_id {
+                        found.push(unit.entity_id);
+                    }
+                }
+            }
+        }
+    }
+    found.sort();
+    found.dedup();
+    return found;
+}
+
+// every one of the player's units spawned from the prototype (i.e. double-click on one)
+pub fn get_units_of_type(
+    units: &UnitSystem,
+    player_id: TPlayerID,
+    prototype_id: TPrototypeID,
+) -> Vec<TEntityID> {
+    return units
+        .get_units()
+        .iter()
+        .filter(|u| u.owner == player_id)
+        .filter(|u| {
+            entity_system::modify(&u.entity_id, |e| e.prototype_id) == Ok(Some(prototype_id))
+        })
+        .map(|u| u.entity_id)
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage_system::{self, Damage, DamageTypes};
+    use crate::prototype_system;
+    use crate::simulation::Simulation;
+    use crate::test_helpers::{add_prototype, new_simulation, TestWorld};
+
+    struct Squads {
+        simulation: Simulation,
+        unit_id: TPrototypeID,
+        mine: Vec<TEntityID>,
+        theirs: TEntityID,
+    }
+
+    // 4 units of player 1 in a square at (1, 1), and one of player 2's just below them
+    fn spawn_squads() -> Squads {
+        let unit_id = add_prototype("test unit", |p| {
+            p.max_health_points = 10;
+            p.max_velocity = 30; // a cell per tick
+        });
+        let mut simulation = new_simulation(16, 16);
+        let mut spawn = |player_id: TPlayerID, map_x: u16, map_y: u16| {
+            let entity_id = prototype_system::spawn(&unit_id, map_x, map_y).unwrap();
+            simulation
+                .map
+                .place_entity(map_x, map_y, entity_id)
+                .unwrap();
+            simulation.units.add_unit(&entity_id, player_id).unwrap();
+            return entity_id;
+        };
+        let mine: Vec<TEntityID> = vec![
+            spawn(1, 1, 1),
+            spawn(1, 2, 1),
+            spawn(1, 1, 2),
+            spawn(1, 2, 2),
+        ];
+        let theirs = spawn(2, 2, 3);
+        return Squads {
+            simulation,
+            unit_id,
+            mine,
+            theirs,
+        };
+    }
+
+    #[test]
+    fn test_box_select_only_own_units() {
+        let _world = TestWorld::new();
+        let squads = spawn_squads();
+        let (map, units) = (&squads.simulation.map, &squads.simulation.units);
+        // corners given either way around
+        assert_eq!(
+            get_units_in_rect(map, units, 1, (3, 3), (0, 0)),
+            squads.mine
+        );
+        let whole_map = (u16::MAX, u16::MAX);
+        assert_eq!(
+            get_units_in_rect(map, units, 1, (0, 0), whole_map),
+            squads.mine
+        );
+        assert!(get_units_in_rect(map, units, 1, (20, 0), whole_map).is_empty());
+        assert_eq!(
+            get_units_of_type(units, 2, squads.unit_id),
+            vec![squads.theirs]
+        );
+    }
+
+    #[test]
+    fn test_control_groups_forget_the_dead() {
+        let _world = TestWorld::new();
+        let mut squads = spawn_squads();
+        let mut selection = Selection::new(1);
+        selection.select(&squads.mine);
+        selection.assign_group(1).unwrap();
+        selection.clear();
+        assert!(selection.assign_group(CONTROL_GROUP_COUNT).is_err());
+
+        let killing_blow = Damage::new(10, DamageTypes::Physical, None);
+        damage_system::apply_damage(&squads.mine[3], &killing_blow).unwrap();
+        squads.simulation.step_once();
+        selection.prune(&squads.simulation.units);
+        assert_eq!(
+            selection.recall_group(1).unwrap(),
+            &squads.mine[0..3].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_move_in_formation() {
+        let _world = TestWorld::new();
+        let mut squads = spawn_squads();
+        let mut selection = Selection::new(1);
+        selection.select(&squads.mine);
+        let command = selection
+            .get_order_command(UnitOrders::Move {
+                map_x: 10,
+                map_y: 10,
+            })
+            .unwrap();
+        squads.simulation.queue_command(1, command);
+        for _ in 0..30 {
+            squads.simulation.step_once();
+        }
+        // spread around the cell they were sent to
+        let mut cells: Vec<(u16, u16)> = squads
+            .mine
+            .iter()
+            .map(|id| entity_system::try_get(*id).unwrap())
+            .map(|e| (e.map_x, e.map_y))
+            .collect();
+        cells.sort();
+        cells.dedup();
+        assert_eq!(cells.len(), 4);
+        assert!(cells.contains(&(10, 10)));
+        assert!(cells
+            .iter()
+            .all(|c| c.0.abs_diff(10) <= 1 && c.1.abs_diff(10) <= 1));
+    }
+}
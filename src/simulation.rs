@@ -0,0 +1,546 @@
+// Fixed-timestep driver for the whole game state.  Wall-clock time only decides how many ticks
+// are due; every tick is TICKS_PER_SECOND-th of a second of game time regardless of how fast the
+// machine is, and all systems are stepped in the same order with integer math, so that the same
+// inputs always end up in the same state (which is what replays and lockstep rely on)
+use crate::behaviour_system::BehaviourSystem;
+use crate::command_system::{self, IssuedCommand, PlayerCommands};
+use crate::computer_player_system::ComputerPlayer;
+use crate::conveyor_system::{ConveyorNetwork, DeliveredItem};
+use crate::crafting_system::CraftingSystem;
+use crate::damage_system::{self, DeathEvent};
+use crate::diplomacy_system::{Diplomacy, Relations};
+use crate::economy_system::{Economy, ItemStack, TPlayerID, FULL_REFUND_PERCENT};
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::Map;
+use crate::mining_system::MiningSystem;
+use crate::power_system::PowerGrid;
+use crate::production_system::ProductionSystem;
+use crate::prototype_system::{self, TPrototypeID};
+use crate::random::{RandomStreams, RngStreamTypes};
+use crate::status_effect_system::{self, Aura};
+use crate::tech_system::{TResearchID, TechSystem};
+use crate::unit_system::{self, UnitSystem};
+use crate::upgrade_system::UpgradeTree;
+use crate::wave_system::{WaveEvents, WaveScheduler};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::time::Duration;
+
+pub const TICKS_PER_SECOND: u64 = 30;
+pub const MAX_TICKS_PER_ADVANCE: usize = 10; // when the host falls further behind than this, the rest is dropped rather than trying to catch up
+
+pub type TTick = u64;
+
+// 33, 33, 34, ... so that every TICKS_PER_SECOND ticks add up to exactly a second
+pub fn get_tick_millis(tick: TTick) -> u128 {
+    return (((tick + 1) * 1000 / TICKS_PER_SECOND) - (tick * 1000 / TICKS_PER_SECOND)) as u128;
+}
+
+// game time at the start of the tick
+pub fn get_tick_start_millis(tick: TTick) -> u128 {
+    return (tick * 1000 / TICKS_PER_SECOND) as u128;
+}
+
+/// Turns wall-clock frame times into whole ticks, carrying over the remainder (in microseconds)
+/// so that no time is lost between frames
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TickClock {
+    tick: TTick, // next tick to run
+    accumulator_micros: u128,
+}
+
+impl TickClock {
+    pub fn new() -> TickClock {
+        TickClock {
+            tick: 0,
+            accumulator_micros: 0,
+        }
+    }
+    pub fn get_tick(self: &Self) -> TTick {
+        return self.tick;
+    }
+
+    // the ticks that are now due (in order), each paired with its millis
+    pub fn accumulate(self: &mut Self, elapsed: Duration) -> Vec<(TTick, u128)> {
+        self.accumulator_micros += elapsed.as_micros();
+        let mut due = Vec::new();
+        while self.accumulator_micros >= get_tick_millis(self.tick) * 1000 {
+            if due.len() >= MAX_TICKS_PER_ADVANCE {
+                self.accumulator_micros = 0;
+                break;
+            }
+            self.accumulator_micros -= get_tick_millis(self.tick) * 1000;
+            due.push((self.tick, get_tick_millis(self.tick)));
+            self.tick += 1;
+        }
+        return due;
+    }
+
+    // i.e. how long the host can sleep before it has something to do
+    pub fn get_time_until_next_tick(self: &Self) -> Duration {
+        let next_micros = get_tick_millis(self.tick) * 1000;
+        return Duration::from_micros(next_micros.saturating_sub(self.accumulator_micros) as u64);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TickReport {
+    pub tick: TTick,
+    pub commands: Vec<(IssuedCommand, Result<(), String>)>, // in the order they were queued
+    // what the computer players did, applied right after the commands; not part of replays,
+    // which play the computers again
+    pub computer_commands: Vec<(IssuedCommand, Result<(), String>)>,
+    pub wave_events: Vec<WaveEvents>,
+    pub death_events: Vec<DeathEvent>,
+    pub delivered: Vec<DeliveredItem>, // items that reached sinks (not factory ports)
+    pub produced: Vec<TEntityID>,      // units that came out of production
+    pub researched: Vec<(TPlayerID, TResearchID)>,
+    pub bounties: Vec<(TPlayerID, ItemStack)>, // paid out for this tick's kills
+}
+
+/// Owns everything but the entities themselves (entity_system is still a singleton), which
+/// means that there can only be one running Simulation per process
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Simulation {
+    pub map: Map,
+    pub economy: Economy,
+    pub waves: WaveScheduler,
+    pub mining: MiningSystem,
+    pub conveyors: ConveyorNetwork,
+    pub power: PowerGrid,
+    pub crafting: CraftingSystem,
+    pub rng: RandomStreams, // every random roll of the match comes from here, never thread_rng()
+    clock: TickClock,
+    pending_commands: Vec<(TPlayerID, PlayerCommands)>,
+    #[serde(default)] // last, so that saves from before units and upgrades still load
+    pub units: UnitSystem,
+    #[serde(default)]
+    pub upgrades: UpgradeTree,
+    #[serde(default)]
+    pub production: ProductionSystem,
+    #[serde(default)]
+    pub tech: TechSystem,
+    #[serde(default)]
+    pub diplomacy: Diplomacy,
+    #[serde(default)]
+    pub behaviours: BehaviourSystem,
+    #[serde(default)] // none, for saves of matches without computer opponents
+    pub computers: Vec<ComputerPlayer>,
+}
+
+/// The whole state of the match between two ticks, including the entities which live outside of
+/// the Simulation; prototypes are NOT included, they are game data that is loaded up front
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SimulationSnapshot {
+    pub simulation: Simulation,
+    pub entities: Vec<Entity>,
+}
+
+impl Simulation {
+    // the same seed (and the same inputs) plays out the same match
+    pub fn new(map: Map, waves: WaveScheduler, crafting: CraftingSystem, seed: u64) -> Simulation {
+        Simulation {
+            map,
+            economy: Economy::new(),
+            waves,
+            mining: MiningSystem::new(),
+            conveyors: ConveyorNetwork::new(),
+            power: PowerGrid::new(),
+            crafting,
+            rng: RandomStreams::new(seed),
+            clock: TickClock::new(),
+            pending_commands: Vec::new(),
+            units: UnitSystem::new(),
+            upgrades: UpgradeTree::default(),
+            production: ProductionSystem::new(),
+            tech: TechSystem::default(),
+            diplomacy: Diplomacy::new(),
+            behaviours: BehaviourSystem::default(),
+            computers: Vec::new(),
+        }
+    }
+
+    pub fn snapshot(self: &Self) -> SimulationSnapshot {
+        return SimulationSnapshot {
+            simulation: self.clone(),
+            entities: entity_system::snapshot(),
+        };
+    }
+    // NOTE: also replaces every entity in entity_system
+    pub fn restore(snapshot: &SimulationSnapshot) -> Simulation {
+        entity_system::restore(snapshot.entities.clone());
+        return snapshot.simulation.clone();
+    }
+
+    // applied (in the order queued) at the start of the next tick, see command_system::apply()
+    pub fn queue_command(self: &mut Self, player_id: TPlayerID, command: PlayerCommands) {
+        self.pending_commands.push((player_id, command));
+    }
+
+    // the next tick to be run, which is also the number of ticks run so far
+    pub fn get_tick(self: &Self) -> TTick {
+        return self.clock.get_tick();
+    }
+    pub fn get_game_time_millis(self: &Self) -> u128 {
+        return get_tick_start_millis(self.get_tick());
+    }
+    pub fn get_time_until_next_tick(self: &Self) -> Duration {
+        return self.clock.get_time_until_next_tick();
+    }
+
+    // runs whichever ticks have become due since the last frame
+    pub fn advance(self: &mut Self, elapsed: Duration) -> Vec<TickReport> {
+        let due = self.clock.accumulate(elapsed);
+        return due
+            .into_iter()
+            .map(|(tick, tick_millis)| self.step(tick, tick_millis))
+            .collect();
+    }
+
+    // runs exactly one tick, regardless of wall-clock time (i.e. headless, tests, replays)
+    pub fn step_once(self: &mut Self) -> TickReport {
+        let tick = self.clock.tick;
+        self.clock.tick += 1;
+        return self.step(tick, get_tick_millis(tick));
+    }
+
+    // registers a placed structure with every system its prototype takes part in
+    pub fn add_structure(
+        self: &mut Self,
+        owner: TPlayerID,
+        entity_id: &TEntityID,
+    ) -> Result<(), String> {
+        let prototype = match entity_system::modify(entity_id, |e| e.prototype_id)?
+            .and_then(|id| prototype_system::get(&id))
+        {
+            Some(p) => p,
+            None => return Err(format!("entityID={} has no prototype", entity_id)),
+        };
+        self.tech.add_structure(entity_id, owner);
+        if prototype.mining_millis_per_item > 0 {
+            self.mining.add_drill(entity_id, owner)?;
+        }
+        if prototype.power.is_some() {
+            self.power.add_structure(&self.map, entity_id)?;
+        }
+        if prototype.crafting_recipe_id.is_some() {
+            self.crafting
+                .add_factory(&self.map, &mut self.conveyors, entity_id)?;
+        }
+        if prototype.trains.is_empty() == false || prototype.supply_provided > 0 {
+            self.production.add_structure(entity_id, owner)?;
+        }
+        return Ok(());
+    }
+
+    // takes a structure (or unit) off every system it may be part of (i.e. sold or destroyed)
+    pub fn remove_structure(self: &mut Self, entity_id: &TEntityID) {
+        self.units.remove(entity_id);
+        self.behaviours.remove(entity_id);
+        self.tech.remove_structure(entity_id);
+        if let Some((owner, costs)) = self.production.remove_structure(entity_id) {
+            // whatever was still queued is refunded, same as cancelling it
+            let _ = self.economy.refund(owner, &costs, FULL_REFUND_PERCENT);
+        }
+        self.power.remove_structure(entity_id);
+        self.mining.remove_drill(entity_id);
+        self.crafting.remove_factory(&mut self.conveyors, entity_id);
+    }
+
+    // every living entity whose prototype has an aura applies it around itself, in entityID order
+    fn apply_auras(self: &Self) {
+        let auras: Vec<(TPrototypeID, Aura)> = prototype_system::get_all()
+            .iter()
+            .filter_map(|p| p.aura.map(|aura| (p.id, aura)))
+            .collect();
+        if auras.is_empty() {
+            return;
+        }
+        for emitter in entity_system::snapshot() {
+            let aura = match emitter
+                .prototype_id
+                .and_then(|id| auras.binary_search_by(|(p, _)| p.cmp(&id)).ok())
+            {
+                Some(index) if emitter.is_alive() => auras[index].1,
+                _ => continue,
+            };
+            let mut effect = aura.effect;
+            effect.source = Some(emitter.id);
+            status_effect_system::apply_aura(
+                &self.map,
+                emitter.map_x,
+                emitter.map_y,
+                aura.radius,
+                effect,
+                |target| {
+                    let relation = self.diplomacy.get_relation(emitter.owner, target.owner);
+                    return match aura.affects_enemies {
+                        true => relation == Relations::Enemy,
+                        false => relation == Relations::Ally,
+                    };
+                },
+            );
+        }
+    }
+
+    // armed structures shoot at the nearest enemy in range, same as idle units do (lowest
+    // entityID first, so that the order of the shots is the same on every machine)
+    fn fire_towers(self: &Self) {
+        for tower in entity_system::snapshot() {
+            if tower.is_structure() == false
+                || tower.weapon.is_armed() == false
+                || tower.is_alive() == false
+            {
+                continue;
+            }
+            if let Some(target_id) =
+                self.diplomacy
+                    .get_nearest_enemy(&self.map, &tower.id, tower.weapon.range)
+            {
+                unit_system::fire(&tower.id, &target_id);
+            }
+        }
+    }
+
+    // every computer player's commands for the tick, as that player
+    fn think_computers(self: &mut Self, tick: TTick) -> Vec<(TPlayerID, PlayerCommands)> {
+        let mut computers = std::mem::take(&mut self.computers);
+        let mut commands = Vec::new();
+        for computer in computers.iter_mut() {
+            for command in computer.think(self, tick) {
+                commands.push((computer.player_id, command));
+            }
+        }
+        self.computers = computers;
+        return commands;
+    }
+
+    fn apply_commands(
+        self: &mut Self,
+        tick: TTick,
+        commands: Vec<(TPlayerID, PlayerCommands)>,
+    ) -> Vec<(IssuedCommand, Result<(), String>)> {
+        let mut applied = Vec::new();
+        for (player_id, command) in commands {
+            let result = command_system::apply(self, player_id, &command);
+            let issued = IssuedCommand {
+                tick,
+                player_id,
+                command,
+            };
+            applied.push((issued, result));
+        }
+        return applied;
+    }
+
+    fn step(self: &mut Self, tick: TTick, tick_millis: u128) -> TickReport {
+        // the computer players decide on the state everyone else's commands were issued on
+        let thought = self.think_computers(tick);
+        let pending_commands = std::mem::take(&mut self.pending_commands);
+        let commands = self.apply_commands(tick, pending_commands);
+        let computer_commands = self.apply_commands(tick, thought);
+
+        // power first, so that consumers are throttled based on this tick's supply
+        self.power.update(tick_millis);
+        entity_system::update(tick_millis, 0); // no time slicing, every entity every tick
+        self.apply_auras(); // right after the durations were ticked, so they never lapse
+        let wave_events = self.waves.update(
+            tick_millis,
+            &mut self.map,
+            self.rng.stream(RngStreamTypes::Spawning),
+        );
+        for event in wave_events.iter() {
+            if let WaveEvents::EntitySpawned { entity_id, .. } = event {
+                // a prototype whose behaviour does not exist just stands there
+                let _ = self.behaviours.add_agent(entity_id);
+            }
+        }
+        let produced = self
+            .production
+            .update(tick_millis, &mut self.map, &mut self.units);
+        for entity_id in produced.iter() {
+            if let Some(unit) = self.units.get_unit(entity_id) {
+                self.tech.apply_completed_modifiers(entity_id, unit.owner);
+            }
+        }
+        let researched = self.tech.update(tick_millis, &self.units);
+        self.units
+            .update(tick_millis, &mut self.map, &self.diplomacy);
+        self.fire_towers();
+        self.behaviours
+            .update(tick_millis, &mut self.map, &self.diplomacy);
+
+        // mined items go onto adjacent conveyors, or straight to the owner when there is no room
+        for (drill_id, owner, items) in self.mining.update(tick_millis, &mut self.map) {
+            let footprint = match self
+                .mining
+                .get_drills()
+                .iter()
+                .find(|d| d.entity_id == drill_id)
+            {
+                Some(drill) => drill.footprint.clone(),
+                None => continue,
+            };
+            let mut leftover: Vec<ItemStack> = Vec::new();
+            for stack in items {
+                let mut amount = 0;
+                for _ in 0..stack.amount {
+                    if self.conveyors.insert_adjacent(&footprint, stack.item) == false {
+                        amount += 1;
+                    }
+                }
+                if amount > 0 {
+                    leftover.push(ItemStack::new(stack.item, amount));
+                }
+            }
+            // a player without a ledger (i.e. neutral drills) simply does not collect
+            let _ = self.economy.earn(owner, &leftover);
+        }
+        self.conveyors.update(tick_millis);
+        self.crafting.update(tick_millis, &mut self.conveyors);
+        let delivered = self.conveyors.take_delivered();
+
+        let death_events = damage_system::remove_dead(&mut self.map);
+        for event in death_events.iter() {
+            self.remove_structure(&event.entity.id);
+        }
+        let bounties = self.economy.award_kill_bounties(&death_events);
+        return TickReport {
+            tick,
+            commands,
+            computer_commands,
+            wave_events,
+            death_events,
+            delivered,
+            produced,
+            researched,
+            bounties,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diplomacy_system::CREEP_PLAYER_ID;
+    use crate::economy_system::ItemTypes;
+    use crate::prototype_system::{self, TPrototypeID};
+    use crate::status_effect_system::{self, StatusEffect, StatusEffectTypes};
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+    use crate::wave_system::{SpawnGroup, WaveDefinition};
+
+    // runs a wave of poisoned creeps for (at least) the given ticks, either with the given frame
+    // times or by stepping a tick at a time
+    fn run_match(
+        creep_id: TPrototypeID,
+        frame_micros: Option<&[u64]>,
+        ticks: TTick,
+    ) -> (Simulation, Vec<entity_system::Entity>, usize) {
+        entity_system::reset();
+        let waves = WaveScheduler::new(vec![WaveDefinition {
+            delay_millis: 1000,
+            early_call_bonus_per_second: 0,
+            groups: vec![SpawnGroup {
+                prototype_id: creep_id,
+                count: 5,
+                spacing_millis: 700,
+                delay_millis: 0,
+                spawn_x: 1,
+                spawn_y: 1,
+                spawn_radius: 3,
+            }],
+        }])
+        .unwrap();
+        let mut simulation = Simulation::new(
+            Map::create(8, 8).unwrap(),
+            waves,
+            CraftingSystem::new(Vec::new()).unwrap(),
+            1234,
+        );
+
+        let mut deaths = 0;
+        let mut frame_index = 0;
+        while simulation.get_tick() < ticks {
+            let reports = match frame_micros {
+                Some(frames) => {
+                    frame_index += 1;
+                    simulation.advance(Duration::from_micros(frames[frame_index % frames.len()]))
+                }
+                None => vec![simulation.step_once()],
+            };
+            for report in reports {
+                for event in report.wave_events {
+                    if let WaveEvents::EntitySpawned { entity_id, .. } = event {
+                        let mut poison = StatusEffect::new(StatusEffectTypes::Poison, 3, 5000);
+                        poison.tick_interval_millis = 333;
+                        status_effect_system::apply_status_effect(&entity_id, poison).unwrap();
+                    }
+                }
+                deaths += report.death_events.len();
+            }
+        }
+        return (simulation, entity_system::snapshot(), deaths);
+    }
+
+    #[test]
+    fn test_ticks_add_up_to_a_second() {
+        assert_eq!(
+            (0..TICKS_PER_SECOND).map(get_tick_millis).sum::<u128>(),
+            1000
+        );
+    }
+
+    #[test]
+    fn test_same_inputs_same_state() {
+        let _world = TestWorld::new();
+        let creep_id = add_prototype("test creep", |p| p.max_health_points = 20);
+        // a jittery host against stepping a tick at a time, same ticks so the same results
+        let (jittery, jittery_entities, jittery_deaths) = run_match(
+            creep_id,
+            Some(&[5_000, 48_000, 1_000, 120_000, 33_000, 16_667]),
+            10 * TICKS_PER_SECOND,
+        );
+        let (stepped, stepped_entities, stepped_deaths) =
+            run_match(creep_id, None, jittery.get_tick());
+        assert_eq!(stepped.get_tick(), jittery.get_tick());
+        assert_eq!(stepped.map, jittery.map);
+        assert_eq!(stepped.waves, jittery.waves);
+        assert_eq!(stepped.economy, jittery.economy);
+        assert_eq!(stepped.rng, jittery.rng);
+        assert_eq!(stepped_entities, jittery_entities);
+        assert_eq!(stepped_deaths, jittery_deaths);
+        assert!(stepped_deaths > 0 && stepped_entities.len() < 5);
+    }
+
+    #[test]
+    fn test_kills_pay_the_killers_owner() {
+        let _world = TestWorld::new();
+        let creep_prototype_id = add_prototype("test creep", |p| {
+            p.max_health_points = 20;
+            p.bounty = vec![ItemStack::new(ItemTypes::Gold, 7)];
+        });
+        let soldier_prototype_id = add_prototype("test soldier", |p| {
+            p.max_health_points = 50;
+            p.max_velocity = 10;
+            p.weapon.damage = 10;
+            p.weapon.range = 3;
+            p.weapon.fire_interval_millis = 100;
+        });
+        let mut simulation = new_simulation(8, 8);
+        add_player(&mut simulation, 1, 0);
+        let soldier_id = prototype_system::spawn(&soldier_prototype_id, 2, 2).unwrap();
+        simulation.map.place_entity(2, 2, soldier_id).unwrap();
+        simulation.units.add_unit(&soldier_id, 1).unwrap();
+        let creep_id = prototype_system::spawn(&creep_prototype_id, 4, 2).unwrap();
+        entity_system::modify(&creep_id, |e| e.owner = CREEP_PLAYER_ID).unwrap();
+        simulation.map.place_entity(4, 2, creep_id).unwrap();
+
+        let mut bounties = Vec::new();
+        for _ in 0..TICKS_PER_SECOND {
+            bounties.extend(simulation.step_once().bounties);
+        }
+        assert!(entity_system::try_get(creep_id).is_none());
+        assert_eq!(bounties, vec![(1, ItemStack::new(ItemTypes::Gold, 7))]);
+        assert_eq!(simulation.economy.get_balance(1, ItemTypes::Gold), 7);
+    }
+}
@@ -0,0 +1,467 @@
+// Desync detection.  A StateHash is a 64-bit FNV-1a over the Map, every Entity and the economy,
+// fed field by field in a fixed order (entities by id, ledgers by player id, cells row by row) so
+// that it does not depend on allocation order, serializer layout nor platform; two peers (or a
+// live run and its replay) that agree on it are in the same state.  Once hashes diverge, diff()
+// tells which cells/entities/ledgers actually differ, given both full states (i.e. save games).
+use crate::damage_system::{DefenseInfo, WeaponInfo};
+use crate::economy_system::{ItemTypes, PlayerLedger, TPlayerID};
+use crate::entity_system::{Entity, PhysicsObject, TEntityID};
+use crate::map::{Map, MapCell};
+use crate::simulation::{Simulation, TTick};
+use crate::status_effect_system::StatusEffect;
+use crate::sync_system::ClientState;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// integers are fed little endian, whatever the platform
+struct Fnv64 {
+    hash: u64,
+}
+
+impl Fnv64 {
+    fn new() -> Fnv64 {
+        Fnv64 {
+            hash: FNV_OFFSET_BASIS,
+        }
+    }
+    fn write(self: &mut Self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    fn write_u8(self: &mut Self, value: u8) {
+        self.write(&[value]);
+    }
+    fn write_u16(self: &mut Self, value: u16) {
+        self.write(&value.to_le_bytes());
+    }
+    fn write_i16(self: &mut Self, value: i16) {
+        self.write(&value.to_le_bytes());
+    }
+    fn write_u32(self: &mut Self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+    fn write_u64(self: &mut Self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+    fn write_u128(self: &mut Self, value: u128) {
+        self.write(&value.to_le_bytes());
+    }
+    // a leading tag, so that None and Some(0) do not hash the same
+    fn write_option_u16(self: &mut Self, value: Option<u16>) {
+        match value {
+            Some(v) => {
+                self.write_u8(1);
+                self.write_u16(v);
+            }
+            None => self.write_u8(0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct StateHash {
+    pub tick: TTick, // the state after this many ticks
+    pub map: u64,
+    pub entities: u64,
+    pub economy: u64,
+    pub total: u64, // of the three above, which is what peers compare first
+}
+
+impl StateHash {
+    pub fn from_simulation(simulation: &Simulation) -> StateHash {
+        return StateHash::compute(
+            simulation.get_tick(),
+            &simulation.map,
+            &crate::entity_system::snapshot(),
+            simulation.economy.get_ledgers(),
+        );
+    }
+    pub fn from_client_state(state: &ClientState) -> StateHash {
+        return StateHash::compute(state.tick, &state.map, &state.entities, &state.ledgers);
+    }
+
+    fn compute(tick: TTick, map: &Map, entities: &[Entity], ledgers: &[PlayerLedger]) -> StateHash {
+        let mut map_hash = Fnv64::new();
+        map_hash.write_u16(map.get_width());
+        map_hash.write_u16(map.get_height());
+        for (_, _, cell) in map.iter_cells() {
+            hash_cell(&mut map_hash, cell);
+        }
+
+        let mut sorted_entities: Vec<&Entity> = entities.iter().collect();
+        sorted_entities.sort_by_key(|e| e.id);
+        let mut entities_hash = Fnv64::new();
+        entities_hash.write_u32(sorted_entities.len() as u32);
+        for entity in sorted_entities {
+            hash_entity(&mut entities_hash, entity);
+        }
+
+        let mut sorted_ledgers: Vec<&PlayerLedger> = ledgers.iter().collect();
+        sorted_ledgers.sort_by_key(|l| l.player_id);
+        let mut economy_hash = Fnv64::new();
+        economy_hash.write_u32(sorted_ledgers.len() as u32);
+        for ledger in sorted_ledgers {
+            economy_hash.write_u8(ledger.player_id);
+            for item in ItemTypes::ALL {
+                economy_hash.write_u32(ledger.get_balance(item));
+                economy_hash.write_u32(ledger.get_total_earned(item));
+                economy_hash.write_u32(ledger.get_total_spent(item));
+            }
+        }
+
+        let mut total = Fnv64::new();
+        total.write_u64(tick);
+        total.write_u64(map_hash.hash);
+        total.write_u64(entities_hash.hash);
+        total.write_u64(economy_hash.hash);
+        return StateHash {
+            tick,
+            map: map_hash.hash,
+            entities: entities_hash.hash,
+            economy: economy_hash.hash,
+            total: total.hash,
+        };
+    }
+
+    // i.e. ["entities", "economy"], to narrow down where to look
+    pub fn get_differing_parts(self: &Self, other: &StateHash) -> Vec<&'static str> {
+        let mut parts = Vec::new();
+        if self.tick != other.tick {
+            parts.push("tick");
+        }
+        if self.map != other.map {
+            parts.push("map");
+        }
+        if self.entities != other.entities {
+            parts.push("entities");
+        }
+        if self.economy != other.economy {
+            parts.push("economy");
+        }
+        return parts;
+    }
+}
+
+fn hash_cell(hash: &mut Fnv64, cell: &MapCell) {
+    hash.write_u32(cell.layers.len() as u32);
+    for layer in cell.layers.iter() {
+        hash.write_u8(layer.id);
+        hash.write_u16(layer.entity);
+    }
+    match cell.deposit {
+        Some(deposit) => {
+            hash.write_u8(1);
+            hash.write_u8(deposit.item as u8);
+            match deposit.remaining {
+                Some(remaining) => {
+                    hash.write_u8(1);
+                    hash.write_u32(remaining);
+                }
+                None => hash.write_u8(0),
+            }
+        }
+        None => hash.write_u8(0),
+    }
+}
+
+// every field, so that no drift (not even a cooldown) goes unnoticed; the
+// patterns below list all of them on purpose, a new field will not compile
+// until it is hashed (or explicitly ignored) here
+fn hash_entity(hash: &mut Fnv64, entity: &Entity) {
+    let Entity {
+        id,
+        prototype_id,
+        map_x,
+        map_y,
+        sprites,
+        current_sprite_index,
+        layer_weight,
+        sprite_update_interval_reset,
+        last_sprite_update_millis,
+        health_points,
+        max_health_points,
+        mana_points,
+        defense,
+        weapon,
+        status_effects,
+        power_permille,
+        physics_info,
+        owner,
+    } = entity;
+    hash.write_u16(*id);
+    hash.write_option_u16(*prototype_id);
+    hash.write_u16(*map_x);
+    hash.write_u16(*map_y);
+    hash.write_u8(*sprites);
+    hash.write_u64(*current_sprite_index as u64);
+    hash.write_u8(*layer_weight);
+    hash.write_u128(*sprite_update_interval_reset);
+    hash.write_u128(*last_sprite_update_millis);
+    hash.write_u16(*health_points);
+    hash.write_u16(*max_health_points);
+    hash.write_u16(*mana_points);
+
+    let DefenseInfo {
+        armor,
+        resistances,
+        shield_points,
+        max_shield_points,
+    } = defense;
+    hash.write_u16(*armor);
+    hash.write(resistances);
+    hash.write_u16(*shield_points);
+    hash.write_u16(*max_shield_points);
+
+    let WeaponInfo {
+        damage,
+        damage_type,
+        range,
+        fire_interval_millis,
+        cooldown_millis,
+    } = weapon;
+    hash.write_u16(*damage);
+    hash.write_u8(*damage_type as u8);
+    hash.write_u16(*range);
+    hash.write_u128(*fire_interval_millis);
+    hash.write_u128(*cooldown_millis);
+
+    for effect in status_effects.iter() {
+        match effect {
+            Some(StatusEffect {
+                effect_type,
+                magnitude,
+                stacks,
+                max_stacks,
+                stacking,
+                duration_millis,
+                remaining_millis,
+                tick_interval_millis,
+                tick_elapsed_millis,
+                source,
+            }) => {
+                hash.write_u8(1);
+                hash.write_u8(*effect_type as u8);
+                hash.write_u16(*magnitude);
+                hash.write_u8(*stacks);
+                hash.write_u8(*max_stacks);
+                hash.write_u8(*stacking as u8);
+                hash.write_u128(*duration_millis);
+                hash.write_u128(*remaining_millis);
+                hash.write_u128(*tick_interval_millis);
+                hash.write_u128(*tick_elapsed_millis);
+                hash.write_option_u16(*source);
+            }
+            None => hash.write_u8(0),
+        }
+    }
+    hash.write_u16(*power_permille);
+
+    let PhysicsObject {
+        collision_type,
+        max_velocity,
+        max_acceleration,
+        current_velocity_x,
+        current_velocity_y,
+        current_acceleration_x,
+        current_acceleration_y,
+    } = physics_info;
+    hash.write_u8(*collision_type as u8);
+    hash.write_u8(*max_velocity);
+    hash.write_u8(*max_acceleration);
+    hash.write_i16(*current_velocity_x);
+    hash.write_i16(*current_velocity_y);
+    hash.write_u8(*current_acceleration_x as u8);
+    hash.write_u8(*current_acceleration_y as u8);
+    hash.write_u8(*owner);
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StateDifferences {
+    Tick {
+        left: TTick,
+        right: TTick,
+    },
+    MapSize {
+        left: (u16, u16),
+        right: (u16, u16),
+    },
+    Cell {
+        map_x: u16,
+        map_y: u16,
+        left: MapCell,
+        right: MapCell,
+    },
+    // None when the entity only exists on the other side
+    Entity {
+        entity_id: TEntityID,
+        left: Option<Entity>,
+        right: Option<Entity>,
+    },
+    Ledger {
+        player_id: TPlayerID,
+        left: Option<PlayerLedger>,
+        right: Option<PlayerLedger>,
+    },
+}
+
+// everything that differs between the two, cells row by row then entities and ledgers by id
+pub fn diff(left: &ClientState, right: &ClientState) -> Vec<StateDifferences> {
+    let mut differences = Vec::new();
+    if left.tick != right.tick {
+        differences.push(StateDifferences::Tick {
+            left: left.tick,
+            right: right.tick,
+        });
+    }
+    let left_size = (left.map.get_width(), left.map.get_height());
+    let right_size = (right.map.get_width(), right.map.get_height());
+    if left_size != right_size {
+        differences.push(StateDifferences::MapSize {
+            left: left_size,
+            right: right_size,
+        });
+    } else {
+        for ((map_x, map_y, left_cell), (_, _, right_cell)) in
+            left.map.iter_cells().zip(right.map.iter_cells())
+        {
+            if left_cell != right_cell {
+                differences.push(StateDifferences::Cell {
+                    map_x,
+                    map_y,
+                    left: left_cell.clone(),
+                    right: right_cell.clone(),
+                });
+            }
+        }
+    }
+
+    let mut entity_ids: Vec<TEntityID> = left
+        .entities
+        .iter()
+        .chain(right.entities.iter())
+        .map(|e| e.id)
+        .collect();
+    entity_ids.sort();
+    entity_ids.dedup();
+    for entity_id in entity_ids {
+        // not get_entity(), either side may not be sorted (i.e. hand edited)
+        let left_entity = left.entities.iter().find(|e| e.id == entity_id).copied();
+        let right_entity = right.entities.iter().find(|e| e.id == entity_id).copied();
+        if left_entity != right_entity {
+            differences.push(StateDifferences::Entity {
+                entity_id,
+                left: left_entity,
+                right: right_entity,
+            });
+        }
+    }
+
+    let mut player_ids: Vec<TPlayerID> = left
+        .ledgers
+        .iter()
+        .chain(right.ledgers.iter())
+        .map(|l| l.player_id)
+        .collect();
+    player_ids.sort();
+    player_ids.dedup();
+    for player_id in player_ids {
+        let left_ledger = left.ledgers.iter().find(|l| l.player_id == player_id);
+        let right_ledger = right.ledgers.iter().find(|l| l.player_id == player_id);
+        if left_ledger != right_ledger {
+            differences.push(StateDifferences::Ledger {
+                player_id,
+                left: left_ledger.cloned(),
+                right: right_ledger.cloned(),
+            });
+        }
+    }
+    return differences;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::{Economy, ItemStack};
+    use crate::map::OreDeposit;
+
+    #[test]
+    fn test_hash_is_stable_and_diff_finds_changes() {
+        let mut map = Map::create(6, 4).unwrap();
+        map.set_deposit(
+            2,
+            3,
+            Some(OreDeposit {
+                item: ItemTypes::Copper,
+                remaining: Some(40),
+            }),
+        )
+        .unwrap();
+        let mut tower = Entity::new(&3, &0, &0);
+        tower.health_points = 50;
+        let creep = Entity::new(&7, &0, &0);
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        let left = ClientState {
+            tick: 90,
+            map,
+            entities: vec![tower, creep],
+            ledgers: economy.get_ledgers().clone(),
+        };
+
+        // entity order does not matter, the content does
+        let mut right = left.clone();
+        right.entities.reverse();
+        assert_eq!(
+            StateHash::from_client_state(&left),
+            StateHash::from_client_state(&right)
+        );
+        assert!(diff(&left, &right).is_empty());
+
+        right.entities.retain(|e| e.id == 3);
+        right.entities[0].health_points = 49;
+        right.map.extract_deposit(2, 3, 1).unwrap();
+        economy
+            .earn(1, &vec![ItemStack::new(ItemTypes::Gold, 1)])
+            .unwrap();
+        right.ledgers = economy.get_ledgers().clone();
+        let left_hash = StateHash::from_client_state(&left);
+        let right_hash = StateHash::from_client_state(&right);
+        assert_ne!(left_hash.total, right_hash.total);
+        assert_eq!(
+            left_hash.get_differing_parts(&right_hash),
+            vec!["map", "entities", "economy"]
+        );
+
+        let differences = diff(&left, &right);
+        assert_eq!(differences.len(), 4);
+        assert!(matches!(
+            differences[0],
+            StateDifferences::Cell {
+                map_x: 2,
+                map_y: 3,
+                ..
+            }
+        ));
+        assert!(matches!(
+            differences[1],
+            StateDifferences::Entity { entity_id: 3, .. }
+        ));
+        assert!(matches!(
+            differences[2],
+            StateDifferences::Entity {
+                entity_id: 7,
+                right: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            differences[3],
+            StateDifferences::Ledger { player_id: 1, .. }
+        ));
+    }
+}
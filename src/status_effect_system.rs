@@ -0,0 +1,444 @@
+// Status effects (slows, poison, stuns, aura buffs, etc) attached to entities.  Effects are stored
+// in a fixed sized array on the Entity (so that Entity can remain Copy and gets serialized along
+// with it), and are ticked from entity_system::update() via Entity::update()
+use crate::damage_system::{Damage, DamageTypes};
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::Map;
+use serde_derive::{Deserialize, Serialize};
+
+pub const MAX_STATUS_EFFECTS_PER_ENTITY: usize = 8;
+pub const BASE_PERCENT: u16 = 100; // all modifiers are in percent, 100% means unmodified
+pub const MAX_MODIFIER_PERCENT: u16 = 500;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum StatusEffectTypes {
+    Slow,       // reduces movement speed by magnitude% per stack
+    Haste,      // increases movement speed by magnitude% per stack
+    Stun,       // cannot move nor fire
+    Poison,     // magnitude (per stack) of Poison damage per tick_interval
+    Burn,       // magnitude (per stack) of Fire damage per tick_interval
+    Vulnerable, // increases damage taken by magnitude% per stack
+    Fortified,  // reduces damage taken by magnitude% per stack
+    Rallied,    // increases fire rate by magnitude% per stack (i.e. aura from a command tower)
+    Jammed,     // reduces fire rate by magnitude% per stack
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum StackingRules {
+    Refresh, // re-applying only restarts it with the new duration (and keeps the stronger magnitude)
+    Stack,   // re-applying adds a stack (up to max_stacks) and resets the duration
+    Ignore,  // re-applying has no effect until the current one expires
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct StatusEffect {
+    pub effect_type: StatusEffectTypes,
+    pub magnitude: u16, // per stack, see StatusEffectTypes on how it is interpreted
+    pub stacks: u8,
+    pub max_stacks: u8,
+    pub stacking: StackingRules,
+    pub duration_millis: u128, // reset value for remaining_millis
+    pub remaining_millis: u128,
+    pub tick_interval_millis: u128, // only for damage over time effects
+    pub tick_elapsed_millis: u128,
+    pub source: Option<TEntityID>,
+}
+impl StatusEffect {
+    pub fn new(
+        effect_type: StatusEffectTypes,
+        magnitude: u16,
+        duration_millis: u128,
+    ) -> StatusEffect {
+        StatusEffect {
+            effect_type,
+            magnitude,
+            stacks: 1,
+            max_stacks: 1,
+            stacking: StackingRules::Refresh,
+            duration_millis,
+            remaining_millis: duration_millis,
+            tick_interval_millis: 1000,
+            tick_elapsed_millis: 0,
+            source: None,
+        }
+    }
+    pub fn total_magnitude(self: &Self) -> u32 {
+        return self.magnitude as u32 * self.stacks as u32;
+    }
+    fn damage_over_time_type(self: &Self) -> Option<DamageTypes> {
+        return match self.effect_type {
+            StatusEffectTypes::Poison => Some(DamageTypes::Poison),
+            StatusEffectTypes::Burn => Some(DamageTypes::Fire),
+            _ => None,
+        };
+    }
+}
+
+impl Entity {
+    // returns false if the effect was not applied (ignored, or no more slots available)
+    pub fn add_status_effect(self: &mut Self, effect: StatusEffect) -> bool {
+        let existing = self
+            .status_effects
+            .iter_mut()
+            .flatten()
+            .find(|e| e.effect_type == effect.effect_type);
+        match existing {
+            Some(current) => match current.stacking {
+                StackingRules::Refresh => {
+                    current.magnitude = current.magnitude.max(effect.magnitude);
+                    current.duration_millis = effect.duration_millis;
+                    current.remaining_millis = effect.duration_millis;
+                    true
+                }
+                StackingRules::Stack => {
+                    current.stacks = current
+                        .stacks
+                        .saturating_add(1)
+                        .min(current.max_stacks.max(1));
+                    current.remaining_millis = current.duration_millis;
+                    true
+                }
+                StackingRules::Ignore => false,
+            },
+            None => match self.status_effects.iter_mut().find(|e| e.is_none()) {
+                Some(free_slot) => {
+                    *free_slot = Some(effect);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+    pub fn remove_status_effect(self: &mut Self, effect_type: StatusEffectTypes) -> bool {
+        let mut removed = false;
+        for slot in self.status_effects.iter_mut() {
+            if slot.map(|e| e.effect_type == effect_type).unwrap_or(false) {
+                *slot = None;
+                removed = true;
+            }
+        }
+        return removed;
+    }
+    pub fn has_status_effect(self: &Self, effect_type: StatusEffectTypes) -> bool {
+        return self
+            .status_effects
+            .iter()
+            .flatten()
+            .any(|e| e.effect_type == effect_type);
+    }
+
+    // sum of all (stacked) magnitudes of the given type
+    fn status_magnitude(self: &Self, effect_type: StatusEffectTypes) -> i32 {
+        return self
+            .status_effects
+            .iter()
+            .flatten()
+            .filter(|e| e.effect_type == effect_type)
+            .map(|e| e.total_magnitude() as i32)
+            .sum();
+    }
+    fn clamp_percent(percent: i32) -> u16 {
+        return percent.clamp(0, MAX_MODIFIER_PERCENT as i32) as u16;
+    }
+    pub fn movement_speed_percent(self: &Self) -> u16 {
+        if self.has_status_effect(StatusEffectTypes::Stun) {
+            return 0;
+        }
+        return Entity::clamp_percent(
+            BASE_PERCENT as i32 - self.status_magnitude(StatusEffectTypes::Slow)
+                + self.status_magnitude(StatusEffectTypes::Haste),
+        );
+    }
+    pub fn damage_taken_percent(self: &Self) -> u16 {
+        return Entity::clamp_percent(
+            BASE_PERCENT as i32 + self.status_magnitude(StatusEffectTypes::Vulnerable)
+                - self.status_magnitude(StatusEffectTypes::Fortified),
+        );
+    }
+    pub fn fire_rate_percent(self: &Self) -> u16 {
+        if self.has_status_effect(StatusEffectTypes::Stun) {
+            return 0;
+        }
+        return Entity::clamp_percent(
+            BASE_PERCENT as i32 + self.status_magnitude(StatusEffectTypes::Rallied)
+                - self.status_magnitude(StatusEffectTypes::Jammed),
+        );
+    }
+    // max_velocity (grids per second) after slows/hastes
+    pub fn effective_max_velocity(self: &Self) -> u8 {
+        return (self.physics_info.max_velocity as u32 * self.movement_speed_percent() as u32
+            / BASE_PERCENT as u32)
+            .min(u8::MAX as u32) as u8;
+    }
+
+    // ticks the durations and returns the damages over time that are due during this frame
+    pub fn update_status_effects(self: &mut Self, last_frame_delta_millis: u128) -> Vec<Damage> {
+        let mut damages = Vec::new();
+        for slot in self.status_effects.iter_mut() {
+            if let Some(effect) = slot {
+                let elapsed = last_frame_delta_millis.min(effect.remaining_millis);
+                if let Some(damage_type) = effect.damage_over_time_type() {
+                    if effect.tick_interval_millis > 0 {
+                        effect.tick_elapsed_millis += elapsed;
+                        while effect.tick_elapsed_millis >= effect.tick_interval_millis {
+                            effect.tick_elapsed_millis -= effect.tick_interval_millis;
+                            damages.push(Damage::new(
+                                effect.total_magnitude().min(u16::MAX as u32) as u16,
+                                damage_type,
+                                effect.source,
+                            ));
+                        }
+                    }
+                }
+                effect.remaining_millis -= elapsed;
+                if effect.remaining_millis == 0 {
+                    *slot = None; // expired
+                }
+            }
+        }
+        return damages;
+    }
+}
+
+pub fn apply_status_effect(target: &TEntityID, effect: StatusEffect) -> Result<bool, String> {
+    return entity_system::modify(target, |entity| entity.add_status_effect(effect));
+}
+
+pub fn remove_status_effect(
+    target: &TEntityID,
+    effect_type: StatusEffectTypes,
+) -> Result<bool, String> {
+    return entity_system::modify(target, |entity| entity.remove_status_effect(effect_type));
+}
+
+/// An effect that an entity keeps applying around itself (see EntityPrototype::aura), i.e. a
+/// command tower rallying nearby towers or a jammer slowing down enemy fire
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Aura {
+    pub radius: u16,
+    pub effect: StatusEffect, // short duration and StackingRules::Refresh, see apply_aura()
+    pub affects_enemies: bool, // the emitter's enemies when true, else its allies (itself excluded)
+}
+
+/// Applies the effect to every entity within radius (square, in grids) of the center that the
+/// filter accepts, which is meant to be called on each update by the aura emitter with a short
+/// duration and StackingRules::Refresh so that the buff wears off shortly after leaving the aura
+/// (see Simulation::step()).  Returns the number of entities the effect was applied to
+pub fn apply_aura(
+    map: &Map,
+    center_x: u16,
+    center_y: u16,
+    radius: u16,
+    effect: StatusEffect,
+    filter: impl Fn(&Entity) -> bool,
+) -> usize {
+    let mut affected: Vec<TEntityID> = Vec::new();
+    let min_x = center_x.saturating_sub(radius);
+    let min_y = center_y.saturating_sub(radius);
+    let max_x = center_x
+        .saturating_add(radius)
+        .min(map.get_width().saturating_sub(1));
+    let max_y = center_y
+        .saturating_add(radius)
+        .min(map.get_height().saturating_sub(1));
+    for map_y in min_y..=max_y {
+        for map_x in min_x..=max_x {
+            if let Ok(cell) = map.get_cell(map_x, map_y) {
+                for layer in cell.layers {
+                    if affected.contains(&layer.entity) == false
+                        && Some(layer.entity) != effect.source
+                    {
+                        affected.push(layer.entity);
+                    }
+                }
+            }
+        }
+    }
+    return affected
+        .iter()
+        .filter(|entity_id| {
+            entity_system::modify(entity_id, |e| filter(e) && e.add_status_effect(effect))
+                .unwrap_or(false)
+        })
+        .count();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::TPlayerID;
+    use crate::prototype_system;
+    use crate::simulation::Simulation;
+    use crate::test_helpers::{add_prototype, new_simulation, TestWorld};
+
+    fn new_slow(max_stacks: u8) -> StatusEffect {
+        let mut slow = StatusEffect::new(StatusEffectTypes::Slow, 20, 1000);
+        slow.stacking = StackingRules::Stack;
+        slow.max_stacks = max_stacks;
+        return slow;
+    }
+
+    fn new_stun() -> StatusEffect {
+        let mut stun = StatusEffect::new(StatusEffectTypes::Stun, 0, 500);
+        stun.stacking = StackingRules::Ignore;
+        return stun;
+    }
+
+    #[test]
+    fn test_stacks_are_capped() {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        entity.physics_info.max_velocity = 10;
+        for _ in 0..5 {
+            assert!(entity.add_status_effect(new_slow(3)));
+        }
+        assert_eq!(entity.movement_speed_percent(), 40); // capped to 3 stacks
+        assert_eq!(entity.effective_max_velocity(), 4);
+    }
+
+    #[test]
+    fn test_stacks_cap_at_u8_max() {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        let mut slow = new_slow(u8::MAX);
+        slow.magnitude = 0;
+        slow.stacks = u8::MAX - 1;
+        assert!(entity.add_status_effect(slow));
+        assert!(entity.add_status_effect(slow));
+        assert!(entity.add_status_effect(slow)); // would overflow without saturating
+        let stacks = entity.status_effects[0].unwrap().stacks;
+        assert_eq!(stacks, u8::MAX);
+    }
+
+    #[test]
+    fn test_ignored_effects_are_not_reapplied() {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        assert!(entity.add_status_effect(new_stun()));
+        assert!(entity.add_status_effect(new_stun()) == false);
+        assert_eq!(entity.movement_speed_percent(), 0);
+        assert_eq!(entity.fire_rate_percent(), 0);
+    }
+
+    #[test]
+    fn test_effects_expire_on_their_own() {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        entity.add_status_effect(new_slow(3));
+        entity.add_status_effect(new_stun());
+        // stun expires first, then the slow
+        entity.update_status_effects(600);
+        assert_eq!(entity.fire_rate_percent(), BASE_PERCENT);
+        assert_eq!(entity.movement_speed_percent(), 80);
+        entity.update_status_effects(400);
+        assert_eq!(entity.movement_speed_percent(), BASE_PERCENT);
+        assert!(entity.status_effects.iter().all(|e| e.is_none()));
+    }
+
+    #[test]
+    fn test_poison_and_vulnerable() {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        entity.set_max_health_points(100);
+        let mut poison = StatusEffect::new(StatusEffectTypes::Poison, 5, 3000);
+        poison.tick_interval_millis = 1000;
+        entity.add_status_effect(poison);
+        entity.add_status_effect(StatusEffect::new(StatusEffectTypes::Vulnerable, 100, 5000));
+        assert_eq!(entity.damage_taken_percent(), 200);
+
+        let mut total_ticks = 0;
+        for _ in 0..10 {
+            for damage in entity.update_status_effects(500) {
+                total_ticks += 1;
+                entity.take_damage(&damage);
+            }
+        }
+        assert_eq!(total_ticks, 3); // 3000 millis of poison at 1 tick per second
+        assert_eq!(entity.health_points, 100 - (3 * 5 * 2));
+    }
+
+    #[test]
+    fn test_refresh_takes_the_new_duration() {
+        let mut entity = Entity::new(&0, &0, &0x80);
+        entity.add_status_effect(StatusEffect::new(StatusEffectTypes::Haste, 10, 5000));
+        entity.update_status_effects(1000);
+        entity.add_status_effect(StatusEffect::new(StatusEffectTypes::Haste, 20, 500));
+        let haste = entity.status_effects[0].unwrap();
+        assert_eq!(haste.magnitude, 20);
+        assert_eq!(haste.remaining_millis, 500);
+        entity.update_status_effects(500);
+        assert!(entity.has_status_effect(StatusEffectTypes::Haste) == false);
+    }
+
+    struct AuraTest {
+        simulation: Simulation,
+        emitter: TEntityID,
+        mine: TEntityID,
+        enemy: TEntityID,
+        too_far: TEntityID,
+    }
+
+    // a command tower of player 1 rallying those around it, already for a second
+    fn start_rallying() -> AuraTest {
+        let mut rallied = StatusEffect::new(StatusEffectTypes::Rallied, 50, 200);
+        rallied.stacking = StackingRules::Refresh;
+        let command_tower_id = add_prototype("test command tower", |p| {
+            p.max_health_points = 10;
+            p.aura = Some(Aura {
+                radius: 2,
+                effect: rallied,
+                affects_enemies: false,
+            });
+        });
+        let mut simulation = new_simulation(8, 8);
+        let mut spawn = |map_x: u16, owner: TPlayerID| {
+            let entity_id = entity_system::add(&0, 0x80).unwrap();
+            entity_system::modify(&entity_id, |e| {
+                e.set_max_health_points(10);
+                e.map_x = map_x;
+                e.owner = owner;
+            })
+            .unwrap();
+            simulation.map.place_entity(map_x, 1, entity_id).unwrap();
+            return entity_id;
+        };
+        let mine = spawn(2, 1);
+        let enemy = spawn(3, 2);
+        let too_far = spawn(7, 1);
+        let emitter = prototype_system::spawn(&command_tower_id, 1, 1).unwrap();
+        entity_system::modify(&emitter, |e| e.owner = 1).unwrap();
+        simulation.map.place_entity(1, 1, emitter).unwrap();
+        for _ in 0..30 {
+            simulation.step_once();
+        }
+        return AuraTest {
+            simulation,
+            emitter,
+            mine,
+            enemy,
+            too_far,
+        };
+    }
+
+    fn get_fire_rate(entity_id: TEntityID) -> u16 {
+        return entity_system::try_get(entity_id)
+            .unwrap()
+            .fire_rate_percent();
+    }
+
+    #[test]
+    fn test_auras_reach_allies_in_range() {
+        let _world = TestWorld::new();
+        let aura = start_rallying();
+        assert_eq!(get_fire_rate(aura.mine), 150);
+        assert_eq!(get_fire_rate(aura.enemy), BASE_PERCENT);
+        assert_eq!(get_fire_rate(aura.too_far), BASE_PERCENT);
+        assert_eq!(get_fire_rate(aura.emitter), BASE_PERCENT); // not itself
+    }
+
+    #[test]
+    fn test_auras_wear_off_with_their_emitter() {
+        let _world = TestWorld::new();
+        let mut aura = start_rallying();
+        entity_system::modify(&aura.emitter, |e| e.health_points = 0).unwrap();
+        for _ in 0..10 {
+            aura.simulation.step_once();
+        }
+        assert_eq!(get_fire_rate(aura.mine), BASE_PERCENT);
+    }
+}
@@ -0,0 +1,231 @@
+// What clients (TUI, SDL, Bevy) get to see of a match running in another process: a ClientState
+// sent once when they join, then a StateDelta per tick with only what changed since.  It is kept
+// free of any transport so that it can be tested here (see rpc_system for the Cap'n Proto side)
+use crate::economy_system::PlayerLedger;
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::{Map, MapCell};
+use crate::simulation::{Simulation, SimulationSnapshot, TTick};
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ClientState {
+    pub tick: TTick, // the next tick to be run, same as Simulation::get_tick()
+    pub map: Map,
+    pub entities: Vec<Entity>, // sorted by id
+    pub ledgers: Vec<PlayerLedger>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CellChange {
+    pub map_x: u16,
+    pub map_y: u16,
+    pub cell: MapCell,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StateDelta {
+    pub from_tick: TTick, // only applies on top of a ClientState of this tick
+    pub tick: TTick,
+    pub changed_cells: Vec<CellChange>,
+    pub changed_entities: Vec<Entity>, // new or changed, sorted by id
+    pub removed_entities: Vec<TEntityID>,
+    pub changed_ledgers: Vec<PlayerLedger>,
+}
+
+impl StateDelta {
+    // nothing but the tick moved on
+    pub fn is_empty(self: &Self) -> bool {
+        return self.changed_cells.is_empty()
+            && self.changed_entities.is_empty()
+            && self.removed_entities.is_empty()
+            && self.changed_ledgers.is_empty();
+    }
+}
+
+impl ClientState {
+    pub fn from_simulation(simulation: &Simulation) -> ClientState {
+        ClientState {
+            tick: simulation.get_tick(),
+            map: simulation.map.clone(),
+            entities: entity_system::snapshot(),
+            ledgers: simulation.economy.get_ledgers().clone(),
+        }
+    }
+
+    // i.e. of a save game, without restoring it into entity_system
+    pub fn from_snapshot(snapshot: &SimulationSnapshot) -> ClientState {
+        ClientState {
+            tick: snapshot.simulation.get_tick(),
+            map: snapshot.simulation.map.clone(),
+            entities: snapshot.entities.clone(),
+            ledgers: snapshot.simulation.economy.get_ledgers().clone(),
+        }
+    }
+
+    // what it takes to go from self to the newer state
+    pub fn get_delta_to(self: &Self, newer: &ClientState) -> Result<StateDelta, String> {
+        let changed_cells = newer
+            .map
+            .get_changed_cells(&self.map)?
+            .into_iter()
+            .map(|(map_x, map_y, cell)| CellChange { map_x, map_y, cell })
+            .collect();
+        let changed_entities = newer
+            .entities
+            .iter()
+            .filter(|e| self.get_entity(&e.id).as_ref() != Some(*e))
+            .copied()
+            .collect();
+        let removed_entities = self
+            .entities
+            .iter()
+            .filter(|e| newer.get_entity(&e.id).is_none())
+            .map(|e| e.id)
+            .collect();
+        let changed_ledgers = newer
+            .ledgers
+            .iter()
+            .filter(|l| self.ledgers.contains(l) == false)
+            .cloned()
+            .collect();
+        return Ok(StateDelta {
+            from_tick: self.tick,
+            tick: newer.tick,
+            changed_cells,
+            changed_entities,
+            removed_entities,
+            changed_ledgers,
+        });
+    }
+
+    pub fn apply(self: &mut Self, delta: &StateDelta) -> Result<(), String> {
+        if delta.from_tick != self.tick {
+            return Err(format!(
+                "delta from tick {} cannot be applied on tick {}",
+                delta.from_tick, self.tick
+            ));
+        }
+        for change in delta.changed_cells.iter() {
+            if self.map.is_in_bounds(change.map_x, change.map_y) == false {
+                return Err(format!(
+                    "changed cell ({}, {}) is out of bounds",
+                    change.map_x, change.map_y
+                ));
+            }
+            self.map
+                .set(change.map_x, change.map_y, change.cell.clone())?;
+        }
+        self.entities
+            .retain(|e| delta.removed_entities.contains(&e.id) == false);
+        for entity in delta.changed_entities.iter() {
+            match self.entities.binary_search_by(|e| e.id.cmp(&entity.id)) {
+                Ok(index) => self.entities[index] = *entity,
+                Err(index) => self.entities.insert(index, *entity),
+            }
+        }
+        for ledger in delta.changed_ledgers.iter() {
+            match self
+                .ledgers
+                .binary_search_by(|l| l.player_id.cmp(&ledger.player_id))
+            {
+                Ok(index) => self.ledgers[index] = ledger.clone(),
+                Err(index) => self.ledgers.insert(index, ledger.clone()),
+            }
+        }
+        self.tick = delta.tick;
+        return Ok(());
+    }
+
+    pub fn get_entity(self: &Self, entity_id: &TEntityID) -> Option<Entity> {
+        return match self.entities.binary_search_by(|e| e.id.cmp(entity_id)) {
+            Ok(index) => Some(self.entities[index]),
+            Err(_) => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::crafting_system::CraftingSystem;
+    use crate::economy_system::{ItemStack, ItemTypes};
+    use crate::prototype_system::TPrototypeID;
+    use crate::test_helpers::{add_player, add_prototype, TestWorld};
+    use crate::wave_system::{SpawnGroup, WaveDefinition, WaveScheduler};
+
+    // a wave of 3 creeps soon after the start, with 30 gold for player 1 and towers of 10
+    fn new_match() -> (Simulation, TPrototypeID) {
+        let tower_id = add_prototype("test tower", |p| {
+            p.build_costs = vec![ItemStack::new(ItemTypes::Gold, 10)];
+        });
+        let creep_id = add_prototype("test creep", |p| p.max_health_points = 10);
+        let waves = WaveScheduler::new(vec![WaveDefinition {
+            delay_millis: 200,
+            early_call_bonus_per_second: 0,
+            groups: vec![SpawnGroup {
+                prototype_id: creep_id,
+                count: 3,
+                spacing_millis: 100,
+                delay_millis: 0,
+                spawn_x: 4,
+                spawn_y: 4,
+                spawn_radius: 1,
+            }],
+        }])
+        .unwrap();
+        let mut simulation = Simulation::new(
+            Map::create(8, 8).unwrap(),
+            waves,
+            CraftingSystem::new(Vec::new()).unwrap(),
+            11,
+        );
+        add_player(&mut simulation, 1, 30);
+        return (simulation, tower_id);
+    }
+
+    #[test]
+    fn test_deltas_rebuild_state() {
+        let _world = TestWorld::new();
+        let (mut simulation, tower_id) = new_match();
+        // the client joins, then only gets deltas
+        let mut client = ClientState::from_simulation(&simulation);
+        for tick in 0..30 {
+            if tick == 3 {
+                let place = PlayerCommands::PlaceStructure {
+                    prototype_id: tower_id,
+                    map_x: 0,
+                    map_y: 0,
+                };
+                simulation.queue_command(1, place);
+            }
+            if tick == 20 {
+                let entity_id = entity_system::snapshot()[0].id;
+                simulation.queue_command(1, PlayerCommands::SellStructure { entity_id });
+            }
+            simulation.step_once();
+            let current = ClientState::from_simulation(&simulation);
+            let delta = client.get_delta_to(&current).unwrap();
+            assert!(delta.changed_cells.len() <= 3);
+            client.apply(&delta).unwrap();
+            assert_eq!(client, current);
+        }
+        assert_eq!(client.entities.len(), 3);
+    }
+
+    #[test]
+    fn test_deltas_only_apply_to_their_base() {
+        let _world = TestWorld::new();
+        let (mut simulation, _) = new_match();
+        let base = ClientState::from_simulation(&simulation);
+        simulation.step_once();
+        let next = ClientState::from_simulation(&simulation);
+        let delta = base.get_delta_to(&next).unwrap();
+
+        let mut client = base.clone();
+        client.apply(&delta).unwrap();
+        assert!(client.apply(&delta).is_err()); // already applied
+        assert_eq!(client, next); // and left as it was
+    }
+}
@@ -0,0 +1,586 @@
+// Tech tree: data-defined researches that take time and items, have prerequisites (other
+// researches and/or a kind of structure the player has standing), and once completed unlock
+// prototypes and/or modify the stats of that player's entities of a prototype.  Prototypes can
+// have prerequisites of their own, i.e. a tower that needs a workshop.  Placement and production
+// ask is_available() first (see command_system), and frontends can use the same to grey out what
+// cannot be built yet.  Each player researches one thing at a time
+use crate::economy_system::{Economy, ItemStack, TPlayerID, FULL_REFUND_PERCENT};
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::prototype_system::{self, TPrototypeID};
+use crate::unit_system::UnitSystem;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+pub type TResearchID = u16;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Prerequisites {
+    Research(TResearchID),   // completed
+    Structure(TPrototypeID), // at least one standing
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum StatTypes {
+    MaxHealthPoints,
+    Armor,
+    WeaponDamage,
+    WeaponRange,
+    MaxVelocity,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct StatModifier {
+    pub prototype_id: TPrototypeID,
+    pub stat: StatTypes,
+    pub amount: i16, // added on top of the prototype's, negative to nerf
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Research {
+    pub id: TResearchID,
+    pub name: String,
+    pub costs: Vec<ItemStack>,
+    pub research_millis: u128,
+    pub prerequisites: Vec<Prerequisites>,
+    pub unlocks: Vec<TPrototypeID>, // these cannot be built/trained by anyone who lacks it
+    pub modifiers: Vec<StatModifier>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PrototypePrerequisites {
+    pub prototype_id: TPrototypeID,
+    pub prerequisites: Vec<Prerequisites>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerTech {
+    pub completed: Vec<TResearchID>, // sorted
+    pub in_progress: Option<TResearchID>,
+    progress_millis: u128,
+}
+
+impl PlayerTech {
+    pub fn get_progress_millis(self: &Self) -> u128 {
+        return self.progress_millis;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct TechSystem {
+    researches: Vec<Research>, // sorted by id
+    prototype_prerequisites: BTreeMap<TPrototypeID, Vec<Prerequisites>>,
+    players: BTreeMap<TPlayerID, PlayerTech>,
+    structures: BTreeMap<TEntityID, TPlayerID>, // standing structures and their owners
+}
+
+fn get_entity_prototype_id(entity_id: &TEntityID) -> Option<TPrototypeID> {
+    return entity_system::modify(entity_id, |e| e.prototype_id)
+        .ok()
+        .flatten();
+}
+
+// adds the modifier to the entity's current stats (saturating)
+pub fn apply_modifier(entity: &mut Entity, modifier: &StatModifier) {
+    match modifier.stat {
+        StatTypes::MaxHealthPoints => {
+            let max_health_points = entity
+                .max_health_points
+                .saturating_add_signed(modifier.amount);
+            if entity.max_health_points > 0 && max_health_points > 0 {
+                // keep it destructible (or not), and as hurt as it was
+                let missing = entity.max_health_points - entity.health_points;
+                entity.max_health_points = max_health_points;
+                entity.health_points = max_health_points.saturating_sub(missing).max(1);
+            }
+        }
+        StatTypes::Armor => {
+            entity.defense.armor = entity.defense.armor.saturating_add_signed(modifier.amount)
+        }
+        StatTypes::WeaponDamage => {
+            entity.weapon.damage = entity.weapon.damage.saturating_add_signed(modifier.amount)
+        }
+        StatTypes::WeaponRange => {
+            entity.weapon.range = entity.weapon.range.saturating_add_signed(modifier.amount)
+        }
+        StatTypes::MaxVelocity => {
+            let max_velocity = (entity.physics_info.max_velocity as i32 + modifier.amount as i32)
+                .clamp(1, u8::MAX as i32);
+            if entity.physics_info.max_velocity > 0 {
+                entity.physics_info.max_velocity = max_velocity as u8; // structures stay put
+            }
+        }
+    }
+}
+
+impl TechSystem {
+    pub fn new(
+        researches: Vec<Research>,
+        prototype_prerequisites: Vec<PrototypePrerequisites>,
+    ) -> Result<TechSystem, String> {
+        let mut sorted = researches;
+        sorted.sort_by_key(|r| r.id);
+        for pair in sorted.windows(2) {
+            if pair[0].id == pair[1].id {
+                return Err(format!(
+                    "researchID={} is defined more than once",
+                    pair[0].id
+                ));
+            }
+        }
+        let has_research = |id: &TResearchID| sorted.binary_search_by(|r| r.id.cmp(id)).is_ok();
+        let all_prerequisites = sorted.iter().flat_map(|r| r.prerequisites.iter()).chain(
+            prototype_prerequisites
+                .iter()
+                .flat_map(|p| p.prerequisites.iter()),
+        );
+        for prerequisite in all_prerequisites {
+            if let Prerequisites::Research(id) = prerequisite {
+                if has_research(id) == false {
+                    return Err(format!("researchID={} does not exist", id));
+                }
+            }
+        }
+        // walk every research's prerequisites, to make sure none ends up needing itself
+        for research in sorted.iter() {
+            let mut visited: Vec<TResearchID> = Vec::new();
+            let mut to_visit: Vec<TResearchID> = Vec::new();
+            let add_prerequisites = |to_visit: &mut Vec<TResearchID>, r: &Research| {
+                for prerequisite in r.prerequisites.iter() {
+                    if let Prerequisites::Research(id) = prerequisite {
+                        to_visit.push(*id);
+                    }
+                }
+            };
+            add_prerequisites(&mut to_visit, research);
+            while let Some(current) = to_visit.pop() {
+                if current == research.id {
+                    return Err(format!(
+                        "researchID={} ('{}') requires itself",
+                        research.id, research.name
+                    ));
+                }
+                if visited.contains(&current) {
+                    continue;
+                }
+                visited.push(current);
+                if let Ok(index) = sorted.binary_search_by(|r| r.id.cmp(&current)) {
+                    add_prerequisites(&mut to_visit, &sorted[index]);
+                }
+            }
+        }
+        return Ok(TechSystem {
+            researches: sorted,
+            prototype_prerequisites: prototype_prerequisites
+                .into_iter()
+                .map(|p| (p.prototype_id, p.prerequisites))
+                .collect(),
+            players: BTreeMap::new(),
+            structures: BTreeMap::new(),
+        });
+    }
+
+    pub fn get_research(self: &Self, research_id: TResearchID) -> Option<&Research> {
+        return match self.researches.binary_search_by(|r| r.id.cmp(&research_id)) {
+            Ok(index) => Some(&self.researches[index]),
+            Err(_) => None,
+        };
+    }
+    pub fn get_player(self: &Self, player_id: TPlayerID) -> Option<&PlayerTech> {
+        return self.players.get(&player_id);
+    }
+    pub fn has_completed(self: &Self, player_id: TPlayerID, research_id: TResearchID) -> bool {
+        return match self.players.get(&player_id) {
+            Some(tech) => tech.completed.binary_search(&research_id).is_ok(),
+            None => false,
+        };
+    }
+
+    // get_prototype_id looks up the standing structures, which is entity_system but for clients
+    // (see prediction_system) their copy of the entities
+    fn check_prerequisites(
+        self: &Self,
+        player_id: TPlayerID,
+        name: &String,
+        prerequisites: &Vec<Prerequisites>,
+        get_prototype_id: &dyn Fn(&TEntityID) -> Option<TPrototypeID>,
+    ) -> Result<(), String> {
+        for prerequisite in prerequisites.iter() {
+            match prerequisite {
+                Prerequisites::Research(research_id) => {
+                    if self.has_completed(player_id, *research_id) == false {
+                        let research_name = match self.get_research(*research_id) {
+                            Some(r) => r.name.clone(),
+                            None => research_id.to_string(),
+                        };
+                        return Err(format!("'{}' needs research '{}'", name, research_name));
+                    }
+                }
+                Prerequisites::Structure(prototype_id) => {
+                    let has_structure = self
+                        .structures
+                        .iter()
+                        .filter(|(_, owner)| **owner == player_id)
+                        .any(|(entity_id, _)| get_prototype_id(entity_id) == Some(*prototype_id));
+                    if has_structure == false {
+                        let structure_name = match prototype_system::get(prototype_id) {
+                            Some(p) => p.name,
+                            None => prototype_id.to_string(),
+                        };
+                        return Err(format!("'{}' needs a '{}'", name, structure_name));
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Ok(()) when the player may build/train the prototype: either no research unlocks it or
+    /// they have completed one that does, and they meet its prerequisites
+    pub fn is_available(
+        self: &Self,
+        player_id: TPlayerID,
+        prototype_id: TPrototypeID,
+    ) -> Result<(), String> {
+        return self.is_available_with(player_id, prototype_id, &get_entity_prototype_id);
+    }
+
+    // same as is_available(), with the structures' prototypes looked up by get_prototype_id
+    pub fn is_available_with(
+        self: &Self,
+        player_id: TPlayerID,
+        prototype_id: TPrototypeID,
+        get_prototype_id: &dyn Fn(&TEntityID) -> Option<TPrototypeID>,
+    ) -> Result<(), String> {
+        let name = match prototype_system::get(&prototype_id) {
+            Some(p) => p.name,
+            None => return Err(format!("prototypeID={} does not exist", prototype_id)),
+        };
+        let unlocked_by: Vec<&Research> = self
+            .researches
+            .iter()
+            .filter(|r| r.unlocks.contains(&prototype_id))
+            .collect();
+        if unlocked_by.is_empty() == false
+            && unlocked_by
+                .iter()
+                .all(|r| self.has_completed(player_id, r.id) == false)
+        {
+            return Err(format!(
+                "'{}' needs research '{}'",
+                name, unlocked_by[0].name
+            ));
+        }
+        return match self.prototype_prerequisites.get(&prototype_id) {
+            Some(prerequisites) => {
+                self.check_prerequisites(player_id, &name, prerequisites, get_prototype_id)
+            }
+            None => Ok(()),
+        };
+    }
+
+    // the researches the player could start right now (regardless of whether it can be afforded)
+    pub fn get_available_researches(self: &Self, player_id: TPlayerID) -> Vec<&Research> {
+        return self
+            .researches
+            .iter()
+            .filter(|r| self.has_completed(player_id, r.id) == false)
+            .filter(|r| {
+                self.check_prerequisites(
+                    player_id,
+                    &r.name,
+                    &r.prerequisites,
+                    &get_entity_prototype_id,
+                )
+                .is_ok()
+            })
+            .collect();
+    }
+
+    // a standing structure counts towards Prerequisites::Structure, and gets the owner's
+    // completed modifiers applied
+    pub fn add_structure(self: &mut Self, entity_id: &TEntityID, owner: TPlayerID) {
+        self.structures.insert(*entity_id, owner);
+        self.apply_completed_modifiers(entity_id, owner);
+    }
+    pub fn remove_structure(self: &mut Self, entity_id: &TEntityID) {
+        self.structures.remove(entity_id);
+    }
+
+    // i.e. a unit that was just trained, or a structure that was upgraded to another prototype
+    pub fn apply_completed_modifiers(self: &Self, entity_id: &TEntityID, owner: TPlayerID) {
+        let completed = match self.players.get(&owner) {
+            Some(tech) => &tech.completed,
+            None => return,
+        };
+        let _ = entity_system::modify(entity_id, |entity| {
+            for research_id in completed.iter() {
+                if let Some(research) = self.get_research(*research_id) {
+                    for modifier in research.modifiers.iter() {
+                        if entity.prototype_id == Some(modifier.prototype_id) {
+                            apply_modifier(entity, modifier);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Charges the player and starts the research; fails without side effects when it is
+    /// already done, something else is being researched, or its prerequisites are not met
+    pub fn start_research(
+        self: &mut Self,
+        economy: &mut Economy,
+        player_id: TPlayerID,
+        research_id: TResearchID,
+    ) -> Result<(), String> {
+        let research = match self.get_research(research_id) {
+            Some(r) => r,
+            None => return Err(format!("researchID={} does not exist", research_id)),
+        };
+        if self.has_completed(player_id, research_id) {
+            return Err(format!("'{}' is already researched", research.name));
+        }
+        if let Some(current) = self.players.get(&player_id).and_then(|t| t.in_progress) {
+            return Err(format!(
+                "playerID={} is already researching researchID={}",
+                player_id, current
+            ));
+        }
+        self.check_prerequisites(
+            player_id,
+            &research.name,
+            &research.prerequisites,
+            &get_entity_prototype_id,
+        )?;
+        economy.spend(player_id, &research.costs)?;
+        let tech = self.players.entry(player_id).or_default();
+        tech.in_progress = Some(research_id);
+        tech.progress_millis = 0;
+        return Ok(());
+    }
+
+    // refunds in full, the progress is lost
+    pub fn cancel_research(
+        self: &mut Self,
+        economy: &mut Economy,
+        player_id: TPlayerID,
+    ) -> Result<(), String> {
+        let research_id = match self.players.get(&player_id).and_then(|t| t.in_progress) {
+            Some(id) => id,
+            None => return Err(format!("playerID={} is not researching", player_id)),
+        };
+        if let Some(research) = self.get_research(research_id) {
+            economy.refund(player_id, &research.costs, FULL_REFUND_PERCENT)?;
+        }
+        if let Some(tech) = self.players.get_mut(&player_id) {
+            tech.in_progress = None;
+            tech.progress_millis = 0;
+        }
+        return Ok(());
+    }
+
+    /// Progresses every player's research (in player order), and applies the modifiers of those
+    /// completed to the player's standing structures and units.  Returns what got completed
+    pub fn update(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        units: &UnitSystem,
+    ) -> Vec<(TPlayerID, TResearchID)> {
+        let mut completed = Vec::new();
+        for (player_id, tech) in self.players.iter_mut() {
+            let research_id = match tech.in_progress {
+                Some(id) => id,
+                None => continue,
+            };
+            let research_millis = match self.researches.binary_search_by(|r| r.id.cmp(&research_id))
+            {
+                Ok(index) => self.researches[index].research_millis,
+                Err(_) => 0,
+            };
+            tech.progress_millis += last_frame_delta_millis;
+            if tech.progress_millis < research_millis {
+                continue;
+            }
+            tech.in_progress = None;
+            tech.progress_millis = 0;
+            if let Err(index) = tech.completed.binary_search(&research_id) {
+                tech.completed.insert(index, research_id);
+            }
+            completed.push((*player_id, research_id));
+        }
+
+        for (player_id, research_id) in completed.iter() {
+            let modifiers = match self.get_research(*research_id) {
+                Some(r) => r.modifiers.clone(),
+                None => continue,
+            };
+            let owned = self
+                .structures
+                .iter()
+                .filter(|(_, owner)| *owner == player_id)
+                .map(|(entity_id, _)| *entity_id)
+                .chain(
+                    units
+                        .get_units()
+                        .iter()
+                        .filter(|u| u.owner == *player_id)
+                        .map(|u| u.entity_id),
+                );
+            for entity_id in owned {
+                let _ = entity_system::modify(&entity_id, |entity| {
+                    for modifier in modifiers.iter() {
+                        if entity.prototype_id == Some(modifier.prototype_id) {
+                            apply_modifier(entity, modifier);
+                        }
+                    }
+                });
+            }
+        }
+        return completed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::economy_system::ItemTypes;
+    use crate::simulation::{Simulation, TICKS_PER_SECOND};
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+
+    fn make_research(id: TResearchID, prerequisites: Vec<Prerequisites>) -> Research {
+        Research {
+            id,
+            name: format!("research {}", id),
+            costs: vec![ItemStack::new(ItemTypes::Gold, 10)],
+            research_millis: 1000,
+            prerequisites,
+            unlocks: Vec::new(),
+            modifiers: Vec::new(),
+        }
+    }
+
+    struct TechTree {
+        simulation: Simulation,
+        workshop_id: TPrototypeID,
+        tower_id: TPrototypeID,
+    }
+
+    // research 1 needs a workshop and unlocks towers, research 2 (after 1) gives them +10 health;
+    // player 1 has 100 gold
+    fn make_tech_tree() -> TechTree {
+        let workshop_id = add_prototype("test workshop", |_| {});
+        let tower_id = add_prototype("test tower", |p| p.max_health_points = 50);
+        let mut towers = make_research(1, vec![Prerequisites::Structure(workshop_id)]);
+        towers.unlocks = vec![tower_id];
+        let mut walls = make_research(2, vec![Prerequisites::Research(1)]);
+        walls.modifiers = vec![StatModifier {
+            prototype_id: tower_id,
+            stat: StatTypes::MaxHealthPoints,
+            amount: 10,
+        }];
+        let mut simulation = new_simulation(16, 16);
+        simulation.tech = TechSystem::new(vec![towers, walls], Vec::new()).unwrap();
+        add_player(&mut simulation, 1, 100);
+        return TechTree {
+            simulation,
+            workshop_id,
+            tower_id,
+        };
+    }
+
+    fn place(prototype_id: TPrototypeID, map_x: u16) -> PlayerCommands {
+        return PlayerCommands::PlaceStructure {
+            prototype_id,
+            map_x,
+            map_y: 1,
+        };
+    }
+
+    fn research(research_id: TResearchID) -> PlayerCommands {
+        return PlayerCommands::StartResearch { research_id };
+    }
+
+    // issues the commands of player 1, then gives them a second; which of them were accepted
+    fn run(simulation: &mut Simulation, commands: Vec<PlayerCommands>) -> Vec<bool> {
+        for command in commands {
+            simulation.queue_command(1, command);
+        }
+        let report = simulation.step_once();
+        for _ in 0..TICKS_PER_SECOND {
+            simulation.step_once();
+        }
+        return report.commands.iter().map(|(_, r)| r.is_ok()).collect();
+    }
+
+    #[test]
+    fn test_looping_prerequisites_are_rejected() {
+        let looping = vec![
+            make_research(1, vec![Prerequisites::Research(2)]),
+            make_research(2, vec![Prerequisites::Research(1)]),
+        ];
+        assert!(TechSystem::new(looping, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_prerequisites_come_first() {
+        let _world = TestWorld::new();
+        let mut tree = make_tech_tree();
+        // locked, then missing the workshop
+        assert_eq!(
+            run(
+                &mut tree.simulation,
+                vec![place(tree.tower_id, 1), research(1)]
+            ),
+            vec![false, false]
+        );
+        assert_eq!(tree.simulation.economy.get_balance(1, ItemTypes::Gold), 100);
+        assert_eq!(
+            run(&mut tree.simulation, vec![place(tree.workshop_id, 1)]),
+            vec![true]
+        );
+        assert_eq!(tree.simulation.tech.get_available_researches(1)[0].id, 1);
+        assert_eq!(run(&mut tree.simulation, vec![research(1)]), vec![true]);
+        assert!(tree.simulation.tech.has_completed(1, 1));
+    }
+
+    #[test]
+    fn test_unlocks_are_per_player() {
+        let _world = TestWorld::new();
+        let mut tree = make_tech_tree();
+        run(&mut tree.simulation, vec![place(tree.workshop_id, 1)]);
+        run(&mut tree.simulation, vec![research(1)]);
+        assert!(tree.simulation.tech.is_available(1, tree.tower_id).is_ok());
+        assert!(tree.simulation.tech.is_available(2, tree.tower_id).is_err());
+    }
+
+    #[test]
+    fn test_modifiers_apply_to_old_and_new_structures() {
+        let _world = TestWorld::new();
+        let mut tree = make_tech_tree();
+        run(&mut tree.simulation, vec![place(tree.workshop_id, 1)]);
+        run(&mut tree.simulation, vec![research(1)]);
+        assert_eq!(
+            run(
+                &mut tree.simulation,
+                vec![place(tree.tower_id, 3), research(2)]
+            ),
+            vec![true, true]
+        );
+        assert_eq!(
+            run(&mut tree.simulation, vec![place(tree.tower_id, 5)]),
+            vec![true]
+        );
+        // both the tower that stood when it completed and the one built after get the bonus
+        let towers: Vec<Entity> = entity_system::snapshot()
+            .into_iter()
+            .filter(|e| e.prototype_id == Some(tree.tower_id))
+            .collect();
+        assert_eq!(towers.len(), 2);
+        assert!(towers
+            .iter()
+            .all(|e| (e.health_points, e.max_health_points) == (60, 60)));
+        assert_eq!(tree.simulation.economy.get_balance(1, ItemTypes::Gold), 80);
+    }
+}
@@ -0,0 +1,67 @@
+// Fixtures shared by the tests of the systems.  Entities and prototypes live in (process wide)
+// singletons while the tests run in parallel, so a test that spawns anything holds a TestWorld and
+// registers its prototypes with add_prototype() rather than under IDs of its own choosing
+use crate::crafting_system::CraftingSystem;
+use crate::economy_system::{ItemStack, ItemTypes, TPlayerID};
+use crate::entity_system;
+use crate::map::Map;
+use crate::prototype_system::{self, EntityPrototype, TPrototypeID};
+use crate::simulation::Simulation;
+use crate::wave_system::WaveScheduler;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::MutexGuard;
+
+// well above the IDs scenarios/samples use, so that those can be registered next to test ones
+const FIRST_TEST_PROTOTYPE_ID: TPrototypeID = 30000;
+static NEXT_PROTOTYPE_ID: AtomicU16 = AtomicU16::new(FIRST_TEST_PROTOTYPE_ID);
+
+// never the same twice, so concurrent tests can't replace each other's prototypes
+pub fn new_prototype_id() -> TPrototypeID {
+    return NEXT_PROTOTYPE_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+// registers a prototype (set up by the given closure) under a new ID, and returns that ID
+pub fn add_prototype(name: &str, setup: impl FnOnce(&mut EntityPrototype)) -> TPrototypeID {
+    let mut prototype = EntityPrototype::new(new_prototype_id(), name, 0);
+    setup(&mut prototype);
+    let prototype_id = prototype.id;
+    prototype_system::add(prototype);
+    return prototype_id;
+}
+
+// exclusive use of entity_system for as long as it lives; starts without any entities and removes
+// whatever the test spawned once dropped (also when the test panics)
+pub struct TestWorld {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl TestWorld {
+    pub fn new() -> TestWorld {
+        let guard = entity_system::lock_for_test();
+        entity_system::reset();
+        return TestWorld { _guard: guard };
+    }
+}
+
+impl Drop for TestWorld {
+    fn drop(self: &mut Self) {
+        entity_system::reset(); // before _guard is released
+    }
+}
+
+// an empty map without waves or recipes, seeded the same every time
+pub fn new_simulation(width: u16, height: u16) -> Simulation {
+    return Simulation::new(
+        Map::create(width, height).unwrap(),
+        WaveScheduler::new(Vec::new()).unwrap(),
+        CraftingSystem::new(Vec::new()).unwrap(),
+        1,
+    );
+}
+
+// a player holding only the given gold
+pub fn add_player(simulation: &mut Simulation, player_id: TPlayerID, gold: u32) {
+    simulation.economy.add_player(player_id).unwrap();
+    let income = vec![ItemStack::new(ItemTypes::Gold, gold)];
+    simulation.economy.earn(player_id, &income).unwrap();
+}
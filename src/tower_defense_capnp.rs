@@ -0,0 +1,2 @@
+// Generated by build.rs (capnpc) from schema/tower_defense.capnp, only with the "rpc" feature
+include!(concat!(env!("OUT_DIR"), "/tower_defense_capnp.rs"));
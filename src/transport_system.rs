@@ -0,0 +1,437 @@
+// How peers (lockstep_system) and clients/servers (prediction_system) get messages to one another,
+// without either caring whether the other end is in the same process or across the network.
+// Messages are plain serde types; over TCP each one is a MessagePack frame
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024; // a corrupted length prefix should not allocate the world
+const MAX_QUEUED_BYTES: usize = 16 * MAX_MESSAGE_BYTES; // per connection, for a peer that stopped reading
+
+// which of the transport's peers a message came from (i.e. the host's connection index), only
+// meaningful to the transport that received it; relayed messages come from the relaying peer
+pub type TSourceID = usize;
+
+/// Whatever carries messages between the peers; send() goes to every other peer
+pub trait Transport<M> {
+    fn send(self: &mut Self, message: &M) -> Result<(), String>;
+    // never blocks, Ok(None) when nothing has arrived (yet)
+    fn receive_from(self: &mut Self) -> Result<Option<(TSourceID, M)>, String>;
+    fn receive(self: &mut Self) -> Result<Option<M>, String> {
+        return self
+            .receive_from()
+            .map(|received| received.map(|(_, message)| message));
+    }
+}
+
+/// In-process transport, i.e. tests and local AI players
+pub struct ChannelTransport<M> {
+    senders: Vec<Sender<(TSourceID, M)>>, // to every other peer
+    receiver: Receiver<(TSourceID, M)>,
+    source_id: TSourceID, // its index in the mesh, as the others see it
+}
+
+impl<M> ChannelTransport<M> {
+    // one transport per peer, each connected to all the others
+    pub fn create_mesh(peer_count: usize) -> Vec<ChannelTransport<M>> {
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            (0..peer_count).map(|_| mpsc::channel()).unzip();
+        return receivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, receiver)| ChannelTransport {
+                senders: senders
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, s)| s.clone())
+                    .collect(),
+                receiver,
+                source_id: i,
+            })
+            .collect();
+    }
+}
+
+impl<M: Clone> Transport<M> for ChannelTransport<M> {
+    fn send(self: &mut Self, message: &M) -> Result<(), String> {
+        for sender in self.senders.iter() {
+            sender
+                .send((self.source_id, message.clone()))
+                .map_err(|_| "peer disconnected".to_owned())?;
+        }
+        return Ok(());
+    }
+    fn receive_from(self: &mut Self) -> Result<Option<(TSourceID, M)>, String> {
+        return match self.receiver.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err("peer disconnected".to_owned()),
+        };
+    }
+}
+
+// a length-prefixed (u32 little endian) MessagePack frame per message
+struct TcpConnection {
+    stream: TcpStream,
+    read_buffer: Vec<u8>,
+    write_queue: VecDeque<u8>, // what the socket would not take yet, flushed on every send/receive
+    is_closed: bool,           // by the peer, though what it sent before that can still be read
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream) -> Result<TcpConnection, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        return Ok(TcpConnection {
+            stream,
+            read_buffer: Vec::new(),
+            write_queue: VecDeque::new(),
+            is_closed: false,
+        });
+    }
+
+    // queues the frame behind whatever is still pending and writes as much as the socket takes
+    // right now, the rest goes out on later calls (never waits for a slow reader)
+    fn write_frame(self: &mut Self, frame: &[u8]) -> Result<(), String> {
+        if self.is_closed {
+            return Ok(());
+        }
+        if self.write_queue.len() + frame.len() > MAX_QUEUED_BYTES {
+            return Err(format!(
+                "peer is not reading, {} bytes are still queued",
+                self.write_queue.len()
+            ));
+        }
+        self.write_queue.extend(frame);
+        return self.flush();
+    }
+
+    // a peer that has left (i.e. finished the match first) is not an error until something it
+    // should have sent is missing, see read_frame()
+    fn flush(self: &mut Self) -> Result<(), String> {
+        while self.write_queue.is_empty() == false && self.is_closed == false {
+            let (pending, _) = self.write_queue.as_slices();
+            match self.stream.write(pending) {
+                Ok(0) => self.is_closed = true,
+                Ok(n) => {
+                    self.write_queue.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e)
+                    if e.kind() == ErrorKind::BrokenPipe
+                        || e.kind() == ErrorKind::ConnectionReset =>
+                {
+                    self.is_closed = true
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        if self.is_closed {
+            self.write_queue.clear(); // nobody left to read it
+        }
+        return Ok(());
+    }
+
+    fn read_frame(self: &mut Self) -> Result<Option<Vec<u8>>, String> {
+        let mut chunk = [0u8; 4096];
+        while self.is_closed == false {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.is_closed = true,
+                Ok(n) => self.read_buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => self.is_closed = true,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let has_frame_header = self.read_buffer.len() >= 4;
+        if has_frame_header == false && self.is_closed {
+            return Err("peer disconnected".to_owned());
+        }
+        if has_frame_header == false {
+            return Ok(None);
+        }
+        let length = u32::from_le_bytes([
+            self.read_buffer[0],
+            self.read_buffer[1],
+            self.read_buffer[2],
+            self.read_buffer[3],
+        ]) as usize;
+        if length > MAX_MESSAGE_BYTES {
+            return Err(format!("message of {} bytes is too large", length));
+        }
+        if self.read_buffer.len() < 4 + length {
+            return match self.is_closed {
+                true => Err("peer disconnected".to_owned()),
+                false => Ok(None),
+            };
+        }
+        let frame = self.read_buffer[4..4 + length].to_vec();
+        self.read_buffer.drain(..4 + length);
+        return Ok(Some(frame));
+    }
+}
+
+/// Localhost/LAN transport in a star: the host accepts every other peer and (unless told not to)
+/// relays what each one sends to the rest, so that guests only need to know the host's address
+pub struct TcpTransport<M> {
+    connections: Vec<TcpConnection>,
+    is_relaying: bool,
+    next_connection_to_read: usize, // round-robin, so that one chatty peer cannot starve the others
+    message_type: PhantomData<M>,
+}
+
+impl<M: Serialize + DeserializeOwned> TcpTransport<M> {
+    // blocks until guest_count peers have connected
+    pub fn host(listener: &TcpListener, guest_count: usize) -> Result<TcpTransport<M>, String> {
+        let mut connections = Vec::new();
+        while connections.len() < guest_count {
+            let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+            connections.push(TcpConnection::new(stream)?);
+        }
+        return Ok(TcpTransport {
+            connections,
+            is_relaying: true,
+            next_connection_to_read: 0,
+            message_type: PhantomData,
+        });
+    }
+
+    pub fn join<A: ToSocketAddrs>(address: A) -> Result<TcpTransport<M>, String> {
+        let stream = TcpStream::connect(address).map_err(|e| e.to_string())?;
+        return Ok(TcpTransport {
+            connections: vec![TcpConnection::new(stream)?],
+            is_relaying: false,
+            next_connection_to_read: 0,
+            message_type: PhantomData,
+        });
+    }
+
+    // i.e. an authoritative server, whose guests only ever talk to it
+    pub fn set_relaying(self: &mut Self, is_relaying: bool) {
+        self.is_relaying = is_relaying;
+    }
+
+    fn to_frame(message: &M) -> Result<Vec<u8>, String> {
+        let mut frame = vec![0u8; 4];
+        message
+            .serialize(&mut rmp_serde::Serializer::new(&mut frame))
+            .map_err(|e| e.to_string())?;
+        let length = frame.len() - 4;
+        if length > MAX_MESSAGE_BYTES {
+            // the other end would reject it anyway, see read_frame()
+            return Err(format!("message of {} bytes is too large", length));
+        }
+        frame[..4].copy_from_slice(&(length as u32).to_le_bytes());
+        return Ok(frame);
+    }
+}
+
+impl<M: Serialize + DeserializeOwned> Transport<M> for TcpTransport<M> {
+    fn send(self: &mut Self, message: &M) -> Result<(), String> {
+        let frame = TcpTransport::to_frame(message)?;
+        for connection in self.connections.iter_mut() {
+            connection.write_frame(&frame)?;
+        }
+        return Ok(());
+    }
+
+    fn receive_from(self: &mut Self) -> Result<Option<(TSourceID, M)>, String> {
+        for connection in self.connections.iter_mut() {
+            connection.flush()?; // whatever earlier sends (and relays) could not write
+        }
+        for _ in 0..self.connections.len() {
+            let index = self.next_connection_to_read % self.connections.len();
+            self.next_connection_to_read = index + 1;
+            let frame = match self.connections[index].read_frame()? {
+                Some(f) => f,
+                None => continue,
+            };
+            if self.is_relaying {
+                let mut relayed = (frame.len() as u32).to_le_bytes().to_vec();
+                relayed.extend_from_slice(&frame);
+                for (other, connection) in self.connections.iter_mut().enumerate() {
+                    if other != index {
+                        connection.write_frame(&relayed)?;
+                    }
+                }
+            }
+            return rmp_serde::from_slice(frame.as_slice())
+                .map(|message| Some((index, message)))
+                .map_err(|e| e.to_string());
+        }
+        return Ok(None);
+    }
+}
+
+/// Holds back whatever goes through the wrapped transport, both ways, for a fixed latency, i.e. to
+/// try out how responsive a client stays over a slow link while running everything on localhost.
+/// What is sent only goes out on a later send() or receive() once its latency has passed
+pub struct LatencyTransport<M, T: Transport<M>> {
+    inner: T,
+    latency: Duration,
+    in_flight: VecDeque<(Instant, TSourceID, M)>, // with when it is to be delivered, in the order received
+    outgoing: VecDeque<(Instant, M)>,             // with when it is to be sent, in the order sent
+}
+
+impl<M, T: Transport<M>> LatencyTransport<M, T> {
+    pub fn new(inner: T, latency: Duration) -> LatencyTransport<M, T> {
+        LatencyTransport {
+            inner,
+            latency,
+            in_flight: VecDeque::new(),
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    fn send_due(self: &mut Self, now: Instant) -> Result<(), String> {
+        while let Some((send_at, _)) = self.outgoing.front() {
+            if *send_at > now {
+                break;
+            }
+            if let Some((_, message)) = self.outgoing.pop_front() {
+                self.inner.send(&message)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl<M: Clone, T: Transport<M>> Transport<M> for LatencyTransport<M, T> {
+    fn send(self: &mut Self, message: &M) -> Result<(), String> {
+        let now = Instant::now();
+        self.outgoing
+            .push_back((now + self.latency, message.clone()));
+        return self.send_due(now);
+    }
+    fn receive_from(self: &mut Self) -> Result<Option<(TSourceID, M)>, String> {
+        let now = Instant::now();
+        self.send_due(now)?;
+        while let Some((source_id, message)) = self.inner.receive_from()? {
+            self.in_flight
+                .push_back((now + self.latency, source_id, message));
+        }
+        return match self.in_flight.front() {
+            Some((deliver_at, _, _)) if *deliver_at <= now => Ok(self
+                .in_flight
+                .pop_front()
+                .map(|(_, source_id, message)| (source_id, message))),
+            _ => Ok(None),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_transport_relays() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let host = std::thread::spawn(move || TcpTransport::host(&listener, 2));
+        let mut guests: Vec<TcpTransport<Vec<u8>>> = vec![
+            TcpTransport::join(address).unwrap(),
+            TcpTransport::join(address).unwrap(),
+        ];
+        let mut host: TcpTransport<Vec<u8>> = host.join().unwrap().unwrap();
+
+        let message = vec![2u8, 7];
+        guests[0].send(&message).unwrap();
+        let mut received = None;
+        while received.is_none() {
+            received = host.receive().unwrap();
+        }
+        assert_eq!(received, Some(message.clone()));
+        // relayed on to the other guest, but not echoed back to the sender
+        let mut relayed = None;
+        while relayed.is_none() {
+            relayed = guests[1].receive().unwrap();
+        }
+        assert_eq!(relayed, Some(message));
+        assert_eq!(guests[0].receive().unwrap(), None);
+
+        // the host tells its guests apart by connection
+        guests[1].send(&vec![1u8]).unwrap();
+        let mut received_from = None;
+        while received_from.is_none() {
+            received_from = host.receive_from().unwrap();
+        }
+        assert_eq!(received_from, Some((1, vec![1u8])));
+    }
+
+    #[test]
+    fn test_tcp_transport_queues_when_the_socket_is_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let host = std::thread::spawn(move || TcpTransport::host(&listener, 1));
+        let mut guest: TcpTransport<Vec<u8>> = TcpTransport::join(address).unwrap();
+        let mut host: TcpTransport<Vec<u8>> = host.join().unwrap().unwrap();
+
+        // the guest is not reading, so sooner or later the socket stops taking more
+        let mut sent = 0u8;
+        while host.connections[0].write_queue.is_empty() {
+            host.send(&vec![sent; MAX_MESSAGE_BYTES / 2]).unwrap();
+            sent += 1;
+        }
+        let mut received = 0u8;
+        while received < sent {
+            host.receive().unwrap(); // flushes the queue
+            if let Some(message) = guest.receive().unwrap() {
+                assert_eq!(message, vec![received; MAX_MESSAGE_BYTES / 2]);
+                received += 1;
+            }
+        }
+        assert!(host.connections[0].write_queue.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_transport_rejects_large_messages() {
+        let too_large = vec![0u8; MAX_MESSAGE_BYTES + 1];
+        let result = TcpTransport::<Vec<u8>>::to_frame(&too_large);
+        assert!(result.unwrap_err().contains("too large"));
+        assert!(TcpTransport::<Vec<u8>>::to_frame(&vec![0u8; 16]).is_ok());
+    }
+
+    #[test]
+    fn test_latency_transport_holds_back() {
+        let mut transports = ChannelTransport::create_mesh(2);
+        let mut slow = LatencyTransport::new(transports.pop().unwrap(), Duration::from_millis(50));
+        let mut fast = transports.pop().unwrap();
+        let sent_at = Instant::now();
+        fast.send(&"ping".to_owned()).unwrap();
+        fast.send(&"pong".to_owned()).unwrap();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            if let Some(message) = slow.receive().unwrap() {
+                assert!(sent_at.elapsed() >= Duration::from_millis(50));
+                received.push(message);
+            }
+        }
+        assert_eq!(received, vec!["ping".to_owned(), "pong".to_owned()]);
+    }
+
+    #[test]
+    fn test_latency_transport_holds_back_sends() {
+        let mut transports = ChannelTransport::create_mesh(2);
+        let mut fast = transports.pop().unwrap();
+        let mut slow = LatencyTransport::new(transports.pop().unwrap(), Duration::from_millis(50));
+        let sent_at = Instant::now();
+        slow.send(&"ping".to_owned()).unwrap();
+        assert_eq!(fast.receive().unwrap(), None);
+        let mut received = None;
+        while received.is_none() {
+            slow.receive().unwrap(); // sends what is due
+            received = fast.receive().unwrap();
+        }
+        assert!(sent_at.elapsed() >= Duration::from_millis(50));
+        assert_eq!(received, Some("ping".to_owned()));
+    }
+}
@@ -0,0 +1,586 @@
+// Player-owned mobile units, and the orders they carry out.  Orders are issued through
+// command_system like everything else a player does; every tick, each unit steps towards
+// wherever its order takes it (straight line, one cell at a time at its max_velocity, going
+// around or waiting when structures are in the way, see pathfinding_system) and fires
+// at its target once within weapon range; idle and holding units fire at the nearest enemy in
+// range (see diplomacy_system) without chasing it.  Structures only get a rally point, which is
+// where the units they produce head to
+use crate::damage_system;
+use crate::diplomacy_system::{Diplomacy, Relations};
+use crate::economy_system::TPlayerID;
+use crate::entity_system::{self, Entity, TEntityID};
+use crate::map::{get_distance_squared, Map};
+use crate::pathfinding_system;
+use crate::status_effect_system::BASE_PERCENT;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum UnitOrders {
+    Idle,
+    Move {
+        map_x: u16,
+        map_y: u16,
+    },
+    AttackTarget {
+        target_id: TEntityID, // chased until it dies
+    },
+    // back and forth between the two, currently heading to (to_x, to_y)
+    Patrol {
+        from_x: u16,
+        from_y: u16,
+        to_x: u16,
+        to_y: u16,
+    },
+    Hold, // stays put until ordered otherwise
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Unit {
+    pub entity_id: TEntityID,
+    pub owner: TPlayerID,
+    pub order: UnitOrders,
+    move_millis: u128, // accumulated towards the next step
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct UnitSystem {
+    units: Vec<Unit>,                              // sorted by entity_id
+    rally_points: BTreeMap<TEntityID, (u16, u16)>, // per structure
+}
+
+fn get_entity(entity_id: &TEntityID) -> Result<Entity, String> {
+    return entity_system::modify(entity_id, |e| *e);
+}
+
+/// One destination per position (in the same order): the cells around (map_x, map_y), nearest
+/// first, handed out to the units nearest to it first, each taking the free cell closest to
+/// itself so that the group roughly keeps its shape instead of criss-crossing
+pub fn get_formation(
+    map: &Map,
+    map_x: u16,
+    map_y: u16,
+    positions: &Vec<(u16, u16)>,
+) -> Vec<(u16, u16)> {
+    let center = (map_x, map_y);
+    let mut cells = Vec::new();
+    let max_radius = map.get_width().max(map.get_height()) as i32;
+    let mut radius = 0;
+    while cells.len() < positions.len() && radius <= max_radius {
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx.abs() != radius && dy.abs() != radius {
+                    continue; // inner rings are already in
+                }
+                let (x, y) = (map_x as i32 + dx, map_y as i32 + dy);
+                if x >= 0 && y >= 0 && map.is_in_bounds(x as u16, y as u16) {
+                    cells.push((x as u16, y as u16));
+                }
+            }
+        }
+        radius += 1;
+    }
+    cells.sort_by_key(|c| (get_distance_squared(*c, center), c.1, c.0));
+    cells.truncate(positions.len());
+
+    let mut order: Vec<usize> = (0..positions.len()).collect();
+    order.sort_by_key(|i| (get_distance_squared(positions[*i], center), *i));
+    let mut destinations = vec![center; positions.len()];
+    for i in order {
+        let nearest = cells
+            .iter()
+            .enumerate()
+            .min_by_key(|(rank, c)| (get_distance_squared(**c, positions[i]), *rank))
+            .map(|(rank, _)| rank);
+        if let Some(rank) = nearest {
+            destinations[i] = cells.remove(rank);
+        }
+    }
+    return destinations;
+}
+
+// one cell closer, along whichever axis is further off (x first when tied), or along the other
+// one when that cell is blocked; None when neither gets any closer
+fn get_next_step(map: &Map, from: (u16, u16), to: (u16, u16)) -> Option<(u16, u16)> {
+    let mut steps = Vec::new();
+    if to.0 != from.0 {
+        steps.push((
+            if to.0 > from.0 {
+                from.0 + 1
+            } else {
+                from.0 - 1
+            },
+            from.1,
+        ));
+    }
+    if to.1 != from.1 {
+        steps.push((
+            from.0,
+            if to.1 > from.1 {
+                from.1 + 1
+            } else {
+                from.1 - 1
+            },
+        ));
+    }
+    if to.1.abs_diff(from.1) > to.0.abs_diff(from.0) {
+        steps.reverse();
+    }
+    return steps
+        .into_iter()
+        .find(|step| pathfinding_system::is_passable(map, step.0, step.1));
+}
+
+// once the weapon is ready (see WeaponInfo::fire())
+pub fn fire(entity_id: &TEntityID, target_id: &TEntityID) {
+    let damage = entity_system::modify(entity_id, |e| e.weapon.fire(*entity_id));
+    if let Ok(Some(d)) = damage {
+        let _ = damage_system::apply_damage(target_id, &d);
+    }
+}
+
+impl UnitSystem {
+    pub fn new() -> UnitSystem {
+        UnitSystem {
+            units: Vec::new(),
+            rally_points: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_units(self: &Self) -> &Vec<Unit> {
+        return &self.units;
+    }
+    pub fn get_unit(self: &Self, entity_id: &TEntityID) -> Option<&Unit> {
+        return match self.units.binary_search_by(|u| u.entity_id.cmp(entity_id)) {
+            Ok(index) => Some(&self.units[index]),
+            Err(_) => None,
+        };
+    }
+    pub fn get_rally_point(self: &Self, structure_id: &TEntityID) -> Option<(u16, u16)> {
+        return self.rally_points.get(structure_id).copied();
+    }
+
+    // the entity must already be on the map, and be able to move; it becomes the owner's
+    pub fn add_unit(
+        self: &mut Self,
+        entity_id: &TEntityID,
+        owner: TPlayerID,
+    ) -> Result<(), String> {
+        if get_entity(entity_id)?.physics_info.max_velocity == 0 {
+            return Err(format!("entityID={} cannot move", entity_id));
+        }
+        match self.units.binary_search_by(|u| u.entity_id.cmp(entity_id)) {
+            Ok(_) => return Err(format!("entityID={} is already a unit", entity_id)),
+            Err(index) => {
+                entity_system::modify(entity_id, |e| e.owner = owner)?;
+                self.units.insert(
+                    index,
+                    Unit {
+                        entity_id: *entity_id,
+                        owner,
+                        order: UnitOrders::Idle,
+                        move_millis: 0,
+                    },
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    // i.e. died; also forgets the rally point if it was a structure
+    pub fn remove(self: &mut Self, entity_id: &TEntityID) {
+        self.units.retain(|u| u.entity_id != *entity_id);
+        self.rally_points.remove(entity_id);
+    }
+
+    pub fn set_rally_point(
+        self: &mut Self,
+        map: &Map,
+        structure_id: &TEntityID,
+        map_x: u16,
+        map_y: u16,
+    ) -> Result<(), String> {
+        if get_entity(structure_id)?.prototype_id.is_none() || self.get_unit(structure_id).is_some()
+        {
+            return Err(format!("entityID={} is not a structure", structure_id));
+        }
+        if map.is_in_bounds(map_x, map_y) == false {
+            return Err(format!("({}, {}) is off the map", map_x, map_y));
+        }
+        self.rally_points.insert(*structure_id, (map_x, map_y));
+        return Ok(());
+    }
+
+    /// Gives the same order to every one of the player's units listed; either all of them take
+    /// it or none does.  For Patrol, from_x/from_y are ignored, each unit patrols between where
+    /// it currently is and (to_x, to_y).  A group sent to a cell (Move and Patrol) spreads out in
+    /// formation around it rather than piling up on it, see get_formation()
+    pub fn issue(
+        self: &mut Self,
+        map: &Map,
+        player_id: TPlayerID,
+        unit_ids: &Vec<TEntityID>,
+        order: UnitOrders,
+    ) -> Result<(), String> {
+        let mut unit_ids = unit_ids.clone();
+        unit_ids.sort();
+        unit_ids.dedup();
+        if unit_ids.is_empty() {
+            return Err("no units given".to_owned());
+        }
+        let target = match order {
+            UnitOrders::Move { map_x, map_y }
+            | UnitOrders::Patrol {
+                to_x: map_x,
+                to_y: map_y,
+                ..
+            } => Some((map_x, map_y)),
+            _ => None,
+        };
+        if let Some((map_x, map_y)) = target {
+            if map.is_in_bounds(map_x, map_y) == false {
+                return Err(format!("({}, {}) is off the map", map_x, map_y));
+            }
+        }
+        if let UnitOrders::AttackTarget { target_id } = order {
+            let target = get_entity(&target_id)?;
+            if target.is_destructible() == false || target.is_alive() == false {
+                return Err(format!("entityID={} cannot be attacked", target_id));
+            }
+            if unit_ids.contains(&target_id) {
+                return Err(format!("entityID={} cannot attack itself", target_id));
+            }
+        }
+
+        let mut indices = Vec::new();
+        let mut positions = Vec::new();
+        for unit_id in unit_ids.iter() {
+            let index = match self.units.binary_search_by(|u| u.entity_id.cmp(unit_id)) {
+                Ok(i) if self.units[i].owner == player_id => i,
+                _ => {
+                    return Err(format!(
+                        "entityID={} is not a unit of playerID={}",
+                        unit_id, player_id
+                    ))
+                }
+            };
+            let entity = get_entity(unit_id)?;
+            if let UnitOrders::AttackTarget { .. } = order {
+                if entity.weapon.is_armed() == false {
+                    return Err(format!("entityID={} is unarmed", unit_id));
+                }
+            }
+            indices.push(index);
+            positions.push((entity.map_x, entity.map_y));
+        }
+        let destinations = match target {
+            Some((map_x, map_y)) => get_formation(map, map_x, map_y, &positions),
+            None => positions.clone(),
+        };
+        for (i, index) in indices.into_iter().enumerate() {
+            let (to_x, to_y) = destinations[i];
+            let unit = &mut self.units[index];
+            unit.order = match order {
+                UnitOrders::Move { .. } => UnitOrders::Move {
+                    map_x: to_x,
+                    map_y: to_y,
+                },
+                UnitOrders::Patrol { .. } => UnitOrders::Patrol {
+                    from_x: positions[i].0,
+                    from_y: positions[i].1,
+                    to_x,
+                    to_y,
+                },
+                _ => order,
+            };
+            unit.move_millis = 0;
+        }
+        return Ok(());
+    }
+
+    // moves and fires; kills only queue DeathEvents (see damage_system::remove_dead())
+    pub fn update(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        map: &mut Map,
+        diplomacy: &Diplomacy,
+    ) {
+        for unit in self.units.iter_mut() {
+            let entity = match get_entity(&unit.entity_id) {
+                Ok(e) if e.is_alive() => e,
+                _ => continue, // dying this tick, taken off by the Simulation
+            };
+            let destination = match unit.order {
+                UnitOrders::Idle | UnitOrders::Hold => {
+                    let range = entity.weapon.range;
+                    if entity.weapon.is_armed() {
+                        if let Some(target_id) =
+                            diplomacy.get_nearest_enemy(map, &unit.entity_id, range)
+                        {
+                            fire(&unit.entity_id, &target_id);
+                        }
+                    }
+                    None
+                }
+                UnitOrders::Move { map_x, map_y } => {
+                    if (entity.map_x, entity.map_y) == (map_x, map_y) {
+                        unit.order = UnitOrders::Idle;
+                        None
+                    } else {
+                        Some((map_x, map_y))
+                    }
+                }
+                UnitOrders::AttackTarget { target_id } => match get_entity(&target_id) {
+                    // i.e. became allies since the order was given
+                    Ok(target)
+                        if target.is_alive()
+                            && diplomacy.get_relation(unit.owner, target.owner)
+                                != Relations::Ally =>
+                    {
+                        let dx = target.map_x as i32 - entity.map_x as i32;
+                        let dy = target.map_y as i32 - entity.map_y as i32;
+                        let range = entity.weapon.range as i32;
+                        if dx * dx + dy * dy <= range * range {
+                            fire(&unit.entity_id, &target_id);
+                            None
+                        } else {
+                            Some((target.map_x, target.map_y))
+                        }
+                    }
+                    _ => {
+                        unit.order = UnitOrders::Idle;
+                        None
+                    }
+                },
+                UnitOrders::Patrol {
+                    from_x,
+                    from_y,
+                    to_x,
+                    to_y,
+                } => {
+                    if (entity.map_x, entity.map_y) == (to_x, to_y) {
+                        unit.order = UnitOrders::Patrol {
+                            from_x: to_x,
+                            from_y: to_y,
+                            to_x: from_x,
+                            to_y: from_y,
+                        };
+                        Some((from_x, from_y))
+                    } else {
+                        Some((to_x, to_y))
+                    }
+                }
+            };
+            let (to_x, to_y) = match destination {
+                Some(d) => d,
+                None => {
+                    unit.move_millis = 0;
+                    continue;
+                }
+            };
+            unit.move_millis += last_frame_delta_millis;
+
+            // slowed units need proportionally longer per cell, stunned ones never get there
+            let speed_percent = entity.movement_speed_percent() as u128;
+            if speed_percent == 0 {
+                unit.move_millis = 0;
+                continue;
+            }
+            let millis_per_cell = 1000 * BASE_PERCENT as u128
+                / (entity.physics_info.max_velocity as u128 * speed_percent);
+            let mut position = (entity.map_x, entity.map_y);
+            while unit.move_millis >= millis_per_cell && position != (to_x, to_y) {
+                let next = match get_next_step(map, position, (to_x, to_y)) {
+                    Some(n) => n,
+                    None => {
+                        unit.move_millis = 0; // blocked, waits for the way to clear
+                        break;
+                    }
+                };
+                if map.move_entity(position, next, unit.entity_id).is_err() {
+                    unit.move_millis = 0; // i.e. no free layer on the cell
+                    break;
+                }
+                unit.move_millis -= millis_per_cell;
+                position = next;
+            }
+            if position == (entity.map_x, entity.map_y) {
+                continue;
+            }
+            let _ = entity_system::modify(&unit.entity_id, |e| {
+                e.map_x = position.0;
+                e.map_y = position.1;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_system::PlayerCommands;
+    use crate::economy_system::ItemTypes;
+    use crate::prototype_system::{self, TPrototypeID};
+    use crate::simulation::Simulation;
+    use crate::test_helpers::{add_player, add_prototype, new_simulation, TestWorld};
+
+    fn spawn(simulation: &mut Simulation, prototype_id: TPrototypeID, x: u16, y: u16) -> TEntityID {
+        let entity_id = prototype_system::spawn(&prototype_id, x, y).unwrap();
+        simulation.map.place_entity(x, y, entity_id).unwrap();
+        return entity_id;
+    }
+
+    fn move_units(entity_id: TEntityID, map_x: u16, map_y: u16) -> PlayerCommands {
+        return PlayerCommands::MoveUnits {
+            entity_ids: vec![entity_id],
+            map_x,
+            map_y,
+        };
+    }
+
+    // on the cell it is at, and on that one only
+    fn is_at(simulation: &Simulation, entity_id: TEntityID, map_x: u16, map_y: u16) -> bool {
+        let entity = entity_system::try_get(entity_id).unwrap();
+        let cells: Vec<(u16, u16)> = simulation
+            .map
+            .iter_cells()
+            .filter(|(_, _, cell)| cell.contains_entity(&entity_id))
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        return (entity.map_x, entity.map_y) == (map_x, map_y) && cells == vec![(map_x, map_y)];
+    }
+
+    // a soldier of player 1 at (1, 1), with a creep further down the map
+    fn spawn_soldier() -> (Simulation, TEntityID, TEntityID) {
+        let soldier_id = add_prototype("test unit", |p| {
+            p.max_health_points = 10;
+            p.max_velocity = 10; // 100ms per cell
+            p.weapon.damage = 5;
+            p.weapon.range = 1;
+            p.weapon.fire_interval_millis = 100;
+        });
+        let creep_id = add_prototype("test creep", |p| p.max_health_points = 20);
+        let mut simulation = new_simulation(16, 16);
+        let soldier = spawn(&mut simulation, soldier_id, 1, 1);
+        let creep = spawn(&mut simulation, creep_id, 10, 4);
+        simulation.units.add_unit(&soldier, 1).unwrap();
+        return (simulation, soldier, creep);
+    }
+
+    #[test]
+    fn test_invalid_orders_are_rejected() {
+        let _world = TestWorld::new();
+        let (mut simulation, soldier, _) = spawn_soldier();
+        simulation.queue_command(2, move_units(soldier, 5, 5)); // someone else's unit
+        simulation.queue_command(1, move_units(soldier, 50, 5)); // off the map
+        let report = simulation.step_once();
+        assert!(report.commands.iter().all(|(_, r)| r.is_err()));
+        assert_eq!(
+            simulation.units.get_unit(&soldier).unwrap().order,
+            UnitOrders::Idle
+        );
+    }
+
+    #[test]
+    fn test_units_cannot_be_sold() {
+        let _world = TestWorld::new();
+        let (mut simulation, soldier, _) = spawn_soldier();
+        add_player(&mut simulation, 1, 0);
+        simulation.queue_command(1, PlayerCommands::SellStructure { entity_id: soldier });
+        let report = simulation.step_once();
+        assert!(report.commands[0].1.is_err());
+        assert!(simulation.units.get_unit(&soldier).is_some());
+        assert!(is_at(&simulation, soldier, 1, 1));
+        assert_eq!(simulation.economy.get_balance(1, ItemTypes::Gold), 0);
+    }
+
+    #[test]
+    fn test_move_to_a_cell() {
+        let _world = TestWorld::new();
+        let (mut simulation, soldier, _) = spawn_soldier();
+        simulation.queue_command(1, move_units(soldier, 5, 5));
+        simulation.step_once();
+        while simulation.units.get_unit(&soldier).unwrap().order != UnitOrders::Idle {
+            simulation.step_once();
+        }
+        assert!(is_at(&simulation, soldier, 5, 5));
+    }
+
+    #[test]
+    fn test_attack_chases_down_the_target() {
+        let _world = TestWorld::new();
+        let (mut simulation, soldier, creep) = spawn_soldier();
+        simulation.queue_command(
+            1,
+            PlayerCommands::AttackTarget {
+                entity_ids: vec![soldier],
+                target_id: creep,
+            },
+        );
+        let mut deaths = Vec::new();
+        for _ in 0..100 {
+            deaths.extend(simulation.step_once().death_events);
+        }
+        assert_eq!(deaths.len(), 1);
+        assert_eq!(deaths[0].entity.id, creep);
+        assert_eq!(deaths[0].killer, Some(soldier));
+        // then goes idle once it died
+        assert_eq!(
+            simulation.units.get_unit(&soldier).unwrap().order,
+            UnitOrders::Idle
+        );
+        assert!(is_at(&simulation, soldier, 10, 3)); // x first, then in range
+    }
+
+    // a wall down x=3 with a gap at the bottom (which the straight line does not find), and a
+    // cell at (8, 15) too crowded to take anyone else
+    fn build_obstacles() -> (Simulation, TPrototypeID) {
+        let unit_id = add_prototype("test unit", |p| {
+            p.max_health_points = 10;
+            p.max_velocity = 10;
+        });
+        let wall_id = add_prototype("test wall", |p| p.max_health_points = 10);
+        let mut simulation = new_simulation(16, 16);
+        for map_y in 0..15 {
+            spawn(&mut simulation, wall_id, 3, map_y);
+        }
+        while simulation.map.get_cell(8, 15).unwrap().layers.len() < 15 {
+            spawn(&mut simulation, unit_id, 8, 15);
+        }
+        return (simulation, unit_id);
+    }
+
+    // where the unit (of player 1) ends up, sent from one cell to the other
+    fn send_around_obstacles(from: (u16, u16), to: (u16, u16)) -> (Simulation, TEntityID) {
+        let (mut simulation, unit_id) = build_obstacles();
+        let entity_id = spawn(&mut simulation, unit_id, from.0, from.1);
+        simulation.units.add_unit(&entity_id, 1).unwrap();
+        simulation.queue_command(1, move_units(entity_id, to.0, to.1));
+        for _ in 0..60 {
+            simulation.step_once();
+        }
+        return (simulation, entity_id);
+    }
+
+    #[test]
+    fn test_units_wait_in_front_of_walls() {
+        let _world = TestWorld::new();
+        let (simulation, walled_in) = send_around_obstacles((1, 1), (5, 1));
+        assert!(is_at(&simulation, walled_in, 2, 1));
+    }
+
+    #[test]
+    fn test_units_go_along_the_other_axis_when_blocked() {
+        let _world = TestWorld::new();
+        let (simulation, going_around) = send_around_obstacles((1, 12), (5, 15));
+        assert!(is_at(&simulation, going_around, 5, 15)); // down along y once x was blocked
+    }
+
+    #[test]
+    fn test_units_stop_before_crowded_cells() {
+        let _world = TestWorld::new();
+        let (simulation, crowded_out) = send_around_obstacles((6, 15), (10, 15));
+        assert!(is_at(&simulation, crowded_out, 7, 15));
+    }
+}
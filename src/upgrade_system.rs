@@ -0,0 +1,306 @@
+// Tower upgrade trees, defined in data as edges between prototypes, i.e.
+//     Cannon Lv1 -> Cannon Lv2a (rapid) -> Cannon Lv3a
+//                -> Cannon Lv2b (heavy) -> Cannon Lv3b
+// Applying an upgrade swaps the entity over to the target prototype (stats and sprite sub-group)
+// NOTE: since selling refunds based on the current prototype's build_costs, the upgraded
+// prototypes' build_costs should reflect the accumulated worth of the tower
+use crate::economy_system::{Economy, ItemStack, TPlayerID, FULL_REFUND_PERCENT};
+use crate::entity_system::{self, TEntityID};
+use crate::prototype_system::{self, TPrototypeID};
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+pub type TUpgradeID = u16;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UpgradeDefinition {
+    pub id: TUpgradeID,
+    pub name: String,
+    pub from_prototype_id: TPrototypeID,
+    pub to_prototype_id: TPrototypeID,
+    pub costs: Vec<ItemStack>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct UpgradeTree {
+    upgrades: Vec<UpgradeDefinition>, // sorted by id
+}
+
+impl UpgradeTree {
+    pub fn new(upgrades: Vec<UpgradeDefinition>) -> Result<UpgradeTree, String> {
+        let mut sorted = upgrades;
+        sorted.sort_by_key(|u| u.id);
+        for pair in sorted.windows(2) {
+            if pair[0].id == pair[1].id {
+                return Err(format!(
+                    "upgradeID={} is defined more than once",
+                    pair[0].id
+                ));
+            }
+        }
+        for upgrade in sorted.iter() {
+            if upgrade.from_prototype_id == upgrade.to_prototype_id {
+                return Err(format!("upgradeID={} upgrades onto itself", upgrade.id));
+            }
+            // walk every branch above it, to make sure none loops back onto itself (i.e. 2a -> 1)
+            let mut visited: Vec<TPrototypeID> = Vec::new();
+            let mut to_visit = vec![upgrade.to_prototype_id];
+            while let Some(current) = to_visit.pop() {
+                if current == upgrade.from_prototype_id {
+                    return Err(format!(
+                        "upgradeID={} ('{}') creates a cycle",
+                        upgrade.id, upgrade.name
+                    ));
+                }
+                if visited.contains(&current) {
+                    continue;
+                }
+                visited.push(current);
+                for next in sorted.iter().filter(|u| u.from_prototype_id == current) {
+                    to_visit.push(next.to_prototype_id);
+                }
+            }
+        }
+        Ok(UpgradeTree { upgrades: sorted })
+    }
+
+    pub fn get_upgrade(self: &Self, upgrade_id: TUpgradeID) -> Option<&UpgradeDefinition> {
+        return match self.upgrades.binary_search_by(|u| u.id.cmp(&upgrade_id)) {
+            Ok(index) => Some(&self.upgrades[index]),
+            Err(_) => None,
+        };
+    }
+
+    pub fn get_upgrades_for_prototype(
+        self: &Self,
+        prototype_id: TPrototypeID,
+    ) -> Vec<UpgradeDefinition> {
+        return self
+            .upgrades
+            .iter()
+            .filter(|u| u.from_prototype_id == prototype_id)
+            .cloned()
+            .collect();
+    }
+
+    // upgrades that the entity can take next (regardless of whether it can be afforded)
+    pub fn get_available_upgrades(
+        self: &Self,
+        entity_id: &TEntityID,
+    ) -> Result<Vec<UpgradeDefinition>, String> {
+        let prototype_id = entity_system::modify(entity_id, |e| e.prototype_id)?;
+        return Ok(match prototype_id {
+            Some(id) => self.get_upgrades_for_prototype(id),
+            None => Vec::new(),
+        });
+    }
+
+    /// Charges the player and swaps the entity over to the upgraded prototype, keeping the
+    /// same ratio of health.  Either all of it happens, or nothing does
+    pub fn apply_upgrade(
+        self: &Self,
+        economy: &mut Economy,
+        player_id: TPlayerID,
+        entity_id: &TEntityID,
+        upgrade_id: TUpgradeID,
+    ) -> Result<TPrototypeID, String> {
+        let upgrade = match self.get_upgrade(upgrade_id) {
+            Some(u) => u,
+            None => return Err(format!("upgradeID={} does not exist", upgrade_id)),
+        };
+        let entity = entity_system::modify(entity_id, |e| *e)?;
+        if entity.prototype_id != Some(upgrade.from_prototype_id) {
+            return Err(format!(
+                "'{}' cannot be applied to entityID={} (prototypeID={:?})",
+                upgrade.name, entity_id, entity.prototype_id
+            ));
+        }
+        if entity.is_alive() == false {
+            return Err(format!("entityID={} is dead", entity_id));
+        }
+        let (from_prototype, to_prototype) = match (
+            prototype_system::get(&upgrade.from_prototype_id),
+            prototype_system::get(&upgrade.to_prototype_id),
+        ) {
+            (Some(from), Some(to)) => (from, to),
+            _ => {
+                return Err(format!(
+                    "'{}' references a prototype that does not exist",
+                    upgrade.name
+                ))
+            }
+        };
+        if (
+            from_prototype.footprint_width,
+            from_prototype.footprint_height,
+        ) != (to_prototype.footprint_width, to_prototype.footprint_height)
+        {
+            return Err(format!(
+                "'{}' changes the footprint, which is not supported",
+                upgrade.name
+            ));
+        }
+        economy.spend(player_id, &upgrade.costs)?;
+
+        let result = entity_system::modify(entity_id, |e| {
+            let health_ratio_numerator = e.health_points as u32;
+            let health_ratio_denominator = (e.max_health_points as u32).max(1);
+            to_prototype.apply_stats(e);
+            e.health_points = match from_prototype.max_health_points {
+                0 => to_prototype.max_health_points, // was indestructible
+                _ => (to_prototype.max_health_points as u32 * health_ratio_numerator
+                    / health_ratio_denominator)
+                    .max(1) as u16,
+            };
+        });
+        if let Err(e) = result {
+            // entity vanished in between, give the money back
+            economy.refund(player_id, &upgrade.costs, FULL_REFUND_PERCENT)?;
+            return Err(e);
+        }
+        return Ok(to_prototype.id);
+    }
+}
+
+pub fn serialize_upgrades_for_save(tree: &UpgradeTree) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match tree.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+// validates it (see UpgradeTree::new()) after deserializing
+pub fn deserialize_upgrades_for_load(bin_data: &Vec<u8>) -> Result<UpgradeTree, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    let tree: UpgradeTree =
+        rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string())?;
+    return UpgradeTree::new(tree.upgrades);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy_system::ItemTypes;
+    use crate::test_helpers::{add_prototype, TestWorld};
+
+    struct Cannons {
+        tree: UpgradeTree,
+        level_1: TPrototypeID,
+        level_2b: TPrototypeID,
+    }
+
+    // a level 1 cannon upgrades into either 2a (upgrade 1) or 2b (upgrade 2), for 50 gold
+    fn make_tree() -> Cannons {
+        let add_cannon = |hp: u16, sprites| {
+            add_prototype("test cannon", |p| {
+                p.sprites = sprites;
+                p.max_health_points = hp;
+                p.weapon.damage = hp / 10;
+            })
+        };
+        let level_1 = add_cannon(100, 1);
+        let level_2a = add_cannon(200, 2);
+        let level_2b = add_cannon(300, 3);
+        let make = |id, to| UpgradeDefinition {
+            id,
+            name: format!("upgrade {}", id),
+            from_prototype_id: level_1,
+            to_prototype_id: to,
+            costs: vec![ItemStack::new(ItemTypes::Gold, 50)],
+        };
+        let tree = UpgradeTree::new(vec![make(2, level_2b), make(1, level_2a)]).unwrap();
+        return Cannons {
+            tree,
+            level_1,
+            level_2b,
+        };
+    }
+
+    fn new_economy(gold: u32) -> Economy {
+        let mut economy = Economy::new();
+        economy.add_player(1).unwrap();
+        economy
+            .earn(1, &vec![ItemStack::new(ItemTypes::Gold, gold)])
+            .unwrap();
+        return economy;
+    }
+
+    #[test]
+    fn test_upgrade_trees_are_saved() {
+        let cannons = make_tree();
+        let bin = serialize_upgrades_for_save(&cannons.tree).unwrap();
+        assert_eq!(deserialize_upgrades_for_load(&bin).unwrap(), cannons.tree);
+    }
+
+    #[test]
+    fn test_branching_upgrade() {
+        let _world = TestWorld::new();
+        let cannons = make_tree();
+        let mut economy = new_economy(60);
+        let tower_id = prototype_system::spawn(&cannons.level_1, 0, 0).unwrap();
+        entity_system::modify(&tower_id, |e| e.health_points = 50).unwrap(); // half health
+        let available = cannons.tree.get_available_upgrades(&tower_id).unwrap();
+        assert_eq!(
+            available.iter().map(|u| u.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        assert_eq!(
+            cannons
+                .tree
+                .apply_upgrade(&mut economy, 1, &tower_id, 2)
+                .unwrap(),
+            cannons.level_2b
+        );
+        let tower = entity_system::modify(&tower_id, |e| *e).unwrap();
+        assert_eq!(tower.sprites, 3);
+        assert_eq!(tower.weapon.damage, 30);
+        assert_eq!(tower.health_points, 150); // still at half health
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 10);
+
+        // already a 2b, and there's nothing above it
+        assert!(cannons
+            .tree
+            .apply_upgrade(&mut economy, 1, &tower_id, 1)
+            .is_err());
+        assert!(cannons
+            .tree
+            .get_available_upgrades(&tower_id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_unaffordable_upgrade_leaves_it_as_is() {
+        let _world = TestWorld::new();
+        let cannons = make_tree();
+        let mut economy = new_economy(40);
+        let tower_id = prototype_system::spawn(&cannons.level_1, 1, 0).unwrap();
+        assert!(cannons
+            .tree
+            .apply_upgrade(&mut economy, 1, &tower_id, 1)
+            .is_err());
+        assert_eq!(
+            entity_system::modify(&tower_id, |e| e.prototype_id).unwrap(),
+            Some(cannons.level_1)
+        );
+        assert_eq!(economy.get_balance(1, ItemTypes::Gold), 40);
+    }
+
+    #[test]
+    fn test_cycle_is_rejected() {
+        let make = |id, from, to| UpgradeDefinition {
+            id,
+            name: format!("upgrade {}", id),
+            from_prototype_id: from,
+            to_prototype_id: to,
+            costs: Vec::new(),
+        };
+        assert!(UpgradeTree::new(vec![make(1, 1, 2), make(2, 2, 1)]).is_err());
+        assert!(UpgradeTree::new(vec![make(1, 1, 2), make(1, 2, 3)]).is_err());
+        assert!(UpgradeTree::new(vec![make(1, 1, 2), make(2, 2, 3)]).is_ok());
+    }
+}
@@ -0,0 +1,441 @@
+// Enemy waves: the definitions (which are data, so that it can be authored and persisted via
+// resource_system) and the scheduler which spawns them into entity_system and onto the Map
+use crate::diplomacy_system::CREEP_PLAYER_ID;
+use crate::entity_system::{self, TEntityID};
+use crate::map::Map;
+use crate::prototype_system::{self, TPrototypeID};
+use crate::random::Rng;
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpawnGroup {
+    pub prototype_id: TPrototypeID,
+    pub count: u16,
+    pub spacing_millis: u128, // time between each spawn of this group
+    pub delay_millis: u128,   // offset from the start of the wave
+    pub spawn_x: u16,
+    pub spawn_y: u16,
+    #[serde(default)] // 0 spawns exactly at (spawn_x, spawn_y)
+    pub spawn_radius: u16, // else each spawn picks a random cell within this many cells of it
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct WaveDefinition {
+    pub delay_millis: u128, // countdown until this wave starts, counted from the start of the previous wave (or start of the game)
+    pub early_call_bonus_per_second: u32, // bonus for each (whole) second skipped when called early
+    pub groups: Vec<SpawnGroup>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum WaveEvents {
+    WaveStarted {
+        wave_index: usize,
+        early_call_bonus: u32, // 0 unless it was called early
+    },
+    EntitySpawned {
+        wave_index: usize,
+        entity_id: TEntityID,
+        map_x: u16,
+        map_y: u16,
+    },
+    SpawnFailed {
+        wave_index: usize,
+        reason: String, // i.e. missing or indestructible prototype; skipped, not retried
+    },
+    WaveCleared {
+        wave_index: usize,
+    },
+    AllWavesCleared,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct ActiveWave {
+    wave_index: usize,
+    elapsed_millis: u128,
+    spawned_per_group: Vec<u16>, // number of spawns done (or skipped) per SpawnGroup
+    alive: Vec<TEntityID>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct WaveScheduler {
+    waves: Vec<WaveDefinition>,
+    next_wave_index: usize,
+    next_wave_countdown_millis: u128,
+    active_waves: Vec<ActiveWave>, // more than one wave can be active if the player keeps calling them early
+    cleared_waves: Vec<usize>,
+    pending_events: Vec<WaveEvents>, // events that happened outside of update(), i.e. call_next_wave_early()
+}
+
+impl WaveScheduler {
+    pub fn new(waves: Vec<WaveDefinition>) -> Result<WaveScheduler, String> {
+        for (wave_index, wave) in waves.iter().enumerate() {
+            if wave.groups.is_empty() {
+                return Err(format!("Wave {} has no spawn groups", wave_index));
+            }
+            if let Some(group) = wave.groups.iter().find(|g| g.count == 0) {
+                return Err(format!(
+                    "Wave {} spawns 0 of prototypeID={}",
+                    wave_index, group.prototype_id
+                ));
+            }
+        }
+        let first_delay = match waves.first() {
+            Some(wave) => wave.delay_millis,
+            None => 0,
+        };
+        Ok(WaveScheduler {
+            waves,
+            next_wave_index: 0,
+            next_wave_countdown_millis: first_delay,
+            active_waves: Vec::new(),
+            cleared_waves: Vec::new(),
+            pending_events: Vec::new(),
+        })
+    }
+
+    pub fn get_wave_count(self: &Self) -> usize {
+        return self.waves.len();
+    }
+    // None when there are no more waves to come
+    pub fn next_wave_index(self: &Self) -> Option<usize> {
+        return match self.next_wave_index < self.waves.len() {
+            true => Some(self.next_wave_index),
+            false => None,
+        };
+    }
+    pub fn next_wave_in_millis(self: &Self) -> Option<u128> {
+        return self
+            .next_wave_index()
+            .map(|_| self.next_wave_countdown_millis);
+    }
+    // rounded up, so that it'll say "1" until the very last moment
+    pub fn next_wave_in_seconds(self: &Self) -> Option<u128> {
+        return self.next_wave_in_millis().map(|ms| (ms + 999) / 1000);
+    }
+    pub fn is_wave_cleared(self: &Self, wave_index: usize) -> bool {
+        return self.cleared_waves.contains(&wave_index);
+    }
+    pub fn is_all_waves_cleared(self: &Self) -> bool {
+        return self.cleared_waves.len() == self.waves.len();
+    }
+    // entities of the waves that are still on the field
+    pub fn get_alive_entities(self: &Self) -> Vec<TEntityID> {
+        return self
+            .active_waves
+            .iter()
+            .flat_map(|wave| wave.alive.iter().copied())
+            .collect();
+    }
+
+    // starts the next wave right away, returning the bonus earned for the time skipped
+    pub fn call_next_wave_early(self: &mut Self) -> Result<u32, String> {
+        let wave_index = match self.next_wave_index() {
+            Some(i) => i,
+            None => return Err("There are no more waves to call".to_owned()),
+        };
+        let skipped_seconds = (self.next_wave_countdown_millis / 1000) as u32;
+        let bonus =
+            skipped_seconds.saturating_mul(self.waves[wave_index].early_call_bonus_per_second);
+        self.start_next_wave(0, bonus);
+        return Ok(bonus);
+    }
+
+    fn start_next_wave(self: &mut Self, elapsed_millis: u128, early_call_bonus: u32) {
+        let wave_index = self.next_wave_index;
+        self.active_waves.push(ActiveWave {
+            wave_index,
+            elapsed_millis,
+            spawned_per_group: vec![0; self.waves[wave_index].groups.len()],
+            alive: Vec::new(),
+        });
+        self.pending_events.push(WaveEvents::WaveStarted {
+            wave_index,
+            early_call_bonus,
+        });
+        self.next_wave_index += 1;
+        self.next_wave_countdown_millis = match self.waves.get(self.next_wave_index) {
+            Some(next_wave) => next_wave.delay_millis,
+            None => 0,
+        };
+    }
+
+    // spawns whatever is due for the given active wave; if the spawn point is full, it stops and
+    // retries on the next update
+    fn spawn_due(
+        self: &mut Self,
+        active_index: usize,
+        map: &mut Map,
+        rng: &mut Rng,
+        events: &mut Vec<WaveEvents>,
+    ) {
+        let wave_index = self.active_waves[active_index].wave_index;
+        for (group_index, group) in self.waves[wave_index].groups.iter().enumerate() {
+            let active = &mut self.active_waves[active_index];
+            while active.spawned_per_group[group_index] < group.count {
+                let due_millis = group.delay_millis
+                    + group.spacing_millis * active.spawned_per_group[group_index] as u128;
+                if due_millis > active.elapsed_millis {
+                    break;
+                }
+                // only roll when there is something to roll for, so that radius 0 groups do not
+                // shift the stream for everyone else
+                let (spawn_x, spawn_y) = match group.spawn_radius {
+                    0 => (group.spawn_x, group.spawn_y),
+                    radius => {
+                        let low_x = group.spawn_x.saturating_sub(radius);
+                        let low_y = group.spawn_y.saturating_sub(radius);
+                        let high_x = group
+                            .spawn_x
+                            .saturating_add(radius)
+                            .min(map.get_width().saturating_sub(1));
+                        let high_y = group
+                            .spawn_y
+                            .saturating_add(radius)
+                            .min(map.get_height().saturating_sub(1));
+                        (
+                            rng.gen_range(low_x as u32, high_x as u32 + 1) as u16,
+                            rng.gen_range(low_y as u32, high_y as u32 + 1) as u16,
+                        )
+                    }
+                };
+                // one that cannot die would keep its wave (and so all waves) from ever clearing
+                let spawned = match prototype_system::get(&group.prototype_id) {
+                    Some(prototype) if prototype.max_health_points == 0 => Err(format!(
+                        "prototypeID={} is indestructible",
+                        group.prototype_id
+                    )),
+                    _ => prototype_system::spawn(&group.prototype_id, spawn_x, spawn_y),
+                };
+                let entity_id = match spawned {
+                    Ok(id) => id,
+                    Err(e) => {
+                        events.push(WaveEvents::SpawnFailed {
+                            wave_index,
+                            reason: e,
+                        });
+                        active.spawned_per_group[group_index] += 1; // skip it
+                        continue;
+                    }
+                };
+                let _ = entity_system::modify(&entity_id, |e| e.owner = CREEP_PLAYER_ID);
+                if map.place_entity(spawn_x, spawn_y, entity_id).is_err() {
+                    // spawn point is crowded, undo and retry on next update
+                    let _ = entity_system::remove(&entity_id);
+                    break;
+                }
+                active.spawned_per_group[group_index] += 1;
+                active.alive.push(entity_id);
+                events.push(WaveEvents::EntitySpawned {
+                    wave_index,
+                    entity_id,
+                    map_x: spawn_x,
+                    map_y: spawn_y,
+                });
+            }
+        }
+    }
+
+    // See: entity_system::update(), should be called with the same delta; rng should be the
+    // simulation's RngStreamTypes::Spawning stream
+    pub fn update(
+        self: &mut Self,
+        last_frame_delta_millis: u128,
+        map: &mut Map,
+        rng: &mut Rng,
+    ) -> Vec<WaveEvents> {
+        for active in self.active_waves.iter_mut() {
+            active.elapsed_millis += last_frame_delta_millis;
+        }
+        let mut remaining_millis = last_frame_delta_millis;
+        while self.next_wave_index().is_some() {
+            if self.next_wave_countdown_millis > remaining_millis {
+                self.next_wave_countdown_millis -= remaining_millis;
+                break;
+            }
+            // the part of the frame after the countdown hit 0 counts towards the new wave
+            remaining_millis -= self.next_wave_countdown_millis;
+            self.start_next_wave(remaining_millis, 0);
+        }
+
+        let mut events = std::mem::take(&mut self.pending_events);
+        for active_index in 0..self.active_waves.len() {
+            self.spawn_due(active_index, map, rng, &mut events);
+        }
+
+        // see who is still alive, and if all spawns are done and everyone is dead, it's cleared
+        let mut newly_cleared: Vec<usize> = Vec::new();
+        for active in self.active_waves.iter_mut() {
+            active.alive.retain(|entity_id| {
+                entity_system::modify(entity_id, |entity| entity.is_alive()).unwrap_or(false)
+            });
+            let groups = &self.waves[active.wave_index].groups;
+            let is_done_spawning = groups
+                .iter()
+                .zip(active.spawned_per_group.iter())
+                .all(|(group, spawned)| *spawned >= group.count);
+            if is_done_spawning && active.alive.is_empty() {
+                newly_cleared.push(active.wave_index);
+            }
+        }
+        for wave_index in newly_cleared {
+            self.active_waves
+                .retain(|active| active.wave_index != wave_index);
+            self.cleared_waves.push(wave_index);
+            events.push(WaveEvents::WaveCleared { wave_index });
+            if self.is_all_waves_cleared() {
+                events.push(WaveEvents::AllWavesCleared);
+            }
+        }
+        return events;
+    }
+}
+
+// NOTE: same as Map, no I/O here; use resource_system (i.e. Resource::read_data()) to persist it
+pub fn serialize_waves_for_save(waves: &Vec<WaveDefinition>) -> Result<Vec<u8>, String> {
+    let mut dest_buffer = Vec::new();
+    return match waves.serialize(&mut rmp_serde::Serializer::new(&mut dest_buffer)) {
+        Ok(_) => Ok(dest_buffer),
+        Err(e) => Err(e.to_string()),
+    };
+}
+
+pub fn deserialize_waves_for_load(bin_data: &Vec<u8>) -> Result<Vec<WaveDefinition>, String> {
+    if bin_data.len() == 0 {
+        return Err("bin_data buffer is 0 bytes".to_owned());
+    }
+    return rmp_serde::from_slice(bin_data.as_slice()).map_err(|e| e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage_system::{self, Damage, DamageTypes};
+    use crate::test_helpers::{add_prototype, TestWorld};
+
+    // 3 grunts at (1, 1) after a second, then one around (2, 2) 10 seconds later
+    fn make_test_waves() -> Vec<WaveDefinition> {
+        let grunt_id = add_prototype("test grunt", |p| p.max_health_points = 10);
+        return vec![
+            WaveDefinition {
+                delay_millis: 1000,
+                early_call_bonus_per_second: 0,
+                groups: vec![SpawnGroup {
+                    prototype_id: grunt_id,
+                    count: 3,
+                    spacing_millis: 500,
+                    delay_millis: 0,
+                    spawn_x: 1,
+                    spawn_y: 1,
+                    spawn_radius: 0,
+                }],
+            },
+            WaveDefinition {
+                delay_millis: 10000,
+                early_call_bonus_per_second: 5,
+                groups: vec![SpawnGroup {
+                    prototype_id: grunt_id,
+                    count: 1,
+                    spacing_millis: 0,
+                    delay_millis: 0,
+                    spawn_x: 2,
+                    spawn_y: 2,
+                    spawn_radius: 1,
+                }],
+            },
+        ];
+    }
+
+    // the first wave, all spawned by now
+    fn start_first_wave() -> (WaveScheduler, Map, Rng) {
+        let mut the_map = Map::create(8, 8).unwrap();
+        let mut scheduler = WaveScheduler::new(make_test_waves()).unwrap();
+        let mut rng = Rng::new(7);
+        scheduler.update(2000, &mut the_map, &mut rng);
+        return (scheduler, the_map, rng);
+    }
+
+    #[test]
+    fn test_waves_are_saved() {
+        let waves = make_test_waves();
+        let bin = serialize_waves_for_save(&waves).unwrap();
+        assert_eq!(deserialize_waves_for_load(&bin).unwrap(), waves);
+    }
+
+    #[test]
+    fn test_spawn_timing() {
+        let _world = TestWorld::new();
+        let mut the_map = Map::create(8, 8).unwrap();
+        let mut scheduler = WaveScheduler::new(make_test_waves()).unwrap();
+        let mut rng = Rng::new(7);
+        assert_eq!(scheduler.next_wave_in_seconds(), Some(1));
+
+        let events = scheduler.update(1250, &mut the_map, &mut rng);
+        assert!(events.contains(&WaveEvents::WaveStarted {
+            wave_index: 0,
+            early_call_bonus: 0
+        }));
+        assert_eq!(scheduler.get_alive_entities().len(), 1); // 250ms into the wave, 2nd is due at 500ms
+        scheduler.update(750, &mut the_map, &mut rng);
+        assert_eq!(scheduler.get_alive_entities().len(), 3);
+        assert_eq!(the_map.get_cell(1, 1).unwrap().layers.len(), 3);
+    }
+
+    #[test]
+    fn test_call_next_wave_early() {
+        let _world = TestWorld::new();
+        let (mut scheduler, mut the_map, mut rng) = start_first_wave();
+        // 1 second has passed since wave 0 started, so the bonus is for 9 seconds
+        assert_eq!(scheduler.next_wave_in_seconds(), Some(9));
+        assert_eq!(scheduler.call_next_wave_early().unwrap(), 9 * 5);
+        assert!(scheduler.call_next_wave_early().is_err()); // already called
+        let events = scheduler.update(0, &mut the_map, &mut rng);
+        assert!(events.contains(&WaveEvents::WaveStarted {
+            wave_index: 1,
+            early_call_bonus: 45
+        }));
+        // spawn_radius 1 around (2, 2)
+        assert!(events.iter().any(|event| match event {
+            WaveEvents::EntitySpawned { map_x, map_y, .. } => {
+                (1..=3).contains(map_x) && (1..=3).contains(map_y)
+            }
+            _ => false,
+        }));
+        assert!(scheduler.call_next_wave_early().is_err()); // no waves left
+    }
+
+    #[test]
+    fn test_waves_clear_once_all_died() {
+        let _world = TestWorld::new();
+        let (mut scheduler, mut the_map, mut rng) = start_first_wave();
+        scheduler.call_next_wave_early().unwrap();
+        scheduler.update(0, &mut the_map, &mut rng);
+        for entity_id in scheduler.get_alive_entities() {
+            damage_system::apply_damage(&entity_id, &Damage::new(10, DamageTypes::Magic, None))
+                .unwrap();
+        }
+        let events = scheduler.update(0, &mut the_map, &mut rng);
+        assert!(events.contains(&WaveEvents::WaveCleared { wave_index: 0 }));
+        assert!(events.contains(&WaveEvents::WaveCleared { wave_index: 1 }));
+        assert!(events.contains(&WaveEvents::AllWavesCleared));
+        assert!(scheduler.is_all_waves_cleared());
+    }
+
+    #[test]
+    fn test_indestructible_spawns_fail() {
+        let _world = TestWorld::new();
+        let mut waves = make_test_waves();
+        waves.truncate(1);
+        waves[0].groups[0].prototype_id = add_prototype("test wall", |p| p.max_health_points = 0);
+        let mut the_map = Map::create(8, 8).unwrap();
+        let mut scheduler = WaveScheduler::new(waves).unwrap();
+        let mut rng = Rng::new(7);
+        let events = scheduler.update(2000, &mut the_map, &mut rng);
+        assert!(events.iter().any(|event| match event {
+            WaveEvents::SpawnFailed { reason, .. } => reason.contains("indestructible"),
+            _ => false,
+        }));
+        assert!(the_map.get_cell(1, 1).unwrap().layers.is_empty());
+        assert!(events.contains(&WaveEvents::AllWavesCleared));
+    }
+}